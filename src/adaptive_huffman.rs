@@ -0,0 +1,381 @@
+// Adaptive Huffman coding (FGK), for one-pass streaming. Both sides start
+// from the same empty tree and update it after every symbol, so no table is
+// stored and each byte is coded as soon as it arrives, where `huffman.rs`
+// has to count a whole block first. A byte not seen before is sent as the
+// code of the NYT ("not yet transmitted") leaf followed by 9 literal bits.
+//
+// body:   coded symbols, MSB-first, ended by END and padded to a byte
+// stream: [ADAPTIVE_MAGIC] then a body; FLUSH pads to a byte mid-stream, so
+//         everything written before a `flush` can be decoded
+use std::io::{self, Read, Write};
+
+use crate::{Result, RsZipError};
+
+// read as a block length by HuffmanDecoder this is over 1 GiB, far past
+// STREAM_BLOCK, so the two stream kinds can't be confused
+pub const ADAPTIVE_MAGIC: [u8; 4] = *b"RSZA";
+
+const END: usize = 256;
+const FLUSH: usize = 257;
+const SYMBOLS: usize = 258;
+const NYT: usize = SYMBOLS;
+const LITERAL_BITS: u32 = 9;
+// every symbol's leaf, NYT's, and the internal nodes above them
+const NODES: usize = 2 * SYMBOLS + 1;
+const ROOT: usize = NODES - 1;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Leaf(usize),
+    Internal(usize, usize),
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    weight: u64,
+    parent: usize,
+    kind: Kind,
+}
+
+// Nodes live at their FGK order number, the root highest, and weights never
+// decrease along it (the sibling property). Swapping two slots moves whole
+// subtrees, so only parent links and the leaf index need fixing up.
+struct Model {
+    nodes: Vec<Node>,
+    leaves: [Option<usize>; SYMBOLS],
+    nyt: usize,
+    // scratch for a leaf's path, which is found from the leaf upwards
+    path: Vec<bool>,
+}
+
+impl Model {
+    fn new() -> Model {
+        let mut nodes = vec![Node { weight: 0, parent: ROOT, kind: Kind::Leaf(NYT) }; NODES];
+        nodes[ROOT].parent = ROOT;
+        Model { nodes, leaves: [None; SYMBOLS], nyt: ROOT, path: Vec::new() }
+    }
+
+    fn encode(&mut self, symbol: usize, out: &mut BitSink) {
+        let leaf = self.leaves[symbol].unwrap_or(self.nyt);
+        self.path.clear();
+        let mut at = leaf;
+        while at != ROOT {
+            let parent = self.nodes[at].parent;
+            self.path.push(matches!(self.nodes[parent].kind, Kind::Internal(_, right) if right == at));
+            at = parent;
+        }
+        for &bit in self.path.iter().rev() {
+            out.bit(bit);
+        }
+        if self.leaves[symbol].is_none() {
+            out.bits(symbol as u32, LITERAL_BITS);
+        }
+        self.update(symbol);
+    }
+
+    fn decode<R: Read>(&mut self, input: &mut BitSource<R>) -> Result<usize> {
+        let mut at = ROOT;
+        let symbol = loop {
+            match self.nodes[at].kind {
+                Kind::Internal(left, right) => at = if input.bit()? { right } else { left },
+                Kind::Leaf(NYT) => {
+                    let symbol = input.bits(LITERAL_BITS)? as usize;
+                    if symbol >= SYMBOLS || self.leaves[symbol].is_some() {
+                        return Err(RsZipError::CorruptData("adaptive huffman literal"));
+                    }
+                    break symbol;
+                }
+                Kind::Leaf(symbol) => break symbol,
+            }
+        };
+        self.update(symbol);
+        Ok(symbol)
+    }
+
+    fn update(&mut self, symbol: usize) {
+        let mut at = match self.leaves[symbol] {
+            Some(leaf) => leaf,
+            None => {
+                // NYT splits into a node over the new NYT and the new leaf
+                let split = self.nyt;
+                self.nodes[split - 1] = Node { weight: 0, parent: split, kind: Kind::Leaf(symbol) };
+                self.nodes[split - 2] = Node { weight: 0, parent: split, kind: Kind::Leaf(NYT) };
+                self.nodes[split].kind = Kind::Internal(split - 2, split - 1);
+                self.leaves[symbol] = Some(split - 1);
+                self.nyt = split - 2;
+                split - 1
+            }
+        };
+        loop {
+            // the highest-numbered node of the same weight takes the increment
+            let weight = self.nodes[at].weight;
+            let mut leader = at;
+            while leader < ROOT && self.nodes[leader + 1].weight == weight {
+                leader += 1;
+            }
+            if leader != at && leader != self.nodes[at].parent {
+                self.swap(at, leader);
+                at = leader;
+            }
+            self.nodes[at].weight += 1;
+            if at == ROOT {
+                return;
+            }
+            at = self.nodes[at].parent;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        let (parent_a, parent_b) = (self.nodes[a].parent, self.nodes[b].parent);
+        self.nodes.swap(a, b);
+        (self.nodes[a].parent, self.nodes[b].parent) = (parent_a, parent_b);
+        for at in [a, b] {
+            match self.nodes[at].kind {
+                Kind::Leaf(NYT) => self.nyt = at,
+                Kind::Leaf(symbol) => self.leaves[symbol] = Some(at),
+                Kind::Internal(left, right) => {
+                    self.nodes[left].parent = at;
+                    self.nodes[right].parent = at;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct BitSink {
+    bytes: Vec<u8>,
+    acc: u8,
+    filled: u32,
+}
+
+impl BitSink {
+    fn bit(&mut self, bit: bool) {
+        self.acc = self.acc << 1 | bit as u8;
+        self.filled += 1;
+        if self.filled == 8 {
+            self.bytes.push(self.acc);
+            (self.acc, self.filled) = (0, 0);
+        }
+    }
+
+    fn bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.bit(value >> i & 1 != 0);
+        }
+    }
+
+    fn align(&mut self) {
+        while self.filled != 0 {
+            self.bit(false);
+        }
+    }
+}
+
+struct BitSource<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    acc: u8,
+    left: u32,
+}
+
+impl<R: Read> BitSource<R> {
+    fn new(inner: R) -> BitSource<R> {
+        BitSource { inner, buf: Vec::new(), pos: 0, acc: 0, left: 0 }
+    }
+
+    fn bit(&mut self) -> Result<bool> {
+        if self.left == 0 {
+            if self.pos == self.buf.len() {
+                self.buf.resize(64 * 1024, 0);
+                let n = self.inner.read(&mut self.buf)?;
+                self.buf.truncate(n);
+                self.pos = 0;
+                if n == 0 {
+                    return Err(RsZipError::Truncated("adaptive huffman bits"));
+                }
+            }
+            (self.acc, self.left) = (self.buf[self.pos], 8);
+            self.pos += 1;
+        }
+        self.left -= 1;
+        Ok(self.acc >> self.left & 1 != 0)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.left = 0;
+    }
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let (mut model, mut out) = (Model::new(), BitSink::default());
+    for &b in data {
+        model.encode(b as usize, &mut out);
+    }
+    model.encode(END, &mut out);
+    out.align();
+    out.bytes
+}
+
+// `len` is the decoded length from the header
+pub fn decompress(body: &[u8], len: usize) -> Result<Vec<u8>> {
+    let (mut model, mut input) = (Model::new(), BitSource::new(body));
+    let mut out = Vec::new();
+    loop {
+        match model.decode(&mut input)? {
+            END => break,
+            FLUSH => input.align(),
+            byte if out.len() < len => out.push(byte as u8),
+            _ => return Err(RsZipError::CorruptData("adaptive huffman codes run past the length")),
+        }
+    }
+    if out.len() < len {
+        return Err(RsZipError::Truncated("adaptive huffman codes"));
+    }
+    Ok(out)
+}
+
+// Streaming form: bytes are coded as they are written and reach `inner`
+// 64K at a time, so memory stays flat. `flush` pads to a byte boundary and
+// passes everything on; `finish` writes END and must be called.
+pub struct AdaptiveHuffmanEncoder<W: Write> {
+    inner: W,
+    model: Model,
+    out: BitSink,
+}
+
+impl<W: Write> AdaptiveHuffmanEncoder<W> {
+    pub fn new(inner: W) -> AdaptiveHuffmanEncoder<W> {
+        let out = BitSink { bytes: ADAPTIVE_MAGIC.to_vec(), ..Default::default() };
+        AdaptiveHuffmanEncoder { inner, model: Model::new(), out }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn pass_on(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out.bytes)?;
+        self.out.bytes.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.model.encode(END, &mut self.out);
+        self.out.align();
+        self.pass_on()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for AdaptiveHuffmanEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        for &b in data {
+            self.model.encode(b as usize, &mut self.out);
+        }
+        if self.out.bytes.len() >= 64 * 1024 {
+            self.pass_on()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.model.encode(FLUSH, &mut self.out);
+        self.out.align();
+        self.pass_on()?;
+        self.inner.flush()
+    }
+}
+
+pub struct AdaptiveHuffmanDecoder<R: Read> {
+    input: BitSource<R>,
+    model: Model,
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> AdaptiveHuffmanDecoder<R> {
+    pub fn new(inner: R) -> AdaptiveHuffmanDecoder<R> {
+        AdaptiveHuffmanDecoder { input: BitSource::new(inner), model: Model::new(), started: false, done: false }
+    }
+}
+
+impl<R: Read> Read for AdaptiveHuffmanDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            if self.input.bits(32)?.to_be_bytes() != ADAPTIVE_MAGIC {
+                return Err(RsZipError::CorruptHeader("not an adaptive huffman stream".into()).into());
+            }
+            self.started = true;
+        }
+        let mut n = 0;
+        while n < out.len() && !self.done {
+            match self.model.decode(&mut self.input)? {
+                END => self.done = true,
+                // stop at a flush point rather than wait on input that may
+                // not have been sent yet
+                FLUSH if n > 0 => {
+                    self.input.align();
+                    break;
+                }
+                FLUSH => self.input.align(),
+                byte => {
+                    out[n] = byte as u8;
+                    n += 1;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{CODEC_ADAPTIVE, CODEC_LZ77};
+    use crate::lz77::{Lz77Decoder, Lz77Encoder};
+    use crate::{compress, decompress, testdata, CompressOptions, Entropy};
+
+    #[test]
+    fn streams_in_one_pass() {
+        let text = testdata::text(20_000);
+        let mut encoder = AdaptiveHuffmanEncoder::new(Vec::new());
+        encoder.write_all(&text[..1000]).unwrap();
+        encoder.flush().unwrap();
+        // what a peer has after the flush decodes without the rest
+        let mut partial = vec![0; 1000];
+        AdaptiveHuffmanDecoder::new(&encoder.get_ref()[..]).read_exact(&mut partial).unwrap();
+        assert_eq!(partial, text[..1000]);
+        encoder.write_all(&text[1000..]).unwrap();
+        let stream = encoder.finish().unwrap();
+        let mut back = Vec::new();
+        AdaptiveHuffmanDecoder::new(&stream[..]).read_to_end(&mut back).unwrap();
+        assert_eq!(back, text);
+        assert!(AdaptiveHuffmanDecoder::new(&stream[..stream.len() - 1]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn codes_lz77_streams_and_whole_files() {
+        let text = testdata::text(20_000);
+        // as `--stream --entropy adaptive` does
+        let mut lz = Lz77Encoder::new(AdaptiveHuffmanEncoder::new(Vec::new()), 3);
+        lz.write_all(&text).unwrap();
+        let piped = lz.finish().unwrap().finish().unwrap();
+        let mut unpiped = Vec::new();
+        Lz77Decoder::new(AdaptiveHuffmanDecoder::new(&piped[..])).read_to_end(&mut unpiped).unwrap();
+        assert_eq!(unpiped, text);
+        let file = compress(&text, &CompressOptions { entropy: Entropy::Adaptive, ..Default::default() }).unwrap();
+        assert_eq!(file[5], CODEC_LZ77 | CODEC_ADAPTIVE);
+        assert_eq!(decompress(&file, None).unwrap(), text);
+    }
+}
@@ -0,0 +1,1037 @@
+// Multi-file container: every entry's bytes back to back, each compressed
+// on its own so one entry can be listed or pulled out without decoding the
+// rest, then the file table. With the table last, `update` adds, removes
+// and renames entries by appending a new table, after the data of any new
+// entries, leaving what is already there alone.
+//
+// archive: ["RSZA"][APPENDABLE u32][data][table][table_offset u32]["RSZT"]
+// table:   [count u32][entry...], then [chunk_count u32][chunk...] when any file is chunked
+// entry:   [path_len u16][path][kind u8][method u8][size u32][packed u32][offset u32]
+//          then, when kind has KIND_META, [mtime u64][mode u32][uid u32][gid u32],
+//          when it has KIND_ENCRYPTED, [iterations u32][salt 16][nonce 12][check 4]
+//          and, for the chunked method, [count u32][chunk SHA-256 32...] in file order
+// chunk:   [sha256 32][method u8][size u32][packed u32][offset u32][refs u32]
+// `offset` counts from the start of the data area and `table_offset` from the
+// start of the file; directories and chunked files have no data of their
+// own, chunks follow the entries', and symlinks store their target as it.
+// Older archives have the table
+// up front, ["RSZA"][table][data], and still read.
+// An encrypted entry's data is its packed bytes sealed with ChaCha20-Poly1305
+// under a key stretched from the password with PBKDF2 and the entry's salt;
+// the same derivation yields `check`, so a wrong password is told apart from
+// damaged data without decrypting anything.
+// Chunked files (`add_file_dedup`) are cut where a gear hash of the content
+// says, so the cut points survive insertions, and each chunk is stored once
+// however many files hold it; `refs` counts the references to it, and a
+// chunk nothing refers to any more is left out when the table is written.
+// Paths are relative, '/'-separated and never contain `.`, `..` or a drive,
+// so joining one onto an extraction directory can't land outside it.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::checksum::GearHash;
+use crate::crypto::{
+    chacha20_poly1305_open, chacha20_poly1305_seal, ct_eq, hex_lower, pbkdf2_sha256, random_bytes, sha256, SecretKey,
+    MAX_KDF_ITERATIONS,
+};
+use crate::format::{put_u16, put_u32, u32_at, Reader};
+use crate::{compress, decompress, decompress_range, len_u32, CompressOptions, Result, RsZipError};
+
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"RSZA";
+const TABLE_MAGIC: &[u8; 4] = b"RSZT";
+// in place of an up-front table's count, which can't reach it
+const APPENDABLE: u32 = u32::MAX;
+const DATA_START: usize = 8;
+// [table_offset u32][TABLE_MAGIC]
+const TRAILER_LEN: usize = 8;
+
+// set in an entry's kind byte when its metadata follows
+const KIND_META: u8 = 0x80;
+const KIND_ENCRYPTED: u8 = 0x40;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const CHECK_LEN: usize = 4;
+
+const TRUNCATED_TABLE: RsZipError = RsZipError::Truncated("archive table");
+
+pub const CHUNK_MIN: usize = 16 * 1024;
+pub const CHUNK_MAX: usize = 256 * 1024;
+// top 16 bits of the gear hash zero: ~64 KiB average past CHUNK_MIN
+const CHUNK_MASK: u64 = 0xffff << 48;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    // recorded as a link rather than followed, so a tree's links survive
+    // and one pointing back up can't loop
+    Symlink,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    // kept as-is when compressing wouldn't shrink it
+    Stored,
+    // a complete rszip file, see format.rs
+    Compressed,
+    // no data of its own, only the chunks listed in the table
+    Chunked,
+}
+
+// What extraction restores besides the bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntryMeta {
+    // seconds since the Unix epoch
+    pub mtime: u64,
+    // permission bits, as in tar
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Sealing {
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    check: [u8; CHECK_LEN],
+}
+
+impl Sealing {
+    // the entry key, or BadKey when `password` isn't the one it was sealed with
+    fn key(&self, password: &[u8]) -> Result<SecretKey> {
+        let derived = pbkdf2_sha256(password, &self.salt, self.iterations, 32 + CHECK_LEN);
+        if !ct_eq(&derived.as_bytes()[32..], &self.check) {
+            return Err(RsZipError::BadKey("wrong password for encrypted archive entry"));
+        }
+        Ok(SecretKey::new(derived.as_bytes()[..32].to_vec()))
+    }
+}
+
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub method: Method,
+    // original length
+    pub size: u32,
+    // where the entry sits in the data area; set when read or written
+    pub offset: u32,
+    // None for entries added without it
+    pub meta: Option<EntryMeta>,
+    sealing: Option<Sealing>,
+    packed: Vec<u8>,
+    packed_len: usize,
+    // the SHA-256 of each chunk of a chunked file, in order
+    chunks: Vec<[u8; 32]>,
+}
+
+impl Entry {
+    fn new(path: String, kind: EntryKind, method: Method, size: u32, packed: Vec<u8>) -> Entry {
+        let packed_len = packed.len();
+        Entry { path, kind, method, size, offset: 0, meta: None, sealing: None, packed, packed_len, chunks: Vec::new() }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.sealing.is_some()
+    }
+
+    pub fn packed_len(&self) -> usize {
+        self.packed_len
+    }
+
+    // false for the entries `update` leaves in the file
+    fn loaded(&self) -> bool {
+        self.packed.len() == self.packed_len
+    }
+
+    fn packed(&self) -> Result<&[u8]> {
+        if !self.loaded() {
+            return Err(RsZipError::Unsupported(format!("reading {} while updating the archive", self.path)));
+        }
+        Ok(&self.packed)
+    }
+}
+
+// Part of one or more chunked files, stored once.
+pub struct Chunk {
+    pub hash: [u8; 32],
+    method: Method,
+    // original length
+    pub size: u32,
+    offset: u32,
+    // how many times the table lists it
+    pub refs: u32,
+    packed: Vec<u8>,
+    packed_len: usize,
+}
+
+impl Chunk {
+    pub fn packed_len(&self) -> usize {
+        self.packed_len
+    }
+
+    fn loaded(&self) -> bool {
+        self.packed.len() == self.packed_len
+    }
+
+    fn packed(&self) -> Result<&[u8]> {
+        if !self.loaded() {
+            let msg = format!("reading chunk {} while updating the archive", hex_lower(&self.hash[..8]));
+            return Err(RsZipError::Unsupported(msg));
+        }
+        Ok(&self.packed)
+    }
+
+    // the original bytes, checked against the hash
+    fn unpack(&self) -> Result<Vec<u8>> {
+        let data = match self.method {
+            Method::Compressed => decompress(self.packed()?, None)?,
+            _ => self.packed()?.to_vec(),
+        };
+        if data.len() != self.size as usize {
+            return Err(RsZipError::CorruptData("archive chunk size"));
+        }
+        if sha256(&data) != self.hash {
+            return Err(RsZipError::CorruptData("archive chunk hash"));
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Default)]
+pub struct Archive {
+    entries: Vec<Entry>,
+    chunks: Vec<Chunk>,
+    // each chunk's place in `chunks`
+    chunk_index: HashMap<[u8; 32], usize>,
+}
+
+// Content-defined pieces of `data`, CHUNK_MIN to CHUNK_MAX bytes but for the
+// last: a cut falls where the gear hash of the bytes since the previous one
+// has its top bits clear, so data shared between files, or shifted inside
+// one, is cut the same way.
+pub fn content_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut gear = GearHash::default();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (i, &b) in data.iter().enumerate() {
+        let hash = gear.update(b);
+        let len = i + 1 - start;
+        if (len >= CHUNK_MIN && hash & CHUNK_MASK == 0) || len >= CHUNK_MAX {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+            gear.reset();
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+// `data` compressed, or as it is when compressing wouldn't shrink it
+fn pack(data: &[u8], opts: &CompressOptions) -> Result<(Method, Vec<u8>)> {
+    let compressed = compress(data, opts)?;
+    Ok(if compressed.len() < data.len() { (Method::Compressed, compressed) } else { (Method::Stored, data.to_vec()) })
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(ARCHIVE_MAGIC)
+}
+
+// whether `Archive::update` can edit the archive starting with `data`;
+// its first 8 bytes are enough
+pub fn is_appendable(data: &[u8]) -> bool {
+    is_archive(data) && u32_at(data, ARCHIVE_MAGIC.len()) == Some(APPENDABLE)
+}
+
+// the stored form of `path`: `\` becomes `/`, and leading `/`, `.` and empty
+// components are dropped; `..` and drive letters have no stored form
+pub fn normalize_path(path: &str) -> Result<String> {
+    let unsafe_path = || RsZipError::InvalidOption(format!("archive path escapes the archive root: {:?}", path));
+    let mut parts = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return Err(unsafe_path()),
+            _ if parts.is_empty() && part.ends_with(':') => return Err(unsafe_path()),
+            _ => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return Err(RsZipError::InvalidOption(format!("archive path names no entry: {:?}", path)));
+    }
+    Ok(parts.join("/"))
+}
+
+// the stored name of `child` inside the directory stored as `parent`, which
+// is empty at the root
+pub fn entry_child_name(parent: &str, child: &str) -> String {
+    if parent.is_empty() { child.to_string() } else { format!("{}/{}", parent, child) }
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    // the chunks some entry refers to
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter().filter(|c| c.refs > 0)
+    }
+
+    pub fn find(&self, path: &str) -> Option<&Entry> {
+        let path = normalize_path(path).ok()?;
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    // adds or replaces the file stored under `path`
+    pub fn add_file(&mut self, path: &str, data: &[u8], opts: &CompressOptions) -> Result<()> {
+        let size = len_u32(data.len())?;
+        let (method, packed) = pack(data, opts)?;
+        let path = normalize_path(path)?;
+        self.insert(Entry::new(path, EntryKind::File, method, size, packed))
+    }
+
+    // Like `add_file`, but stored as content-defined chunks, compressing and
+    // storing only those the archive doesn't hold already.
+    pub fn add_file_dedup(&mut self, path: &str, data: &[u8], opts: &CompressOptions) -> Result<()> {
+        let size = len_u32(data.len())?;
+        let path = normalize_path(path)?;
+        let mut hashes = Vec::new();
+        for piece in content_chunks(data) {
+            let hash = sha256(piece);
+            if !self.chunk_index.contains_key(&hash) {
+                let (method, packed) = pack(piece, opts)?;
+                let size = len_u32(piece.len())?;
+                self.put_chunk(Chunk { hash, method, size, offset: 0, refs: 0, packed_len: packed.len(), packed });
+            }
+            hashes.push(hash);
+        }
+        let mut entry = Entry::new(path, EntryKind::File, Method::Chunked, size, Vec::new());
+        entry.chunks = hashes;
+        self.insert(entry)
+    }
+
+    // a chunk no entry refers to yet; `insert` counts the references
+    fn put_chunk(&mut self, chunk: Chunk) {
+        self.chunk_index.insert(chunk.hash, self.chunks.len());
+        self.chunks.push(chunk);
+    }
+
+    fn chunk(&self, hash: &[u8; 32]) -> Result<&Chunk> {
+        let missing = || RsZipError::CorruptHeader(format!("archive chunk {} is missing", hex_lower(&hash[..8])));
+        self.chunk_index.get(hash).map(|&i| &self.chunks[i]).ok_or_else(missing)
+    }
+
+    // counts `entry`'s references, `add` of them in or out
+    fn count_refs(&mut self, entry: &Entry, add: bool) -> Result<()> {
+        for hash in &entry.chunks {
+            let i = *self.chunk_index.get(hash).ok_or(RsZipError::CorruptData("archive chunk is missing"))?;
+            let refs = &mut self.chunks[i].refs;
+            *refs = if add { refs.checked_add(1).ok_or(RsZipError::TooLarge)? } else { refs.saturating_sub(1) };
+        }
+        Ok(())
+    }
+
+    pub fn add_dir(&mut self, path: &str) -> Result<()> {
+        let path = normalize_path(path)?;
+        self.insert(Entry::new(path, EntryKind::Dir, Method::Stored, 0, Vec::new()))
+    }
+
+    // `target` is kept as given; extraction never writes through a link
+    pub fn add_symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        let path = normalize_path(path)?;
+        let size = len_u32(target.len())?;
+        self.insert(Entry::new(path, EntryKind::Symlink, Method::Stored, size, target.as_bytes().to_vec()))
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<()> {
+        if entry.path.len() > u16::MAX as usize {
+            return Err(RsZipError::InvalidOption(format!("archive path over 65535 bytes: {:?}", entry.path)));
+        }
+        self.count_refs(&entry, true)?;
+        match self.entries.iter().position(|e| e.path == entry.path) {
+            Some(i) => {
+                let old = std::mem::replace(&mut self.entries[i], entry);
+                self.count_refs(&old, false)?;
+            }
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    pub fn set_meta(&mut self, path: &str, meta: EntryMeta) -> Result<()> {
+        let path = normalize_path(path)?;
+        let entry = self.entries.iter_mut().find(|e| e.path == path);
+        entry.ok_or_else(|| RsZipError::InvalidOption(format!("no archive entry {:?}", path)))?.meta = Some(meta);
+        Ok(())
+    }
+
+    // Encrypts the file entry at `path` under `password`, with a fresh salt
+    // and nonce; `iterations` is the PBKDF2 work factor.
+    pub fn encrypt(&mut self, path: &str, password: &[u8], iterations: u32) -> Result<()> {
+        if password.is_empty() {
+            return Err(RsZipError::BadKey("empty password"));
+        }
+        if !(1..=MAX_KDF_ITERATIONS).contains(&iterations) {
+            return Err(RsZipError::InvalidOption(format!("KDF iterations must be 1 to {}", MAX_KDF_ITERATIONS)));
+        }
+        let path = normalize_path(path)?;
+        let Some(entry) = self.entries.iter_mut().find(|e| e.path == path) else {
+            return Err(RsZipError::InvalidOption(format!("no archive entry {:?}", path)));
+        };
+        // sealing a shared chunk would seal it for every file holding it
+        let chunked = entry.method == Method::Chunked;
+        if entry.kind != EntryKind::File || entry.sealing.is_some() || !entry.loaded() || chunked {
+            return Err(RsZipError::InvalidOption(format!("archive entry {:?} can't be encrypted", path)));
+        }
+        let random = random_bytes(SALT_LEN + NONCE_LEN)?;
+        let mut sealing = Sealing {
+            iterations,
+            salt: random[..SALT_LEN].try_into().expect("salt length"),
+            nonce: random[SALT_LEN..].try_into().expect("nonce length"),
+            check: [0; CHECK_LEN],
+        };
+        let derived = pbkdf2_sha256(password, &sealing.salt, iterations, 32 + CHECK_LEN);
+        sealing.check.copy_from_slice(&derived.as_bytes()[32..]);
+        let key: &[u8; 32] = derived.as_bytes()[..32].try_into().expect("32 bytes");
+        entry.packed = chacha20_poly1305_seal(key, &sealing.nonce, &[], &entry.packed);
+        entry.packed_len = entry.packed.len();
+        entry.sealing = Some(sealing);
+        Ok(())
+    }
+
+    // adds or replaces every entry of `other`, bringing over the chunks
+    // this archive doesn't hold yet
+    pub fn extend(&mut self, other: Archive) -> Result<()> {
+        for chunk in other.chunks.into_iter().filter(|c| c.refs > 0) {
+            if !self.chunk_index.contains_key(&chunk.hash) {
+                chunk.packed()?;
+                self.put_chunk(Chunk { refs: 0, ..chunk });
+            }
+        }
+        other.entries.into_iter().try_for_each(|entry| self.insert(entry))
+    }
+
+    // entries stored as `path` or, for a directory, under it
+    fn matching(&self, path: &str) -> Result<Vec<usize>> {
+        let path = normalize_path(path)?;
+        let under = format!("{}/", path);
+        let found: Vec<usize> = (0..self.entries.len())
+            .filter(|&i| self.entries[i].path == path || self.entries[i].path.starts_with(&under))
+            .collect();
+        if found.is_empty() {
+            return Err(RsZipError::InvalidOption(format!("no archive entry {:?}", path)));
+        }
+        Ok(found)
+    }
+
+    // removes `path` and everything under it; returns how many entries went
+    pub fn remove(&mut self, path: &str) -> Result<usize> {
+        let found = self.matching(path)?;
+        for &i in found.iter().rev() {
+            let old = self.entries.remove(i);
+            self.count_refs(&old, false)?;
+        }
+        Ok(found.len())
+    }
+
+    // moves `from` and everything under it to `to`, refusing to overwrite an
+    // entry that isn't moving; returns how many entries moved
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<usize> {
+        let found = self.matching(from)?;
+        let (from, to) = (normalize_path(from)?, normalize_path(to)?);
+        let renamed: Vec<String> = found.iter().map(|&i| format!("{}{}", to, &self.entries[i].path[from.len()..])).collect();
+        for (n, path) in renamed.iter().enumerate() {
+            if path.len() > u16::MAX as usize {
+                return Err(RsZipError::InvalidOption(format!("archive path over 65535 bytes: {:?}", path)));
+            }
+            let taken = self.entries.iter().enumerate().any(|(i, e)| e.path == *path && !found.contains(&i));
+            if taken || renamed[..n].contains(path) {
+                return Err(RsZipError::InvalidOption(format!("archive entry {:?} already exists", path)));
+            }
+        }
+        for (&i, path) in found.iter().zip(renamed) {
+            self.entries[i].path = path;
+        }
+        Ok(found.len())
+    }
+
+    // the original bytes of a file entry; encrypted ones need `extract_with_password`
+    pub fn extract(&self, entry: &Entry) -> Result<Vec<u8>> {
+        if entry.sealing.is_some() {
+            return Err(RsZipError::BadKey("archive entry is encrypted, a password is needed"));
+        }
+        self.unpack(entry, entry.packed()?)
+    }
+
+    // BadKey for a wrong password, AuthenticationFailed for damaged data
+    pub fn extract_with_password(&self, entry: &Entry, password: &[u8]) -> Result<Vec<u8>> {
+        let Some(sealing) = &entry.sealing else { return self.extract(entry) };
+        let key = sealing.key(password)?;
+        let packed = chacha20_poly1305_open(key.as_array().expect("32 bytes"), &sealing.nonce, &[], entry.packed()?)?;
+        self.unpack(entry, &packed)
+    }
+
+    // an entry's original bytes from its packed, decrypted, ones
+    fn unpack(&self, entry: &Entry, packed: &[u8]) -> Result<Vec<u8>> {
+        let data = match entry.method {
+            Method::Stored => packed.to_vec(),
+            Method::Compressed => decompress(packed, None)?,
+            Method::Chunked => self.join_chunks(entry, 0..entry.size as usize)?,
+        };
+        if data.len() != entry.size as usize {
+            return Err(RsZipError::CorruptData("archive entry size"));
+        }
+        Ok(data)
+    }
+
+    // bytes `range` of a chunked file, decoding only the chunks it overlaps
+    fn join_chunks(&self, entry: &Entry, range: Range<usize>) -> Result<Vec<u8>> {
+        let (mut out, mut at) = (Vec::new(), 0);
+        for hash in &entry.chunks {
+            let chunk = self.chunk(hash)?;
+            let (start, end) = (at, at + chunk.size as usize);
+            at = end;
+            if start < range.end && end > range.start {
+                let data = chunk.unpack()?;
+                out.extend_from_slice(&data[range.start.max(start) - start..range.end.min(end) - start]);
+            }
+        }
+        Ok(out)
+    }
+
+    // bytes `range` of a file entry, see `crate::decompress_range`
+    pub fn extract_range(&self, entry: &Entry, range: Range<usize>) -> Result<Vec<u8>> {
+        if entry.sealing.is_some() {
+            return Err(RsZipError::BadKey("archive entry is encrypted, a password is needed"));
+        }
+        let packed = entry.packed()?;
+        match entry.method {
+            Method::Stored => {
+                let part = packed.get(range.start..range.end.min(packed.len()));
+                part.map(<[u8]>::to_vec).ok_or_else(|| {
+                    let msg = format!("range start {} is past the end of {} bytes", range.start, packed.len());
+                    RsZipError::InvalidOption(msg)
+                })
+            }
+            Method::Compressed => decompress_range(packed, range, None, 0),
+            Method::Chunked if range.start > entry.size as usize => {
+                let msg = format!("range start {} is past the end of {} bytes", range.start, entry.size);
+                Err(RsZipError::InvalidOption(msg))
+            }
+            Method::Chunked => self.join_chunks(entry, range),
+        }
+    }
+
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut out = ARCHIVE_MAGIC.to_vec();
+        put_u32(&mut out, APPENDABLE);
+        for entry in &mut self.entries {
+            entry.offset = len_u32(out.len() - DATA_START)?;
+            out.extend_from_slice(entry.packed()?);
+        }
+        for chunk in self.chunks.iter_mut().filter(|c| c.refs > 0) {
+            chunk.offset = len_u32(out.len() - DATA_START)?;
+            out.extend_from_slice(chunk.packed()?);
+        }
+        let table_offset = len_u32(out.len())?;
+        write_table(&mut out, &self.entries, &self.chunks, table_offset)?;
+        len_u32(out.len())?;
+        Ok(out)
+    }
+
+    pub fn read(data: &[u8]) -> Result<Archive> {
+        if !is_archive(data) {
+            return Err(RsZipError::CorruptHeader("not an rszip archive".into()));
+        }
+        let (table, body) = if is_appendable(data) {
+            if data.len() < DATA_START + TRAILER_LEN {
+                return Err(RsZipError::Truncated("archive trailer"));
+            }
+            let table_offset = table_offset(&data[data.len() - TRAILER_LEN..], data.len())?;
+            (read_table(&data[table_offset..data.len() - TRAILER_LEN])?, &data[DATA_START..table_offset])
+        } else {
+            let mut r = Reader::new(&data[ARCHIVE_MAGIC.len()..]);
+            ((table_entries(&mut r)?, Vec::new()), r.rest())
+        };
+        let (mut entries, mut chunks) = table;
+        let data_at = |offset: u32, len: usize| {
+            let start = offset as usize;
+            body.get(start..start + len).map(<[u8]>::to_vec).ok_or(RsZipError::Truncated("archive data"))
+        };
+        for entry in &mut entries {
+            entry.packed = data_at(entry.offset, entry.packed_len)?;
+        }
+        for chunk in &mut chunks {
+            chunk.packed = data_at(chunk.offset, chunk.packed_len)?;
+        }
+        Archive::from_table(entries, chunks)
+    }
+
+    // Checks that every chunk reference resolves, that a chunked file's
+    // chunks add up to its size and that each chunk's count is the number of
+    // references to it.
+    fn from_table(entries: Vec<Entry>, chunks: Vec<Chunk>) -> Result<Archive> {
+        let mut archive = Archive::new();
+        for chunk in chunks {
+            if archive.chunk_index.contains_key(&chunk.hash) {
+                return Err(RsZipError::CorruptHeader("archive chunk listed twice".into()));
+            }
+            archive.put_chunk(chunk);
+        }
+        let mut counted = vec![0u64; archive.chunks.len()];
+        for entry in &entries {
+            let mut size = 0;
+            for hash in &entry.chunks {
+                let missing = || {
+                    RsZipError::CorruptHeader(format!("archive entry {:?} refers to a missing chunk", entry.path))
+                };
+                let i = *archive.chunk_index.get(hash).ok_or_else(missing)?;
+                counted[i] += 1;
+                size += archive.chunks[i].size as u64;
+            }
+            if entry.method == Method::Chunked && size != entry.size as u64 {
+                return Err(RsZipError::CorruptHeader(format!("chunks of archive entry {:?} don't add up", entry.path)));
+            }
+        }
+        if archive.chunks.iter().zip(counted).any(|(chunk, n)| chunk.refs as u64 != n) {
+            return Err(RsZipError::CorruptHeader("archive chunk reference count".into()));
+        }
+        archive.entries = entries;
+        Ok(archive)
+    }
+
+    // Applies `edit` to the archive in `file` without reading or rewriting
+    // the entries already there. `edit` sees those as table rows only, which
+    // it may remove or rename but not extract; entries it adds have their
+    // data appended after the current trailer, then the new table, and the
+    // new trailer last. Until those final bytes are on disk the old table
+    // is untouched, and a write that fails cuts the file back to it. The
+    // old table, and data no longer listed, stay as dead space until the
+    // archive is next written whole. Returns the entry counts before and after.
+    pub fn update(file: &mut File, edit: impl FnOnce(&mut Archive) -> Result<()>) -> Result<(usize, usize)> {
+        let len = usize::try_from(file.seek(SeekFrom::End(0))?).map_err(|_| RsZipError::TooLarge)?;
+        let mut start = [0; DATA_START];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut start).map_err(|_| RsZipError::Truncated("archive header"))?;
+        if !is_archive(&start) {
+            return Err(RsZipError::CorruptHeader("not an rszip archive".into()));
+        }
+        if !is_appendable(&start) {
+            return Err(RsZipError::Unsupported("updating an archive with its table up front".into()));
+        }
+        if len < DATA_START + TRAILER_LEN {
+            return Err(RsZipError::Truncated("archive trailer"));
+        }
+        let mut trailer = [0; TRAILER_LEN];
+        file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        file.read_exact(&mut trailer)?;
+        let table_offset = table_offset(&trailer, len)?;
+        let mut table = vec![0; len - TRAILER_LEN - table_offset];
+        file.seek(SeekFrom::Start(table_offset as u64))?;
+        file.read_exact(&mut table)?;
+        let (entries, chunks) = read_table(&table)?;
+        let mut archive = Archive::from_table(entries, chunks)?;
+        let before = archive.entries.len();
+        edit(&mut archive)?;
+
+        let appended = archive.append_after(file, len);
+        if appended.is_err() {
+            // back to the old trailer, which nothing above has touched
+            let _ = file.set_len(len as u64);
+        }
+        appended?;
+        Ok((before, archive.entries.len()))
+    }
+
+    // new entry and chunk data, the table and then the trailer, from `len`
+    fn append_after(&mut self, file: &mut File, len: usize) -> Result<()> {
+        file.seek(SeekFrom::Start(len as u64))?;
+        let mut at = len;
+        for entry in self.entries.iter_mut().filter(|e| e.loaded()) {
+            entry.offset = len_u32(at - DATA_START)?;
+            file.write_all(&entry.packed)?;
+            at += entry.packed_len;
+        }
+        // chunks already in the file keep their place; that includes any an
+        // edit dropped and then took up again, as nothing here overwrites data
+        for chunk in self.chunks.iter_mut().filter(|c| c.refs > 0 && c.loaded()) {
+            chunk.offset = len_u32(at - DATA_START)?;
+            file.write_all(&chunk.packed)?;
+            at += chunk.packed_len;
+        }
+        let mut table = Vec::new();
+        write_table(&mut table, &self.entries, &self.chunks, len_u32(at)?)?;
+        len_u32(at + table.len())?;
+        let (table, trailer) = table.split_at(table.len() - TRAILER_LEN);
+        file.write_all(table)?;
+        // everything the trailer points at is down before it is
+        file.sync_data()?;
+        file.write_all(trailer)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+// where the table starts, from the trailer of a `len`-byte archive
+fn table_offset(trailer: &[u8], len: usize) -> Result<usize> {
+    if &trailer[4..] != TABLE_MAGIC {
+        return Err(RsZipError::Truncated("archive trailer"));
+    }
+    let at = u32_at(trailer, 0).ok_or(RsZipError::Truncated("archive trailer"))? as usize;
+    if !(DATA_START..=len - TRAILER_LEN).contains(&at) {
+        return Err(RsZipError::CorruptHeader(format!("archive table offset {}", at)));
+    }
+    Ok(at)
+}
+
+// the table and trailer, for a table starting at `table_offset`
+fn write_table(out: &mut Vec<u8>, entries: &[Entry], chunks: &[Chunk], table_offset: u32) -> Result<()> {
+    put_u32(out, len_u32(entries.len())?);
+    for entry in entries {
+        put_u16(out, entry.path.len() as u16);
+        out.extend_from_slice(entry.path.as_bytes());
+        let mut kind = entry.kind as u8;
+        if entry.meta.is_some() {
+            kind |= KIND_META;
+        }
+        if entry.sealing.is_some() {
+            kind |= KIND_ENCRYPTED;
+        }
+        out.push(kind);
+        out.push(entry.method as u8);
+        put_u32(out, entry.size);
+        put_u32(out, len_u32(entry.packed_len)?);
+        put_u32(out, entry.offset);
+        if let Some(meta) = entry.meta {
+            out.extend_from_slice(&meta.mtime.to_le_bytes());
+            put_u32(out, meta.mode);
+            put_u32(out, meta.uid);
+            put_u32(out, meta.gid);
+        }
+        if let Some(sealing) = &entry.sealing {
+            put_u32(out, sealing.iterations);
+            out.extend_from_slice(&sealing.salt);
+            out.extend_from_slice(&sealing.nonce);
+            out.extend_from_slice(&sealing.check);
+        }
+        if entry.method == Method::Chunked {
+            put_u32(out, len_u32(entry.chunks.len())?);
+            entry.chunks.iter().for_each(|hash| out.extend_from_slice(hash));
+        }
+    }
+    let live: Vec<&Chunk> = chunks.iter().filter(|c| c.refs > 0).collect();
+    if !live.is_empty() {
+        put_u32(out, len_u32(live.len())?);
+        for chunk in live {
+            out.extend_from_slice(&chunk.hash);
+            out.push(chunk.method as u8);
+            put_u32(out, chunk.size);
+            put_u32(out, len_u32(chunk.packed_len)?);
+            put_u32(out, chunk.offset);
+            put_u32(out, chunk.refs);
+        }
+    }
+    put_u32(out, table_offset);
+    out.extend_from_slice(TABLE_MAGIC);
+    Ok(())
+}
+
+// A trailing table, which has to fill exactly the bytes before the trailer.
+fn read_table(table: &[u8]) -> Result<(Vec<Entry>, Vec<Chunk>)> {
+    let mut r = Reader::new(table);
+    let entries = table_entries(&mut r)?;
+    let chunks = if r.rest().is_empty() { Vec::new() } else { table_chunks(&mut r)? };
+    if !r.rest().is_empty() {
+        return Err(RsZipError::CorruptHeader("archive table length".into()));
+    }
+    Ok((entries, chunks))
+}
+
+// [chunk_count u32][chunk...], the chunks without their data
+fn table_chunks(r: &mut Reader) -> Result<Vec<Chunk>> {
+    let count = r.u32().ok_or(TRUNCATED_TABLE)?;
+    let mut chunks = Vec::new();
+    for _ in 0..count {
+        let hash = r.bytes(32).ok_or(TRUNCATED_TABLE)?.try_into().expect("32 bytes");
+        let method = match r.u8().ok_or(TRUNCATED_TABLE)? {
+            0 => Method::Stored,
+            1 => Method::Compressed,
+            other => return Err(RsZipError::Unsupported(format!("archive chunk method {}", other))),
+        };
+        let size = r.u32().ok_or(TRUNCATED_TABLE)?;
+        let packed_len = r.u32().ok_or(TRUNCATED_TABLE)? as usize;
+        let offset = r.u32().ok_or(TRUNCATED_TABLE)?;
+        let refs = r.u32().ok_or(TRUNCATED_TABLE)?;
+        chunks.push(Chunk { hash, method, size, offset, refs, packed: Vec::new(), packed_len });
+    }
+    Ok(chunks)
+}
+
+// [count u32][entry...], the entries without their data
+fn table_entries(r: &mut Reader) -> Result<Vec<Entry>> {
+    let count = r.u32().ok_or(TRUNCATED_TABLE)?;
+    let mut table = Vec::new();
+    for _ in 0..count {
+        let path_len = r.u16().ok_or(TRUNCATED_TABLE)? as usize;
+        let path = String::from_utf8(r.bytes(path_len).ok_or(TRUNCATED_TABLE)?.to_vec())
+            .map_err(|_| RsZipError::CorruptHeader("archive path is not UTF-8".into()))?;
+        // no writer stores these, so they are hostile rather than old
+        if normalize_path(&path).ok().as_ref() != Some(&path) {
+            return Err(RsZipError::CorruptHeader(format!("unsafe archive path {:?}", path)));
+        }
+        let kind_byte = r.u8().ok_or(TRUNCATED_TABLE)?;
+        let kind = match kind_byte & !(KIND_META | KIND_ENCRYPTED) {
+            0 => EntryKind::File,
+            1 => EntryKind::Dir,
+            2 => EntryKind::Symlink,
+            other => return Err(RsZipError::Unsupported(format!("archive entry kind {}", other))),
+        };
+        let method = match r.u8().ok_or(TRUNCATED_TABLE)? {
+            0 => Method::Stored,
+            1 => Method::Compressed,
+            2 => Method::Chunked,
+            other => return Err(RsZipError::Unsupported(format!("archive method {}", other))),
+        };
+        let size = r.u32().ok_or(TRUNCATED_TABLE)?;
+        let packed_len = r.u32().ok_or(TRUNCATED_TABLE)? as usize;
+        let offset = r.u32().ok_or(TRUNCATED_TABLE)?;
+        let meta = match kind_byte & KIND_META {
+            0 => None,
+            _ => {
+                let mtime = r.bytes(8).ok_or(TRUNCATED_TABLE)?;
+                Some(EntryMeta {
+                    mtime: u64::from_le_bytes(mtime.try_into().expect("8 bytes")),
+                    mode: r.u32().ok_or(TRUNCATED_TABLE)?,
+                    uid: r.u32().ok_or(TRUNCATED_TABLE)?,
+                    gid: r.u32().ok_or(TRUNCATED_TABLE)?,
+                })
+            }
+        };
+        let sealing = match kind_byte & KIND_ENCRYPTED {
+            0 => None,
+            _ => {
+                let iterations = r.u32().ok_or(TRUNCATED_TABLE)?;
+                // more would stall whoever tries a password
+                if !(1..=MAX_KDF_ITERATIONS).contains(&iterations) {
+                    return Err(RsZipError::CorruptHeader(format!("archive entry KDF iterations {}", iterations)));
+                }
+                let mut field = |n| r.bytes(n).ok_or(TRUNCATED_TABLE);
+                Some(Sealing {
+                    iterations,
+                    salt: field(SALT_LEN)?.try_into().expect("salt length"),
+                    nonce: field(NONCE_LEN)?.try_into().expect("nonce length"),
+                    check: field(CHECK_LEN)?.try_into().expect("check length"),
+                })
+            }
+        };
+        let mut chunks = Vec::new();
+        if method == Method::Chunked {
+            if kind != EntryKind::File || sealing.is_some() || packed_len != 0 {
+                return Err(RsZipError::CorruptHeader(format!("chunked archive entry {:?}", path)));
+            }
+            for _ in 0..r.u32().ok_or(TRUNCATED_TABLE)? {
+                chunks.push(r.bytes(32).ok_or(TRUNCATED_TABLE)?.try_into().expect("32 bytes"));
+            }
+        }
+        let entry = Entry { path, kind, method, size, offset, meta, sealing, packed: Vec::new(), packed_len, chunks };
+        table.push(entry);
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testdata, Algorithm};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::process;
+
+    // `Archive::update` on a scratch file holding `bytes`: the counts and the file after
+    fn update_file(name: &str, bytes: &[u8], edit: impl FnOnce(&mut Archive) -> Result<()>) -> ((usize, usize), Vec<u8>) {
+        let path = env::temp_dir().join(format!("rszip-test-{}-{}.rsz", name, process::id()));
+        fs::write(&path, bytes).unwrap();
+        let counts = Archive::update(&mut OpenOptions::new().read(true).write(true).open(&path).unwrap(), edit);
+        let after = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (counts.unwrap(), after)
+    }
+
+    fn extracted(archive: &Archive, path: &str) -> Vec<u8> {
+        archive.extract(archive.find(path).unwrap()).unwrap()
+    }
+
+    // the trailer ends [table offset u32]["RSZT"]
+    fn table_offset(bytes: &[u8]) -> usize {
+        u32_at(bytes, bytes.len() - 8).unwrap() as usize
+    }
+
+    #[test]
+    fn round_trip() {
+        let corpora = testdata::corpora();
+        let mut archive = Archive::new();
+        archive.add_dir("corpora").unwrap();
+        for (name, data) in &corpora {
+            archive.add_file(&format!("corpora/{}", name), data, &CompressOptions::default()).unwrap();
+        }
+        archive.add_file("corpora/text", b"replaced", &CompressOptions::default()).unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let back = Archive::read(&bytes).unwrap();
+        assert_eq!(back.entries().len(), corpora.len() + 1);
+        for (name, data) in &corpora {
+            let expected: &[u8] = if *name == "text" { b"replaced" } else { data };
+            assert_eq!(extracted(&back, &format!("corpora/{}", name)), expected, "{}", name);
+        }
+        for len in (0..bytes.len()).step_by(97) {
+            assert!(Archive::read(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn append_leaves_existing_entries_in_place() {
+        let text = testdata::text(4_000);
+        let mut archive = Archive::new();
+        archive.add_file("a", b"", &CompressOptions::default()).unwrap();
+        archive.add_file("b", b"old b", &CompressOptions::default()).unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let (counts, appended) = update_file("append", &bytes, |archive| {
+            archive.add_file("c", &text, &CompressOptions::default())?;
+            archive.add_file("b", b"new b", &CompressOptions::default())
+        });
+        assert_eq!(counts, (2, 3));
+        assert_eq!(appended[..bytes.len()], bytes[..]);
+        let back = Archive::read(&appended).unwrap();
+        assert_eq!(extracted(&back, "a"), b"");
+        assert_eq!(extracted(&back, "b"), b"new b");
+        assert_eq!(extracted(&back, "c"), text);
+
+        // a table written up front, as archives were before appending
+        let mut up_front = ARCHIVE_MAGIC.to_vec();
+        for field in [&1u32.to_le_bytes()[..], &[1, 0, b'a', 0, 0], &[3, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0], b"abc"] {
+            up_front.extend_from_slice(field);
+        }
+        assert_eq!(extracted(&Archive::read(&up_front).unwrap(), "a"), b"abc");
+        let path = env::temp_dir().join(format!("rszip-test-up-front-{}.rsz", process::id()));
+        fs::write(&path, &up_front).unwrap();
+        assert!(Archive::update(&mut OpenOptions::new().read(true).write(true).open(&path).unwrap(), |_| Ok(())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_and_rename_rewrite_only_the_table() {
+        let mut archive = Archive::new();
+        archive.add_dir("docs").unwrap();
+        archive.add_file("docs/a.txt", b"first", &CompressOptions::default()).unwrap();
+        archive.add_file("docs/b.txt", b"second", &CompressOptions::default()).unwrap();
+        archive.add_file("top.txt", b"third", &CompressOptions::default()).unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let (counts, edited) = update_file("rename", &bytes, |archive| {
+            archive.remove("docs/a.txt")?;
+            archive.rename("docs", "notes")?;
+            archive.rename("top.txt", "notes/top.txt").map(drop)
+        });
+        assert_eq!(counts, (4, 3));
+        let back = Archive::read(&edited).unwrap();
+        let paths: Vec<&str> = back.entries().iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["notes", "notes/b.txt", "notes/top.txt"]);
+        assert_eq!(extracted(&back, "notes/top.txt"), b"third");
+        // the old table and trailer are left as they were, ahead of the new
+        assert_eq!(edited[..bytes.len()], bytes[..]);
+        assert_eq!(table_offset(&edited), bytes.len());
+        assert!(archive.rename("top.txt", "docs/b.txt").is_err());
+        assert!(archive.remove("missing").is_err());
+        assert!(archive.rename("docs", "../up").is_err());
+        assert_eq!(archive.entries().len(), 4);
+    }
+
+    #[test]
+    fn encrypted_entries_need_their_password() {
+        let text = testdata::text(4_000);
+        let mut archive = Archive::new();
+        archive.add_file("secret.txt", &text, &CompressOptions::default()).unwrap();
+        archive.add_file("plain.txt", b"open to all", &CompressOptions::default()).unwrap();
+        archive.encrypt("secret.txt", b"hunter2", 1000).unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let back = Archive::read(&bytes).unwrap();
+        let (secret, plain) = (back.find("secret.txt").unwrap(), back.find("plain.txt").unwrap());
+        assert!(secret.is_encrypted() && !plain.is_encrypted());
+        assert_eq!(back.extract(plain).unwrap(), b"open to all");
+        assert_eq!(back.extract_with_password(secret, b"hunter2").unwrap(), text);
+        assert!(matches!(back.extract(secret), Err(RsZipError::BadKey(_))));
+        assert!(matches!(back.extract_with_password(secret, b"hunter3"), Err(RsZipError::BadKey(_))));
+        assert!(!bytes.windows(16).any(|w| text.windows(16).take(64).any(|t| t == w)));
+        let mut damaged = bytes.clone();
+        damaged[DATA_START + secret.offset as usize] ^= 1;
+        let damaged = Archive::read(&damaged).unwrap();
+        let result = damaged.extract_with_password(damaged.find("secret.txt").unwrap(), b"hunter2");
+        assert!(matches!(result, Err(RsZipError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn paths_stay_inside_the_root() {
+        for path in ["a/b", "./a/b", "/a//b/", "a\\b"] {
+            assert_eq!(normalize_path(path).unwrap(), "a/b", "{}", path);
+        }
+        for path in ["../a", "a/../../b", "C:/a", "C:\\a", "", "./"] {
+            assert!(normalize_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn deduplicated_entries_share_their_chunks() {
+        let data = testdata::random(600_000);
+        // the same bytes behind a prefix, cut the same way after the first chunk
+        let shifted = [b"prefix ".as_slice(), &data].concat();
+        // random bytes don't compress, so no time goes on trying
+        let opts = CompressOptions { algo: Algorithm::Rle, huffman: false, ..Default::default() };
+        let mut archive = Archive::new();
+        archive.add_file_dedup("a", &data, &opts).unwrap();
+        archive.add_file_dedup("b", &data, &opts).unwrap();
+        archive.add_file_dedup("c", &shifted, &opts).unwrap();
+        assert!(archive.encrypt("a", b"pw", 1).is_err());
+        let bytes = archive.to_bytes().unwrap();
+        let back = Archive::read(&bytes).unwrap();
+        assert_eq!(extracted(&back, "a"), data);
+        assert_eq!(extracted(&back, "b"), data);
+        assert_eq!(extracted(&back, "c"), shifted);
+        let c = back.find("c").unwrap();
+        assert_eq!(back.extract_range(c, 100_000..300_000).unwrap(), shifted[100_000..300_000]);
+        let listed: usize = back.entries().iter().map(|e| e.chunk_count()).sum();
+        assert_eq!(back.chunks().map(|c| c.refs as usize).sum::<usize>(), listed);
+        // three copies in well under two means one is stored
+        assert!(bytes.len() < data.len() * 3 / 2);
+        assert!(back.chunks().count() <= listed / 3 + 1);
+
+        // the new entry's chunks are all there already, so only the table grows
+        let (_, edited) = update_file("dedup", &bytes, |archive| {
+            archive.remove("a")?;
+            let mut more = Archive::new();
+            more.add_file_dedup("d", &data, &opts)?;
+            archive.extend(more)
+        });
+        let after = Archive::read(&edited).unwrap();
+        assert_eq!(extracted(&after, "b"), data);
+        assert_eq!(extracted(&after, "d"), data);
+        assert!(edited.len() < bytes.len() + 4096);
+        let (_, emptied) = update_file("dedup", &edited, |archive| {
+            ["b", "c", "d"].iter().try_for_each(|p| archive.remove(p).map(drop))
+        });
+        assert_eq!(Archive::read(&emptied).unwrap().chunks().count(), 0);
+        for len in (0..bytes.len()).step_by(4999) {
+            assert!(Archive::read(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+}
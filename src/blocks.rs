@@ -0,0 +1,293 @@
+// Independent blocks: the input cut into fixed-size pieces, each a whole
+// rszip file of its own with its own codecs, lengths and CRC-32. Blocks
+// compress and decompress on separate threads, and damage to one costs only
+// that block's bytes: `decompress_recover` zero-fills it and carries on.
+//
+// body: [block_size u32] then per block [index u32][packed_len u32][rsz file];
+//       the outer header's lz_len is the original length, which together
+//       with block_size gives the block count
+// seek: FLAG_SEEK_TABLE extras, [count u32][frame offset u32 per block], so
+//       `read_range` goes straight to the blocks it needs
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::format::{put_u32, u32_at, Header, Reader, FLAG_BLOCKS, FLAG_SEEK_TABLE, MAGIC};
+use crate::{compress_with_stats, decompress, len_u32, CompressOptions, Result, RsZipError, Stage, TokenStats};
+
+pub const BLOCKS_RANGE: std::ops::RangeInclusive<usize> = 64 << 10..=64 << 20;
+pub const DEFAULT_BLOCKS: usize = 1 << 20;
+// [index u32][packed_len u32]
+const FRAME_LEN: usize = 8;
+
+// Runs `f` for 0..count on up to `threads` threads, results in index order.
+fn parallel_map<T: Send>(count: usize, threads: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    if threads <= 1 || count <= 1 {
+        return (0..count).map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut done: Vec<Option<T>> = (0..count).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(count))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut out = Vec::new();
+                    loop {
+                        let n = next.fetch_add(1, Ordering::Relaxed);
+                        if n >= count {
+                            break;
+                        }
+                        out.push((n, f(n)));
+                    }
+                    out
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (n, result) in worker.join().expect("block worker panicked") {
+                done[n] = Some(result);
+            }
+        }
+    });
+    done.into_iter().flatten().collect()
+}
+
+// The body after the outer header, and totals over the blocks' stats.
+pub fn compress(data: &[u8], opts: &CompressOptions, block_size: usize) -> Result<(Vec<u8>, TokenStats)> {
+    if !BLOCKS_RANGE.contains(&block_size) {
+        return Err(RsZipError::InvalidOption(format!("blocks of {} bytes are not between 64K and 64M", block_size)));
+    }
+    // the threads go to whole blocks instead
+    let inner = CompressOptions { blocks: None, threads: 1, on_progress: None, ..opts.clone() };
+    let count = data.len().div_ceil(block_size);
+    let finished = AtomicUsize::new(0);
+    opts.report(Stage::Match, data.len(), 0);
+    let packed = parallel_map(count, opts.worker_threads(), |i| {
+        let block = &data[i * block_size..((i + 1) * block_size).min(data.len())];
+        let packed = compress_with_stats(block, &inner);
+        let done = finished.fetch_add(block.len(), Ordering::Relaxed) + block.len();
+        opts.report_progress(Stage::Match, data.len(), done, data.len());
+        packed
+    });
+    let mut body = len_u32(block_size)?.to_le_bytes().to_vec();
+    let mut stats = TokenStats { blocks: 0, ..TokenStats::collect(&[], None) };
+    for (i, result) in packed.into_iter().enumerate() {
+        let (file, block_stats) = result?;
+        body.extend_from_slice(&len_u32(i)?.to_le_bytes());
+        body.extend_from_slice(&len_u32(file.len())?.to_le_bytes());
+        body.extend_from_slice(&file);
+        stats.add(&block_stats);
+    }
+    Ok((body, stats))
+}
+
+// (index, block file, where the next frame starts) for a frame that looks
+// whole; None for anything else, which only recovery gets past
+fn frame_at(body: &[u8], at: usize, count: usize) -> Option<(usize, &[u8], usize)> {
+    let index = u32_at(body, at)? as usize;
+    let len = u32_at(body, at + 4)? as usize;
+    let start = at + FRAME_LEN;
+    let file = body.get(start..start.checked_add(len)?)?;
+    (index < count && file.starts_with(MAGIC)).then_some((index, file, start + len))
+}
+
+// The seek table for a body `compress` wrote.
+pub fn seek_table(body: &[u8]) -> Result<Vec<u8>> {
+    let mut offsets = Vec::new();
+    let mut at = 4;
+    while let Some((_, _, next)) = frame_at(body, at, usize::MAX) {
+        offsets.push(len_u32(at)?);
+        at = next;
+    }
+    let mut table = Vec::new();
+    put_u32(&mut table, len_u32(offsets.len())?);
+    for offset in offsets {
+        put_u32(&mut table, offset);
+    }
+    Ok(table)
+}
+
+// Frame offsets from the header's seek table, or by walking the frames in
+// files without one.
+fn frame_offsets(header: &Header, body: &[u8], count: usize) -> Result<Vec<usize>> {
+    if header.flags & FLAG_SEEK_TABLE != 0 {
+        if u32_at(&header.extras, 0) != Some(len_u32(count)?) {
+            return Err(RsZipError::CorruptHeader("seek table size".into()));
+        }
+        return (0..count)
+            .map(|i| u32_at(&header.extras, 4 + i * 4).map(|at| at as usize).ok_or(RsZipError::Truncated("seek table")))
+            .collect();
+    }
+    let mut offsets = Vec::new();
+    let mut at = 4;
+    while offsets.len() < count {
+        let (_, _, next) = frame_at(body, at, count).ok_or(RsZipError::CorruptData("block framing"))?;
+        offsets.push(at);
+        at = next;
+    }
+    Ok(offsets)
+}
+
+// (block size, each block's packed length in order), for `rszip info`
+pub fn layout(header: &Header, body: &[u8]) -> Result<(usize, Vec<usize>)> {
+    let block_size = block_size(body)?;
+    let offsets = frame_offsets(header, body, (header.lz_len as usize).div_ceil(block_size))?;
+    let packed = offsets.iter().map(|&at| u32_at(body, at + 4).map(|len| len as usize).ok_or(RsZipError::Truncated("block framing")));
+    Ok((block_size, packed.collect::<Result<_>>()?))
+}
+
+fn block_size(body: &[u8]) -> Result<usize> {
+    let block_size = u32_at(body, 0).ok_or(RsZipError::Truncated("block size"))? as usize;
+    if !BLOCKS_RANGE.contains(&block_size) {
+        return Err(RsZipError::CorruptHeader(format!("block size {}", block_size)));
+    }
+    Ok(block_size)
+}
+
+fn decode_block(file: &[u8], expected: usize, dictionary: Option<&[u8]>) -> Result<Vec<u8>> {
+    // a block holding blocks of its own could nest until the stack runs out
+    if Header::read(&mut Reader::new(file))?.flags & FLAG_BLOCKS != 0 {
+        return Err(RsZipError::CorruptData("nested blocks"));
+    }
+    let block = decompress(file, dictionary)?;
+    if block.len() != expected {
+        return Err(RsZipError::CorruptData("block length"));
+    }
+    Ok(block)
+}
+
+// Bytes `range` of the original, decoding only the blocks it overlaps;
+// `range` is within lz_len.
+pub fn read_range(header: &Header, body: &[u8], range: Range<usize>, dictionary: Option<&[u8]>, threads: usize) -> Result<Vec<u8>> {
+    let len = header.lz_len as usize;
+    let block_size = block_size(body)?;
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
+    let offsets = frame_offsets(header, body, len.div_ceil(block_size))?;
+    let (first, last) = (range.start / block_size, (range.end - 1) / block_size);
+    let decoded = parallel_map(last - first + 1, threads, |n| {
+        let i = first + n;
+        match frame_at(body, offsets[i], offsets.len()) {
+            Some((index, file, _)) if index == i => decode_block(file, block_size.min(len - i * block_size), dictionary),
+            _ => Err(RsZipError::CorruptData("seek table points outside a block")),
+        }
+    });
+    let mut out = Vec::new();
+    for block in decoded {
+        out.extend_from_slice(&block?);
+    }
+    let skip = range.start - first * block_size;
+    Ok(out[skip..skip + range.len()].to_vec())
+}
+
+// Decodes every block on `threads` workers, each taking the next block
+// still to do, then joins them in order; `recover` zero-fills those that are
+// missing or damaged rather than failing. Returns the data and the lost
+// block indexes.
+pub fn decompress_blocks(
+    header: &Header,
+    body: &[u8],
+    dictionary: Option<&[u8]>,
+    recover: bool,
+    threads: usize,
+) -> Result<(Vec<u8>, Vec<usize>)> {
+    let len = header.lz_len as usize;
+    let block_size = block_size(body)?;
+    let count = len.div_ceil(block_size);
+    let mut files: Vec<Option<&[u8]>> = vec![None; count];
+    let mut at = 4;
+    while at < body.len() {
+        match frame_at(body, at, count) {
+            Some((index, file, next)) => {
+                files[index].get_or_insert(file);
+                at = next;
+            }
+            None if recover => {
+                // resynchronize on the next block file's signature
+                let Some(skip) = body[at + 1..].windows(MAGIC.len()).position(|w| w == MAGIC) else { break };
+                at = (at + 1 + skip).saturating_sub(FRAME_LEN).max(at + 1);
+            }
+            None => return Err(RsZipError::CorruptData("block framing")),
+        }
+    }
+    let decoded = parallel_map(count, threads, |i| {
+        let expected = block_size.min(len - i * block_size);
+        decode_block(files[i].ok_or(RsZipError::Truncated("blocks"))?, expected, dictionary)
+    });
+    let mut out = Vec::new();
+    let mut lost = Vec::new();
+    for (i, block) in decoded.into_iter().enumerate() {
+        match block {
+            Ok(block) => out.extend_from_slice(&block),
+            Err(e) if !recover => return Err(e),
+            Err(_) => {
+                out.resize(out.len() + block_size.min(len - i * block_size), 0);
+                lost.push(i);
+            }
+        }
+    }
+    Ok((out, lost))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decompress_range, decompress_recover, decompress_threaded, testdata};
+
+    // ten differing copies of some text: 200K, four 64K blocks
+    fn blocks_data() -> Vec<u8> {
+        let text = testdata::text(20_000);
+        (0..10u8).flat_map(|i| text.iter().map(move |b| b ^ i)).collect()
+    }
+
+    fn body_start(file: &[u8]) -> usize {
+        let mut r = Reader::new(file);
+        Header::read(&mut r).unwrap();
+        r.pos
+    }
+
+    #[test]
+    fn independent_blocks_survive_damage() {
+        let data = blocks_data();
+        let opts = CompressOptions { blocks: Some(64 << 10), ..Default::default() };
+        let file = crate::compress(&data, &opts).unwrap();
+        assert!(file == crate::compress(&data, &CompressOptions { threads: 1, ..opts.clone() }).unwrap());
+        assert_eq!(decompress_threaded(&file, None, 1).unwrap(), data);
+        assert_eq!(decompress_threaded(&file, None, 3).unwrap(), data);
+        assert_eq!(decompress_recover(&file, None, 0).unwrap(), (data.clone(), Vec::new()));
+        // block 1's frame, past the block size and block 0's frame
+        let at = body_start(&file);
+        let frame = at + 4 + FRAME_LEN + u32_at(&file, at + 8).unwrap() as usize;
+        let frame_len = u32_at(&file, frame + 4).unwrap() as usize;
+        let mut expected = data.clone();
+        expected[64 << 10..128 << 10].fill(0);
+        let mut damaged = file.clone();
+        damaged[frame + FRAME_LEN + frame_len - 1] ^= 0xff;
+        assert!(crate::decompress(&damaged, None).is_err());
+        assert_eq!(decompress_recover(&damaged, None, 0).unwrap(), (expected.clone(), vec![1]));
+        // a bad length loses the framing, found again at block 2's signature
+        let mut unframed = file.clone();
+        unframed[frame + 4..frame + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(crate::decompress(&unframed, None).is_err());
+        assert_eq!(decompress_recover(&unframed, None, 0).unwrap(), (expected, vec![1]));
+    }
+
+    #[test]
+    fn byte_ranges_decode_only_their_blocks() {
+        let data = blocks_data();
+        let file = crate::compress(&data, &CompressOptions { blocks: Some(64 << 10), ..Default::default() }).unwrap();
+        let whole = crate::compress(&data, &CompressOptions::default()).unwrap();
+        for range in [0..0, 10..20, 1000..70_000, 65_536..131_072, 130_000..usize::MAX] {
+            let expected = &data[range.start..range.end.min(data.len())];
+            assert_eq!(decompress_range(&file, range.clone(), None, 0).unwrap(), expected, "{:?}", range);
+            assert_eq!(decompress_range(&whole, range.clone(), None, 0).unwrap(), expected, "{:?}", range);
+        }
+        assert!(decompress_range(&file, data.len() + 1..usize::MAX, None, 0).is_err());
+        // block 0 is never read for a range in block 2
+        let mut damaged = file.clone();
+        damaged[body_start(&file) + 100] ^= 0xff;
+        assert_eq!(decompress_range(&damaged, 140_000..150_000, None, 0).unwrap(), data[140_000..150_000]);
+        assert!(decompress_range(&damaged, 0..10, None, 0).is_err());
+    }
+}
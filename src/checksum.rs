@@ -0,0 +1,119 @@
+// Non-cryptographic hashes shared by the rest of the crate, each with an
+// incremental API: CRC-32 for integrity checks, a polynomial rolling hash
+// over a fixed window for match finding, and a gear hash for content-defined
+// chunk boundaries.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+// CRC-32/ISO-HDLC, the zlib/gzip/PNG polynomial
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { state: 0xffffffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state = CRC32_TABLE[((self.state ^ b as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// Rabin-Karp hash of the last `window` bytes
+pub struct RollingHash {
+    hash: u64,
+    // ROLL_BASE^(window-1), to drop the outgoing byte
+    top: u64,
+}
+
+const ROLL_BASE: u64 = 0x100000001b3;
+
+impl RollingHash {
+    pub fn new(window: &[u8]) -> RollingHash {
+        let top = (1..window.len()).fold(1u64, |acc, _| acc.wrapping_mul(ROLL_BASE));
+        RollingHash { hash: Self::of(window), top }
+    }
+
+    // hash of a whole window, equal to what rolling onto it produces
+    pub fn of(window: &[u8]) -> u64 {
+        window.iter().fold(0u64, |h, &b| h.wrapping_mul(ROLL_BASE).wrapping_add(b as u64))
+    }
+
+    pub fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.hash = self.hash.wrapping_sub((outgoing as u64).wrapping_mul(self.top))
+            .wrapping_mul(ROLL_BASE)
+            .wrapping_add(incoming as u64);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.hash
+    }
+}
+
+pub const GEAR_TABLE: [u64; 256] = {
+    // splitmix64, so the table (and every chunk boundary) is fixed forever
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// gear hash: every byte shifts the state left one bit, so the top bits
+// depend on roughly the last 64 bytes and older input drops out on its own
+#[derive(Default)]
+pub struct GearHash {
+    hash: u64,
+}
+
+impl GearHash {
+    pub fn update(&mut self, byte: u8) -> u64 {
+        self.hash = (self.hash << 1).wrapping_add(GEAR_TABLE[byte as usize]);
+        self.hash
+    }
+
+    pub fn reset(&mut self) {
+        self.hash = 0;
+    }
+}
+
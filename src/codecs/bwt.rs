@@ -0,0 +1,161 @@
+// bzip2-style block sorting: each block is Burrows-Wheeler transformed,
+// which groups bytes by the context that follows them, then move-to-front
+// coded, which turns those groups into runs of small numbers, then
+// run-length coded (see rle.rs). Huffman coding of the result is up to the
+// caller, as for RLE.
+//
+// block: [primary u32][coded_len u32][rle(mtf(bwt)) coded_len bytes]
+// `primary` is the row of the sorted rotations holding the block itself.
+use std::ops::RangeInclusive;
+
+use crate::codecs::rle;
+use crate::format::Reader;
+use crate::{Result, RsZipError};
+
+// bzip2 -9; sorting needs about 28 bytes per byte of block
+pub const DEFAULT_BLOCK: usize = 900 * 1024;
+pub const BLOCK_RANGE: RangeInclusive<usize> = 1 << 10..=8 << 20;
+
+pub fn compress(data: &[u8], block_size: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for block in data.chunks(block_size.max(1)) {
+        let rows = sort_rotations(block);
+        let primary = rows.iter().position(|&r| r == 0).unwrap_or(0);
+        let n = block.len();
+        let mut last: Vec<u8> = rows.iter().map(|&r| block[(r as usize + n - 1) % n]).collect();
+        move_to_front(&mut last);
+        let coded = rle::compress(&last);
+        out.extend_from_slice(&(primary as u32).to_le_bytes());
+        out.extend_from_slice(&(coded.len() as u32).to_le_bytes());
+        out.extend_from_slice(&coded);
+    }
+    out
+}
+
+pub fn decompress(body: &[u8]) -> Result<Vec<u8>> {
+    let mut r = Reader::new(body);
+    let mut out = Vec::new();
+    while r.pos < body.len() {
+        let primary = r.u32().ok_or(RsZipError::Truncated("bwt block"))? as usize;
+        let len = r.u32().ok_or(RsZipError::Truncated("bwt block"))? as usize;
+        let mut last = rle::decompress(r.bytes(len).ok_or(RsZipError::Truncated("bwt block"))?)?;
+        if primary >= last.len() {
+            return Err(RsZipError::CorruptData("bwt primary index"));
+        }
+        undo_move_to_front(&mut last);
+        out.extend_from_slice(&invert(&last, primary));
+    }
+    Ok(out)
+}
+
+// Start offsets of the block's rotations in sorted order, by prefix
+// doubling: after each round rotations are ranked by their first 2k bytes.
+// Rotations that are still tied after n bytes are identical, so their
+// order doesn't matter.
+fn sort_rotations(s: &[u8]) -> Vec<u32> {
+    let n = s.len();
+    let mut rank: Vec<u32> = s.iter().map(|&b| b as u32).collect();
+    let mut next = vec![0u32; n];
+    let mut keyed: Vec<(u64, u32)> = Vec::with_capacity(n);
+    let mut k = 1;
+    loop {
+        keyed.clear();
+        keyed.extend((0..n).map(|i| ((rank[i] as u64) << 32 | rank[(i + k) % n] as u64, i as u32)));
+        keyed.sort_unstable();
+        let mut distinct = 0;
+        for w in 0..n {
+            if w > 0 && keyed[w].0 != keyed[w - 1].0 {
+                distinct += 1;
+            }
+            next[keyed[w].1 as usize] = distinct;
+        }
+        std::mem::swap(&mut rank, &mut next);
+        if distinct as usize + 1 == n || k >= n {
+            return keyed.into_iter().map(|(_, i)| i).collect();
+        }
+        k *= 2;
+    }
+}
+
+// Walks the last column backwards: the i-th occurrence of a byte in the
+// last column is the i-th occurrence in the first, one rotation earlier.
+fn invert(last: &[u8], primary: usize) -> Vec<u8> {
+    let mut starts = [0usize; 256];
+    for &b in last {
+        starts[b as usize] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        (*start, total) = (total, total + *start);
+    }
+    let lf: Vec<usize> = last
+        .iter()
+        .map(|&b| {
+            starts[b as usize] += 1;
+            starts[b as usize] - 1
+        })
+        .collect();
+    let mut out = vec![0u8; last.len()];
+    let mut row = primary;
+    for byte in out.iter_mut().rev() {
+        *byte = last[row];
+        row = lf[row];
+    }
+    out
+}
+
+fn move_to_front(data: &mut [u8]) {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+    for b in data.iter_mut() {
+        let at = order.iter().position(|&o| o == *b).unwrap_or(0);
+        order.copy_within(..at, 1);
+        order[0] = *b;
+        *b = at as u8;
+    }
+}
+
+fn undo_move_to_front(data: &mut [u8]) {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+    for b in data.iter_mut() {
+        let at = *b as usize;
+        let byte = order[at];
+        order.copy_within(..at, 1);
+        order[0] = byte;
+        *b = byte;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{Header, Reader, CODEC_BWT, CODEC_HUFFMAN};
+    use crate::{compress, decompress, testdata, Algorithm, CompressOptions};
+
+    #[test]
+    fn blocks_and_repeats() {
+        let text = testdata::text(20_000);
+        let opts = CompressOptions { algo: Algorithm::Bwt, block_size: 4096, ..Default::default() };
+        let blocks = compress(&text, &opts).unwrap();
+        assert_eq!(blocks[5], CODEC_BWT | CODEC_HUFFMAN);
+        assert_eq!(decompress(&blocks, None).unwrap(), text);
+        let bwt = compress(&text, &CompressOptions { algo: Algorithm::Bwt, ..Default::default() }).unwrap();
+        assert!(bwt.len() < compress(&text, &CompressOptions::default()).unwrap().len());
+        assert!(compress(&text, &CompressOptions { block_size: 16, ..opts.clone() }).is_err());
+
+        // single bytes, one byte repeated and a short period leave rotations tied
+        let unhuffed = CompressOptions { huffman: false, ..opts };
+        for data in [b"x".to_vec(), vec![7u8; 5000], b"ab".repeat(3000), b"abcabd".repeat(777)] {
+            assert_eq!(decompress(&compress(&data, &unhuffed).unwrap(), None).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn refuses_a_primary_index_past_the_block() {
+        let opts = CompressOptions { algo: Algorithm::Bwt, block_size: 4096, huffman: false, ..Default::default() };
+        let mut bad = compress(&b"abcabd".repeat(777), &opts).unwrap();
+        let mut r = Reader::new(&bad);
+        Header::read(&mut r).unwrap();
+        let body = r.pos;
+        bad[body..body + 4].copy_from_slice(&(1u32 << 20).to_le_bytes());
+        assert!(decompress(&bad, None).is_err());
+    }
+}
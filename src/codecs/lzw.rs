@@ -0,0 +1,134 @@
+// LZW: every code names a string, codes 0-255 the single bytes, and each
+// code emitted adds "that string plus the next byte" to a dictionary both
+// sides grow in step, so the dictionary is never stored. Codes start 9 bits
+// wide and widen as the dictionary grows, up to 16 bits; a full dictionary
+// is reset with CLEAR. The body is the MSB-first code sequence only; the
+// decoded length comes from the header.
+use std::collections::HashMap;
+
+use crate::format::{BitReader, BitWriter};
+use crate::{Result, RsZipError};
+
+const CLEAR: u32 = 256;
+const FIRST_CODE: u32 = 257;
+const MAX_BITS: u32 = 16;
+const MAX_CODES: u32 = 1 << MAX_BITS;
+
+// Width of the i-th code since the last reset. Code i is at most 256 + i,
+// and both sides count codes, so neither needs the other's table size.
+fn code_width(i: u32) -> u32 {
+    (u32::BITS - (256 + i).leading_zeros()).clamp(9, MAX_BITS)
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::new();
+    let Some((&first, rest)) = data.split_first() else { return out.finish() };
+    // (code of a string) << 8 | next byte -> code of the longer string
+    let mut dict: HashMap<u32, u32> = HashMap::new();
+    let (mut next, mut emitted) = (FIRST_CODE, 0);
+    let mut current = first as u32;
+    for &b in rest {
+        if let Some(&code) = dict.get(&(current << 8 | b as u32)) {
+            current = code;
+            continue;
+        }
+        out.write(current, code_width(emitted));
+        emitted += 1;
+        dict.insert(current << 8 | b as u32, next);
+        next += 1;
+        if next == MAX_CODES {
+            out.write(CLEAR, code_width(emitted));
+            dict.clear();
+            (next, emitted) = (FIRST_CODE, 0);
+        }
+        current = b as u32;
+    }
+    out.write(current, code_width(emitted));
+    out.finish()
+}
+
+// `len` is the decoded length from the header
+pub fn decompress(body: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut r = BitReader::new(body);
+    // grown as codes decode; `len` comes from the file
+    let mut out = Vec::new();
+    // entry for code FIRST_CODE + k: (prefix code, last byte, first byte)
+    let mut entries: Vec<(u32, u8, u8)> = Vec::new();
+    let mut prev: Option<u32> = None;
+    let mut read = 0;
+    let first_byte = |entries: &[(u32, u8, u8)], code: u32| {
+        if code < CLEAR { code as u8 } else { entries[(code - FIRST_CODE) as usize].2 }
+    };
+    while out.len() < len {
+        let code = r.read(code_width(read)).ok_or(RsZipError::Truncated("lzw codes"))?;
+        read += 1;
+        if code == CLEAR {
+            entries.clear();
+            (prev, read) = (None, 0);
+            continue;
+        }
+        let next = FIRST_CODE + entries.len() as u32;
+        let start = out.len();
+        match prev {
+            // the one code the encoder can send before the decoder has it:
+            // the previous string plus its own first byte
+            Some(p) if code == next => {
+                let byte = first_byte(&entries, p);
+                expand(&entries, p, &mut out);
+                out.push(byte);
+            }
+            _ if code > CLEAR && code >= next => return Err(RsZipError::CorruptData("lzw code not yet defined")),
+            _ => expand(&entries, code, &mut out),
+        }
+        if let Some(p) = prev.filter(|_| next < MAX_CODES) {
+            let first = first_byte(&entries, p);
+            entries.push((p, out[start], first));
+        }
+        prev = Some(code);
+    }
+    if out.len() > len {
+        return Err(RsZipError::CorruptData("lzw codes run past the length"));
+    }
+    Ok(out)
+}
+
+// appends the string for `code`, walking prefixes from its end
+fn expand(entries: &[(u32, u8, u8)], mut code: u32, out: &mut Vec<u8>) {
+    let start = out.len();
+    while code >= FIRST_CODE {
+        let (prefix, byte, _) = entries[(code - FIRST_CODE) as usize];
+        out.push(byte);
+        code = prefix;
+    }
+    out.push(code as u8);
+    out[start..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, ENGLISH_WORDS_ID};
+    use crate::format::CODEC_LZW;
+    use crate::{compress, decompress, testdata, Algorithm, CompressOptions, LZ77_WINDOW};
+
+    #[test]
+    fn resets_a_full_dictionary() {
+        // 16 symbols at random: compressible, but ~100K codes, more than 16 bits hold
+        let data = testdata::lcg(1, 300_000, |s| (s >> 16) as u8 % 16);
+        let opts = CompressOptions { algo: Algorithm::Lzw, filter: Filter::Text { dict: ENGLISH_WORDS_ID }, ..Default::default() };
+        let file = compress(&data, &opts).unwrap();
+        assert_eq!(file[5], CODEC_LZW);
+        assert_eq!(decompress(&file, None).unwrap(), data);
+        let text = testdata::text(4_000);
+        let filtered = compress(&text, &opts).unwrap();
+        assert_eq!(filtered[5], CODEC_LZW);
+        assert_eq!(decompress(&filtered, None).unwrap(), text);
+        assert!(decompress(&filtered[..filtered.len() - 1], None).is_err());
+    }
+
+    #[test]
+    fn refuses_a_preset_dictionary() {
+        let text = testdata::text(LZ77_WINDOW * 2);
+        let opts = CompressOptions { algo: Algorithm::Lzw, dictionary: Some(text[..LZ77_WINDOW].to_vec()), ..Default::default() };
+        assert!(compress(&text, &opts).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+// Alternatives to the LZ77 stage, each a whole-buffer compress/decompress
+// pair. The file header's codecs byte records which one wrote a body and
+// whether Huffman coding followed it, see format.rs.
+//
+// The `Codec` registry numbers whole codecs for FLAG_CODEC files, whose body
+// starts with the codec's id: the built-ins below hold ids under
+// FIRST_USER_ID, and library users register their own from there up. A file
+// names its codec only by id, so reading it needs the same registration.
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::huffman::{huffman_compress, huffman_decompress, read_code_table, write_code_table};
+use crate::{adaptive_huffman, deflate, range, CompressionLevel, ContextModel, Result, RsZipError};
+
+pub mod bwt;
+pub mod lzw;
+pub mod rle;
+
+pub trait Codec: Send + Sync {
+    // for `compress --codec` and listings; unique among registered codecs
+    fn name(&self) -> &str;
+    // output must carry whatever `decompress` needs, the length included
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub const FIRST_USER_ID: u8 = 128;
+
+type Registry = RwLock<Vec<(u8, Arc<dyn Codec>)>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let builtins: [Arc<dyn Codec>; 8] = [
+            Arc::new(Huffman),
+            Arc::new(Lzw),
+            Arc::new(Rle),
+            Arc::new(Bwt),
+            Arc::new(Range(ContextModel::Order0)),
+            Arc::new(Range(ContextModel::Order1)),
+            Arc::new(AdaptiveHuffman),
+            Arc::new(Deflate),
+        ];
+        RwLock::new(builtins.into_iter().enumerate().map(|(i, codec)| (i as u8 + 1, codec)).collect())
+    })
+}
+
+// For ids from FIRST_USER_ID up; an id or name already taken is refused
+// rather than replaced, so files already written keep their meaning.
+pub fn register(id: u8, codec: Arc<dyn Codec>) -> Result<()> {
+    if id < FIRST_USER_ID {
+        return Err(RsZipError::InvalidOption(format!("codec ids below {} are reserved", FIRST_USER_ID)));
+    }
+    let mut codecs = registry().write().unwrap_or_else(|e| e.into_inner());
+    if let Some((taken, _)) = codecs.iter().find(|(i, c)| *i == id || c.name() == codec.name()) {
+        return Err(RsZipError::InvalidOption(format!("codec id {} or name {} already registered", taken, codec.name())));
+    }
+    codecs.push((id, codec));
+    Ok(())
+}
+
+pub fn by_id(id: u8) -> Option<Arc<dyn Codec>> {
+    let codecs = registry().read().unwrap_or_else(|e| e.into_inner());
+    codecs.iter().find(|(i, _)| *i == id).map(|(_, c)| c.clone())
+}
+
+pub fn by_name(name: &str) -> Option<(u8, Arc<dyn Codec>)> {
+    let codecs = registry().read().unwrap_or_else(|e| e.into_inner());
+    codecs.iter().find(|(_, c)| c.name() == name).cloned()
+}
+
+// (id, name) of every registered codec, in id order
+pub fn registered() -> Vec<(u8, String)> {
+    let codecs = registry().read().unwrap_or_else(|e| e.into_inner());
+    let mut list: Vec<(u8, String)> = codecs.iter().map(|(i, c)| (*i, c.name().to_string())).collect();
+    list.sort();
+    list
+}
+
+// Built-ins whose decoders need the decoded length get a [len u64] prefix.
+fn with_len(len: usize, body: Vec<u8>) -> Vec<u8> {
+    [(len as u64).to_le_bytes().to_vec(), body].concat()
+}
+
+fn split_len(data: &[u8]) -> Result<(usize, &[u8])> {
+    let mut len = [0u8; 8];
+    len.copy_from_slice(data.get(..8).ok_or(RsZipError::Truncated("codec length"))?);
+    Ok((usize::try_from(u64::from_le_bytes(len)).map_err(|_| RsZipError::TooLarge)?, &data[8..]))
+}
+
+struct Huffman;
+
+impl Codec for Huffman {
+    fn name(&self) -> &str {
+        "huffman"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return with_len(0, Vec::new());
+        }
+        let (bits, tree, _) = huffman_compress(data);
+        let mut body = Vec::new();
+        write_code_table(&tree, &mut body);
+        body.extend_from_slice(&bits);
+        with_len(data.len(), body)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (len, body) = split_len(data)?;
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut idx = 0;
+        let table = read_code_table(body, &mut idx)?;
+        let out = huffman_decompress(&body[idx..], &table, len);
+        if out.len() < len {
+            return Err(RsZipError::Truncated("huffman bits"));
+        }
+        Ok(out)
+    }
+}
+
+struct Lzw;
+
+impl Codec for Lzw {
+    fn name(&self) -> &str {
+        "lzw"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        with_len(data.len(), lzw::compress(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (len, body) = split_len(data)?;
+        lzw::decompress(body, len)
+    }
+}
+
+struct Rle;
+
+impl Codec for Rle {
+    fn name(&self) -> &str {
+        "rle"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        rle::compress(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        rle::decompress(data)
+    }
+}
+
+struct Bwt;
+
+impl Codec for Bwt {
+    fn name(&self) -> &str {
+        "bwt"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        bwt::compress(data, bwt::DEFAULT_BLOCK)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        bwt::decompress(data)
+    }
+}
+
+struct Range(ContextModel);
+
+impl Codec for Range {
+    fn name(&self) -> &str {
+        match self.0 {
+            ContextModel::Order0 => "range",
+            ContextModel::Order1 => "range-order1",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        with_len(data.len(), range::compress(data, self.0))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (len, body) = split_len(data)?;
+        range::decompress(body, len, self.0)
+    }
+}
+
+struct AdaptiveHuffman;
+
+impl Codec for AdaptiveHuffman {
+    fn name(&self) -> &str {
+        "adaptive-huffman"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        with_len(data.len(), adaptive_huffman::compress(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (len, body) = split_len(data)?;
+        adaptive_huffman::decompress(body, len)
+    }
+}
+
+struct Deflate;
+
+impl Codec for Deflate {
+    fn name(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        deflate::compress(data, CompressionLevel::Default)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        deflate::decompress(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{Filter, ENGLISH_WORDS_ID};
+    use crate::format::{Header, Reader};
+    use crate::{compress, decompress, testdata, Algorithm, CompressOptions};
+
+    struct Xor;
+    impl Codec for Xor {
+        fn name(&self) -> &str {
+            "test-xor"
+        }
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            data.iter().map(|b| b ^ 0x5a).collect()
+        }
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+    }
+
+    #[test]
+    fn built_ins_round_trip_by_id() {
+        for (name, data) in testdata::corpora() {
+            for (id, codec) in registered().into_iter().filter(|&(id, _)| id < FIRST_USER_ID) {
+                let opts = CompressOptions { algo: Algorithm::Registered(id), ..Default::default() };
+                assert_eq!(decompress(&compress(&data, &opts).unwrap(), None).unwrap(), data, "{}/{}", codec, name);
+            }
+        }
+    }
+
+    #[test]
+    fn registered_codecs_round_trip_by_id() {
+        assert!(register(7, Arc::new(Xor)).is_err());
+        register(200, Arc::new(Xor)).unwrap();
+        // the name is taken too
+        assert!(register(201, Arc::new(Xor)).is_err());
+        assert_eq!(by_id(200).unwrap().name(), "test-xor");
+        let text = testdata::text(4_000);
+        // incompressible through XOR, so the filter is what shrinks it
+        let opts = CompressOptions { algo: Algorithm::Registered(200), filter: Filter::Text { dict: ENGLISH_WORDS_ID }, ..Default::default() };
+        let file = compress(&text, &opts).unwrap();
+        assert_eq!(decompress(&file, None).unwrap(), text);
+        // XOR keeps the length, so the id byte sits just before lz_len bytes
+        let mut unknown = file.clone();
+        unknown[file.len() - Header::read(&mut Reader::new(&file)).unwrap().lz_len as usize - 1] = 251;
+        assert!(matches!(decompress(&unknown, None), Err(RsZipError::Unsupported(_))));
+        assert!(compress(&text, &CompressOptions { algo: Algorithm::Registered(250), ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+// PackBits-style run-length coding, for bitmaps and logs where runs of one
+// byte are common. A control byte n below 128 is followed by n + 1 literal
+// bytes; from 128 up it is a run of n - 128 + MIN_RUN copies of the single
+// byte after it. A run costs two bytes wherever it falls, where LZ77 pays
+// for a literal and a match token.
+use crate::{Result, RsZipError};
+
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 127 + MIN_RUN;
+const MAX_LITERALS: usize = 128;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_LITERALS + 1);
+    let mut literals = 0..0;
+    let flush = |out: &mut Vec<u8>, literals: &mut std::ops::Range<usize>| {
+        for chunk in data[literals.clone()].chunks(MAX_LITERALS) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+        *literals = literals.end..literals.end;
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(MAX_RUN).take_while(|&&b| b == data[i]).count();
+        if run >= MIN_RUN {
+            flush(&mut out, &mut literals);
+            out.push((run - MIN_RUN + 128) as u8);
+            out.push(data[i]);
+            i += run;
+            literals = i..i;
+        } else {
+            i += run;
+            literals.end = i;
+        }
+    }
+    flush(&mut out, &mut literals);
+    out
+}
+
+// decodes all of `body`; its length comes from the header (lz_len)
+pub fn decompress(body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        let control = body[i] as usize;
+        if control < 128 {
+            let literals = body.get(i + 1..i + 2 + control).ok_or(RsZipError::Truncated("rle data"))?;
+            out.extend_from_slice(literals);
+            i += 2 + control;
+        } else {
+            let byte = *body.get(i + 1).ok_or(RsZipError::Truncated("rle data"))?;
+            out.extend(std::iter::repeat_n(byte, control - 128 + MIN_RUN));
+            i += 2;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{CODEC_HUFFMAN, CODEC_RLE};
+    use crate::{compress, decompress, Algorithm, CompressOptions};
+
+    #[test]
+    fn codes_long_runs() {
+        // a 1-bit-per-pixel bitmap: long runs of 00 and ff with short noisy spans
+        let mut bitmap = Vec::new();
+        for row in 0..400u32 {
+            bitmap.extend(std::iter::repeat_n(0u8, 90 + (row % 7) as usize));
+            bitmap.extend([0x3c, 0x7e, (row % 251) as u8, 0xff, 0xff]);
+            bitmap.extend(std::iter::repeat_n(0xffu8, 300));
+        }
+        let rle = CompressOptions { algo: Algorithm::Rle, ..Default::default() };
+        let coded = compress(&bitmap, &rle).unwrap();
+        assert_eq!(coded[5], CODEC_RLE | CODEC_HUFFMAN);
+        assert_eq!(decompress(&coded, None).unwrap(), bitmap);
+        let plain = compress(&bitmap, &CompressOptions { huffman: false, ..rle }).unwrap();
+        assert_eq!(plain[5], CODEC_RLE);
+        assert!(plain.len() < bitmap.len() / 25);
+        assert_eq!(decompress(&plain, None).unwrap(), bitmap);
+        assert!(decompress(&plain[..plain.len() - 1], None).is_err());
+    }
+}
@@ -0,0 +1,1017 @@
+// The ciphers behind `encrypt`/`decrypt`: ChaCha20-Poly1305 (`seal`/`open`)
+// and the older Feistel network, plus SHA-256 (FIPS 180-4) with HMAC and
+// PBKDF2 on top of it, used for content digests and password stretching.
+// Passphrases and keys derived from them are held in `SecretKey`, which
+// wipes them when dropped, and tags and key checks are compared with `ct_eq`.
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+use crate::format::{put_u32, Reader};
+use crate::{Result, RsZipError};
+
+// Overwrites `buf` with zeros in a way the optimizer can't drop as a dead
+// store, even though `buf` is about to be freed.
+pub fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // SAFETY: `b` is a valid, exclusive reference
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+// Equality whose time depends only on the lengths, never on where the bytes
+// differ, for tags, key checks and anything else an attacker may be guessing
+// at one byte at a time.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    // keeps the optimizer from turning the fold back into an early exit
+    std::hint::black_box(diff) == 0
+}
+
+// Key material: a passphrase, or a key derived from one. Zeroed on drop and
+// deliberately not Clone, so there is one copy to wipe; Debug shows only the
+// length.
+pub struct SecretKey(Vec<u8>);
+
+impl SecretKey {
+    pub fn new(bytes: Vec<u8>) -> SecretKey {
+        SecretKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // the whole key as a fixed-size array, for ciphers taking one
+    pub fn as_array<const N: usize>(&self) -> Option<&[u8; N]> {
+        self.0.as_slice().try_into().ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// takes over the string's buffer rather than copying it
+impl From<String> for SecretKey {
+    fn from(s: String) -> SecretKey {
+        SecretKey(s.into_bytes())
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey({} bytes)", self.0.len())
+    }
+}
+
+// Feistel round keys, wiped like SecretKey
+struct RoundKeys(Vec<u32>);
+
+impl Deref for RoundKeys {
+    type Target = [u32];
+    fn deref(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl Drop for RoundKeys {
+    fn drop(&mut self) {
+        for k in self.0.iter_mut() {
+            // SAFETY: `k` is a valid, exclusive reference
+            unsafe { std::ptr::write_volatile(k, 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+fn round_function(input: u32, key: u32) -> u32 {
+    let x = input.wrapping_add(key);
+    x.rotate_left(5) ^ (x >> 3)
+}
+fn feistel_encrypt_block(mut left: u32, mut right: u32, keys: &[u32]) -> (u32, u32) {
+    for &k in keys {
+        let f = round_function(right, k);
+        let new_left = right;
+        let new_right = left ^ f;
+        left = new_left;
+        right = new_right;
+    }
+    (left, right)
+}
+fn feistel_decrypt_block(mut left: u32, mut right: u32, keys: &[u32]) -> (u32, u32) {
+    for &k in keys.iter().rev() {
+        let f = round_function(left, k);
+        let new_right = left;
+        let new_left = right ^ f;
+        left = new_left;
+        right = new_right;
+    }
+    (left, right)
+}
+// little-endian word from up to 4 bytes, zero-padded
+fn word(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |w, &b| (w << 8) | b as u32)
+}
+
+// How a key becomes round keys. `Direct` is the original schedule, one round
+// per 4 key bytes taken as they are, so a short key gets only a round or two;
+// it stays for reading what was written with it. `Expanded` stretches any key
+// to the given number of rounds, at least MIN_FEISTEL_ROUNDS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeySchedule {
+    Direct,
+    Expanded(u8),
+}
+
+pub const MIN_FEISTEL_ROUNDS: u8 = 16;
+pub const DEFAULT_FEISTEL_ROUNDS: u8 = 32;
+
+// Round keys from HMAC-SHA256 keyed by the key, over a counter and the round
+// count: each output block is mixed from the whole key, and every count gets
+// an unrelated schedule rather than a prefix of a longer one.
+fn expand_key(key_material: &[u8], rounds: u8) -> Vec<u32> {
+    let mut keys = Vec::with_capacity(rounds as usize);
+    let mut counter = 0u32;
+    while keys.len() < rounds as usize {
+        let mut info = b"rszip feistel key schedule".to_vec();
+        info.push(rounds);
+        info.extend_from_slice(&counter.to_le_bytes());
+        let mut block = hmac_sha256(key_material, &info);
+        let wanted = rounds as usize - keys.len();
+        keys.extend(block.chunks(4).map(word).take(wanted));
+        zeroize(&mut block);
+        counter += 1;
+    }
+    keys
+}
+
+fn derive_keys(key_material: &[u8], schedule: KeySchedule) -> Result<RoundKeys> {
+    if key_material.is_empty() {
+        // no round keys means no rounds: "ciphertext" would be the plaintext
+        return Err(RsZipError::BadKey("key is empty"));
+    }
+    match schedule {
+        KeySchedule::Direct => Ok(RoundKeys(key_material.chunks(4).map(word).collect())),
+        KeySchedule::Expanded(rounds) if rounds < MIN_FEISTEL_ROUNDS => {
+            Err(RsZipError::InvalidOption(format!("Feistel needs at least {} rounds", MIN_FEISTEL_ROUNDS)))
+        }
+        KeySchedule::Expanded(rounds) => Ok(RoundKeys(expand_key(key_material, rounds))),
+    }
+}
+// (left, right) halves of a block; a short final block is zero-padded
+fn halves(chunk: &[u8]) -> (u32, u32) {
+    let (left, right) = chunk.split_at(chunk.len().min(4));
+    (word(left), word(right))
+}
+pub fn feistel_encrypt(data: &[u8], key_material: &[u8], schedule: KeySchedule) -> Result<Vec<u8>> {
+    Ok(ecb_encrypt(data, &derive_keys(key_material, schedule)?))
+}
+pub fn feistel_decrypt(data: &[u8], key_material: &[u8], schedule: KeySchedule) -> Result<Vec<u8>> {
+    ecb_decrypt(data, &derive_keys(key_material, schedule)?)
+}
+// each block alone, under round keys already derived
+fn ecb_encrypt(data: &[u8], keys: &[u32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len().next_multiple_of(8));
+    for chunk in data.chunks(8) {
+        let (left, right) = halves(chunk);
+        let (el, er) = feistel_encrypt_block(left, right, keys);
+        put_u32(&mut out, el);
+        put_u32(&mut out, er);
+    }
+    out
+}
+fn ecb_decrypt(data: &[u8], keys: &[u32]) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(8) {
+        return Err(RsZipError::Truncated("ciphertext (not a whole number of 8-byte blocks)"));
+    }
+    let mut out = Vec::with_capacity(data.len());
+    for chunk in data.chunks(8) {
+        let (left, right) = halves(chunk);
+        let (dl, dr) = feistel_decrypt_block(left, right, keys);
+        put_u32(&mut out, dl);
+        put_u32(&mut out, dr);
+    }
+    Ok(out)
+}
+
+// PKCS#7: pad with n bytes of value n, 1 to `block` of them, so even an
+// input that fills its last block gets a whole block of padding and the
+// original length is always recoverable
+pub fn pkcs7_pad(data: &[u8], block: usize) -> Vec<u8> {
+    let n = block - data.len() % block;
+    let mut out = Vec::with_capacity(data.len() + n);
+    out.extend_from_slice(data);
+    out.resize(data.len() + n, n as u8);
+    out
+}
+
+// Reads the whole last block whatever the padding says, so how long it takes
+// doesn't tell a padding oracle how much of it was right.
+pub fn pkcs7_unpad(data: &[u8], block: usize) -> Result<&[u8]> {
+    let n = *data.last().ok_or(RsZipError::Truncated("ciphertext (no padding block)"))? as usize;
+    let mut bad = (n == 0) as u8 | (n > block) as u8 | (n > data.len()) as u8;
+    for (i, &b) in data.iter().rev().take(block).enumerate() {
+        // all ones for the bytes that should be padding
+        let in_padding = ((i < n) as u8).wrapping_neg();
+        bad |= in_padding & (b ^ n as u8);
+    }
+    if std::hint::black_box(bad) != 0 {
+        return Err(RsZipError::CorruptData("block padding (wrong key?)"));
+    }
+    Ok(&data[..data.len() - n])
+}
+
+// Feistel over 8-byte blocks with PKCS#7 padding, so decrypt returns exactly
+// what was encrypted. feistel_encrypt/feistel_decrypt are the unpadded
+// primitive, whose last block is zero-filled.
+pub fn encrypt(data: &[u8], key_material: &[u8], schedule: KeySchedule) -> Result<Vec<u8>> {
+    feistel_encrypt(&pkcs7_pad(data, 8), key_material, schedule)
+}
+
+pub fn decrypt(data: &[u8], key_material: &[u8], schedule: KeySchedule) -> Result<Vec<u8>> {
+    Ok(pkcs7_unpad(&feistel_decrypt(data, key_material, schedule)?, 8)?.to_vec())
+}
+
+// How 8-byte Feistel blocks are chained. ECB encrypts each block alone, so
+// equal plaintext blocks show as equal ciphertext; CBC XORs each block with
+// the previous ciphertext block, CTR encrypts an incrementing counter and
+// XORs that in, which needs no padding. Both start from a random IV, written
+// as the first 8 bytes of the output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherMode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+fn encrypt_block(block: [u8; 8], keys: &[u32]) -> [u8; 8] {
+    let (left, right) = halves(&block);
+    let (el, er) = feistel_encrypt_block(left, right, keys);
+    let mut out = [0u8; 8];
+    out[..4].copy_from_slice(&el.to_le_bytes());
+    out[4..].copy_from_slice(&er.to_le_bytes());
+    out
+}
+
+fn decrypt_block(block: [u8; 8], keys: &[u32]) -> [u8; 8] {
+    let (left, right) = halves(&block);
+    let (dl, dr) = feistel_decrypt_block(left, right, keys);
+    let mut out = [0u8; 8];
+    out[..4].copy_from_slice(&dl.to_le_bytes());
+    out[4..].copy_from_slice(&dr.to_le_bytes());
+    out
+}
+
+fn xor8(a: [u8; 8], b: [u8; 8]) -> [u8; 8] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+// the counter is the IV read as a little-endian u64, plus the block index
+fn feistel_ctr(data: &[u8], keys: &[u32], iv: [u8; 8]) -> Vec<u8> {
+    let start = u64::from_le_bytes(iv);
+    let mut out = Vec::with_capacity(data.len());
+    for (i, chunk) in data.chunks(8).enumerate() {
+        let keystream = encrypt_block(start.wrapping_add(i as u64).to_le_bytes(), keys);
+        out.extend(chunk.iter().zip(keystream).map(|(b, k)| b ^ k));
+    }
+    out
+}
+
+// byte-exact in every mode: ECB and CBC pad with PKCS#7
+pub fn encrypt_with_mode(data: &[u8], key_material: &[u8], mode: CipherMode, schedule: KeySchedule) -> Result<Vec<u8>> {
+    let keys = derive_keys(key_material, schedule)?;
+    if mode == CipherMode::Ecb {
+        return Ok(ecb_encrypt(&pkcs7_pad(data, 8), &keys));
+    }
+    let iv: [u8; 8] = random_bytes(8)?.try_into().unwrap();
+    let mut out = iv.to_vec();
+    match mode {
+        CipherMode::Cbc => {
+            let mut prev = iv;
+            for chunk in pkcs7_pad(data, 8).chunks(8) {
+                prev = encrypt_block(xor8(chunk.try_into().unwrap(), prev), &keys);
+                out.extend_from_slice(&prev);
+            }
+        }
+        _ => out.extend_from_slice(&feistel_ctr(data, &keys, iv)),
+    }
+    Ok(out)
+}
+
+pub fn decrypt_with_mode(data: &[u8], key_material: &[u8], mode: CipherMode, schedule: KeySchedule) -> Result<Vec<u8>> {
+    let keys = derive_keys(key_material, schedule)?;
+    if mode == CipherMode::Ecb {
+        return Ok(pkcs7_unpad(&ecb_decrypt(data, &keys)?, 8)?.to_vec());
+    }
+    if data.len() < 8 {
+        return Err(RsZipError::Truncated("ciphertext (no IV)"));
+    }
+    let (iv, body) = data.split_at(8);
+    let iv: [u8; 8] = iv.try_into().unwrap();
+    match mode {
+        CipherMode::Cbc => {
+            if !body.len().is_multiple_of(8) {
+                return Err(RsZipError::Truncated("ciphertext (not a whole number of 8-byte blocks)"));
+            }
+            let mut prev = iv;
+            let mut out = Vec::with_capacity(body.len());
+            for chunk in body.chunks(8) {
+                let block: [u8; 8] = chunk.try_into().unwrap();
+                out.extend_from_slice(&xor8(decrypt_block(block, &keys), prev));
+                prev = block;
+            }
+            Ok(pkcs7_unpad(&out, 8)?.to_vec())
+        }
+        _ => Ok(feistel_ctr(body, &keys, iv)),
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (x, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 32];
+    for (i, x) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    let mac = sha256(&outer);
+    // the padded key; the message after it is the caller's to keep or wipe
+    zeroize(&mut block);
+    zeroize(&mut inner[..64]);
+    zeroize(&mut outer[..64]);
+    mac
+}
+
+// RFC 8018 PBKDF2 with HMAC-SHA256
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, len: usize) -> SecretKey {
+    // sized up front so the key is never copied by a reallocation
+    let mut out = Vec::with_capacity(len.next_multiple_of(32));
+    for block in 1..=len.div_ceil(32) as u32 {
+        let mut msg = salt.to_vec();
+        msg.extend_from_slice(&block.to_be_bytes());
+        let mut u = hmac_sha256(password, &msg);
+        let mut t = u;
+        for _ in 1..iterations {
+            u = hmac_sha256(password, &u);
+            for (a, b) in t.iter_mut().zip(u) {
+                *a ^= b;
+            }
+        }
+        out.extend_from_slice(&t);
+        zeroize(&mut u);
+        zeroize(&mut t);
+    }
+    zeroize(&mut out[len..]);
+    out.truncate(len);
+    SecretKey(out)
+}
+
+pub fn hex_lower(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ChaCha20-Poly1305, the RFC 8439 AEAD, the one `encrypt` uses by default: confidentiality plus a
+// tag over ciphertext and header, so a wrong key or any modified byte is
+// caught before plaintext is returned. The key comes from the passphrase via
+// PBKDF2 with a random per-file salt, and every file gets a fresh nonce.
+pub const SEALED_MAGIC: &[u8; 4] = b"RSZE";
+// 2 added the contents byte, 3 the Feistel modes' HMAC tag, 4 the key source byte
+const SEALED_VERSION: u8 = 4;
+// key source byte: what the cipher keys were derived from
+const KEY_PASSPHRASE: u8 = 0;
+const KEY_FILE: u8 = 1;
+const CIPHER_CHACHA20_POLY1305: u8 = 1;
+// 2 to 5 were Feistel with 16 rounds straight from the KDF output, and no
+// tag before version 3. They are refused: a reader that skips the tag for
+// old versions lets anyone strip it by rewriting the version byte.
+const CIPHER_FEISTEL_DIRECT: u8 = 2;
+// the KDF output expanded to the rounds in a [rounds u8] after the salt
+const CIPHER_FEISTEL_ECB_EXPANDED: u8 = 6;
+const CIPHER_FEISTEL_CBC_EXPANDED: u8 = 7;
+const CIPHER_FEISTEL_CTR_EXPANDED: u8 = 8;
+// 16 round keys for the direct schedule, the expanded one's seed
+const FEISTEL_KEY_LEN: usize = 64;
+// HMAC-SHA256 over header and ciphertext, keyed by the KDF output after the round keys
+const MAC_KEY_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+pub const DEFAULT_KDF_ITERATIONS: u32 = 100_000;
+// a header asking for more is hostile: it would stall the reader
+pub const MAX_KDF_ITERATIONS: u32 = 100_000_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        state[4 + i] = le_u32(&key[i * 4..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = le_u32(&nonce[i * 4..]);
+    }
+    let mut s = state;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        out[i * 4..i * 4 + 4].copy_from_slice(&s[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    out
+}
+
+// XORs the keystream starting at block `counter` into `data`
+pub fn chacha20_xor(key: &[u8; 32], nonce: &[u8; NONCE_LEN], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let keystream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(keystream) {
+            *b ^= k;
+        }
+    }
+}
+
+// one-time authenticator over 26-bit limbs, after poly1305-donna
+pub fn poly1305(key: &[u8; 32], msg: &[u8]) -> [u8; 16] {
+    const M: u32 = 0x3ffffff;
+    let r = [
+        le_u32(&key[0..]) & 0x3ffffff,
+        (le_u32(&key[3..]) >> 2) & 0x3ffff03,
+        (le_u32(&key[6..]) >> 4) & 0x3ffc0ff,
+        (le_u32(&key[9..]) >> 6) & 0x3f03fff,
+        (le_u32(&key[12..]) >> 8) & 0x00fffff,
+    ];
+    let r: [u64; 5] = r.map(u64::from);
+    let s = [r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+    let mut h = [0u32; 5];
+    for chunk in msg.chunks(16) {
+        // the 2^128 bit (or the 0x01 after a short final block) rides in byte 16
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+        h[0] += le_u32(&block[0..]) & M;
+        h[1] += (le_u32(&block[3..]) >> 2) & M;
+        h[2] += (le_u32(&block[6..]) >> 4) & M;
+        h[3] += (le_u32(&block[9..]) >> 6) & M;
+        h[4] += (le_u32(&block[12..]) >> 8) | ((block[16] as u32) << 24);
+        let h64 = h.map(u64::from);
+        let mut d = [
+            h64[0] * r[0] + h64[1] * s[3] + h64[2] * s[2] + h64[3] * s[1] + h64[4] * s[0],
+            h64[0] * r[1] + h64[1] * r[0] + h64[2] * s[3] + h64[3] * s[2] + h64[4] * s[1],
+            h64[0] * r[2] + h64[1] * r[1] + h64[2] * r[0] + h64[3] * s[3] + h64[4] * s[2],
+            h64[0] * r[3] + h64[1] * r[2] + h64[2] * r[1] + h64[3] * r[0] + h64[4] * s[3],
+            h64[0] * r[4] + h64[1] * r[3] + h64[2] * r[2] + h64[3] * r[1] + h64[4] * r[0],
+        ];
+        for i in 0..4 {
+            d[i + 1] += d[i] >> 26;
+            h[i] = d[i] as u32 & M;
+        }
+        h[4] = d[4] as u32 & M;
+        h[0] += (d[4] >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= M;
+    }
+    // full carry, then subtract p = 2^130 - 5 if h >= p, in constant time
+    for i in 1..5 {
+        h[i] += h[i - 1] >> 26;
+        h[i - 1] &= M;
+    }
+    h[0] += (h[4] >> 26) * 5;
+    h[4] &= M;
+    h[1] += h[0] >> 26;
+    h[0] &= M;
+    let mut g = [0u32; 5];
+    let mut carry = 5;
+    for i in 0..4 {
+        g[i] = h[i] + carry;
+        carry = g[i] >> 26;
+        g[i] &= M;
+    }
+    g[4] = (h[4] + carry).wrapping_sub(1 << 26);
+    let use_g = (g[4] >> 31).wrapping_sub(1);
+    for i in 0..5 {
+        h[i] = (h[i] & !use_g) | (g[i] & use_g);
+    }
+    let words = [h[0] | (h[1] << 26), (h[1] >> 6) | (h[2] << 20), (h[2] >> 12) | (h[3] << 14), (h[3] >> 18) | (h[4] << 8)];
+    let mut out = [0u8; 16];
+    let mut f = 0u64;
+    for i in 0..4 {
+        f = words[i] as u64 + le_u32(&key[16 + i * 4..]) as u64 + (f >> 32);
+        out[i * 4..i * 4 + 4].copy_from_slice(&(f as u32).to_le_bytes());
+    }
+    out
+}
+
+fn aead_tag(key: &[u8; 32], nonce: &[u8; NONCE_LEN], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut one_time = [0u8; 32];
+    one_time.copy_from_slice(&chacha20_block(key, 0, nonce)[..32]);
+    let mut mac = aad.to_vec();
+    mac.resize(aad.len().next_multiple_of(16), 0);
+    mac.extend_from_slice(ciphertext);
+    mac.resize(mac.len().next_multiple_of(16), 0);
+    mac.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    mac.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(&one_time, &mac)
+}
+
+// ciphertext followed by the 16-byte tag
+pub fn chacha20_poly1305_seal(key: &[u8; 32], nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = plaintext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut out);
+    let tag = aead_tag(key, nonce, aad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+pub fn chacha20_poly1305_open(key: &[u8; 32], nonce: &[u8; NONCE_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let body_len = sealed.len().checked_sub(TAG_LEN).ok_or(RsZipError::Truncated("ciphertext (no tag)"))?;
+    let (ciphertext, tag) = sealed.split_at(body_len);
+    if !ct_eq(&aead_tag(key, nonce, aad, ciphertext), tag) {
+        return Err(RsZipError::AuthenticationFailed);
+    }
+    let mut out = ciphertext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut out);
+    Ok(out)
+}
+
+// From the OS generator: /dev/urandom, or RtlGenRandom on Windows.
+#[cfg(not(windows))]
+pub fn random_bytes(n: usize) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut out = vec![0u8; n];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut out)?;
+    Ok(out)
+}
+#[cfg(windows)]
+pub fn random_bytes(n: usize) -> std::io::Result<Vec<u8>> {
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        #[link_name = "SystemFunction036"]
+        fn RtlGenRandom(buffer: *mut u8, len: u32) -> u8;
+    }
+    let mut out = vec![0u8; n];
+    for chunk in out.chunks_mut(u32::MAX as usize) {
+        // SAFETY: the pointer and length describe a live, writable buffer
+        if unsafe { RtlGenRandom(chunk.as_mut_ptr(), chunk.len() as u32) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(out)
+}
+
+// Key files (`rszip keygen`) hold a random 256-bit key behind a signature
+// with a NUL in it, so no passphrase typed or passed as an argument is
+// mistaken for one. `seal` and `open` take a key file's whole contents in
+// place of a passphrase and use the key as is, without stretching it.
+pub const KEY_FILE_MAGIC: &[u8; 5] = b"RSZK\0";
+pub const KEY_FILE_LEN: usize = KEY_FILE_MAGIC.len() + 32;
+
+pub fn generate_key_file() -> std::io::Result<SecretKey> {
+    let mut file = KEY_FILE_MAGIC.to_vec();
+    let mut key = random_bytes(32)?;
+    file.extend_from_slice(&key);
+    zeroize(&mut key);
+    Ok(SecretKey::new(file))
+}
+
+// the key in a key file's contents; None for anything else, a passphrase
+pub fn key_file_key(material: &[u8]) -> Option<&[u8]> {
+    let (magic, key) = material.split_at(material.len().min(KEY_FILE_MAGIC.len()));
+    (material.len() == KEY_FILE_LEN && ct_eq(magic, KEY_FILE_MAGIC)).then_some(key)
+}
+
+fn derived_key(passphrase: &[u8], salt: &[u8], iterations: u32, len: usize) -> Result<SecretKey> {
+    if passphrase.is_empty() {
+        return Err(RsZipError::BadKey("key is empty"));
+    }
+    Ok(pbkdf2_sha256(passphrase, salt, iterations, len))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    // mode and rounds; round keys are expanded from the KDF output, and an
+    // HMAC-SHA256 tag catches a wrong key or any modified byte as
+    // ChaCha20-Poly1305's does
+    Feistel(CipherMode, u8),
+}
+
+// what the plaintext of a sealed file is, so `unpack` knows what to undo
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Contents {
+    Data,
+    // a complete rszip file, written by `pack`
+    Compressed,
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+// A self-describing encrypted file, see format.rs for the layout. The key is
+// stretched from the passphrase with PBKDF2 and a fresh random salt, so equal
+// passphrases never give equal keys. The whole header is authenticated along
+// with the ciphertext, and nothing is decrypted before the tag checks out.
+pub fn seal(data: &[u8], passphrase: &[u8], cipher: Cipher, iterations: u32) -> Result<Vec<u8>> {
+    seal_contents(data, Contents::Data, passphrase, cipher, iterations)
+}
+
+pub fn seal_contents(data: &[u8], contents: Contents, passphrase: &[u8], cipher: Cipher, iterations: u32) -> Result<Vec<u8>> {
+    if !(1..=MAX_KDF_ITERATIONS).contains(&iterations) {
+        return Err(RsZipError::InvalidOption(format!("KDF iterations must be 1 to {}", MAX_KDF_ITERATIONS)));
+    }
+    let salt = random_bytes(SALT_LEN)?;
+    // a key file's key is already full strength: one PBKDF2 round just
+    // spreads it over the cipher and MAC keys
+    let (passphrase, source, iterations) = match key_file_key(passphrase) {
+        Some(key) => (key, KEY_FILE, 1),
+        None => (passphrase, KEY_PASSPHRASE, iterations),
+    };
+    let mut out = SEALED_MAGIC.to_vec();
+    out.push(SEALED_VERSION);
+    out.push(match cipher {
+        Cipher::ChaCha20Poly1305 => CIPHER_CHACHA20_POLY1305,
+        Cipher::Feistel(CipherMode::Ecb, _) => CIPHER_FEISTEL_ECB_EXPANDED,
+        Cipher::Feistel(CipherMode::Cbc, _) => CIPHER_FEISTEL_CBC_EXPANDED,
+        Cipher::Feistel(CipherMode::Ctr, _) => CIPHER_FEISTEL_CTR_EXPANDED,
+    });
+    out.push(contents as u8);
+    out.push(source);
+    put_u32(&mut out, iterations);
+    out.extend_from_slice(&salt);
+    match cipher {
+        Cipher::ChaCha20Poly1305 => {
+            let key = derived_key(passphrase, &salt, iterations, 32)?;
+            let nonce: [u8; NONCE_LEN] = random_bytes(NONCE_LEN)?.try_into().unwrap();
+            out.extend_from_slice(&nonce);
+            let sealed = chacha20_poly1305_seal(key.as_array().unwrap(), &nonce, &out, data);
+            out.extend_from_slice(&sealed);
+        }
+        Cipher::Feistel(mode, rounds) => {
+            let key = derived_key(passphrase, &salt, iterations, FEISTEL_KEY_LEN + MAC_KEY_LEN)?;
+            let (key, mac_key) = key.as_bytes().split_at(FEISTEL_KEY_LEN);
+            out.push(rounds);
+            out.extend_from_slice(&encrypt_with_mode(data, key, mode, KeySchedule::Expanded(rounds))?);
+            let tag = hmac_sha256(mac_key, &out);
+            out.extend_from_slice(&tag);
+        }
+    }
+    Ok(out)
+}
+
+pub fn open(data: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    Ok(open_contents(data, passphrase)?.1)
+}
+
+pub fn open_contents(data: &[u8], passphrase: &[u8]) -> Result<(Contents, Vec<u8>)> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("encryption header");
+    if !is_sealed(data) {
+        return Err(RsZipError::CorruptHeader("not an RSZE encrypted file".into()));
+    }
+    let mut r = Reader::new(&data[SEALED_MAGIC.len()..]);
+    let version = r.u8().ok_or(TRUNCATED)?;
+    if !(1..=SEALED_VERSION).contains(&version) {
+        return Err(RsZipError::Unsupported(format!("encryption format version {}", version)));
+    }
+    let cipher = r.u8().ok_or(TRUNCATED)?;
+    // version 1 had no contents byte and only ever held plain data
+    let contents = match if version == 1 { 0 } else { r.u8().ok_or(TRUNCATED)? } {
+        0 => Contents::Data,
+        1 => Contents::Compressed,
+        other => return Err(RsZipError::Unsupported(format!("sealed contents {}", other))),
+    };
+    // before version 4 there were only passphrases
+    let source = if version < 4 { KEY_PASSPHRASE } else { r.u8().ok_or(TRUNCATED)? };
+    let passphrase = match (source, key_file_key(passphrase)) {
+        (KEY_PASSPHRASE, None) => passphrase,
+        (KEY_FILE, Some(key)) => key,
+        (KEY_PASSPHRASE, Some(_)) => return Err(RsZipError::BadKey("file was encrypted with a passphrase, not a key file")),
+        (KEY_FILE, None) => return Err(RsZipError::BadKey("file was encrypted with a key file, not a passphrase")),
+        (other, _) => return Err(RsZipError::Unsupported(format!("key source {}", other))),
+    };
+    let iterations = r.u32().ok_or(TRUNCATED)?;
+    if !(1..=MAX_KDF_ITERATIONS).contains(&iterations) {
+        return Err(RsZipError::CorruptHeader(format!("KDF iterations {}", iterations)));
+    }
+    let salt = r.bytes(SALT_LEN).ok_or(TRUNCATED)?;
+    let plain = match cipher {
+        CIPHER_CHACHA20_POLY1305 => {
+            let nonce = r.bytes(NONCE_LEN).ok_or(TRUNCATED)?.try_into().unwrap();
+            let header = &data[..SEALED_MAGIC.len() + r.pos];
+            let key = derived_key(passphrase, salt, iterations, 32)?;
+            chacha20_poly1305_open(key.as_array().unwrap(), nonce, header, r.rest())?
+        }
+        CIPHER_FEISTEL_ECB_EXPANDED..=CIPHER_FEISTEL_CTR_EXPANDED if version == SEALED_VERSION => {
+            let rounds = match r.u8().ok_or(TRUNCATED)? {
+                rounds if rounds >= MIN_FEISTEL_ROUNDS => rounds,
+                rounds => return Err(RsZipError::CorruptHeader(format!("{} Feistel rounds", rounds))),
+            };
+            let key = derived_key(passphrase, salt, iterations, FEISTEL_KEY_LEN + MAC_KEY_LEN)?;
+            let (key, mac_key) = key.as_bytes().split_at(FEISTEL_KEY_LEN);
+            let tag_at = r.rest().len().checked_sub(MAC_LEN).ok_or(RsZipError::Truncated("ciphertext (no tag)"))?;
+            let (ciphertext, tag) = r.rest().split_at(tag_at);
+            let expected = hmac_sha256(mac_key, &data[..data.len() - MAC_LEN]);
+            if !ct_eq(&expected, tag) {
+                return Err(RsZipError::AuthenticationFailed);
+            }
+            let mode = match cipher {
+                CIPHER_FEISTEL_ECB_EXPANDED => CipherMode::Ecb,
+                CIPHER_FEISTEL_CBC_EXPANDED => CipherMode::Cbc,
+                _ => CipherMode::Ctr,
+            };
+            decrypt_with_mode(ciphertext, key, mode, KeySchedule::Expanded(rounds))?
+        }
+        CIPHER_FEISTEL_DIRECT..=CIPHER_FEISTEL_CTR_EXPANDED => {
+            return Err(RsZipError::Unsupported(format!(
+                "Feistel file from encryption format version {}, which had no tag or a weaker key schedule; \
+                 decrypt it with an older rszip and encrypt it again",
+                version
+            )));
+        }
+        other => return Err(RsZipError::Unsupported(format!("cipher {}", other))),
+    };
+    Ok((contents, plain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, unhex};
+    use std::collections::HashSet;
+
+    #[test]
+    fn hashes_match_published_vectors() {
+        assert_eq!(hex_lower(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // RFC 7914
+        let derived = pbkdf2_sha256(b"passwd", b"salt", 1, 32);
+        assert_eq!(hex_lower(derived.as_bytes()), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+    }
+
+    #[test]
+    fn chacha20_poly1305_matches_rfc_8439() {
+        let key: [u8; 32] = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").try_into().unwrap();
+        assert_eq!(hex_lower(&poly1305(&key, b"Cryptographic Forum Research Group")), "a8061dc1305136c6c22b8baf0c0127a9");
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plain = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+                      sunscreen would be it.";
+        let sealed = chacha20_poly1305_seal(&key, &nonce, &aad, plain);
+        let (ciphertext, tag) = sealed.split_at(plain.len());
+        assert_eq!(hex_lower(&ciphertext[..16]), "d31a8d34648e60db7b86afbc53ef7ec2");
+        assert_eq!(hex_lower(tag), "1ae10b594f09e26a7e902ecbd0600691");
+        assert_eq!(chacha20_poly1305_open(&key, &nonce, &aad, &sealed).unwrap(), plain);
+        assert!(chacha20_poly1305_open(&key, &nonce, b"other aad", &sealed).is_err());
+    }
+
+    #[test]
+    fn cbc_and_ctr_hide_repeated_blocks() {
+        let repeated = [b'x'; 64];
+        let distinct_blocks = |out: &[u8]| out[8..].chunks(8).collect::<HashSet<_>>().len();
+        let schedule = KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS);
+        let cbc = encrypt_with_mode(&repeated, b"mode key", CipherMode::Cbc, schedule).unwrap();
+        let ctr = encrypt_with_mode(&repeated, b"mode key", CipherMode::Ctr, schedule).unwrap();
+        let ecb = encrypt_with_mode(&repeated, b"mode key", CipherMode::Ecb, schedule).unwrap();
+        assert_eq!(distinct_blocks(&cbc), 9);
+        assert_eq!(distinct_blocks(&ctr), 8);
+        assert_eq!(ctr.len(), 72);
+        assert_eq!(ecb.chunks(8).collect::<HashSet<_>>().len(), 2);
+        assert_eq!(ecb, encrypt(&repeated, b"mode key", schedule).unwrap());
+    }
+
+    #[test]
+    fn salted_feistel_keys_depend_on_the_salt() {
+        let cipher = Cipher::Feistel(CipherMode::Ecb, DEFAULT_FEISTEL_ROUNDS);
+        let first = seal(b"same text", b"same key", cipher, 2).unwrap();
+        let second = seal(b"same text", b"same key", cipher, 2).unwrap();
+        let unsalted = encrypt(b"same text", b"same key", KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS)).unwrap();
+        assert_ne!(first[29..], second[29..]);
+        assert_ne!(first[29..first.len() - 32], unsalted[..]);
+    }
+
+    #[test]
+    fn sealed_files_reject_a_wrong_key_and_any_modified_byte() {
+        let sealed = seal(b"attack at dawn", b"right key", Cipher::ChaCha20Poly1305, 2).unwrap();
+        assert!(matches!(open(&sealed, b"wrong key"), Err(RsZipError::AuthenticationFailed)));
+        // skips the KDF iteration count: a raised one is merely slow, then fails the same way
+        for at in (0..sealed.len()).filter(|at| !(8..12).contains(at)) {
+            let mut bad = sealed.clone();
+            bad[at] ^= 0x01;
+            assert!(open(&bad, b"right key").is_err(), "byte {} flipped", at);
+        }
+        assert_ne!(seal(b"attack at dawn", b"right key", Cipher::ChaCha20Poly1305, 2).unwrap(), sealed);
+        assert_eq!(open(&sealed, b"right key").unwrap(), b"attack at dawn");
+    }
+
+    #[test]
+    fn feistel_sealed_files_carry_an_hmac_tag() {
+        for mode in [CipherMode::Ecb, CipherMode::Cbc, CipherMode::Ctr] {
+            let sealed = seal(b"attack at dawn", b"right key", Cipher::Feistel(mode, DEFAULT_FEISTEL_ROUNDS), 2).unwrap();
+            assert!(matches!(open(&sealed, b"wrong key"), Err(RsZipError::AuthenticationFailed)));
+            for at in 28..sealed.len() {
+                let mut bad = sealed.clone();
+                bad[at] ^= 0x01;
+                assert!(matches!(open(&bad, b"right key"), Err(RsZipError::AuthenticationFailed)), "byte {} flipped", at);
+            }
+            // stripped of its tag and passed off as version 2, which had none, it is refused
+            let mut untagged = sealed[..sealed.len() - 32].to_vec();
+            untagged.remove(7);
+            untagged[4] = 2;
+            assert!(matches!(open(&untagged, b"right key"), Err(RsZipError::Unsupported(_))));
+            let mut downgraded = sealed.clone();
+            downgraded[4] = 3;
+            assert!(open(&downgraded, b"right key").is_err());
+            assert_eq!(open(&sealed, b"right key").unwrap(), b"attack at dawn");
+        }
+    }
+
+    #[test]
+    fn feistel_key_schedule_expands_short_keys_to_the_rounds_asked_for() {
+        let keys = |key: &[u8], rounds| encrypt_with_mode(b"8 bytes!", key, CipherMode::Ecb, KeySchedule::Expanded(rounds));
+        assert!(matches!(keys(b"k", MIN_FEISTEL_ROUNDS - 1), Err(RsZipError::InvalidOption(_))));
+        assert_ne!(keys(b"k", 16).unwrap(), keys(b"k", 17).unwrap());
+        assert_ne!(keys(b"k", 16).unwrap(), keys(b"j", 16).unwrap());
+        let sealed = seal(b"attack at dawn", b"k", Cipher::Feistel(CipherMode::Ctr, 20), 2).unwrap();
+        assert_eq!(sealed[28], 20);
+        assert_eq!(open(&sealed, b"k").unwrap(), b"attack at dawn");
+        let mut weakened = sealed.clone();
+        weakened[28] = 8;
+        assert!(matches!(open(&weakened, b"k"), Err(RsZipError::CorruptHeader(_))));
+    }
+
+    #[test]
+    fn feistel_files_from_the_direct_key_schedule_are_refused() {
+        // a version 3 CTR file, as sealed before the schedule was expanded
+        let salt = [7u8; 16];
+        let mut file = b"RSZE\x03\x05\x00".to_vec();
+        file.extend_from_slice(&2u32.to_le_bytes());
+        file.extend_from_slice(&salt);
+        let key = pbkdf2_sha256(b"old key", &salt, 2, 96);
+        let (round_keys, mac_key) = key.as_bytes().split_at(64);
+        file.extend(encrypt_with_mode(b"old text", round_keys, CipherMode::Ctr, KeySchedule::Direct).unwrap());
+        let tag = hmac_sha256(mac_key, &file);
+        file.extend_from_slice(&tag);
+        assert!(matches!(open(&file, b"old key"), Err(RsZipError::Unsupported(_))));
+    }
+
+    #[test]
+    fn key_files_stand_in_for_passphrases_and_say_so_in_the_header() {
+        let key = generate_key_file().unwrap();
+        assert!(key_file_key(key.as_bytes()).is_some());
+        assert!(key_file_key(b"RSZK plus a passphrase").is_none());
+        for cipher in [Cipher::ChaCha20Poly1305, Cipher::Feistel(CipherMode::Ctr, DEFAULT_FEISTEL_ROUNDS)] {
+            let sealed = seal(b"attack at dawn", key.as_bytes(), cipher, 100_000).unwrap();
+            // one iteration, whatever was asked for
+            assert_eq!(sealed[7], 1);
+            assert_eq!(sealed[8..12], [1, 0, 0, 0]);
+            assert_eq!(open(&sealed, key.as_bytes()).unwrap(), b"attack at dawn");
+            assert!(matches!(open(&sealed, &key.as_bytes()[5..]), Err(RsZipError::BadKey(_))));
+            let passphrase = seal(b"attack", b"passphrase", cipher, 2).unwrap();
+            assert!(matches!(open(&passphrase, key.as_bytes()), Err(RsZipError::BadKey(_))));
+        }
+        assert_ne!(generate_key_file().unwrap().as_bytes(), key.as_bytes());
+    }
+
+    #[test]
+    fn constant_time_comparison_and_padding_checks() {
+        assert!(ct_eq(b"same tag", b"same tag") && ct_eq(b"", b""));
+        assert!(!ct_eq(b"same tag", b"same taG") && !ct_eq(b"Same tag", b"same tag") && !ct_eq(b"same tag", b"same ta"));
+        assert_eq!(pkcs7_unpad(&pkcs7_pad(b"twelve bytes", 8), 8).unwrap(), b"twelve bytes");
+        let whole_block = pkcs7_pad(b"12345678", 8);
+        assert_eq!(whole_block.len(), 16);
+        assert_eq!(pkcs7_unpad(&whole_block, 8).unwrap(), b"12345678");
+        for bad in [&b"1234567\x00"[..], b"1234567\x09", b"123456\x01\x02", b"\x03\x03", b""] {
+            assert!(pkcs7_unpad(bad, 8).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn secret_keys_are_wiped_and_never_printed() {
+        let mut buf = b"hunter2".to_vec();
+        zeroize(&mut buf);
+        assert_eq!(buf, [0; 7]);
+        let key = SecretKey::from("hunter2".to_string());
+        assert_eq!(format!("{:?}", key), "SecretKey(7 bytes)");
+        let derived = pbkdf2_sha256(key.as_bytes(), b"salt", 2, 32);
+        assert!(!format!("{:?}", derived).contains(&hex_lower(derived.as_bytes())));
+    }
+
+    #[test]
+    fn empty_key_is_refused() {
+        for schedule in [KeySchedule::Direct, KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS)] {
+            assert!(matches!(feistel_encrypt(b"data", b"", schedule), Err(RsZipError::BadKey(_))));
+        }
+    }
+
+    #[test]
+    fn feistel_round_trips_with_and_without_padding() {
+        for (name, data) in testdata::corpora() {
+            let schedule = KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS);
+            let encrypted = encrypt(&data, b"test key", schedule).unwrap();
+            assert_eq!(encrypted.len(), data.len() / 8 * 8 + 8, "{}", name);
+            assert_eq!(decrypt(&encrypted, b"test key", schedule).unwrap(), data, "{}", name);
+            // the bare block cipher zero-pads the last block
+            let encrypted = feistel_encrypt(&data, b"test key", KeySchedule::Direct).unwrap();
+            let mut plain = feistel_decrypt(&encrypted, b"test key", KeySchedule::Direct).unwrap();
+            plain.truncate(data.len());
+            assert_eq!(plain, data, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+// Raw DEFLATE streams (RFC 1951) for gzip/zlib interop. The encoder runs a
+// hash-chain LZ77 over the format's 32 KiB window, then codes each block
+// as whichever of stored, fixed-Huffman or dynamic-Huffman is smallest. The
+// decoder reads all three block types from any conforming writer.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::{CompressionLevel, Result, RsZipError};
+
+pub const DEFLATE_WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// symbols per block; each block gets codes fitted to its own statistics
+const BLOCK_SYMBOLS: usize = 1 << 14;
+const MAX_STORED: usize = 0xffff;
+const END_OF_BLOCK: usize = 256;
+
+// base value and extra bits of length codes 257..285 and distance codes 0..29
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// the order dynamic headers list the code-length code's lengths in
+const CL_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const TRUNCATED: RsZipError = RsZipError::Truncated("deflate stream");
+
+#[derive(Clone, Copy)]
+enum Symbol {
+    Literal(u8),
+    Match { len: usize, dist: usize },
+}
+
+// candidates compared per position
+fn chain_depth(level: CompressionLevel) -> usize {
+    match level {
+        CompressionLevel::Fast => 4,
+        CompressionLevel::Default => 32,
+        CompressionLevel::Best => 256,
+    }
+}
+
+// Greedy parse; `head` holds the newest position per 3-byte hash and `prev`
+// links each position to the previous one with the same hash
+fn tokenize(data: &[u8], depth: usize) -> Vec<Symbol> {
+    const HASH_BITS: u32 = 15;
+    const NONE: usize = usize::MAX;
+    let hash = |i: usize| {
+        ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & ((1 << HASH_BITS) - 1)
+    };
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; DEFLATE_WINDOW];
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            prev[i % DEFLATE_WINDOW] = head[h];
+            head[h] = i;
+        }
+    };
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let limit = (data.len() - i).min(MAX_MATCH);
+            let mut candidate = head[hash(i)];
+            for _ in 0..depth {
+                if candidate == NONE || i - candidate > DEFLATE_WINDOW {
+                    break;
+                }
+                let len = data[candidate..].iter().zip(&data[i..i + limit]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, i - candidate);
+                    if len == limit {
+                        break;
+                    }
+                }
+                // slots are reused once a position leaves the window
+                let next = prev[candidate % DEFLATE_WINDOW];
+                if next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            out.push(Symbol::Match { len: best_len, dist: best_dist });
+            for k in i..i + best_len {
+                insert(k, &mut head, &mut prev);
+            }
+            i += best_len;
+        } else {
+            out.push(Symbol::Literal(data[i]));
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    out
+}
+
+// code index, extra-bit count and extra value for a length or distance
+fn bucket(value: usize, base: &[u16], extra: &[u8]) -> (usize, u32, u32) {
+    let k = base.partition_point(|&b| b as usize <= value) - 1;
+    (k, extra[k] as u32, (value - base[k] as usize) as u32)
+}
+
+// Huffman code lengths for `freqs`, none longer than `max_len`. Like
+// huffman.rs, counts are halved until the tree is shallow enough.
+fn code_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    let mut lengths = vec![0; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&s| freqs[s] > 0).collect();
+    if used.len() == 1 {
+        // a lone code of length 1, which the RFC allows
+        lengths[used[0]] = 1;
+    }
+    if used.len() < 2 {
+        return lengths;
+    }
+    let mut scaled: Vec<u64> = used.iter().map(|&s| freqs[s] as u64).collect();
+    loop {
+        // leaves are nodes 0..used.len(); every merge appends a parent
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> = scaled.iter().enumerate().map(|(n, &f)| Reverse((f, n))).collect();
+        let mut parent = vec![usize::MAX; used.len()];
+        while heap.len() > 1 {
+            let (Reverse((fa, a)), Reverse((fb, b))) = (heap.pop().unwrap(), heap.pop().unwrap());
+            parent.push(usize::MAX);
+            (parent[a], parent[b]) = (parent.len() - 1, parent.len() - 1);
+            heap.push(Reverse((fa + fb, parent.len() - 1)));
+        }
+        // parents come after their children, so walk down from the root
+        let mut depth = vec![0u8; parent.len()];
+        for n in (0..parent.len() - 1).rev() {
+            depth[n] = depth[parent[n]] + 1;
+        }
+        if depth[..used.len()].iter().all(|&d| d <= max_len) {
+            for (n, &s) in used.iter().enumerate() {
+                lengths[s] = depth[n];
+            }
+            return lengths;
+        }
+        for f in &mut scaled {
+            *f = (*f / 2).max(1);
+        }
+    }
+}
+
+// canonical codes, bit-reversed since DEFLATE sends Huffman codes MSB first
+// into an LSB-first stream
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut count = [0u16; 16];
+    for &len in lengths.iter().filter(|&&len| len > 0) {
+        count[len as usize] += 1;
+    }
+    let mut next = [0u16; 16];
+    for len in 1..16 {
+        next[len] = (next[len - 1] + count[len - 1]) << 1;
+    }
+    lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            code.reverse_bits() >> (16 - len)
+        })
+        .collect()
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut ll = vec![8; 288];
+    ll[144..256].fill(9);
+    ll[256..280].fill(7);
+    // distance codes 30 and 31 fill out the code but never occur
+    (ll, vec![5; 32])
+}
+
+#[derive(Default)]
+struct BitSink {
+    out: Vec<u8>,
+    pending: u64,
+    count: u32,
+}
+
+impl BitSink {
+    fn bits(&mut self, value: u32, n: u32) {
+        self.pending |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.pending as u8);
+            self.pending >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.pending as u8);
+            (self.pending, self.count) = (0, 0);
+        }
+    }
+}
+
+// The code-length sequence of a dynamic header, run-length coded with
+// symbols 16 (repeat the previous 3-6 times), 17 (3-10 zeros) and 18
+// (11-138 zeros), as (symbol, extra bits, extra value)
+fn rle_lengths(lengths: &[u8]) -> Vec<(usize, u32, u32)> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == len).count();
+        if len == 0 && run >= 11 {
+            let n = run.min(138);
+            out.push((18, 7, (n - 11) as u32));
+            i += n;
+        } else if len == 0 && run >= 3 {
+            out.push((17, 3, (run - 3) as u32));
+            i += run;
+        } else if len > 0 && run >= 4 {
+            out.push((len as usize, 0, 0));
+            let n = (run - 1).min(6);
+            out.push((16, 2, (n - 3) as u32));
+            i += n + 1;
+        } else {
+            out.push((len as usize, 0, 0));
+            i += 1;
+        }
+    }
+    out
+}
+
+struct DynamicHeader {
+    hlit: usize,
+    hdist: usize,
+    cl_lengths: Vec<u8>,
+    hclen: usize,
+    rle: Vec<(usize, u32, u32)>,
+}
+
+impl DynamicHeader {
+    fn new(ll: &[u8], dist: &[u8]) -> DynamicHeader {
+        let hlit = 257.max(ll.iter().rposition(|&l| l > 0).map_or(0, |p| p + 1));
+        let hdist = 1.max(dist.iter().rposition(|&l| l > 0).map_or(0, |p| p + 1));
+        let rle = rle_lengths(&[&ll[..hlit], &dist[..hdist]].concat());
+        let mut cl_freqs = [0u32; 19];
+        for &(symbol, _, _) in &rle {
+            cl_freqs[symbol] += 1;
+        }
+        let cl_lengths = code_lengths(&cl_freqs, 7);
+        let hclen = 4.max(CL_ORDER.iter().rposition(|&s| cl_lengths[s] > 0).map_or(0, |p| p + 1));
+        DynamicHeader { hlit, hdist, cl_lengths, hclen, rle }
+    }
+
+    fn bits(&self) -> u64 {
+        let rle: u64 = self.rle.iter().map(|&(s, extra, _)| self.cl_lengths[s] as u64 + extra as u64).sum();
+        5 + 5 + 4 + 3 * self.hclen as u64 + rle
+    }
+
+    fn write(&self, sink: &mut BitSink) {
+        sink.bits((self.hlit - 257) as u32, 5);
+        sink.bits((self.hdist - 1) as u32, 5);
+        sink.bits((self.hclen - 4) as u32, 4);
+        for &s in &CL_ORDER[..self.hclen] {
+            sink.bits(self.cl_lengths[s] as u32, 3);
+        }
+        let codes = canonical_codes(&self.cl_lengths);
+        for &(symbol, extra, value) in &self.rle {
+            sink.bits(codes[symbol] as u32, self.cl_lengths[symbol] as u32);
+            sink.bits(value, extra);
+        }
+    }
+}
+
+fn write_symbols(sink: &mut BitSink, symbols: &[Symbol], ll: &[u8], dist: &[u8]) {
+    let (ll_codes, dist_codes) = (canonical_codes(ll), canonical_codes(dist));
+    for &symbol in symbols {
+        match symbol {
+            Symbol::Literal(b) => sink.bits(ll_codes[b as usize] as u32, ll[b as usize] as u32),
+            Symbol::Match { len, dist: d } => {
+                let (k, extra, value) = bucket(len, &LENGTH_BASE, &LENGTH_EXTRA);
+                sink.bits(ll_codes[257 + k] as u32, ll[257 + k] as u32);
+                sink.bits(value, extra);
+                let (k, extra, value) = bucket(d, &DIST_BASE, &DIST_EXTRA);
+                sink.bits(dist_codes[k] as u32, dist[k] as u32);
+                sink.bits(value, extra);
+            }
+        }
+    }
+    sink.bits(ll_codes[END_OF_BLOCK] as u32, ll[END_OF_BLOCK] as u32);
+}
+
+// bits the symbols take under the given lengths, extra bits included
+fn symbols_cost(ll_freqs: &[u32], dist_freqs: &[u32], ll: &[u8], dist: &[u8]) -> u64 {
+    let ll_cost: u64 = ll_freqs.iter().enumerate().map(|(s, &f)| {
+        let extra = if s > 256 { LENGTH_EXTRA[s - 257] as u64 } else { 0 };
+        f as u64 * (ll[s] as u64 + extra)
+    }).sum();
+    let dist_cost: u64 = dist_freqs.iter().enumerate().map(|(s, &f)| f as u64 * (dist[s] as u64 + DIST_EXTRA[s] as u64)).sum();
+    ll_cost + dist_cost
+}
+
+fn write_block(sink: &mut BitSink, symbols: &[Symbol], raw: &[u8], last: bool) {
+    let (mut ll_freqs, mut dist_freqs) = (vec![0u32; 286], vec![0u32; 30]);
+    for &symbol in symbols {
+        match symbol {
+            Symbol::Literal(b) => ll_freqs[b as usize] += 1,
+            Symbol::Match { len, dist } => {
+                ll_freqs[257 + bucket(len, &LENGTH_BASE, &LENGTH_EXTRA).0] += 1;
+                dist_freqs[bucket(dist, &DIST_BASE, &DIST_EXTRA).0] += 1;
+            }
+        }
+    }
+    ll_freqs[END_OF_BLOCK] = 1;
+    let (ll, dist) = (code_lengths(&ll_freqs, 15), code_lengths(&dist_freqs, 15));
+    let header = DynamicHeader::new(&ll, &dist);
+    let (fixed_ll, fixed_dist) = fixed_lengths();
+
+    let dynamic = 3 + header.bits() + symbols_cost(&ll_freqs, &dist_freqs, &ll, &dist);
+    let fixed = 3 + symbols_cost(&ll_freqs, &dist_freqs, &fixed_ll, &fixed_dist);
+    // header and alignment (at most 7 bits) per stored block, then the bytes
+    let stored = raw.len().div_ceil(MAX_STORED).max(1) as u64 * (3 + 7 + 32) + raw.len() as u64 * 8;
+    if stored < dynamic.min(fixed) {
+        let pieces: Vec<&[u8]> = if raw.is_empty() { vec![raw] } else { raw.chunks(MAX_STORED).collect() };
+        for (n, piece) in pieces.iter().enumerate() {
+            sink.bits((last && n + 1 == pieces.len()) as u32, 1);
+            sink.bits(0, 2);
+            sink.align();
+            sink.bits(piece.len() as u32, 16);
+            sink.bits(!piece.len() as u32 & 0xffff, 16);
+            sink.out.extend_from_slice(piece);
+        }
+    } else if fixed <= dynamic {
+        sink.bits(last as u32, 1);
+        sink.bits(1, 2);
+        write_symbols(sink, symbols, &fixed_ll, &fixed_dist);
+    } else {
+        sink.bits(last as u32, 1);
+        sink.bits(2, 2);
+        header.write(sink);
+        write_symbols(sink, symbols, &ll, &dist);
+    }
+}
+
+pub fn compress(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let symbols = tokenize(data, chain_depth(level));
+    let mut sink = BitSink::default();
+    let blocks: Vec<&[Symbol]> = if symbols.is_empty() { vec![&[]] } else { symbols.chunks(BLOCK_SYMBOLS).collect() };
+    let mut at = 0;
+    for (n, block) in blocks.iter().enumerate() {
+        let covered: usize = block.iter().map(|s| match s {
+            Symbol::Literal(_) => 1,
+            Symbol::Match { len, .. } => *len,
+        }).sum();
+        write_block(&mut sink, block, &data[at..at + covered], n + 1 == blocks.len());
+        at += covered;
+    }
+    sink.align();
+    sink.out
+}
+
+struct BitSource<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitSource<'_> {
+    fn bit(&mut self) -> Result<u32> {
+        let byte = *self.data.get(self.pos).ok_or(TRUNCATED)?;
+        let bit = (byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            (self.pos, self.bit) = (self.pos + 1, 0);
+        }
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        let mut value = 0;
+        for k in 0..n {
+            value |= self.bit()? << k;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            (self.pos, self.bit) = (self.pos + 1, 0);
+        }
+    }
+}
+
+// canonical decoding as in huffman.rs: codes of each length form one
+// contiguous range, so counts and the symbols in code order are enough
+struct Decoder {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Decoder {
+    // Over-subscribed lengths are corrupt. Incomplete ones are only written
+    // for a single code (or none, for distances), as the RFC allows.
+    fn new(lengths: &[u8]) -> Result<Decoder> {
+        let mut counts = [0u16; 16];
+        for &len in lengths.iter().filter(|&&len| len > 0) {
+            counts[len as usize] += 1;
+        }
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err(RsZipError::CorruptData("over-subscribed deflate code"));
+            }
+        }
+        let used = lengths.iter().filter(|&&len| len > 0).count();
+        if left > 0 && used > 1 {
+            return Err(RsZipError::CorruptData("incomplete deflate code"));
+        }
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] > 0).collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+        Ok(Decoder { counts, symbols })
+    }
+
+    fn decode(&self, r: &mut BitSource) -> Result<usize> {
+        let (mut code, mut first, mut index) = (0u32, 0u32, 0usize);
+        for &count in &self.counts[1..] {
+            code |= r.bit()?;
+            let count = count as u32;
+            if code < first + count {
+                return Ok(self.symbols[index + (code - first) as usize] as usize);
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(RsZipError::CorruptData("deflate code with no symbol"))
+    }
+}
+
+fn read_dynamic(r: &mut BitSource) -> Result<(Decoder, Decoder)> {
+    let hlit = r.bits(5)? as usize + 257;
+    let hdist = r.bits(5)? as usize + 1;
+    let hclen = r.bits(4)? as usize + 4;
+    if hlit > 286 || hdist > 30 {
+        return Err(RsZipError::CorruptData("deflate code counts"));
+    }
+    let mut cl_lengths = [0u8; 19];
+    for &s in &CL_ORDER[..hclen] {
+        cl_lengths[s] = r.bits(3)? as u8;
+    }
+    let cl = Decoder::new(&cl_lengths)?;
+    let mut lengths = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let (len, repeat) = match cl.decode(r)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => (*lengths.last().ok_or(RsZipError::CorruptData("deflate repeat with no length"))?, 3 + r.bits(2)?),
+            17 => (0, 3 + r.bits(3)?),
+            _ => (0, 11 + r.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > hlit + hdist {
+            return Err(RsZipError::CorruptData("deflate code lengths overrun"));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(RsZipError::CorruptData("deflate block has no end-of-block code"));
+    }
+    Ok((Decoder::new(&lengths[..hlit])?, Decoder::new(&lengths[hlit..])?))
+}
+
+fn inflate_block(r: &mut BitSource, out: &mut Vec<u8>, ll: &Decoder, dist: &Decoder) -> Result<()> {
+    loop {
+        let symbol = ll.decode(r)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            END_OF_BLOCK => return Ok(()),
+            257..=285 => {
+                let k = symbol - 257;
+                let len = LENGTH_BASE[k] as usize + r.bits(LENGTH_EXTRA[k] as u32)? as usize;
+                let k = dist.decode(r)?;
+                if k >= 30 {
+                    return Err(RsZipError::CorruptData("deflate distance code"));
+                }
+                let d = DIST_BASE[k] as usize + r.bits(DIST_EXTRA[k] as u32)? as usize;
+                if d > out.len() {
+                    return Err(RsZipError::CorruptData("match reaches before the start of the data"));
+                }
+                let start = out.len() - d;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => return Err(RsZipError::CorruptData("deflate length code")),
+        }
+    }
+}
+
+// (data, bytes of `data` the stream took), for containers that carry
+// more after it
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let mut r = BitSource { data, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = r.bit()? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let header = data.get(r.pos..r.pos + 4).ok_or(TRUNCATED)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if u16::from_le_bytes([header[2], header[3]]) != !len {
+                    return Err(RsZipError::CorruptData("deflate stored block length check"));
+                }
+                let start = r.pos + 4;
+                out.extend_from_slice(data.get(start..start + len as usize).ok_or(TRUNCATED)?);
+                r.pos = start + len as usize;
+            }
+            1 => {
+                let (ll, dist) = fixed_lengths();
+                inflate_block(&mut r, &mut out, &Decoder::new(&ll)?, &Decoder::new(&dist)?)?;
+            }
+            2 => {
+                let (ll, dist) = read_dynamic(&mut r)?;
+                inflate_block(&mut r, &mut out, &ll, &dist)?;
+            }
+            _ => return Err(RsZipError::CorruptData("deflate block type 3")),
+        }
+        if last {
+            break;
+        }
+    }
+    r.align();
+    Ok((out, r.pos))
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(inflate(data)?.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, unhex};
+
+    #[test]
+    fn round_trips_at_every_level() {
+        for (name, data) in testdata::corpora() {
+            for level in [CompressionLevel::Fast, CompressionLevel::Default, CompressionLevel::Best] {
+                assert_eq!(decompress(&compress(&data, level)).unwrap(), data, "{:?}/{}", level, name);
+            }
+        }
+    }
+
+    // written by zlib 1.2: a fixed-code block, and a dynamic one (Z_HUFFMAN_ONLY)
+    #[test]
+    fn reads_zlib_streams() {
+        let text = b"mississippi river banks, mississippi river mud, mississippi river boats\n";
+        let fixed = unhex("cbcd2c2e06a182824c85a2ccb2d42285a4c4bcec621d855c0c89dcd2146cc249f98925c55c00");
+        let dynamic = unhex(concat!(
+            "05c1c10d80300c04c13f555c01692a1179ac90c1f281eb6726b0b1c94445efd29af7e5a1c0c6261315bd4bf19d43818d4d",
+            "262a7a97d6335f1f3f",
+        ));
+        assert_eq!(decompress(&fixed).unwrap(), text);
+        assert_eq!(decompress(&dynamic).unwrap(), text);
+    }
+
+    #[test]
+    fn refuses_corrupt_streams() {
+        let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(decompress(&stored).unwrap(), b"abc");
+        let mut bad_length = stored;
+        bad_length[3] ^= 1;
+        assert!(decompress(&bad_length).is_err());
+        for len in 0..stored.len() {
+            assert!(decompress(&stored[..len]).is_err(), "{} bytes", len);
+        }
+        // block type 3 is reserved
+        assert!(decompress(&[0x07]).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+// Binary deltas: `diff` describes a new version of a file as copies out of
+// the old one plus inserted bytes, and `patch` replays them against the old
+// file. The old file is indexed in BLOCK-byte pieces at aligned offsets and
+// the new one is scanned at every offset for a piece they share; each hit is
+// grown both ways, so shifted and edited content still becomes a copy. The
+// ops are rszip-compressed, so inserts cost about what they'd compress to.
+//
+// delta: ["RSZD"][version u8][old_len u32][old crc32 u32][new_len u32][new crc32 u32][rszip file of the ops]
+// ops:   [0][len u32][bytes] inserts them, [1][len u32][offset u32] copies len bytes of the old file
+use std::collections::HashMap;
+
+use crate::checksum::crc32;
+use crate::format::{put_u32, Reader};
+use crate::{compress, decompress, len_u32, CompressOptions, Result, RsZipError};
+
+pub const DELTA_MAGIC: &[u8; 4] = b"RSZD";
+const DELTA_VERSION: u8 = 1;
+// the shortest run `diff` looks for in the old file; shorter ones are inserted
+const BLOCK: usize = 16;
+const OP_INSERT: u8 = 0;
+const OP_COPY: u8 = 1;
+const TRUNCATED: RsZipError = RsZipError::Truncated("delta");
+
+pub fn is_delta(data: &[u8]) -> bool {
+    data.starts_with(DELTA_MAGIC)
+}
+
+fn insert(ops: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    if !bytes.is_empty() {
+        ops.push(OP_INSERT);
+        put_u32(ops, len_u32(bytes.len())?);
+        ops.extend_from_slice(bytes);
+    }
+    Ok(())
+}
+
+// `new` as a delta against `old`, the ops compressed with `opts`
+pub fn diff(old: &[u8], new: &[u8], opts: &CompressOptions) -> Result<Vec<u8>> {
+    // each block's first aligned offset in the old file
+    let mut index: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in old.chunks_exact(BLOCK).enumerate() {
+        index.entry(block).or_insert(i * BLOCK);
+    }
+    let mut ops = Vec::new();
+    // `pending` is where the bytes not yet covered by an op start
+    let (mut at, mut pending) = (0, 0);
+    while at + BLOCK <= new.len() {
+        let Some(&found) = index.get(&new[at..at + BLOCK]) else {
+            at += 1;
+            continue;
+        };
+        // back over the pending bytes, then forward past the block
+        let back = (1..=found.min(at - pending)).take_while(|&k| old[found - k] == new[at - k]).count();
+        let ahead = old[found + BLOCK..].iter().zip(&new[at + BLOCK..]).take_while(|(a, b)| a == b).count();
+        let (start, len) = (at - back, back + BLOCK + ahead);
+        insert(&mut ops, &new[pending..start])?;
+        ops.push(OP_COPY);
+        put_u32(&mut ops, len_u32(len)?);
+        put_u32(&mut ops, len_u32(found - back)?);
+        at = start + len;
+        pending = at;
+    }
+    insert(&mut ops, &new[pending..])?;
+    let mut out = DELTA_MAGIC.to_vec();
+    out.push(DELTA_VERSION);
+    for field in [len_u32(old.len())?, crc32(old), len_u32(new.len())?, crc32(new)] {
+        put_u32(&mut out, field);
+    }
+    out.extend_from_slice(&compress(&ops, opts)?);
+    Ok(out)
+}
+
+// The new file from `old` and a delta `diff` made against it; any other old
+// file is refused before anything is decoded.
+pub fn patch(old: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut r = Reader::new(delta);
+    if r.bytes(DELTA_MAGIC.len()) != Some(DELTA_MAGIC) {
+        return Err(RsZipError::CorruptHeader("no RSZD signature: not an rszip delta".into()));
+    }
+    match r.u8().ok_or(TRUNCATED)? {
+        0 => return Err(RsZipError::CorruptHeader("delta version 0".into())),
+        version if version > DELTA_VERSION => return Err(RsZipError::Unsupported(format!("delta version {}", version))),
+        _ => {}
+    }
+    let mut field = || r.u32().ok_or(TRUNCATED);
+    let (old_len, old_crc, new_len, new_crc) = (field()?, field()?, field()?, field()?);
+    if old.len() != old_len as usize || crc32(old) != old_crc {
+        return Err(RsZipError::DeltaBaseMismatch);
+    }
+    let ops = decompress(r.rest(), None)?;
+    let (mut r, mut out) = (Reader::new(&ops), Vec::new());
+    while let Some(op) = r.u8() {
+        let len = r.u32().ok_or(TRUNCATED)? as usize;
+        match op {
+            OP_INSERT => out.extend_from_slice(r.bytes(len).ok_or(TRUNCATED)?),
+            OP_COPY => {
+                let offset = r.u32().ok_or(TRUNCATED)? as usize;
+                let from = offset.checked_add(len).and_then(|end| old.get(offset..end));
+                out.extend_from_slice(from.ok_or(RsZipError::CorruptData("delta copy past the old file"))?);
+            }
+            _ => return Err(RsZipError::CorruptData("delta op")),
+        }
+        if out.len() > new_len as usize {
+            return Err(RsZipError::CorruptData("delta longer than its new file"));
+        }
+    }
+    let computed = crc32(&out);
+    if out.len() != new_len as usize || computed != new_crc {
+        return Err(RsZipError::ChecksumMismatch { stored: new_crc, computed });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn rebuilds_edited_files_and_refuses_other_old_files() {
+        let old = testdata::random(800_000);
+        let mut new = old[..300_000].to_vec();
+        new.extend_from_slice(b"a few inserted bytes");
+        new.extend_from_slice(&old[300_010..600_000]);
+        new.extend_from_slice(&old[700_000..]);
+        new[100] ^= 0xff;
+        let opts = CompressOptions::default();
+        let delta = diff(&old, &new, &opts).unwrap();
+        assert!(delta.len() < 1000, "{} bytes", delta.len());
+        assert_eq!(patch(&old, &delta).unwrap(), new);
+        assert!(matches!(patch(&old[1..], &delta), Err(RsZipError::DeltaBaseMismatch)));
+        assert_eq!(patch(&new, &diff(&new, &old, &opts).unwrap()).unwrap(), old);
+        assert!(patch(b"", &diff(b"", b"", &opts).unwrap()).unwrap().is_empty());
+    }
+}
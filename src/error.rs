@@ -0,0 +1,79 @@
+// What the library fails with. Decoding never panics on bad input: a
+// truncated, corrupt or foreign file comes back as one of these.
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum RsZipError {
+    Io(io::Error),
+    // a header field no writer produces
+    CorruptHeader(String),
+    // the data ends before the header or token stream says it should
+    Truncated(&'static str),
+    // the body decodes to something impossible, e.g. a match before the start
+    CorruptData(&'static str),
+    // a key the cipher can't use
+    BadKey(&'static str),
+    // written by a newer version: unknown must-understand flags or filters
+    Unsupported(String),
+    // the file needs a preset dictionary that wasn't given or doesn't match
+    DictionaryMismatch,
+    // a delta applied to an old file other than the one it was made from
+    DeltaBaseMismatch,
+    // a compression option outside its range
+    InvalidOption(String),
+    // a length past the format's 32-bit fields
+    TooLarge,
+    // the AEAD tag doesn't match: wrong key, or the data was modified
+    AuthenticationFailed,
+    // decoded cleanly but isn't what was compressed
+    ChecksumMismatch { stored: u32, computed: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, RsZipError>;
+
+impl fmt::Display for RsZipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RsZipError::Io(e) => write!(f, "{}", e),
+            RsZipError::CorruptHeader(what) => write!(f, "corrupt header: {}", what),
+            RsZipError::Truncated(what) => write!(f, "truncated {}", what),
+            RsZipError::CorruptData(what) => write!(f, "corrupt data: {}", what),
+            RsZipError::BadKey(why) => write!(f, "bad key: {}", why),
+            RsZipError::Unsupported(what) => write!(f, "needs a newer rszip: {}", what),
+            RsZipError::DictionaryMismatch => write!(f, "needs the preset dictionary it was compressed with (--dict)"),
+            RsZipError::DeltaBaseMismatch => write!(f, "made against a different old file"),
+            RsZipError::InvalidOption(what) => write!(f, "invalid option: {}", what),
+            RsZipError::TooLarge => write!(f, "input too large for the 32-bit length fields"),
+            RsZipError::AuthenticationFailed => write!(f, "authentication failed: wrong key, or the data was modified"),
+            RsZipError::ChecksumMismatch { stored, computed } => {
+                write!(f, "CRC-32 mismatch (stored {:08x}, computed {:08x}): the file is corrupt", stored, computed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RsZipError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RsZipError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RsZipError {
+    fn from(e: io::Error) -> Self {
+        RsZipError::Io(e)
+    }
+}
+
+// lets codec calls use `?` inside Read/Write impls and io::Result helpers
+impl From<RsZipError> for io::Error {
+    fn from(e: RsZipError) -> Self {
+        match e {
+            RsZipError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Reversible transforms applied before LZ77 that make the data more
+// repetitive. Image predictors treat the input as rows of `stride` bytes with
+// `bpp` bytes per pixel and store the residual against a predicted value.
+// The text filter swaps common words for single bytes, see `text_encode`, and
+// the branch filters make call/jump targets in machine code absolute.
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::{Result, RsZipError};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    None,
+    Sub { stride: usize, bpp: usize },
+    Up { stride: usize, bpp: usize },
+    Paeth { stride: usize, bpp: usize },
+    // `dict` names the word list, only ENGLISH_WORDS (1) exists so far
+    Text { dict: u8 },
+    X86,
+    Arm,
+}
+
+impl Filter {
+    // "paeth:640", "sub:1920:3" (kind:stride[:bytes-per-pixel]), "text", "x86" or "arm"
+    pub fn parse(spec: &str) -> Option<Filter> {
+        match spec {
+            "text" => return Some(Filter::Text { dict: ENGLISH_WORDS_ID }),
+            "x86" => return Some(Filter::X86),
+            "arm" => return Some(Filter::Arm),
+            _ => {}
+        }
+        let mut parts = spec.split(':');
+        let kind = parts.next()?;
+        let stride: usize = parts.next()?.parse().ok()?;
+        let bpp: usize = match parts.next() {
+            Some(b) => b.parse().ok()?,
+            None => 1,
+        };
+        if parts.next().is_some() || stride == 0 || bpp == 0 || bpp > stride {
+            return None;
+        }
+        match kind {
+            "sub" => Some(Filter::Sub { stride, bpp }),
+            "up" => Some(Filter::Up { stride, bpp }),
+            "paeth" => Some(Filter::Paeth { stride, bpp }),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {
+            Filter::None => "none".to_string(),
+            Filter::Sub { stride, bpp } => format!("sub, stride {}, {} bytes/pixel", stride, bpp),
+            Filter::Up { stride, bpp } => format!("up, stride {}, {} bytes/pixel", stride, bpp),
+            Filter::Paeth { stride, bpp } => format!("paeth, stride {}, {} bytes/pixel", stride, bpp),
+            Filter::Text { dict } => format!("text, word list {}", dict),
+            Filter::X86 => "x86 branches".to_string(),
+            Filter::Arm => "arm branches".to_string(),
+        }
+    }
+
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Filter::None => data.to_vec(),
+            Filter::Text { .. } => text_encode(data),
+            Filter::X86 => bcj_x86(data, true),
+            Filter::Arm => bcj_arm(data, true),
+            _ => (0..data.len()).map(|i| data[i].wrapping_sub(self.predict(data, i))).collect(),
+        }
+    }
+    pub fn reverse(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match *self {
+            Filter::None => data.to_vec(),
+            Filter::Text { .. } => text_decode(data)?,
+            Filter::X86 => bcj_x86(data, false),
+            Filter::Arm => bcj_arm(data, false),
+            _ => {
+                let mut out = data.to_vec();
+                for i in 0..out.len() {
+                    out[i] = out[i].wrapping_add(self.predict(&out, i));
+                }
+                out
+            }
+        })
+    }
+
+    // prediction for byte i from already-known neighbours
+    fn predict(&self, data: &[u8], i: usize) -> u8 {
+        let (stride, bpp) = match *self {
+            Filter::Sub { stride, bpp } | Filter::Up { stride, bpp } | Filter::Paeth { stride, bpp } => (stride, bpp),
+            Filter::None | Filter::Text { .. } | Filter::X86 | Filter::Arm => return 0,
+        };
+        let col = i % stride;
+        let left = if col >= bpp { data[i - bpp] } else { 0 };
+        let up = if i >= stride { data[i - stride] } else { 0 };
+        let up_left = if col >= bpp && i >= stride { data[i - stride - bpp] } else { 0 };
+        match self {
+            Filter::Sub { .. } => left,
+            Filter::Up { .. } => up,
+            _ => paeth_predictor(left, up, up_left),
+        }
+    }
+}
+
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+// Text filter: whole words from the list below are replaced by byte values
+// that never occur in the input. The filtered stream starts with a 32-byte
+// bitmap of those code bytes; the n-th set bit stands for the n-th word.
+pub const ENGLISH_WORDS_ID: u8 = 1;
+pub const ENGLISH_WORDS: &[&str] = &[
+    "the", "and", "that", "have", "for", "not", "with", "you", "this", "but",
+    "his", "from", "they", "say", "her", "she", "will", "one", "all", "would",
+    "there", "their", "what", "out", "about", "who", "get", "which", "when", "make",
+    "can", "like", "time", "just", "him", "know", "take", "people", "into", "year",
+    "your", "good", "some", "could", "them", "see", "other", "than", "then", "now",
+    "look", "only", "come", "its", "over", "think", "also", "back", "after", "use",
+    "two", "how", "our", "work", "first", "well", "way", "even", "new", "want",
+    "because", "any", "these", "give", "day", "most", "are", "was", "were", "been",
+    "has", "had", "said", "did", "made", "may", "should", "very", "through", "where",
+    "much", "before", "must", "under", "never", "same", "another", "while", "last", "might",
+    "great", "old", "own", "those", "both", "being", "little", "down", "many", "such",
+    "long", "each", "between", "without", "again", "here", "still", "found", "thought", "every",
+    "The", "And", "This", "That", "There", "They", "When", "What", "With", "But",
+    "It's", "don't", "can't", "didn't", "something", "nothing", "always", "around", "however", "number",
+    "world", "house", "against", "program", "system", "during", "government", "following", "important",
+];
+
+fn text_codes(data: &[u8]) -> [bool; 256] {
+    let mut used = [false; 256];
+    for &b in data {
+        used[b as usize] = true;
+    }
+    used
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'\''
+}
+
+pub fn text_encode(data: &[u8]) -> Vec<u8> {
+    let used = text_codes(data);
+    let codes: Vec<u8> = (0..=255u8).filter(|&b| !used[b as usize]).take(ENGLISH_WORDS.len()).collect();
+    let mut words: HashMap<&[u8], u8> = HashMap::new();
+    for (word, &code) in ENGLISH_WORDS.iter().zip(&codes) {
+        words.entry(word.as_bytes()).or_insert(code);
+    }
+
+    let mut out = vec![0u8; 32];
+    for &code in &codes {
+        out[code as usize / 8] |= 1 << (code % 8);
+    }
+    let mut i = 0;
+    while i < data.len() {
+        if i == 0 || !is_word_byte(data[i - 1]) {
+            let end = i + data[i..].iter().take_while(|&&b| is_word_byte(b)).count();
+            if let Some(&code) = words.get(&data[i..end]) {
+                out.push(code);
+                i = end;
+                continue;
+            }
+        }
+        out.push(data[i]);
+        i += 1;
+    }
+    out
+}
+
+pub fn text_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let Some((bitmap, body)) = data.split_first_chunk::<32>() else {
+        return Err(RsZipError::Truncated("text filter code bitmap"));
+    };
+    let mut words: [Option<&str>; 256] = [None; 256];
+    let codes = (0..=255u8).filter(|&b| bitmap[b as usize / 8] & (1 << (b % 8)) != 0);
+    for (code, word) in codes.zip(ENGLISH_WORDS) {
+        words[code as usize] = Some(word);
+    }
+    let mut out = Vec::new();
+    for &b in body {
+        match words[b as usize] {
+            Some(word) => out.extend_from_slice(word.as_bytes()),
+            None => out.push(b),
+        }
+    }
+    Ok(out)
+}
+
+// Branch filters: the same function called from many places has a different
+// relative offset at every call site but a single absolute address, so
+// rewriting rel32/imm24 operands as absolute targets creates repeats. The
+// opcode bytes are left untouched, which keeps both directions in step.
+pub fn bcj_x86(data: &[u8], encode: bool) -> Vec<u8> {
+    let mut out = data.to_vec();
+    let mut i = 0;
+    while i + 5 <= out.len() {
+        // E8 = call rel32, E9 = jmp rel32
+        if out[i] == 0xE8 || out[i] == 0xE9 {
+            let operand = u32::from_le_bytes(out[i+1..i+5].try_into().unwrap());
+            let pos = (i + 5) as u32;
+            let converted = if encode { operand.wrapping_add(pos) } else { operand.wrapping_sub(pos) };
+            out[i+1..i+5].copy_from_slice(&converted.to_le_bytes());
+            i += 5;
+        } else {
+            i += 1;
+        }
+    }
+    out
+}
+
+pub fn bcj_arm(data: &[u8], encode: bool) -> Vec<u8> {
+    let mut out = data.to_vec();
+    let mut i = 0;
+    while i + 4 <= out.len() {
+        // BL: little-endian word with condition "always" and opcode 0xB
+        if out[i + 3] == 0xEB {
+            let offset = u32::from_le_bytes([out[i], out[i + 1], out[i + 2], 0]) << 2;
+            let pos = (i + 8) as u32;
+            let converted = if encode { offset.wrapping_add(pos) } else { offset.wrapping_sub(pos) } >> 2;
+            out[i..i + 3].copy_from_slice(&converted.to_le_bytes()[..3]);
+        }
+        i += 4;
+    }
+    out
+}
+
@@ -0,0 +1,657 @@
+// Byte layout of everything rszip writes. Multi-byte integers are always
+// little-endian regardless of the host, and every header and token stream
+// goes through the reader/writer here, so the layout lives in one place.
+//
+// file:   ["RSZ1"][version u8][codecs u8][lz_len u32][tree_len u32][min_match u8][dict_id u32][flags][filter]
+//         [huffman code table][huffman bits], see `huffman::write_code_table`;
+//         files from before version 2 hold a pre-order tree there, told apart by its first byte;
+//         with CODEC_RANGE or CODEC_ADAPTIVE instead of CODEC_HUFFMAN just that coder's bytes;
+//         with FLAG_CODEC [codec id u8][that codec's output], see codecs/mod.rs;
+//         with FLAG_BLOCKS independently compressed blocks, see blocks.rs
+// legacy: files from before the signature start straight at lz_len, see `upgrade_legacy`
+// flags:  only when bit 7 of the min_match byte is set, see `Header`
+// filter: [id u8] then per-filter parameters, see `write_filter`
+// tokens: [count u32][literals_len u32][literal bytes][token bits], see `serialize_lz`
+// stream: [len u32][block] repeated, ended by a zero length, see `write_block`
+// delta:  ["RSZD"] then old and new lengths and CRC-32s, and an rszip file of copy/insert ops, see delta.rs
+// sealed: ["RSZE"][version u8][cipher u8][contents u8][key source u8][kdf iterations u32][salt 16] then per cipher
+//         1 chacha20-poly1305: [nonce 12][ciphertext][tag 16];
+//         6/7/8 feistel ECB/CBC/CTR: [rounds u8][iv 8, CBC and CTR only][ciphertext][hmac-sha256 32],
+//         ECB and CBC PKCS#7 padded, the HMAC over everything before it, see `crypto::seal`;
+//         key source 0 is a passphrase, 1 a key file's key (one iteration); version 1 files have
+//         no contents byte and versions 1 to 3 no key source byte. Feistel files are only read
+//         at version 4: 2 to 5, and earlier versions, are refused, see `crypto::open_contents`
+use std::io::{self, Read, Write};
+
+use crate::filter::{Filter, ENGLISH_WORDS_ID};
+use crate::lz77::Token;
+use crate::{len_u32, Result, RsZipError};
+
+const TRUNCATED_HEADER: RsZipError = RsZipError::Truncated("header");
+
+// The signature never changes; `version` moves instead. A reader accepts
+// every version up to its own and refuses newer ones as Unsupported, so a
+// layout change that old readers can't skip past (feature flags can't
+// express it) bumps FORMAT_VERSION.
+pub const MAGIC: &[u8; 4] = b"RSZ1";
+// 2: canonical Huffman code tables; 3: bit-packed tokens
+pub const FORMAT_VERSION: u8 = 3;
+// codecs byte: which stages the body went through; none at all (stored)
+// means the body is the original data, lz_len bytes of it
+pub const CODEC_STORED: u8 = 0x00;
+pub const CODEC_LZ77: u8 = 0x01;
+pub const CODEC_HUFFMAN: u8 = 0x02;
+// LZW codes on their own, see codecs/lzw.rs; lz_len is what they decode to
+pub const CODEC_LZW: u8 = 0x04;
+// run-length coded, see codecs/rle.rs; on its own or before Huffman, with
+// lz_len the run-length output's size either way
+pub const CODEC_RLE: u8 = 0x08;
+// BWT blocks, see codecs/bwt.rs; Huffman-coded or not as for RLE
+pub const CODEC_BWT: u8 = 0x10;
+// range coded in place of Huffman, see range.rs; no table, so tree_len is 0
+pub const CODEC_RANGE: u8 = 0x20;
+// adaptive Huffman in place of Huffman, see adaptive_huffman.rs; no table either
+pub const CODEC_ADAPTIVE: u8 = 0x40;
+const KNOWN_CODECS: u8 = CODEC_LZ77 | CODEC_HUFFMAN | CODEC_LZW | CODEC_RLE | CODEC_BWT | CODEC_RANGE | CODEC_ADAPTIVE;
+
+pub fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+// None past the end of `data`
+pub fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at.checked_add(4)?)?.try_into().unwrap()))
+}
+
+// Cursor over a byte slice; every read is bounds-checked and returns None
+// once the data runs out.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+}
+
+// Feature flags let later versions add to the format. [flags u16]: the low
+// byte holds must-understand bits, which a reader that doesn't know them
+// refuses; the high byte holds optional bits, whose data sits in the
+// [extras_len u32][extras] block that follows and can be skipped unread.
+// Old readers predate flags and reject any file with FLAGS_PRESENT.
+// Optional data is laid out in flag-bit order, lowest first, so a reader
+// parses the flags it knows from the front and skips the rest.
+pub const FLAGS_PRESENT: u8 = 0x80;
+pub const CRITICAL_FLAGS: u16 = 0x00ff;
+// the range coder's model is order-1 (conditioned on the previous byte)
+// rather than order-0, see range.rs; only with CODEC_RANGE
+pub const FLAG_ORDER1: u16 = 0x0001;
+// a registered `Codec` wrote the body, named by its first byte, see
+// codecs/mod.rs; codecs is CODEC_STORED and lz_len the (filtered) input
+pub const FLAG_CODEC: u16 = 0x0002;
+// the body is independent blocks, each a whole file, see blocks.rs; codecs
+// is CODEC_STORED, lz_len the original length and the filter none
+pub const FLAG_BLOCKS: u16 = 0x0004;
+// extras start with [crc32 u32] of the original data
+pub const FLAG_CRC32: u16 = 0x0100;
+// with FLAG_BLOCKS: [count u32][frame offset u32 per block] next in the
+// extras, where each block starts in the body, see blocks.rs
+pub const FLAG_SEEK_TABLE: u16 = 0x0200;
+// critical flags this version understands
+const KNOWN_FLAGS: u16 = FLAG_ORDER1 | FLAG_CODEC | FLAG_BLOCKS;
+
+pub struct Header {
+    pub version: u8,
+    pub codecs: u8,
+    pub lz_len: u32,
+    // bytes of huffman code table (or legacy tree)
+    pub tree_len: u32,
+    pub min_match: u8,
+    // 0 when no preset dictionary was used
+    pub dict_id: u32,
+    // CRC-32 of the decompressed data; files before FLAG_CRC32 have none
+    pub crc32: Option<u32>,
+    // flags but FLAG_CRC32, and the extras after the crc: a seek table, and
+    // any this version doesn't interpret, kept for rewriting
+    pub flags: u16,
+    pub extras: Vec<u8>,
+    pub filter: Filter,
+}
+
+impl Header {
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        let mut flags = self.flags & !FLAG_CRC32;
+        let mut extras = Vec::new();
+        if let Some(crc) = self.crc32 {
+            flags |= FLAG_CRC32;
+            put_u32(&mut extras, crc);
+        }
+        extras.extend_from_slice(&self.extras);
+        let has_flags = flags != 0;
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        out.push(self.codecs);
+        put_u32(out, self.lz_len);
+        put_u32(out, self.tree_len);
+        out.push(self.min_match | if has_flags { FLAGS_PRESENT } else { 0 });
+        put_u32(out, self.dict_id);
+        if has_flags {
+            put_u16(out, flags);
+            if flags & !CRITICAL_FLAGS != 0 {
+                put_u32(out, len_u32(extras.len())?);
+                out.extend_from_slice(&extras);
+            }
+        }
+        write_filter(&self.filter, out)
+    }
+
+    // Unsupported for values from a newer version (format version, codecs,
+    // filters or must-understand flags)
+    pub fn read(r: &mut Reader) -> Result<Header> {
+        if r.rest().len() >= MAGIC.len() && !r.rest().starts_with(MAGIC) {
+            return Err(RsZipError::CorruptHeader(
+                "no RSZ1 signature: not an rszip file, or one from before signatures (see `rszip upgrade`)".into(),
+            ));
+        }
+        r.bytes(MAGIC.len()).ok_or(TRUNCATED_HEADER)?;
+        let version = r.u8().ok_or(TRUNCATED_HEADER)?;
+        if version == 0 {
+            return Err(RsZipError::CorruptHeader("format version 0".into()));
+        }
+        if version > FORMAT_VERSION {
+            return Err(RsZipError::Unsupported(format!("format version {}", version)));
+        }
+        let codecs = r.u8().ok_or(TRUNCATED_HEADER)?;
+        if codecs & !KNOWN_CODECS != 0 {
+            return Err(RsZipError::Unsupported(format!("codecs {:#04x}", codecs & !KNOWN_CODECS)));
+        }
+        let mut header = Header::read_fields(r)?;
+        header.version = version;
+        header.codecs = codecs;
+        Ok(header)
+    }
+
+    // everything after the signature; legacy files are only this
+    fn read_fields(r: &mut Reader) -> Result<Header> {
+        let lz_len = r.u32().ok_or(TRUNCATED_HEADER)?;
+        let tree_len = r.u32().ok_or(TRUNCATED_HEADER)?;
+        let min_match_byte = r.u8().ok_or(TRUNCATED_HEADER)?;
+        let dict_id = r.u32().ok_or(TRUNCATED_HEADER)?;
+        let (mut flags, mut extras) = (0, Vec::new());
+        if min_match_byte & FLAGS_PRESENT != 0 {
+            flags = r.u16().ok_or(TRUNCATED_HEADER)?;
+            let unknown = flags & CRITICAL_FLAGS & !KNOWN_FLAGS;
+            if unknown != 0 {
+                return Err(RsZipError::Unsupported(format!("format flags {:#06x}", unknown)));
+            }
+            if flags & !CRITICAL_FLAGS != 0 {
+                let extras_len = r.u32().ok_or(TRUNCATED_HEADER)? as usize;
+                extras = r.bytes(extras_len).ok_or(TRUNCATED_HEADER)?.to_vec();
+            }
+        }
+        let mut crc32 = None;
+        if flags & FLAG_CRC32 != 0 {
+            crc32 = Some(u32_at(&extras, 0).ok_or_else(|| RsZipError::CorruptHeader("crc32 flag without a crc".into()))?);
+            extras.drain(..4);
+            flags &= !FLAG_CRC32;
+        }
+        let filter = read_filter(r)?;
+        // legacy files are laid out like version 1
+        let (version, codecs) = (1, CODEC_LZ77 | CODEC_HUFFMAN);
+        let min_match = min_match_byte & !FLAGS_PRESENT;
+        Ok(Header { version, codecs, lz_len, tree_len, min_match, dict_id, crc32, flags, extras, filter })
+    }
+}
+
+// Migration for files written before the signature existed: their header is
+// the current one minus the first six bytes and their body is the version 1
+// layout, so they become version 1 files with the body reused as-is.
+pub fn upgrade_legacy(filedata: &[u8]) -> Result<Vec<u8>> {
+    if filedata.starts_with(MAGIC) {
+        return Err(RsZipError::InvalidOption("already has the RSZ1 signature".into()));
+    }
+    let mut r = Reader::new(filedata);
+    let header = Header::read_fields(&mut r)?;
+    let mut out = Vec::new();
+    header.write(&mut out)?;
+    out.extend_from_slice(r.rest());
+    Ok(out)
+}
+
+pub fn write_filter(filter: &Filter, out: &mut Vec<u8>) -> Result<()> {
+    match *filter {
+        Filter::None => out.push(0),
+        Filter::Sub { stride, bpp } | Filter::Up { stride, bpp } | Filter::Paeth { stride, bpp } => {
+            out.push(match filter { Filter::Sub { .. } => 1, Filter::Up { .. } => 2, _ => 3 });
+            put_u32(out, len_u32(stride)?);
+            out.push(bpp as u8);
+        }
+        Filter::Text { dict } => {
+            out.push(4);
+            out.push(dict);
+        }
+        Filter::X86 => out.push(5),
+        Filter::Arm => out.push(6),
+    }
+    Ok(())
+}
+
+pub fn read_filter(r: &mut Reader) -> Result<Filter> {
+    let id = r.u8().ok_or(TRUNCATED_HEADER)?;
+    Ok(match id {
+        0 => Filter::None,
+        1..=3 => {
+            let stride = r.u32().ok_or(TRUNCATED_HEADER)? as usize;
+            let bpp = r.u8().ok_or(TRUNCATED_HEADER)? as usize;
+            if stride == 0 || bpp == 0 || bpp > stride {
+                return Err(RsZipError::CorruptHeader(format!("filter stride {} with {} bytes/pixel", stride, bpp)));
+            }
+            match id {
+                1 => Filter::Sub { stride, bpp },
+                2 => Filter::Up { stride, bpp },
+                _ => Filter::Paeth { stride, bpp },
+            }
+        }
+        4 => {
+            let dict = r.u8().ok_or(TRUNCATED_HEADER)?;
+            if dict != ENGLISH_WORDS_ID {
+                return Err(RsZipError::Unsupported(format!("text dictionary {}", dict)));
+            }
+            Filter::Text { dict }
+        }
+        5 => Filter::X86,
+        6 => Filter::Arm,
+        _ => return Err(RsZipError::Unsupported(format!("filter {}", id))),
+    })
+}
+
+// Streaming encoders buffer this much input per block; the decoders hold one
+// decoded block at a time, so memory stays flat whatever the stream length.
+pub const STREAM_BLOCK: usize = 1 << 20;
+
+// one non-empty block of a stream; `end_stream` writes the terminator
+pub fn write_block(w: &mut impl Write, block: &[u8]) -> io::Result<()> {
+    assert!(!block.is_empty(), "an empty block would end the stream");
+    w.write_all(&len_u32(block.len())?.to_le_bytes())?;
+    w.write_all(block)
+}
+
+pub fn end_stream(w: &mut impl Write) -> io::Result<()> {
+    w.write_all(&0u32.to_le_bytes())
+}
+
+// None at the terminator. A stream that stops before it is truncated, so
+// a cut-off pipe is told apart from a finished one.
+pub fn read_block(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(RsZipError::Truncated("stream").into()),
+        other => other?,
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    if len == 0 {
+        return Ok(None);
+    }
+    // grows as data arrives instead of trusting `len` for the allocation
+    let mut block = Vec::new();
+    r.take(len).read_to_end(&mut block)?;
+    if (block.len() as u64) < len {
+        return Err(RsZipError::Truncated("stream block").into());
+    }
+    Ok(Some(block))
+}
+
+// MSB-first bit packing; `finish` zero-pads the last byte
+#[derive(Default)]
+pub struct BitWriter {
+    out: Vec<u8>,
+    pending: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter::default()
+    }
+
+    // the low `bits` bits of `value`, at most 32
+    pub fn write(&mut self, value: u32, bits: u32) {
+        self.pending = self.pending << bits | (value as u64 & ((1 << bits) - 1));
+        self.count += bits;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.out.push((self.pending >> self.count) as u8);
+        }
+        self.pending &= (1 << self.count) - 1;
+    }
+
+    // Elias gamma code of n + 1: small values take few bits and no value is
+    // out of range; 1 bit for 0, 3 for 1-2, 2k+1 for up to 2^k - 1
+    pub fn write_varint(&mut self, n: u32) {
+        let v = n as u64 + 1;
+        let bits = v.ilog2();
+        self.write(0, bits);
+        self.write(1, 1);
+        self.write(v as u32, bits);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push((self.pending << (8 - self.count)) as u8);
+        }
+        self.out
+    }
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    pub fn bit(&mut self) -> Option<bool> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1 == 1;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    pub fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut v = 0u64;
+        for _ in 0..bits {
+            v = v << 1 | self.bit()? as u64;
+        }
+        Some(v as u32)
+    }
+
+    // None on a truncated code or one for a value past u32
+    pub fn read_varint(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.bit()? {
+            zeros += 1;
+            if zeros > 32 {
+                return None;
+            }
+        }
+        let mut v = 1u64;
+        for _ in 0..zeros {
+            v = v << 1 | self.bit()? as u64;
+        }
+        u32::try_from(v - 1).ok()
+    }
+}
+
+// [count u32][literals_len u32][literal bytes][token bits]. Per token one
+// bit, 0 for a literal run and 1 for a match, then varints: the run length,
+// or distance and length. Literal bytes are kept together and byte-aligned
+// so the Huffman stage still sees them as bytes.
+pub fn serialize_lz(tokens: &[Token]) -> Result<Vec<u8>> {
+    let mut literals = Vec::new();
+    let mut bits = BitWriter::new();
+    for token in tokens {
+        match token {
+            Token::Literals(bytes) => {
+                bits.write(0, 1);
+                bits.write_varint(len_u32(bytes.len())?);
+                literals.extend_from_slice(bytes);
+            }
+            Token::Match { dist, len } => {
+                bits.write(1, 1);
+                bits.write_varint(len_u32(*dist)?);
+                bits.write_varint(len_u32(*len)?);
+            }
+        }
+    }
+    let mut out = Vec::new();
+    put_u32(&mut out, len_u32(tokens.len())?);
+    put_u32(&mut out, len_u32(literals.len())?);
+    out.extend_from_slice(&literals);
+    out.extend_from_slice(&bits.finish());
+    Ok(out)
+}
+
+// Truncated if the stream ends early; bytes after the last token are ignored.
+// `version` is the file's: before 3 tokens were byte-aligned, see
+// `deserialize_lz_bytes`.
+pub fn deserialize_lz(data: &[u8], version: u8) -> Result<Vec<Token>> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("token stream");
+    if version < 3 {
+        return deserialize_lz_bytes(data);
+    }
+    let mut r = Reader::new(data);
+    let count = r.u32().ok_or(TRUNCATED)? as usize;
+    let literals_len = r.u32().ok_or(TRUNCATED)? as usize;
+    let mut literals = Reader::new(r.bytes(literals_len).ok_or(TRUNCATED)?);
+    let mut bits = BitReader::new(r.rest());
+    // every token takes at least 2 bits, don't trust count for the allocation
+    let mut tokens = Vec::with_capacity(count.min(data.len() * 4));
+    for _ in 0..count {
+        let match_token = bits.bit().ok_or(TRUNCATED)?;
+        let a = bits.read_varint().ok_or(TRUNCATED)? as usize;
+        if match_token {
+            let len = bits.read_varint().ok_or(TRUNCATED)? as usize;
+            tokens.push(Token::Match { dist: a, len });
+        } else {
+            tokens.push(Token::Literals(literals.bytes(a).ok_or(TRUNCATED)?.to_vec()));
+        }
+    }
+    if !literals.rest().is_empty() {
+        return Err(RsZipError::CorruptData("literal bytes left over after the last token"));
+    }
+    Ok(tokens)
+}
+
+// tokens as written before format version 3:
+// [count u32] then per token [0][len u32][bytes] or [1][dist u32][len u32]
+fn deserialize_lz_bytes(data: &[u8]) -> Result<Vec<Token>> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("token stream");
+    let mut r = Reader::new(data);
+    let count = r.u32().ok_or(TRUNCATED)? as usize;
+    // every token takes at least 5 bytes, don't trust count for the allocation
+    let mut tokens = Vec::with_capacity(count.min(data.len() / 5));
+    for _ in 0..count {
+        if r.u8().ok_or(TRUNCATED)? == 0 {
+            let run = r.u32().ok_or(TRUNCATED)? as usize;
+            tokens.push(Token::Literals(r.bytes(run).ok_or(TRUNCATED)?.to_vec()));
+        } else {
+            let dist = r.u32().ok_or(TRUNCATED)? as usize;
+            let len = r.u32().ok_or(TRUNCATED)? as usize;
+            tokens.push(Token::Match { dist, len });
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(flags: u16, extras: &[u8], filter: Filter) -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            codecs: CODEC_LZ77 | CODEC_HUFFMAN,
+            lz_len: 0x0102_0304,
+            tree_len: 77,
+            min_match: 3,
+            dict_id: 0xdead_beef,
+            crc32: None,
+            flags,
+            extras: extras.to_vec(),
+            filter,
+        }
+    }
+
+    fn round_trip(h: &Header) -> Header {
+        let mut out = Vec::new();
+        h.write(&mut out).unwrap();
+        let mut r = Reader::new(&out);
+        let back = Header::read(&mut r).expect("header should parse");
+        assert_eq!(r.pos, out.len(), "reader must consume exactly what was written");
+        back
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        let mut out = Vec::new();
+        put_u32(&mut out, 0x0102_0304);
+        put_u16(&mut out, 0x0506);
+        assert_eq!(out, [4, 3, 2, 1, 6, 5]);
+        let mut r = Reader::new(&out);
+        assert_eq!(r.u32(), Some(0x0102_0304));
+        assert_eq!(r.u16(), Some(0x0506));
+        assert_eq!(r.u8(), None);
+        assert_eq!(u32_at(&out, 0), Some(0x0102_0304));
+        assert_eq!(u32_at(&out, 3), None);
+    }
+
+    #[test]
+    fn header_layout_is_fixed() {
+        let mut out = Vec::new();
+        header(0, b"", Filter::None).write(&mut out).unwrap();
+        assert_eq!(out, [b'R', b'S', b'Z', b'1', 3, 3, 4, 3, 2, 1, 77, 0, 0, 0, 3, 0xef, 0xbe, 0xad, 0xde, 0]);
+    }
+
+    #[test]
+    fn signature_and_version_are_checked() {
+        let mut out = Vec::new();
+        header(0, b"", Filter::None).write(&mut out).unwrap();
+        let read = |data: &[u8]| Header::read(&mut Reader::new(data));
+        assert!(matches!(read(&out[6..]), Err(RsZipError::CorruptHeader(_))));
+        let mut newer = out.clone();
+        newer[4] = FORMAT_VERSION + 1;
+        assert!(matches!(read(&newer), Err(RsZipError::Unsupported(_))));
+        let mut unknown_codec = out.clone();
+        unknown_codec[5] |= 0x80;
+        assert!(matches!(read(&unknown_codec), Err(RsZipError::Unsupported(_))));
+        let mut upgraded = out.clone();
+        upgraded[4] = 1;
+        assert_eq!(upgrade_legacy(&out[6..]).unwrap(), upgraded);
+        assert!(upgrade_legacy(&out).is_err());
+    }
+
+    #[test]
+    fn header_round_trips_every_filter() {
+        let filters = [
+            Filter::None,
+            Filter::Sub { stride: 1920, bpp: 3 },
+            Filter::Up { stride: 7, bpp: 1 },
+            Filter::Paeth { stride: 640, bpp: 4 },
+            Filter::Text { dict: ENGLISH_WORDS_ID },
+            Filter::X86,
+            Filter::Arm,
+        ];
+        for filter in filters {
+            let back = round_trip(&header(0, b"", filter));
+            assert!(back.filter == filter);
+            assert_eq!((back.lz_len, back.tree_len, back.min_match, back.dict_id), (0x0102_0304, 77, 3, 0xdead_beef));
+        }
+    }
+
+    #[test]
+    fn optional_flags_carry_extras() {
+        let back = round_trip(&header(0x8000, b"extra", Filter::X86));
+        assert_eq!((back.flags, back.extras.as_slice(), back.min_match), (0x8000, &b"extra"[..], 3));
+    }
+
+    #[test]
+    fn crc32_leads_the_extras() {
+        let h = Header { crc32: Some(0xcafe_f00d), ..header(0x8000, b"later", Filter::None) };
+        let mut out = Vec::new();
+        h.write(&mut out).unwrap();
+        assert_eq!(&out[19..25], [0x00, 0x81, 9, 0, 0, 0]);
+        assert_eq!(&out[25..29], [0x0d, 0xf0, 0xfe, 0xca]);
+        let back = round_trip(&h);
+        assert_eq!((back.crc32, back.flags, back.extras.as_slice()), (Some(0xcafe_f00d), 0x8000, &b"later"[..]));
+    }
+
+    #[test]
+    fn unknown_critical_flags_are_refused() {
+        let mut out = Vec::new();
+        header(0x0080, b"", Filter::None).write(&mut out).unwrap();
+        assert!(matches!(Header::read(&mut Reader::new(&out)), Err(RsZipError::Unsupported(_))));
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        let mut out = Vec::new();
+        let h = Header { crc32: Some(1), ..header(0x8000, b"extra", Filter::Sub { stride: 4, bpp: 1 }) };
+        h.write(&mut out).unwrap();
+        for len in 0..out.len() {
+            let result = Header::read(&mut Reader::new(&out[..len]));
+            assert!(matches!(result, Err(RsZipError::Truncated(_))), "prefix of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let tokens = vec![
+            Token::Literals(b"abc".to_vec()),
+            Token::Match { dist: 3, len: 9 },
+            Token::Literals(Vec::new()),
+            Token::Match { dist: 8 << 20, len: 1 << 16 },
+        ];
+        let serial = serialize_lz(&tokens).unwrap();
+        assert_eq!(&serial[..11], [4, 0, 0, 0, 3, 0, 0, 0, b'a', b'b', b'c']);
+        let mut padded = serial.clone();
+        padded.extend_from_slice(&[0xff; 3]);
+        for data in [&serial, &padded] {
+            let back = deserialize_lz(data, FORMAT_VERSION).unwrap();
+            assert_eq!(back.len(), tokens.len());
+            for (a, b) in back.iter().zip(&tokens) {
+                match (a, b) {
+                    (Token::Literals(x), Token::Literals(y)) => assert_eq!(x, y),
+                    (Token::Match { dist: d1, len: l1 }, Token::Match { dist: d2, len: l2 }) => assert_eq!((d1, l1), (d2, l2)),
+                    _ => panic!("token kind changed"),
+                }
+            }
+        }
+        assert!(matches!(deserialize_lz(&serial[..serial.len() - 1], FORMAT_VERSION), Err(RsZipError::Truncated(_))));
+    }
+
+    #[test]
+    fn tokens_are_bit_packed() {
+        // 1 + 5 bits for the run, 1 + 19 + 5 for the match: 31 bits in 4 bytes
+        let tokens = [Token::Literals(b"abcd".to_vec()), Token::Match { dist: 999, len: 3 }];
+        let serial = serialize_lz(&tokens).unwrap();
+        assert_eq!(serial.len(), 8 + 4 + 4);
+        let mut bits = BitWriter::new();
+        bits.write_varint(u32::MAX);
+        bits.write(0x5, 3);
+        let packed = bits.finish();
+        let mut r = BitReader::new(&packed);
+        assert_eq!((r.read_varint(), r.read(3), r.read_varint()), (Some(u32::MAX), Some(0x5), None));
+    }
+}
@@ -0,0 +1,337 @@
+// A compressed byte stream as a run of self-contained blocks:
+// [FRAME_SYNC][payload_len u32][check u32][payload], where the payload is a
+// `compress` stream and `check` its CRC-32. With a key the payload is
+// sealed with ChaCha20-Poly1305 instead and `check` is the frame's sequence
+// number, which is also its nonce; the tag covers the frame header too, so
+// a frame can't be altered, replayed, dropped or moved within its stream.
+// A sealed stream starts with FRAME_SALT_LEN random bytes, which the
+// stream's key is derived from, and ends with a sealed frame of no payload,
+// so one cut short between frames is an error rather than a clean end. A block ends
+// once FRAME_BLOCK bytes are buffered or on `flush()`, so interactive peers
+// get their data promptly; a reader that loses its place skips ahead to the
+// next sync marker.
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::checksum::crc32;
+use crate::crypto::{
+    chacha20_poly1305_open, chacha20_poly1305_seal, hmac_sha256, pbkdf2_sha256, random_bytes, SecretKey, DEFAULT_KDF_ITERATIONS,
+};
+use crate::format::{put_u32, u32_at};
+use crate::{compress, decompress_within, len_u32, CompressOptions, RsZipError};
+
+const FRAME_SYNC: [u8; 4] = *b"RSZF";
+pub const FRAME_BLOCK: usize = 64 * 1024;
+// what FRAME_BLOCK bytes compress to at worst: stored, behind a header well
+// under the slack, and then the tag
+const FRAME_MAX_PAYLOAD: usize = FRAME_BLOCK + 256 + FRAME_TAG_LEN;
+const FRAME_TAG_LEN: usize = 16;
+const FRAME_SALT_LEN: usize = 32;
+// salts for the two directions of a tunnel, for serve-api's streams, and
+// for `encrypt --stream` files
+pub const FRAME_LABEL_CLIENT: &str = "rszip frames, tunnel client to server";
+pub const FRAME_LABEL_SERVER: &str = "rszip frames, tunnel server to client";
+pub const FRAME_LABEL_API: &str = "rszip frames, serve-api";
+pub const FRAME_LABEL_FILE: &str = "rszip frames, encrypt --stream";
+
+// the key for frames going one way, derived once and shared by every stream
+// that way; each stream seals with its own key from this and its salt
+pub fn frame_key(secret: &SecretKey, label: &str) -> Arc<SecretKey> {
+    Arc::new(pbkdf2_sha256(secret.as_bytes(), label.as_bytes(), DEFAULT_KDF_ITERATIONS, 32))
+}
+
+// Sequence numbers restart at 0 in every stream, so the salt is what keeps a
+// key and nonce pair from being used twice across connections. HMAC rather
+// than the KDF: `key` is already stretched, and this runs per connection.
+fn stream_key(key: &SecretKey, salt: &[u8]) -> SecretKey {
+    SecretKey::new(hmac_sha256(key.as_bytes(), salt).to_vec())
+}
+
+fn frame_nonce(seq: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+pub struct FrameEncoder<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    // this stream's key, and its salt until that's written ahead of the first frame
+    key: Option<SecretKey>,
+    salt: Option<Vec<u8>>,
+    seq: u32,
+}
+
+impl<W: Write> FrameEncoder<W> {
+    // `key` from `frame_key`; fails only if the system has no randomness to salt it with
+    pub fn new(inner: W, key: Option<Arc<SecretKey>>) -> io::Result<FrameEncoder<W>> {
+        let salt = key.as_ref().map(|_| random_bytes(FRAME_SALT_LEN)).transpose()?;
+        let key = key.zip(salt.as_deref()).map(|(key, salt)| stream_key(&key, salt));
+        Ok(FrameEncoder { inner, buf: Vec::with_capacity(FRAME_BLOCK), key, salt, seq: 0 })
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let payload = compress(&self.buf, &CompressOptions::default())?;
+        self.buf.clear();
+        let Some(key) = &self.key else {
+            let mut head = FRAME_SYNC.to_vec();
+            put_u32(&mut head, len_u32(payload.len())?);
+            put_u32(&mut head, crc32(&payload));
+            self.inner.write_all(&head)?;
+            return self.inner.write_all(&payload);
+        };
+        let sealed = Self::seal(key, &mut self.seq, &payload)?;
+        self.write_salt()?;
+        self.inner.write_all(&sealed)
+    }
+
+    fn write_salt(&mut self) -> io::Result<()> {
+        match self.salt.take() {
+            Some(salt) => self.inner.write_all(&salt),
+            None => Ok(()),
+        }
+    }
+
+    // header and sealed payload of the next frame in sequence
+    fn seal(key: &SecretKey, seq: &mut u32, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = frame_nonce(*seq);
+        let mut head = FRAME_SYNC.to_vec();
+        put_u32(&mut head, len_u32(payload.len() + FRAME_TAG_LEN)?);
+        put_u32(&mut head, *seq);
+        *seq = seq.checked_add(1).ok_or_else(|| io::Error::other("framed stream ran out of nonces"))?;
+        let key = key.as_array().ok_or(RsZipError::BadKey("frame keys are 32 bytes"))?;
+        let sealed = chacha20_poly1305_seal(key, &nonce, &head, payload);
+        Ok([head, sealed].concat())
+    }
+
+    // the last block, then for a sealed stream the empty end frame
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_block()?;
+        if let Some(key) = &self.key {
+            let end = Self::seal(key, &mut self.seq, &[])?;
+            self.write_salt()?;
+            self.inner.write_all(&end)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FrameEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(FRAME_BLOCK - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == FRAME_BLOCK {
+            self.end_block()?;
+        }
+        Ok(n)
+    }
+
+    // sync point: everything written so far becomes decodable by the peer
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_block()?;
+        self.inner.flush()
+    }
+}
+
+pub struct FrameDecoder<R: Read> {
+    inner: R,
+    key: Option<Arc<SecretKey>>,
+    // derived from `key` and the salt the stream starts with
+    stream_key: Option<SecretKey>,
+    // the sequence number the next sealed frame must carry
+    seq: u32,
+    // a sealed stream's end frame was read
+    ended: bool,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> FrameDecoder<R> {
+    pub fn new(inner: R, key: Option<Arc<SecretKey>>) -> FrameDecoder<R> {
+        FrameDecoder { inner, key, stream_key: None, seq: 0, ended: false, block: Vec::new(), pos: 0 }
+    }
+
+    // None at the end: the end frame of a sealed stream, or a clean end of
+    // an unsealed one between blocks
+    fn next_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let cut_short = || io::Error::new(io::ErrorKind::UnexpectedEof, "sealed stream cut short (no end frame)");
+        if self.ended {
+            return Ok(None);
+        }
+        if let (Some(key), None) = (&self.key, &self.stream_key) {
+            let mut salt = [0u8; FRAME_SALT_LEN];
+            self.inner.read_exact(&mut salt).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => cut_short(),
+                _ => e,
+            })?;
+            self.stream_key = Some(stream_key(key, &salt));
+        }
+        let mut head = [0u8; 12];
+        let mut have = 0;
+        while have < 4 {
+            match self.inner.read(&mut head[have..4])? {
+                0 if have == 0 && self.key.is_none() => return Ok(None),
+                0 if have == 0 => return Err(cut_short()),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => have += n,
+            }
+        }
+        // resynchronize: slide a byte at a time until the marker lines up
+        while head[..4] != FRAME_SYNC {
+            head[..4].rotate_left(1);
+            self.inner.read_exact(&mut head[3..4])?;
+        }
+        self.inner.read_exact(&mut head[4..])?;
+        let (payload_len, check) = (u32_at(&head, 4).unwrap() as usize, u32_at(&head, 8).unwrap());
+        // checked before allocating, so a bad length can't ask for 4 GiB
+        if payload_len > FRAME_MAX_PAYLOAD {
+            return Err(bad("frame longer than any block compresses to (damaged stream)"));
+        }
+        let mut payload = vec![0u8; payload_len];
+        self.inner.read_exact(&mut payload)?;
+        let payload = match &self.stream_key {
+            Some(key) => {
+                if check != self.seq {
+                    return Err(bad("frame out of sequence (replayed, dropped or reordered)"));
+                }
+                let key = key.as_array().ok_or(RsZipError::BadKey("frame keys are 32 bytes"))?;
+                let plain = chacha20_poly1305_open(key, &frame_nonce(check), &head, &payload)
+                    .map_err(|_| bad("frame failed authentication (wrong key or damaged stream)"))?;
+                self.seq = self.seq.wrapping_add(1);
+                if plain.is_empty() {
+                    self.ended = true;
+                    return Ok(None);
+                }
+                plain
+            }
+            None if crc32(&payload) != check => return Err(bad("frame checksum mismatch (damaged stream)")),
+            None => payload,
+        };
+        Ok(Some(decompress_within(&payload, None, 0, FRAME_BLOCK)?))
+    }
+}
+
+impl<R: Read> Read for FrameDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            match self.next_block()? {
+                Some(block) => (self.block, self.pos) = (block, 0),
+                None => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.block.len() - self.pos);
+        out[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn framed_streams_round_trip_flushed_pieces() {
+        let key = Arc::new(SecretKey::new(vec![1; 32]));
+        for (name, data) in testdata::corpora() {
+            for key in [None, Some(key.clone())] {
+                let mut encoder = FrameEncoder::new(Vec::new(), key.clone()).unwrap();
+                for piece in data.chunks(5000) {
+                    encoder.write_all(piece).unwrap();
+                    encoder.flush().unwrap();
+                }
+                let framed = encoder.finish().unwrap();
+                let mut out = Vec::new();
+                FrameDecoder::new(&framed[..], key).read_to_end(&mut out).unwrap();
+                assert!(out == data, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn sealed_frames_refuse_tampering_replays_and_the_other_direction() {
+        // what `frame_key` gives the two directions, less the slow KDF
+        let (key, other) = (Arc::new(SecretKey::new(vec![1; 32])), Arc::new(SecretKey::new(vec![2; 32])));
+        let mut encoder = FrameEncoder::new(Vec::new(), Some(key.clone())).unwrap();
+        for piece in [&b"first frame"[..], b"second frame"] {
+            encoder.write_all(piece).unwrap();
+            encoder.flush().unwrap();
+        }
+        let framed = encoder.finish().unwrap();
+        let (salt, frames) = framed.split_at(FRAME_SALT_LEN);
+        let first_len = 12 + u32_at(frames, 4).unwrap() as usize;
+        let decoded = |stream: &[u8], key: &Arc<SecretKey>| {
+            let mut out = Vec::new();
+            FrameDecoder::new(stream, Some(key.clone())).read_to_end(&mut out).map(|_| out)
+        };
+        assert_eq!(decoded(&framed, &key).unwrap(), b"first framesecond frame");
+        assert!(!framed.windows(5).any(|w| w == b"frame"));
+        assert!(decoded(&framed, &other).is_err());
+        // every byte, the salt and headers included, is covered by the tag
+        for at in 0..framed.len() {
+            let mut bad = framed.clone();
+            bad[at] ^= 1;
+            assert!(decoded(&bad, &key).is_err(), "byte {} flipped", at);
+        }
+        // the first frame twice, or the second alone
+        let replayed = [salt, &frames[..first_len], &frames[..first_len]].concat();
+        assert!(decoded(&replayed, &key).is_err());
+        assert!(decoded(&[salt, &frames[first_len..]].concat(), &key).is_err());
+        // cut after a frame, short of the end frame, or before any
+        let second_len = 12 + u32_at(frames, first_len + 4).unwrap() as usize;
+        let cut = decoded(&framed[..FRAME_SALT_LEN + first_len + second_len], &key).unwrap_err();
+        assert_eq!(cut.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decoded(&[], &key).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(frames.len(), first_len + second_len + 12 + FRAME_TAG_LEN);
+    }
+
+    #[test]
+    fn every_sealed_stream_has_its_own_key() {
+        let key = Arc::new(SecretKey::new(vec![1; 32]));
+        let sealed = || {
+            let mut encoder = FrameEncoder::new(Vec::new(), Some(key.clone())).unwrap();
+            encoder.write_all(b"the same bytes on two connections").unwrap();
+            encoder.finish().unwrap()
+        };
+        let (one, two) = (sealed(), sealed());
+        assert_ne!(one[..FRAME_SALT_LEN], two[..FRAME_SALT_LEN]);
+        assert_ne!(one[FRAME_SALT_LEN + 12..], two[FRAME_SALT_LEN + 12..]);
+        // and a frame from one stream doesn't open in the other
+        let first_len = 12 + u32_at(&one, FRAME_SALT_LEN + 4).unwrap() as usize;
+        let spliced = [&two[..FRAME_SALT_LEN], &one[FRAME_SALT_LEN..FRAME_SALT_LEN + first_len]].concat();
+        let result = FrameDecoder::new(&spliced[..], Some(key)).read_to_end(&mut Vec::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frame_lengths_past_a_compressed_block_are_refused() {
+        let mut frame = FRAME_SYNC.to_vec();
+        put_u32(&mut frame, u32::MAX);
+        put_u32(&mut frame, 0);
+        let result = FrameDecoder::new(&frame[..], None).read_to_end(&mut Vec::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // incompressible input comes closest, and fits
+        let random = testdata::random(4 * FRAME_BLOCK);
+        let mut encoder = FrameEncoder::new(Vec::new(), None).unwrap();
+        encoder.write_all(&random).unwrap();
+        let framed = encoder.finish().unwrap();
+        assert!(u32_at(&framed, 4).unwrap() as usize > FRAME_BLOCK);
+        let mut out = Vec::new();
+        FrameDecoder::new(&framed[..], None).read_to_end(&mut out).unwrap();
+        assert!(out == random);
+        // nor may a frame decode past a block, however small its payload
+        let payload = compress(&vec![0; 2 * FRAME_BLOCK], &CompressOptions::default()).unwrap();
+        let mut frame = FRAME_SYNC.to_vec();
+        put_u32(&mut frame, payload.len() as u32);
+        put_u32(&mut frame, crc32(&payload));
+        frame.extend_from_slice(&payload);
+        let result = FrameDecoder::new(&frame[..], None).read_to_end(&mut Vec::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
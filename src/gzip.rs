@@ -0,0 +1,150 @@
+// .gz files (RFC 1952): a DEFLATE stream (see deflate.rs) between a small
+// header and a CRC-32/length trailer, so gzip and gunzip interoperate.
+//
+// member: [1f 8b][method=8][flags][mtime u32][xfl][os][extras...][deflate][crc32 u32][isize u32]
+// Extras follow `flags` in order: FEXTRA, FNAME, FCOMMENT, FHCRC. All fields
+// are little-endian, and a file may hold several members back to back.
+use crate::checksum::crc32;
+use crate::format::Reader;
+use crate::{deflate, CompressionLevel, Result, RsZipError};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+// the OS byte says who wrote the file; 255 is "unknown"
+const OS_UNKNOWN: u8 = 255;
+
+const TRUNCATED_HEADER: RsZipError = RsZipError::Truncated("gzip header");
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.len() >= 3 && data[..2] == GZIP_MAGIC && data[2] == METHOD_DEFLATE
+}
+
+// `name` is stored as the original file name (gunzip -N restores it) and
+// `mtime` as seconds since the epoch, 0 for none
+pub fn compress(data: &[u8], level: CompressionLevel, name: Option<&str>, mtime: u32) -> Vec<u8> {
+    let mut out = GZIP_MAGIC.to_vec();
+    out.push(METHOD_DEFLATE);
+    out.push(if name.is_some() { FNAME } else { 0 });
+    out.extend_from_slice(&mtime.to_le_bytes());
+    // XFL: 2 for maximum compression, 4 for the fastest
+    out.push(match level {
+        CompressionLevel::Fast => 4,
+        CompressionLevel::Default => 0,
+        CompressionLevel::Best => 2,
+    });
+    out.push(OS_UNKNOWN);
+    if let Some(name) = name {
+        // zero-terminated, so a NUL in the name would end it early
+        out.extend(name.bytes().filter(|&b| b != 0));
+        out.push(0);
+    }
+    out.extend_from_slice(&deflate::compress(data, level));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    // ISIZE is the length mod 2^32
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn skip_zero_terminated(r: &mut Reader) -> Result<()> {
+    while r.u8().ok_or(TRUNCATED_HEADER)? != 0 {}
+    Ok(())
+}
+
+// the decoded member and how many bytes of `data` it took
+fn read_member(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    if !is_gzip(data) {
+        return Err(RsZipError::CorruptHeader("not a gzip file".into()));
+    }
+    let mut r = Reader::new(&data[3..]);
+    let flags = r.u8().ok_or(TRUNCATED_HEADER)?;
+    if flags & 0xe0 != 0 {
+        return Err(RsZipError::CorruptHeader(format!("reserved gzip flags {:#04x}", flags)));
+    }
+    // mtime, xfl and os only describe the original
+    r.bytes(6).ok_or(TRUNCATED_HEADER)?;
+    if flags & FEXTRA != 0 {
+        let len = r.u16().ok_or(TRUNCATED_HEADER)? as usize;
+        r.bytes(len).ok_or(TRUNCATED_HEADER)?;
+    }
+    if flags & FNAME != 0 {
+        skip_zero_terminated(&mut r)?;
+    }
+    if flags & FCOMMENT != 0 {
+        skip_zero_terminated(&mut r)?;
+    }
+    let header_len = 3 + r.pos;
+    if flags & FHCRC != 0 {
+        let stored = r.u16().ok_or(TRUNCATED_HEADER)?;
+        if stored as u32 != crc32(&data[..header_len]) & 0xffff {
+            return Err(RsZipError::CorruptHeader("gzip header CRC".into()));
+        }
+    }
+    let body_start = 3 + r.pos;
+    let (out, body_len) = deflate::inflate(&data[body_start..])?;
+    let mut trailer = Reader::new(&data[body_start + body_len..]);
+    let stored = trailer.u32().ok_or(RsZipError::Truncated("gzip trailer"))?;
+    let size = trailer.u32().ok_or(RsZipError::Truncated("gzip trailer"))?;
+    let computed = crc32(&out);
+    if stored != computed {
+        return Err(RsZipError::ChecksumMismatch { stored, computed });
+    }
+    if size != out.len() as u32 {
+        return Err(RsZipError::CorruptData("gzip length"));
+    }
+    Ok((out, body_start + body_len + 8))
+}
+
+// every member, concatenated, as gunzip does
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    loop {
+        let (member, len) = read_member(&data[at..])?;
+        out.extend_from_slice(&member);
+        at += len;
+        if at == data.len() {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, unhex};
+
+    #[test]
+    fn round_trips_every_corpus() {
+        for (name, data) in testdata::corpora() {
+            assert_eq!(decompress(&compress(&data, CompressionLevel::Default, Some(name), 0)).unwrap(), data, "{}", name);
+        }
+    }
+
+    // FEXTRA, FNAME, FCOMMENT and FHCRC all set; none of our writers use them all
+    #[test]
+    fn reads_every_header_field() {
+        let file = unhex(concat!(
+            "1f8b081e0000000000030400414200006669656c64732e747874006120636f6d6d656e74008f8c05c15b0e00100c04c07f",
+            "a7d8ab118b265ea986707a33f9c944630bd4852356c04dbd18d364745f51e823154958a3fb6679438b2e000000",
+        ));
+        assert_eq!(decompress(&file).unwrap(), b"gzip members with every optional header field\n");
+        let mut bad_crc = file.clone();
+        let crc_at = bad_crc.len() - 8;
+        bad_crc[crc_at] ^= 1;
+        assert!(matches!(decompress(&bad_crc), Err(RsZipError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn members_concatenate() {
+        let text = testdata::text(4_000);
+        let first = compress(&text[..500], CompressionLevel::Fast, None, 0);
+        let two = [first, compress(&text[500..], CompressionLevel::Best, Some("b"), 1)].concat();
+        assert!(is_gzip(&two));
+        assert_eq!(decompress(&two).unwrap(), text);
+        assert!(decompress(&two[..two.len() - 1]).is_err());
+    }
+}
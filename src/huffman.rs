@@ -0,0 +1,406 @@
+// Byte-wise canonical Huffman coding with codes of at most MAX_CODE_LEN bits.
+// Only the code lengths are stored next to the packed bits, see
+// `write_code_table`.
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, Read, Write};
+
+use crate::format::{end_stream, put_u32, read_block, write_block, BitReader, BitWriter, Reader, STREAM_BLOCK};
+use crate::{len_u32, Result, RsZipError};
+
+#[derive(Clone)]
+pub struct Node {
+    pub freq: u32,
+    pub byte: Option<u8>,
+    pub left: Option<Box<Node>>,
+    pub right: Option<Box<Node>>,
+}
+impl Eq for Node {}
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool { self.freq == other.freq }
+}
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.freq.cmp(&self.freq)
+    }
+}
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// longest code the tree may assign, so a table-driven decoder can look codes
+// up in 2^15 entries and skewed inputs can't produce 255-bit codes
+pub const MAX_CODE_LEN: usize = 15;
+
+// build huffman
+pub fn build_huffman_tree(data: &[u8]) -> Node {
+    let mut freq_map = HashMap::new();
+    for &b in data {
+        *freq_map.entry(b).or_insert(0u32) += 1;
+    }
+    if freq_map.len() == 1 {
+        // edge case: only one symbol. A lone leaf would get a 0-bit code and
+        // encode to nothing, so pair it with an unused sibling.
+        let (b, f) = freq_map.into_iter().next().unwrap();
+        let leaf = Node{ freq:f, byte:Some(b), left:None, right:None };
+        let unused = Node{ freq:0, byte:Some(b.wrapping_add(1)), left:None, right:None };
+        return Node{ freq:f, byte:None, left:Some(Box::new(leaf)), right:Some(Box::new(unused)) };
+    }
+    // too deep: flatten the distribution by halving every count (never to 0)
+    // and rebuild. All-equal counts give depth 8, so this terminates.
+    let mut scaled = freq_map.clone();
+    loop {
+        let mut tree = huffman_tree_from(&scaled);
+        if tree_depth(&tree) <= MAX_CODE_LEN {
+            // leaves keep the real counts for statistics
+            restore_leaf_freqs(&mut tree, &freq_map);
+            return tree;
+        }
+        for f in scaled.values_mut() {
+            *f = (*f / 2).max(1);
+        }
+    }
+}
+
+fn huffman_tree_from(freq_map: &HashMap<u8, u32>) -> Node {
+    // pushed in byte order: HashMap order varies per run, and with it how
+    // ties are broken, so the same input would compress to different files
+    let mut leaves: Vec<(u8, u32)> = freq_map.iter().map(|(&b, &f)| (b, f)).collect();
+    leaves.sort_unstable();
+    let mut heap = BinaryHeap::new();
+    for (b, f) in leaves {
+        heap.push(Node{ freq:f, byte:Some(b), left:None, right:None });
+    }
+    while heap.len() > 1 {
+        let a = heap.pop().unwrap();
+        let b = heap.pop().unwrap();
+        heap.push(Node{ freq:a.freq+b.freq, byte:None, left:Some(Box::new(a)), right:Some(Box::new(b)) });
+    }
+    heap.pop().unwrap()
+}
+
+fn tree_depth(node: &Node) -> usize {
+    match (&node.left, &node.right) {
+        (Some(l), Some(r)) => 1 + tree_depth(l).max(tree_depth(r)),
+        _ => 0,
+    }
+}
+
+fn restore_leaf_freqs(node: &mut Node, freq_map: &HashMap<u8, u32>) {
+    if let Some(b) = node.byte {
+        node.freq = freq_map[&b];
+    }
+    for child in [&mut node.left, &mut node.right].into_iter().flatten() {
+        restore_leaf_freqs(child, freq_map);
+    }
+}
+
+// Canonical codes: only each symbol's code length is stored, and codes are
+// handed out in (length, symbol) order, so both sides derive the same codes
+// from the lengths alone.
+//
+// table: [CANONICAL_TABLE] then bytes of (length << 4 | repeat - 1) until all
+// 256 symbols have a length, 0 meaning unused. Pre-order trees from older
+// writers start with a 0 node byte instead and are still read.
+const CANONICAL_TABLE: u8 = 2;
+
+fn code_lengths(node: &Node, depth: u8, lengths: &mut [u8; 256]) {
+    match node.byte {
+        Some(b) => lengths[b as usize] = depth,
+        None => {
+            for child in [&node.left, &node.right].into_iter().flatten() {
+                code_lengths(child, depth + 1, lengths);
+            }
+        }
+    }
+}
+
+// (code, length) per symbol
+fn canonical_codes(lengths: &[u8; 256]) -> [(u32, u8); 256] {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0u32;
+    for len in 1..=MAX_CODE_LEN as u8 {
+        for (symbol, _) in lengths.iter().enumerate().filter(|&(_, &l)| l == len) {
+            codes[symbol] = (code, len);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+pub fn huffman_compress(data: &[u8]) -> (Vec<u8>, Node, usize) {
+    let tree = build_huffman_tree(data);
+    let mut lengths = [0; 256];
+    code_lengths(&tree, 0, &mut lengths);
+    let codes = canonical_codes(&lengths);
+    let mut bits = BitWriter::new();
+    for &b in data {
+        let (code, len) = codes[b as usize];
+        bits.write(code, len as u32);
+    }
+    (bits.finish(), tree, data.len())
+}
+
+pub enum CodeTable {
+    // how many codes each length has, and the symbols in canonical order
+    Canonical { counts: [u16; MAX_CODE_LEN + 1], symbols: Vec<u8> },
+    // pre-order tree from an older writer
+    Tree(Node),
+}
+
+// Stops early at the end of `data`; callers compare against the length
+// they expected.
+pub fn huffman_decompress(data: &[u8], table: &CodeTable, orig_len: usize) -> Vec<u8> {
+    let mut bits = BitReader::new(data);
+    let mut out = Vec::new();
+    match table {
+        CodeTable::Canonical { counts, symbols } => {
+            // walk the lengths in order: codes of each length are a
+            // contiguous range starting at `first`
+            'symbols: while out.len() < orig_len {
+                let (mut code, mut first, mut index) = (0u32, 0u32, 0usize);
+                for &count in &counts[1..] {
+                    let Some(bit) = bits.bit() else { break 'symbols };
+                    code |= bit as u32;
+                    let count = count as u32;
+                    if code < first + count {
+                        out.push(symbols[index + (code - first) as usize]);
+                        continue 'symbols;
+                    }
+                    index += count as usize;
+                    first = (first + count) << 1;
+                    code <<= 1;
+                }
+                // read_code_table only accepts complete codes, so this
+                // can't happen; stop rather than panic if it ever does
+                break;
+            }
+        }
+        CodeTable::Tree(tree) => {
+            let mut node = tree;
+            while let Some(bit) = bits.bit() {
+                node = if !bit { node.left.as_ref().unwrap() } else { node.right.as_ref().unwrap() };
+                if let Some(b) = node.byte {
+                    out.push(b);
+                    if out.len() == orig_len {
+                        break;
+                    }
+                    node = tree;
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn write_code_table(tree: &Node, out: &mut Vec<u8>) {
+    let mut lengths = [0; 256];
+    code_lengths(tree, 0, &mut lengths);
+    out.push(CANONICAL_TABLE);
+    for run in lengths.chunk_by(|a, b| a == b) {
+        for piece in run.chunks(16) {
+            out.push(piece[0] << 4 | (piece.len() - 1) as u8);
+        }
+    }
+}
+
+pub fn read_code_table(data: &[u8], idx: &mut usize) -> Result<CodeTable> {
+    match *data.get(*idx).ok_or(RsZipError::Truncated("huffman table"))? {
+        CANONICAL_TABLE => {
+            *idx += 1;
+            read_canonical(data, idx)
+        }
+        0 => Ok(CodeTable::Tree(deserialize_tree(data, idx)?)),
+        _ => Err(RsZipError::CorruptData("unknown huffman table kind")),
+    }
+}
+
+fn read_canonical(data: &[u8], idx: &mut usize) -> Result<CodeTable> {
+    let mut lengths = Vec::with_capacity(256);
+    while lengths.len() < 256 {
+        let run = *data.get(*idx).ok_or(RsZipError::Truncated("huffman table"))?;
+        *idx += 1;
+        let (len, repeat) = (run >> 4, (run & 0x0f) as usize + 1);
+        if len as usize > MAX_CODE_LEN || lengths.len() + repeat > 256 {
+            return Err(RsZipError::CorruptData("huffman code length table"));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat));
+    }
+    let mut counts = [0u16; MAX_CODE_LEN + 1];
+    for &len in lengths.iter().filter(|&&len| len > 0) {
+        counts[len as usize] += 1;
+    }
+    // writers derive the lengths from a full tree, which uses up the code
+    // space exactly; anything else would leave bit patterns with no symbol
+    let space: u32 = (1..=MAX_CODE_LEN).map(|len| (counts[len] as u32) << (MAX_CODE_LEN - len)).sum();
+    if space != 1 << MAX_CODE_LEN {
+        return Err(RsZipError::CorruptData("huffman code lengths are not a complete code"));
+    }
+    // stable, so symbols of one length stay in byte order
+    let mut symbols: Vec<u8> = (0..=255).filter(|&s| lengths[s as usize] > 0).collect();
+    symbols.sort_by_key(|&s| lengths[s as usize]);
+    Ok(CodeTable::Canonical { counts, symbols })
+}
+
+// Writers never produce a lone leaf (see build_huffman_tree) or a tree deeper
+// than 255 levels, so either means the data is corrupt, and the depth bound
+// keeps hostile input from exhausting the stack.
+fn deserialize_tree(data: &[u8], idx: &mut usize) -> Result<Node> {
+    let root = read_subtree(data, idx, 0)?;
+    if root.byte.is_some() {
+        return Err(RsZipError::CorruptData("huffman tree is a single leaf"));
+    }
+    Ok(root)
+}
+
+fn read_subtree(data: &[u8], idx: &mut usize, depth: usize) -> Result<Node> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("huffman tree");
+    if depth > 255 {
+        return Err(RsZipError::CorruptData("huffman tree too deep"));
+    }
+    let flag = *data.get(*idx).ok_or(TRUNCATED)?; *idx += 1;
+    if flag == 1 {
+        let b = *data.get(*idx).ok_or(TRUNCATED)?; *idx += 1;
+        Ok(Node { freq:0, byte:Some(b), left:None, right:None })
+    } else {
+        let left = read_subtree(data, idx, depth + 1)?;
+        let right = read_subtree(data, idx, depth + 1)?;
+        Ok(Node { freq:0, byte:None, left:Some(Box::new(left)), right:Some(Box::new(right)) })
+    }
+}
+
+// Self-contained form for callers outside the pipeline: [len u32][table][bits]
+// (there is no table for empty input, so that is the length field alone)
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    put_u32(&mut out, len_u32(data.len())?);
+    if data.is_empty() {
+        return Ok(out);
+    }
+    let (bits, tree, _) = huffman_compress(data);
+    write_code_table(&tree, &mut out);
+    out.extend_from_slice(&bits);
+    Ok(out)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut r = Reader::new(data);
+    let len = r.u32().ok_or(RsZipError::Truncated("huffman length"))? as usize;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let mut idx = r.pos;
+    let table = read_code_table(data, &mut idx)?;
+    let out = huffman_decompress(&data[idx..], &table, len);
+    if out.len() < len {
+        return Err(RsZipError::Truncated("huffman bits"));
+    }
+    Ok(out)
+}
+
+// Streaming form: every STREAM_BLOCK of input (or whatever is buffered at a
+// `flush`) becomes one block holding `compress` output, each with its own
+// tree. `finish` writes the end marker and must be called.
+pub struct HuffmanEncoder<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> HuffmanEncoder<W> {
+    pub fn new(inner: W) -> HuffmanEncoder<W> {
+        HuffmanEncoder { inner, buf: Vec::new() }
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            write_block(&mut self.inner, &compress(&self.buf)?)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_block()?;
+        end_stream(&mut self.inner)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for HuffmanEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(STREAM_BLOCK - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == STREAM_BLOCK {
+            self.end_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_block()?;
+        self.inner.flush()
+    }
+}
+
+pub struct HuffmanDecoder<R: Read> {
+    inner: R,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> HuffmanDecoder<R> {
+    pub fn new(inner: R) -> HuffmanDecoder<R> {
+        HuffmanDecoder { inner, block: Vec::new(), pos: 0, done: false }
+    }
+}
+
+impl<R: Read> Read for HuffmanDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() && !self.done {
+            match read_block(&mut self.inner)? {
+                Some(encoded) => {
+                    self.block = decompress(&encoded)?;
+                    self.pos = 0;
+                }
+                None => self.done = true,
+            }
+        }
+        let n = out.len().min(self.block.len() - self.pos);
+        out[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn round_trips_every_corpus() {
+        for (name, data) in testdata::corpora() {
+            assert_eq!(decompress(&compress(&data).unwrap()).unwrap(), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        let text = testdata::text(20_000);
+        let mut encoder = HuffmanEncoder::new(Vec::new());
+        for piece in text.chunks(7000) {
+            encoder.write_all(piece).unwrap();
+            encoder.flush().unwrap();
+        }
+        let stream = encoder.finish().unwrap();
+        let mut back = Vec::new();
+        HuffmanDecoder::new(&stream[..]).read_to_end(&mut back).unwrap();
+        assert_eq!(back, text);
+        assert!(HuffmanDecoder::new(&stream[..stream.len() - 1]).read_to_end(&mut Vec::new()).is_err());
+    }
+}
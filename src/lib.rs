@@ -0,0 +1,1092 @@
+// Rs-Zip as a library: LZ77 + Huffman compression with optional pre-filters,
+// preset dictionaries and long-range matching, plus the Feistel cipher. The
+// `rszip` binary is a consumer like any other.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub mod adaptive_huffman;
+pub mod archive;
+pub mod blocks;
+pub mod checksum;
+pub mod codecs;
+pub mod crypto;
+pub mod deflate;
+pub mod delta;
+pub mod error;
+pub mod filter;
+pub mod format;
+pub mod frame;
+pub mod gzip;
+pub mod huffman;
+pub mod lz77;
+pub mod range;
+pub mod repo;
+pub mod tar;
+#[cfg(test)]
+mod testdata;
+
+pub use error::{Result, RsZipError};
+pub use filter::Filter;
+
+use crypto::{Cipher, Contents};
+use format::{
+    deserialize_lz, serialize_lz, Header, Reader, CODEC_ADAPTIVE, CODEC_BWT, CODEC_HUFFMAN, CODEC_LZ77, CODEC_LZW, CODEC_RANGE,
+    CODEC_RLE, CODEC_STORED, FLAG_BLOCKS, FLAG_CODEC, FLAG_ORDER1, FLAG_SEEK_TABLE, FORMAT_VERSION,
+};
+use huffman::{huffman_compress, huffman_decompress, read_code_table, write_code_table, CodeTable, Node};
+use lz77::{
+    long_range_compress, lz77_compress, lz77_decompress, lz77_optimal_parse, parallel_parse, Lz77Config, Token, LZ77_WINDOW,
+    MAX_OUTPUT,
+};
+
+// ======================
+// COMPRESSION PIPELINE
+// ======================
+// file layout and feature flags: see format.rs
+pub const MIN_MATCH_RANGE: std::ops::RangeInclusive<usize> = 2..=8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    // take the longest match at every position
+    Greedy,
+    // minimize the total encoded size; much slower, for the highest level
+    Optimal,
+}
+
+// which compressor writes the body; decompress reads it from the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    // LZ77 tokens, Huffman-coded unless `huffman` is off
+    Lz77,
+    // LZW codes, see codecs/lzw.rs: no window or match settings, and no
+    // preset dictionary
+    Lzw,
+    // byte runs only, see codecs/rle.rs; Huffman-coded unless `huffman` is
+    // off, and like LZW without a preset dictionary
+    Rle,
+    // block sorting, see codecs/bwt.rs, in `block_size` blocks; otherwise
+    // as for RLE
+    Bwt,
+    // the `codecs::Codec` registered under this id does everything after
+    // the filter; `huffman`, `entropy` and the LZ77 settings don't apply
+    Registered(u8),
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match name {
+            "lz77" => Some(Algorithm::Lz77),
+            "lzw" => Some(Algorithm::Lzw),
+            "rle" => Some(Algorithm::Rle),
+            "bwt" => Some(Algorithm::Bwt),
+            _ => None,
+        }
+    }
+}
+
+// the last stage, when `huffman` is on; decompress reads it from the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Entropy {
+    Huffman,
+    // adaptive order-0 range coder, see range.rs: smaller on skewed data,
+    // slower, and no streaming or raw mode
+    Range,
+    // one-pass adaptive Huffman, see adaptive_huffman.rs: no table, and the
+    // one `--stream` can use besides Huffman
+    Adaptive,
+}
+
+impl Entropy {
+    pub fn parse(name: &str) -> Option<Entropy> {
+        match name {
+            "huffman" => Some(Entropy::Huffman),
+            "range" => Some(Entropy::Range),
+            "adaptive" => Some(Entropy::Adaptive),
+            _ => None,
+        }
+    }
+
+    fn codec(self) -> u8 {
+        match self {
+            Entropy::Huffman => CODEC_HUFFMAN,
+            Entropy::Range => CODEC_RANGE,
+            Entropy::Adaptive => CODEC_ADAPTIVE,
+        }
+    }
+}
+
+// what the range coder conditions its probabilities on; recorded in the
+// header as FLAG_ORDER1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContextModel {
+    // byte frequencies alone
+    Order0,
+    // one distribution per previous byte: better on text, 128K of tables
+    Order1,
+}
+
+impl ContextModel {
+    pub fn parse(name: &str) -> Option<ContextModel> {
+        match name {
+            "order0" => Some(ContextModel::Order0),
+            "order1" => Some(ContextModel::Order1),
+            _ => None,
+        }
+    }
+}
+
+// Presets in the spirit of other tools' -1..-9 (1-3 fast, 4-6 default,
+// 7-9 best): the window, how many candidates each position compares, the
+// parser and whether the token stream is Huffman-coded at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionLevel {
+    // short shallow search, no entropy stage
+    Fast,
+    // what CompressOptions::default() does
+    Default,
+    // wider window and the optimal parser
+    Best,
+}
+
+impl CompressionLevel {
+    pub fn from_number(n: u8) -> Option<CompressionLevel> {
+        match n {
+            1..=3 => Some(CompressionLevel::Fast),
+            4..=6 => Some(CompressionLevel::Default),
+            7..=9 => Some(CompressionLevel::Best),
+            _ => None,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<CompressionLevel> {
+        match name {
+            "fast" => Some(CompressionLevel::Fast),
+            "default" => Some(CompressionLevel::Default),
+            "best" => Some(CompressionLevel::Best),
+            _ => CompressionLevel::from_number(name.parse().ok()?),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Filter,
+    // LZ77 parse, the slow part
+    Match,
+    // Huffman or range coding of the token stream
+    Entropy,
+    Done,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProgressEvent {
+    pub stage: Stage,
+    // input consumed and output produced once the stage is entered
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // how far the stage has got, for a progress bar: `done` of `total`
+    // bytes. Events for entering a stage have both 0; the match stage then
+    // reports as each block is parsed.
+    pub done: u64,
+    pub total: u64,
+    // file or chunk being worked on, when the caller named one
+    pub entry: Option<String>,
+}
+
+pub type ProgressFn = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct CompressOptions {
+    pub algo: Algorithm,
+    // window and match lengths; only min_match is recorded in the file
+    pub lz77: Lz77Config,
+    pub parse: ParseMode,
+    // put a multi-megabyte block matcher in front of LZ77
+    pub long_range: bool,
+    // preset history both sides agree on, see `train_dictionary`
+    pub dictionary: Option<Vec<u8>>,
+    pub filter: Filter,
+    // off stores the token stream as-is: faster, and for data with no skew
+    // to exploit barely bigger
+    pub huffman: bool,
+    // which coder that stage uses when it is on
+    pub entropy: Entropy,
+    // order-1 needs Entropy::Range
+    pub model: ContextModel,
+    // BWT block size, within codecs::bwt::BLOCK_RANGE; bigger blocks see
+    // more context but sort slower
+    pub block_size: usize,
+    // Some(size): cut the input into independent blocks of this size, within
+    // blocks::BLOCKS_RANGE, compressed on `threads` threads; see blocks.rs
+    pub blocks: Option<usize>,
+    // LZ77 worker threads; 0 means one per core. The output doesn't depend on it.
+    pub threads: usize,
+    // called as each stage starts, for embedders drawing their own progress
+    pub on_progress: Option<ProgressFn>,
+    pub entry: Option<String>,
+}
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            algo: Algorithm::Lz77,
+            lz77: Lz77Config::default(),
+            parse: ParseMode::Greedy,
+            long_range: false,
+            dictionary: None,
+            filter: Filter::None,
+            huffman: true,
+            entropy: Entropy::Huffman,
+            model: ContextModel::Order0,
+            block_size: codecs::bwt::DEFAULT_BLOCK,
+            blocks: None,
+            threads: 0,
+            on_progress: None,
+            entry: None,
+        }
+    }
+}
+
+impl CompressOptions {
+    // sets only what the level covers; dictionary, filter, min_match and
+    // the rest are kept
+    pub fn set_level(&mut self, level: CompressionLevel) {
+        let (window_size, search_depth, parse, huffman) = match level {
+            CompressionLevel::Fast => (256, 8, ParseMode::Greedy, false),
+            CompressionLevel::Default => (LZ77_WINDOW, usize::MAX, ParseMode::Greedy, true),
+            CompressionLevel::Best => (4 * LZ77_WINDOW, usize::MAX, ParseMode::Optimal, true),
+        };
+        self.lz77.window_size = window_size;
+        self.lz77.search_depth = search_depth;
+        self.parse = parse;
+        self.huffman = huffman;
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        worker_threads(self.threads)
+    }
+
+    pub fn report(&self, stage: Stage, bytes_read: usize, bytes_written: usize) {
+        self.emit(stage, bytes_read, bytes_written, 0, 0);
+    }
+
+    pub fn report_progress(&self, stage: Stage, bytes_read: usize, done: usize, total: usize) {
+        self.emit(stage, bytes_read, 0, done, total);
+    }
+
+    fn emit(&self, stage: Stage, bytes_read: usize, bytes_written: usize, done: usize, total: usize) {
+        if let Some(callback) = &self.on_progress {
+            callback(ProgressEvent {
+                stage,
+                bytes_read: bytes_read as u64,
+                bytes_written: bytes_written as u64,
+                done: done as u64,
+                total: total as u64,
+                entry: self.entry.clone(),
+            });
+        }
+    }
+}
+
+// 0 means one per core
+pub fn worker_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+// FNV-1a, never 0 so that 0 can mean "no dictionary"
+pub fn dictionary_id(dict: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for &b in dict {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash.max(1)
+}
+
+// reads just enough of the header to tell which dictionary a file needs
+pub fn required_dictionary_id(filedata: &[u8]) -> Result<u32> {
+    Ok(Header::read(&mut Reader::new(filedata))?.dict_id)
+}
+
+// LZ77 + Huffman stages without any framing: (code table, huffman bits,
+// token stream length, stats). The table is self-delimiting and the token
+// stream starts with its token count, so [table][bits] can be decoded on its own.
+// Without `opts.huffman` the table is empty and the bits are the token stream,
+// and with the range coder the table is empty and the bits are its output.
+pub fn encode_stream(data: &[u8], opts: &CompressOptions) -> Result<(Vec<u8>, Vec<u8>, usize, TokenStats)> {
+    opts.lz77.validate()?;
+    let dict = opts.dictionary.as_deref().unwrap_or(&[]);
+    opts.report(Stage::Filter, 0, 0);
+    let mut window = dict.to_vec();
+    window.extend_from_slice(&opts.filter.apply(data));
+    opts.report(Stage::Match, data.len(), 0);
+    let threads = opts.worker_threads();
+    // blocks finish out of order and on several threads
+    let (parsed, to_parse, input_len) = (AtomicUsize::new(0), window.len() - dict.len(), data.len());
+    let parse = |data: &[u8], start: usize| {
+        parallel_parse(data, start, threads, |data: &[u8], start: usize| {
+            let tokens = match opts.parse {
+                ParseMode::Greedy => lz77_compress(data, start, &opts.lz77),
+                ParseMode::Optimal => lz77_optimal_parse(data, start, &opts.lz77),
+            };
+            let done = parsed.fetch_add(data.len() - start, Ordering::Relaxed) + data.len() - start;
+            opts.report_progress(Stage::Match, input_len, done, to_parse);
+            tokens
+        })
+    };
+    let tokens = if opts.long_range {
+        let tokens = long_range_compress(&window, dict.len(), opts.lz77.max_match, parse);
+        // bytes covered by long-range matches are never parsed
+        opts.report_progress(Stage::Match, data.len(), to_parse, to_parse);
+        tokens
+    } else {
+        parse(&window, dict.len())
+    };
+    let lz_serial = serialize_lz(&tokens)?;
+    if !opts.huffman {
+        let lz_len = lz_serial.len();
+        return Ok((Vec::new(), lz_serial, lz_len, TokenStats::collect(&tokens, None)));
+    }
+    opts.report(Stage::Entropy, data.len(), 0);
+    if opts.entropy != Entropy::Huffman {
+        let coded = table_free_compress(opts, &lz_serial);
+        let stats = TokenStats::collect(&tokens, None).with_coded_size(coded.len(), lz_serial.len());
+        return Ok((Vec::new(), coded, lz_serial.len(), stats));
+    }
+    let (huff, tree, lz_len) = huffman_compress(&lz_serial);
+    let mut stats = TokenStats::collect(&tokens, Some(&tree));
+
+    let mut tree_bytes = Vec::new();
+    write_code_table(&tree, &mut tree_bytes);
+    stats.table_bytes = tree_bytes.len() as u64;
+    Ok((tree_bytes, huff, lz_len, stats))
+}
+
+// `lz_len` trims the final byte's padding bits; without it they may decode
+// to a few junk bytes past the token stream, which deserialize_lz ignores.
+// `version` picks the token layout, see format.rs.
+pub fn decode_stream(stream: &[u8], lz_len: Option<usize>, dict: &[u8], filter: Filter, version: u8, limit: usize) -> Result<Vec<u8>> {
+    let mut table_idx = 0;
+    let table = read_code_table(stream, &mut table_idx)?;
+    let lz_serial = huffman_decompress(&stream[table_idx..], &table, lz_len.unwrap_or(usize::MAX));
+    let tokens = deserialize_lz(&lz_serial, version)?;
+    filter.reverse(&lz77_decompress(&tokens, dict, limit)?)
+}
+
+// header lengths are u32; inputs past 4 GiB must fail, not wrap
+pub fn len_u32(n: usize) -> Result<u32> {
+    u32::try_from(n).map_err(|_| RsZipError::TooLarge)
+}
+
+pub fn compress(data: &[u8], opts: &CompressOptions) -> Result<Vec<u8>> {
+    Ok(compress_with_stats(data, opts)?.0)
+}
+
+pub fn compress_with_stats(data: &[u8], opts: &CompressOptions) -> Result<(Vec<u8>, TokenStats)> {
+    if opts.model == ContextModel::Order1
+        && (opts.entropy != Entropy::Range || !opts.huffman || matches!(opts.algo, Algorithm::Registered(_)))
+    {
+        return Err(RsZipError::InvalidOption("the order-1 model needs the range coder".into()));
+    }
+    let mut header = Header {
+        version: FORMAT_VERSION,
+        codecs: if opts.huffman { CODEC_LZ77 | opts.entropy.codec() } else { CODEC_LZ77 },
+        lz_len: 0,
+        tree_len: 0,
+        min_match: opts.lz77.min_match as u8,
+        dict_id: opts.dictionary.as_deref().map_or(0, dictionary_id),
+        crc32: Some(checksum::crc32(data)),
+        flags: 0,
+        extras: Vec::new(),
+        filter: opts.filter,
+    };
+    let (body, mut stats) = match opts.algo {
+        _ if opts.blocks.is_some() => {
+            // filters and codecs are the blocks' own
+            (header.codecs, header.flags, header.filter) = (CODEC_STORED, FLAG_BLOCKS, Filter::None);
+            header.lz_len = len_u32(data.len())?;
+            let (body, stats) = blocks::compress(data, opts, opts.blocks.unwrap_or(blocks::DEFAULT_BLOCKS))?;
+            header.flags |= FLAG_SEEK_TABLE;
+            header.extras = blocks::seek_table(&body)?;
+            (body, stats)
+        }
+        Algorithm::Lz77 => {
+            let (tree_bytes, huff, lz_len, stats) = encode_stream(data, opts)?;
+            header.lz_len = len_u32(lz_len)?;
+            header.tree_len = len_u32(tree_bytes.len())?;
+            ([tree_bytes, huff].concat(), stats)
+        }
+        Algorithm::Registered(id) => {
+            let codec = codecs::by_id(id).ok_or_else(|| RsZipError::InvalidOption(format!("no codec registered as {}", id)))?;
+            if opts.dictionary.is_some() {
+                return Err(RsZipError::InvalidOption("preset dictionaries need the LZ77 codec".into()));
+            }
+            opts.report(Stage::Filter, 0, 0);
+            let filtered = opts.filter.apply(data);
+            opts.report(Stage::Match, data.len(), 0);
+            (header.codecs, header.flags) = (CODEC_STORED, FLAG_CODEC);
+            header.lz_len = len_u32(filtered.len())?;
+            ([vec![id], codec.compress(&filtered)].concat(), TokenStats::collect(&[], None))
+        }
+        Algorithm::Lzw | Algorithm::Rle | Algorithm::Bwt => {
+            if opts.dictionary.is_some() {
+                return Err(RsZipError::InvalidOption("preset dictionaries need the LZ77 codec".into()));
+            }
+            opts.report(Stage::Filter, 0, 0);
+            let filtered = opts.filter.apply(data);
+            opts.report(Stage::Match, data.len(), 0);
+            if opts.algo == Algorithm::Lzw {
+                header.codecs = CODEC_LZW;
+                header.lz_len = len_u32(filtered.len())?;
+                (codecs::lzw::compress(&filtered), TokenStats::collect(&[], None))
+            } else {
+                let (codec, staged) = if opts.algo == Algorithm::Rle {
+                    (CODEC_RLE, codecs::rle::compress(&filtered))
+                } else {
+                    if !codecs::bwt::BLOCK_RANGE.contains(&opts.block_size) {
+                        let msg = format!("block_size {} is not between 1K and 8M", opts.block_size);
+                        return Err(RsZipError::InvalidOption(msg));
+                    }
+                    (CODEC_BWT, codecs::bwt::compress(&filtered, opts.block_size))
+                };
+                header.lz_len = len_u32(staged.len())?;
+                if !opts.huffman || staged.is_empty() {
+                    header.codecs = codec;
+                    (staged, TokenStats::collect(&[], None))
+                } else {
+                    opts.report(Stage::Entropy, data.len(), 0);
+                    header.codecs = codec | opts.entropy.codec();
+                    match opts.entropy {
+                        Entropy::Range | Entropy::Adaptive => {
+                            let coded = table_free_compress(opts, &staged);
+                            let stats = TokenStats::collect(&[], None).with_coded_size(coded.len(), staged.len());
+                            (coded, stats)
+                        }
+                        Entropy::Huffman => {
+                            let (bits, tree, _) = huffman_compress(&staged);
+                            let mut table = Vec::new();
+                            write_code_table(&tree, &mut table);
+                            header.tree_len = len_u32(table.len())?;
+                            ([table, bits].concat(), TokenStats::collect(&[], Some(&tree)))
+                        }
+                    }
+                }
+            }
+        }
+    };
+    if header.codecs & CODEC_RANGE != 0 && opts.model == ContextModel::Order1 {
+        header.flags |= FLAG_ORDER1;
+    }
+    let mut final_out = Vec::new();
+    header.write(&mut final_out)?;
+    let header_len = final_out.len();
+    final_out.extend_from_slice(&body);
+    // Random or already-compressed input comes out bigger; store it instead,
+    // so a file is never more than its header larger than the input. Stored
+    // bytes need neither the dictionary nor the filter.
+    if final_out.len() > header_len + data.len() {
+        header.codecs = CODEC_STORED;
+        header.lz_len = len_u32(data.len())?;
+        (header.tree_len, header.dict_id, header.filter, header.flags) = (0, 0, Filter::None, 0);
+        header.extras.clear();
+        final_out.clear();
+        header.write(&mut final_out)?;
+        final_out.extend_from_slice(data);
+        stats.stored = true;
+    }
+    opts.report(Stage::Done, data.len(), final_out.len());
+    Ok((final_out, stats))
+}
+
+pub fn decompress(filedata: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<u8>> {
+    decompress_threaded(filedata, dictionary, 0)
+}
+
+// Files in independent blocks decode on `threads` threads (0 for one per
+// core); the rest decode on this one whatever `threads` says.
+pub fn decompress_threaded(filedata: &[u8], dictionary: Option<&[u8]>, threads: usize) -> Result<Vec<u8>> {
+    decompress_within(filedata, dictionary, threads, MAX_OUTPUT)
+}
+
+// For callers that know how much a file can hold, like a frame's block:
+// LZ77 tokens claiming more than `limit` bytes fail as corrupt data.
+pub(crate) fn decompress_within(filedata: &[u8], dictionary: Option<&[u8]>, threads: usize, limit: usize) -> Result<Vec<u8>> {
+    let mut r = Reader::new(filedata);
+    let header = Header::read(&mut r)?;
+    let known = [
+        CODEC_LZ77 | CODEC_HUFFMAN,
+        CODEC_LZ77 | CODEC_RANGE,
+        CODEC_LZ77 | CODEC_ADAPTIVE,
+        CODEC_LZ77,
+        CODEC_LZW,
+        CODEC_RLE | CODEC_HUFFMAN,
+        CODEC_RLE | CODEC_RANGE,
+        CODEC_RLE | CODEC_ADAPTIVE,
+        CODEC_RLE,
+        CODEC_BWT | CODEC_HUFFMAN,
+        CODEC_BWT | CODEC_RANGE,
+        CODEC_BWT | CODEC_ADAPTIVE,
+        CODEC_BWT,
+        CODEC_STORED,
+    ];
+    if !known.contains(&header.codecs) {
+        return Err(RsZipError::Unsupported(format!("codec combination {:#04x}", header.codecs)));
+    }
+    if !MIN_MATCH_RANGE.contains(&(header.min_match as usize)) {
+        return Err(RsZipError::CorruptHeader(format!("min_match {}", header.min_match)));
+    }
+    if header.flags & FLAG_ORDER1 != 0 && header.codecs & CODEC_RANGE == 0 {
+        return Err(RsZipError::CorruptHeader("order-1 model without the range coder".into()));
+    }
+    if header.flags & (FLAG_CODEC | FLAG_BLOCKS) != 0 && header.codecs != CODEC_STORED {
+        return Err(RsZipError::CorruptHeader("registered codec or blocks alongside built-in codecs".into()));
+    }
+    let dict_id = header.dict_id;
+    let dict = if dict_id == 0 {
+        &[][..]
+    } else {
+        match dictionary {
+            Some(d) if dictionary_id(d) == dict_id => d,
+            _ => return Err(RsZipError::DictionaryMismatch),
+        }
+    };
+    let stream = r.rest();
+    if stream.len() < header.tree_len as usize {
+        return Err(RsZipError::Truncated("huffman tree"));
+    }
+    let data = if header.flags & FLAG_BLOCKS != 0 {
+        blocks::decompress_blocks(&header, stream, dictionary, false, worker_threads(threads))?.0
+    } else if header.flags & FLAG_CODEC != 0 {
+        let (&id, body) = stream.split_first().ok_or(RsZipError::Truncated("codec id"))?;
+        let codec = codecs::by_id(id).ok_or_else(|| RsZipError::Unsupported(format!("codec id {} is not registered", id)))?;
+        let filtered = codec.decompress(body)?;
+        if filtered.len() != header.lz_len as usize {
+            return Err(RsZipError::CorruptData("registered codec output length"));
+        }
+        header.filter.reverse(&filtered)?
+    } else if header.codecs == CODEC_STORED {
+        stream.get(..header.lz_len as usize).ok_or(RsZipError::Truncated("stored data"))?.to_vec()
+    } else if header.codecs == CODEC_LZW {
+        header.filter.reverse(&codecs::lzw::decompress(stream, header.lz_len as usize)?)?
+    } else if header.codecs & (CODEC_RLE | CODEC_BWT) != 0 {
+        let staged = if header.codecs & CODEC_HUFFMAN != 0 {
+            let mut table_idx = 0;
+            let table = read_code_table(stream, &mut table_idx)?;
+            let staged = huffman_decompress(&stream[table_idx..], &table, header.lz_len as usize);
+            if staged.len() < header.lz_len as usize {
+                return Err(RsZipError::Truncated("huffman bits"));
+            }
+            staged
+        } else if header.codecs & (CODEC_RANGE | CODEC_ADAPTIVE) != 0 {
+            table_free_decompress(&header, stream)?
+        } else {
+            let what = if header.codecs == CODEC_RLE { "rle data" } else { "bwt blocks" };
+            stream.get(..header.lz_len as usize).ok_or(RsZipError::Truncated(what))?.to_vec()
+        };
+        let filtered = if header.codecs & CODEC_RLE != 0 {
+            codecs::rle::decompress(&staged)?
+        } else {
+            codecs::bwt::decompress(&staged)?
+        };
+        header.filter.reverse(&filtered)?
+    } else if header.codecs & (CODEC_RANGE | CODEC_ADAPTIVE) != 0 {
+        let lz_serial = table_free_decompress(&header, stream)?;
+        header.filter.reverse(&lz77_decompress(&deserialize_lz(&lz_serial, header.version)?, dict, limit)?)?
+    } else if header.codecs & CODEC_HUFFMAN != 0 {
+        decode_stream(stream, Some(header.lz_len as usize), dict, header.filter, header.version, limit)?
+    } else {
+        let lz_serial = stream.get(..header.lz_len as usize).ok_or(RsZipError::Truncated("token stream"))?;
+        header.filter.reverse(&lz77_decompress(&deserialize_lz(lz_serial, header.version)?, dict, limit)?)?
+    };
+    if let Some(stored) = header.crc32 {
+        let computed = checksum::crc32(&data);
+        if computed != stored {
+            return Err(RsZipError::ChecksumMismatch { stored, computed });
+        }
+    }
+    Ok(data)
+}
+
+// For files in independent blocks: the data with damaged or missing blocks
+// zero-filled, and the indexes of those blocks. Other files decode as with
+// `decompress`, all or nothing.
+pub fn decompress_recover(filedata: &[u8], dictionary: Option<&[u8]>, threads: usize) -> Result<(Vec<u8>, Vec<usize>)> {
+    let mut r = Reader::new(filedata);
+    let header = Header::read(&mut r)?;
+    if header.flags & FLAG_BLOCKS == 0 || header.codecs != CODEC_STORED {
+        return Ok((decompress_threaded(filedata, dictionary, threads)?, Vec::new()));
+    }
+    let (data, lost) = blocks::decompress_blocks(&header, r.rest(), dictionary, true, worker_threads(threads))?;
+    // with blocks lost the whole-file CRC can't match; each block checked its own
+    match header.crc32 {
+        Some(stored) if lost.is_empty() && checksum::crc32(&data) != stored => {
+            Err(RsZipError::ChecksumMismatch { stored, computed: checksum::crc32(&data) })
+        }
+        _ => Ok((data, lost)),
+    }
+}
+
+// Bytes `range` of the original data, `end` capped at its length. Files in
+// independent blocks decode just the blocks the range covers, found through
+// the seek table; anything else is decompressed whole and cut.
+pub fn decompress_range(filedata: &[u8], range: std::ops::Range<usize>, dictionary: Option<&[u8]>, threads: usize) -> Result<Vec<u8>> {
+    let mut r = Reader::new(filedata);
+    let header = Header::read(&mut r)?;
+    let bounded = |len: usize| {
+        if range.start > range.end.min(len) {
+            let msg = format!("range start {} is past the end of {} bytes", range.start, len);
+            return Err(RsZipError::InvalidOption(msg));
+        }
+        Ok(range.start..range.end.min(len))
+    };
+    if header.flags & FLAG_BLOCKS == 0 || header.codecs != CODEC_STORED {
+        let data = decompress(filedata, dictionary)?;
+        return Ok(data[bounded(data.len())?].to_vec());
+    }
+    blocks::read_range(&header, r.rest(), bounded(header.lz_len as usize)?, dictionary, worker_threads(threads))
+}
+
+// the entropy coders that store no table, with lz_len as the decoded length
+fn table_free_compress(opts: &CompressOptions, data: &[u8]) -> Vec<u8> {
+    match opts.entropy {
+        Entropy::Adaptive => adaptive_huffman::compress(data),
+        _ => range::compress(data, opts.model),
+    }
+}
+
+fn table_free_decompress(header: &Header, body: &[u8]) -> Result<Vec<u8>> {
+    let len = header.lz_len as usize;
+    if header.codecs & CODEC_ADAPTIVE != 0 {
+        return adaptive_huffman::decompress(body, len);
+    }
+    let model = if header.flags & FLAG_ORDER1 != 0 { ContextModel::Order1 } else { ContextModel::Order0 };
+    range::decompress(body, len, model)
+}
+
+// Raw mode: just [table][bits], for embedding in someone else's framing.
+// Dictionary and filter are not recorded and must be supplied to decode.
+pub fn compress_raw_with_stats(data: &[u8], opts: &CompressOptions) -> Result<(Vec<u8>, TokenStats)> {
+    if !opts.huffman || opts.algo != Algorithm::Lz77 || opts.entropy != Entropy::Huffman || opts.blocks.is_some() {
+        return Err(RsZipError::InvalidOption("raw streams are always LZ77 + Huffman".into()));
+    }
+    let (mut out, huff, _, stats) = encode_stream(data, opts)?;
+    out.extend_from_slice(&huff);
+    opts.report(Stage::Done, data.len(), out.len());
+    Ok((out, stats))
+}
+
+pub fn decompress_raw(stream: &[u8], dictionary: Option<&[u8]>, filter: Filter) -> Result<Vec<u8>> {
+    decode_stream(stream, None, dictionary.unwrap_or(&[]), filter, FORMAT_VERSION, MAX_OUTPUT)
+}
+
+// Compress, then seal the compressed file. The sealed header records that
+// its contents are compressed, so `unpack` undoes both stages given only the
+// passphrase (and the dictionary, if one was used).
+pub fn pack(data: &[u8], opts: &CompressOptions, passphrase: &[u8], cipher: Cipher, iterations: u32) -> Result<Vec<u8>> {
+    crypto::seal_contents(&compress(data, opts)?, Contents::Compressed, passphrase, cipher, iterations)
+}
+
+// also opens sealed files that were never compressed
+pub fn unpack(data: &[u8], passphrase: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<u8>> {
+    match crypto::open_contents(data, passphrase)? {
+        (Contents::Compressed, file) => decompress(&file, dictionary),
+        (Contents::Data, plain) => Ok(plain),
+    }
+}
+
+// The daemon and serve-api run codecs on per-connection threads, and
+// embedders do the same: everything the pipeline keeps between calls must
+// cross threads and be shareable without a wrapper mutex.
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<CompressOptions>();
+    send_sync::<ProgressEvent>();
+    send_sync::<Filter>();
+    send_sync::<Node>();
+    send_sync::<CodeTable>();
+    send_sync::<Token>();
+};
+
+// ======================
+// COMPRESSION STATISTICS
+// ======================
+// What the parser and the entropy coder did with an input, for tuning the
+// window and match thresholds (`compress -v`). Histograms use power-of-two
+// buckets: bucket k counts values in 2^k..2^(k+1).
+pub struct TokenStats {
+    pub literal_bytes: u64,
+    pub literal_runs: u64,
+    pub match_bytes: u64,
+    pub match_lengths: [u64; 32],
+    pub distances: [u64; 32],
+    // Huffman bits per token-stream byte, weighted by frequency, or what the
+    // range coder actually spent
+    pub avg_code_bits: f64,
+    // the tokens grew the data, so the file holds it uncompressed
+    pub stored: bool,
+    // bytes of Huffman code table, 0 for coders that keep none
+    pub table_bytes: u64,
+    // whole rszip files the data went into: 1, or the --blocks count
+    pub blocks: u64,
+}
+
+impl TokenStats {
+    // without a tree every token-stream byte costs 8 bits
+    pub fn collect(tokens: &[Token], tree: Option<&Node>) -> TokenStats {
+        let mut stats = TokenStats {
+            literal_bytes: 0,
+            literal_runs: 0,
+            match_bytes: 0,
+            match_lengths: [0; 32],
+            distances: [0; 32],
+            avg_code_bits: 0.0,
+            stored: false,
+            table_bytes: 0,
+            blocks: 1,
+        };
+        for token in tokens {
+            match token {
+                Token::Literals(run) => {
+                    stats.literal_runs += 1;
+                    stats.literal_bytes += run.len() as u64;
+                }
+                Token::Match { dist, len } => {
+                    stats.match_bytes += *len as u64;
+                    stats.match_lengths[len.ilog2() as usize] += 1;
+                    stats.distances[dist.ilog2() as usize] += 1;
+                }
+            }
+        }
+        match tree.map(|tree| code_length_totals(tree, 0)) {
+            Some((weighted, total)) if total > 0 => stats.avg_code_bits = weighted as f64 / total as f64,
+            Some(_) => {}
+            None => stats.avg_code_bits = 8.0,
+        }
+        stats
+    }
+
+    pub fn matches(&self) -> u64 {
+        self.match_lengths.iter().sum()
+    }
+
+    // folds in another block's stats; code bits are weighed by bytes covered
+    pub(crate) fn add(&mut self, other: &TokenStats) {
+        let (mine, theirs) = (self.literal_bytes + self.match_bytes, other.literal_bytes + other.match_bytes);
+        if mine + theirs > 0 {
+            self.avg_code_bits = (self.avg_code_bits * mine as f64 + other.avg_code_bits * theirs as f64) / (mine + theirs) as f64;
+        }
+        self.literal_bytes += other.literal_bytes;
+        self.literal_runs += other.literal_runs;
+        self.match_bytes += other.match_bytes;
+        for k in 0..32 {
+            self.match_lengths[k] += other.match_lengths[k];
+            self.distances[k] += other.distances[k];
+        }
+        self.stored |= other.stored;
+        self.table_bytes += other.table_bytes;
+        self.blocks += other.blocks;
+    }
+
+    // for coders without a code table to weigh: the bits coded per byte in
+    fn with_coded_size(mut self, coded: usize, len: usize) -> TokenStats {
+        self.avg_code_bits = coded as f64 * 8.0 / len.max(1) as f64;
+        self
+    }
+
+    // one line per stage, for `compress -v`
+    pub fn stages(&self) -> String {
+        format!(
+            "lz77: {} tokens\nhuffman: {} bytes of code table\nblocks: {}\n",
+            self.literal_runs + self.matches(),
+            self.table_bytes,
+            self.blocks
+        )
+    }
+
+    pub fn report(&self) -> String {
+        let covered = self.literal_bytes + self.match_bytes;
+        let mut out = format!(
+            "tokens: {} literal runs, {} matches\nliteral ratio: {:.1}% of {} bytes\naverage code length: {:.2} bits per token-stream byte\n",
+            self.literal_runs,
+            self.matches(),
+            if covered == 0 { 0.0 } else { self.literal_bytes as f64 * 100.0 / covered as f64 },
+            covered,
+            self.avg_code_bits
+        );
+        if self.stored {
+            out.push_str("stored uncompressed: the token stream came out larger than the input\n");
+        }
+        for (title, histogram) in [("match length", &self.match_lengths), ("distance", &self.distances)] {
+            out.push_str(&format!("{} histogram:\n", title));
+            let peak = histogram.iter().copied().max().unwrap_or(0).max(1);
+            for (k, &count) in histogram.iter().enumerate().filter(|&(_, &c)| c > 0) {
+                let range = format!("{}-{}", 1u64 << k, (1u64 << (k + 1)) - 1);
+                let bar = "#".repeat((count * 40).div_ceil(peak) as usize);
+                out.push_str(&format!("  {:>17} {:>10} {}\n", range, count, bar));
+            }
+        }
+        out
+    }
+}
+
+// (sum of freq * depth, sum of freq) over the leaves
+fn code_length_totals(node: &Node, depth: u64) -> (u64, u64) {
+    if node.byte.is_some() {
+        return (node.freq as u64 * depth, node.freq as u64);
+    }
+    [&node.left, &node.right].into_iter().flatten().fold((0, 0), |(w, t), child| {
+        let (cw, ct) = code_length_totals(child, depth + 1);
+        (w + cw, t + ct)
+    })
+}
+
+// ======================
+// DICTIONARY TRAINING
+// ======================
+// Samples are cut into fixed segments scored by how many samples share their
+// k-mers; the best segments are packed with the most valuable last, so they
+// stay closest to the data and inside the LZ77 window longest.
+const DICT_KMER: usize = 8;
+const DICT_SEGMENT: usize = 32;
+
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Vec<u8> {
+    // k-mer -> number of samples containing it
+    let mut doc_freq: HashMap<&[u8], u32> = HashMap::new();
+    for sample in samples {
+        let mut seen = std::collections::HashSet::new();
+        for kmer in sample.windows(DICT_KMER) {
+            if seen.insert(kmer) {
+                *doc_freq.entry(kmer).or_insert(0) += 1;
+            }
+        }
+    }
+    // only substrings shared between samples help cold-start compression
+    doc_freq.retain(|_, f| *f >= 2);
+
+    let mut segments: Vec<&[u8]> = Vec::new();
+    for sample in samples {
+        for start in (0..sample.len()).step_by(DICT_SEGMENT / 2) {
+            let end = (start + DICT_SEGMENT).min(sample.len());
+            if end - start >= DICT_KMER {
+                segments.push(&sample[start..end]);
+            }
+        }
+    }
+
+    let mut chosen = Vec::new();
+    let mut size = 0;
+    while size < max_size {
+        let score = |seg: &[u8]| -> u32 {
+            seg.windows(DICT_KMER).filter_map(|k| doc_freq.get(k)).sum()
+        };
+        let best = segments.iter().enumerate().max_by_key(|(_, seg)| score(seg));
+        let Some((best_idx, &best)) = best else { break };
+        if score(best) == 0 {
+            break;
+        }
+        // covered k-mers are worth nothing to later segments
+        for kmer in best.windows(DICT_KMER) {
+            doc_freq.remove(kmer);
+        }
+        chosen.push(best);
+        size += best.len();
+        segments.swap_remove(best_idx);
+    }
+
+    let mut dict = Vec::new();
+    for seg in chosen.iter().rev() {
+        dict.extend_from_slice(seg);
+    }
+    let excess = dict.len().saturating_sub(max_size);
+    dict.split_off(excess)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CipherMode;
+    use std::sync::Mutex;
+
+    // a file from a future version: flags and extras spliced in after dict_id
+    fn with_flags(flags: u16, extras: &[u8]) -> Vec<u8> {
+        let plain = compress(b"feature flags", &CompressOptions::default()).unwrap();
+        let mut r = Reader::new(&plain);
+        let header = Header::read(&mut r).unwrap();
+        let mut file = Vec::new();
+        Header { flags, extras: extras.to_vec(), ..header }.write(&mut file).unwrap();
+        file.extend_from_slice(r.rest());
+        file
+    }
+
+    #[test]
+    fn every_setting_round_trips_every_corpus() {
+        let dictionary = testdata::text(LZ77_WINDOW);
+        let mut best = CompressOptions::default();
+        best.set_level(CompressionLevel::Best);
+        let settings = [
+            CompressOptions::default(),
+            CompressOptions { parse: ParseMode::Optimal, ..Default::default() },
+            best,
+            CompressOptions { long_range: true, ..Default::default() },
+            CompressOptions { lz77: Lz77Config { min_match: 2, ..Default::default() }, ..Default::default() },
+            CompressOptions { lz77: Lz77Config { window_size: 4096, ..Default::default() }, ..Default::default() },
+            CompressOptions { dictionary: Some(dictionary), ..Default::default() },
+            CompressOptions { algo: Algorithm::Lzw, ..Default::default() },
+            CompressOptions { algo: Algorithm::Rle, huffman: false, ..Default::default() },
+            CompressOptions { algo: Algorithm::Bwt, block_size: 1024, ..Default::default() },
+            CompressOptions { entropy: Entropy::Range, model: ContextModel::Order1, ..Default::default() },
+            CompressOptions { entropy: Entropy::Adaptive, ..Default::default() },
+            CompressOptions { blocks: Some(64 << 10), ..Default::default() },
+            CompressOptions { filter: Filter::Paeth { stride: 96, bpp: 4 }, ..Default::default() },
+        ];
+        for (name, data) in testdata::corpora() {
+            for (i, opts) in settings.iter().enumerate() {
+                let file = compress(&data, opts).unwrap();
+                assert_eq!(decompress(&file, opts.dictionary.as_deref()).unwrap(), data, "setting {} on {}", i, name);
+            }
+            let (raw, _) = compress_raw_with_stats(&data, &CompressOptions::default()).unwrap();
+            assert_eq!(decompress_raw(&raw, None, Filter::None).unwrap(), data, "raw {}", name);
+        }
+    }
+
+    #[test]
+    fn truncation_and_corruption_are_detected() {
+        for (name, data) in testdata::corpora() {
+            let file = compress(&data, &CompressOptions::default()).unwrap();
+            let step = file.len().div_ceil(64);
+            for len in (0..file.len()).step_by(step) {
+                assert!(decompress(&file[..len], None).is_err(), "{} cut to {}", name, len);
+            }
+            for at in (0..file.len()).step_by(step) {
+                let mut bad = file.clone();
+                bad[at] ^= 0x10;
+                // the odd flip lands somewhere that doesn't matter, but never yields other data
+                assert!(decompress(&bad, None).map_or(true, |out| out == data), "{} flipped at {}", name, at);
+            }
+        }
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let text = testdata::text(20_000);
+        let packed = pack(&text, &CompressOptions::default(), b"pack key", Cipher::ChaCha20Poly1305, 2).unwrap();
+        assert!(packed.len() < text.len());
+        assert_eq!(unpack(&packed, b"pack key", None).unwrap(), text);
+        // sealed without compressing, which unpack takes as it is
+        let cipher = Cipher::Feistel(CipherMode::Ctr, crypto::DEFAULT_FEISTEL_ROUNDS);
+        let sealed = crypto::seal(b"not compressed", b"pack key", cipher, 2).unwrap();
+        assert_eq!(unpack(&sealed, b"pack key", None).unwrap(), b"not compressed");
+    }
+
+    #[test]
+    fn version_1_files_with_a_pre_order_tree_still_decode() {
+        let legacy = testdata::unhex(concat!(
+            "52535a31010335000000380000008300000000000104000000195e88710000000000000101010300012c016900000167010a",
+            "000163016400016500000173017401200000000161016c000001720001620179010f01000ffdff0f28906addb4484e0bfeff",
+            "eff634732f45078aa514",
+        ));
+        assert_eq!(decompress(&legacy, None).unwrap(), b"a legacy tree, a legacy tree, still readable\n");
+    }
+
+    #[test]
+    fn optional_feature_flags_are_skipped_and_unknown_critical_ones_refused() {
+        assert_eq!(decompress(&with_flags(0x8000, b"extra metadata"), None).unwrap(), b"feature flags");
+        assert!(matches!(decompress(&with_flags(0x0080, b""), None), Err(RsZipError::Unsupported(_))));
+    }
+
+    #[test]
+    fn length_fields_stop_at_4_gib() {
+        assert_eq!(len_u32(u32::MAX as usize).unwrap(), u32::MAX);
+        if let Ok(past) = usize::try_from(1u64 << 32) {
+            assert!(len_u32(past).is_err());
+        }
+    }
+
+    #[test]
+    fn incompressible_input_is_stored() {
+        let random = testdata::random(20_000);
+        let opts = CompressOptions { dictionary: Some(testdata::text(LZ77_WINDOW)), ..Default::default() };
+        let file = compress(&random, &opts).unwrap();
+        assert!(file.len() <= random.len() + 32);
+        assert_eq!(file[5], CODEC_STORED);
+        assert_eq!(decompress(&file, None).unwrap(), random);
+    }
+
+    #[test]
+    fn match_progress_counts_up_to_the_input_size() {
+        let data = testdata::text(lz77::PARALLEL_BLOCK * 3 + 10);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let on_progress: ProgressFn = Arc::new(move |e: ProgressEvent| sink.lock().unwrap().push((e.done, e.total)));
+        compress(&data, &CompressOptions { on_progress: Some(on_progress), threads: 2, ..Default::default() }).unwrap();
+        let mut bars: Vec<u64> = seen.lock().unwrap().iter().filter(|e| e.1 > 0).map(|e| e.0).collect();
+        bars.sort();
+        assert_eq!(bars.len(), 4);
+        assert_eq!(bars.last(), Some(&(data.len() as u64)));
+    }
+
+    #[test]
+    fn parallel_parse_matches_one_thread() {
+        let data = [testdata::text(lz77::PARALLEL_BLOCK), testdata::random(lz77::PARALLEL_BLOCK + 5000)].concat();
+        let one = compress(&data, &CompressOptions { threads: 1, ..Default::default() }).unwrap();
+        let four = compress(&data, &CompressOptions { threads: 4, ..Default::default() }).unwrap();
+        assert!(one == four);
+        assert_eq!(decompress(&four, None).unwrap(), data);
+    }
+
+    #[test]
+    fn stage_stats_count_tokens_code_table_bytes_and_blocks() {
+        let data = b"stage by stage, block by block ".repeat(4000);
+        let (_, whole) = compress_with_stats(&data, &CompressOptions::default()).unwrap();
+        let (_, blocked) = compress_with_stats(&data, &CompressOptions { blocks: Some(64 << 10), ..Default::default() }).unwrap();
+        let (_, ranged) = compress_with_stats(&data, &CompressOptions { entropy: Entropy::Range, ..Default::default() }).unwrap();
+        assert_eq!(whole.blocks, 1);
+        assert!(whole.table_bytes > 0);
+        assert_eq!(blocked.blocks, data.len().div_ceil(64 << 10) as u64);
+        assert!(blocked.table_bytes > whole.table_bytes);
+        assert_eq!(ranged.table_bytes, 0);
+        let lines = whole.stages();
+        assert!(lines.contains(&format!("lz77: {} tokens", whole.literal_runs + whole.matches())), "{}", lines);
+        assert!(lines.contains("blocks: 1"), "{}", lines);
+    }
+
+    #[test]
+    fn range_coder_beats_huffman_on_skewed_bytes() {
+        // 95% zeros: Huffman can't spend less than a bit on each
+        let skewed = testdata::lcg(7, 50_000, |s| if (s >> 16).is_multiple_of(20) { (s >> 8) as u8 | 1 } else { 0 });
+        let huffman = compress(&skewed, &CompressOptions::default()).unwrap();
+        let range = compress(&skewed, &CompressOptions { entropy: Entropy::Range, ..Default::default() }).unwrap();
+        assert!(range.len() < huffman.len());
+        assert_eq!(range[5], CODEC_LZ77 | CODEC_RANGE);
+        for algo in [Algorithm::Lz77, Algorithm::Rle, Algorithm::Bwt] {
+            let range = compress(&skewed, &CompressOptions { algo, entropy: Entropy::Range, ..Default::default() }).unwrap();
+            assert_eq!(decompress(&range, None).unwrap(), skewed);
+            assert!(decompress(&range[..range.len() - 2], None).is_err());
+        }
+        assert!(compress_raw_with_stats(&skewed, &CompressOptions { entropy: Entropy::Range, ..Default::default() }).is_err());
+        assert!(range::decompress(&[0; 3], 1, ContextModel::Order0).is_err());
+    }
+
+    #[test]
+    fn order_1_range_model_shrinks_text() {
+        // run-length coding leaves text as it is, so the model sees the letters
+        let text = testdata::text(20_000);
+        let order0 = compress(&text, &CompressOptions { algo: Algorithm::Rle, entropy: Entropy::Range, ..Default::default() }).unwrap();
+        let order1_opts = CompressOptions { entropy: Entropy::Range, model: ContextModel::Order1, ..Default::default() };
+        let order1 = compress(&text, &CompressOptions { algo: Algorithm::Rle, ..order1_opts.clone() }).unwrap();
+        assert!(order1.len() < order0.len());
+        assert_eq!(decompress(&order1, None).unwrap(), text);
+        for algo in [Algorithm::Lz77, Algorithm::Bwt] {
+            let file = compress(&text, &CompressOptions { algo, ..order1_opts.clone() }).unwrap();
+            assert_eq!(decompress(&file, None).unwrap(), text);
+        }
+        // the flag is what tells the decoder, so clearing it breaks the file
+        let mut unflagged = order1.clone();
+        unflagged[19] &= !(FLAG_ORDER1 as u8);
+        assert!(decompress(&unflagged, None).is_err());
+        assert!(compress(&text, &CompressOptions { model: ContextModel::Order1, ..Default::default() }).is_err());
+    }
+}
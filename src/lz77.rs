@@ -0,0 +1,481 @@
+// LZ77 over a 1 KiB window (by default) with greedy and optimal parsers, a block splitter
+// that runs either parser on several threads, and a long-range block matcher
+// for repeats megabytes apart. Tokens are serialized by format.rs.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::checksum::RollingHash;
+use crate::format::{deserialize_lz, end_stream, read_block, serialize_lz, write_block, FORMAT_VERSION, STREAM_BLOCK};
+use crate::{Result, RsZipError, MIN_MATCH_RANGE};
+
+#[derive(Clone)]
+pub enum Token {
+    // a run of bytes copied verbatim
+    Literals(Vec<u8>),
+    // copy `len` bytes starting `dist` bytes back in the output
+    Match { dist: usize, len: usize },
+}
+
+// the default window, and the only one streams use: their decoder keeps
+// exactly this much of the previous block
+pub const LZ77_WINDOW: usize = 1024;
+pub const WINDOW_RANGE: std::ops::RangeInclusive<usize> = 16..=1 << 20;
+// match lengths are stored as u32
+pub const MAX_MATCH: usize = u32::MAX as usize;
+// header lengths are u32 too, so no whole file decodes to more than this
+pub const MAX_OUTPUT: usize = u32::MAX as usize;
+// decoded output grows by at most this much per allocation
+const GROW_STEP: usize = 1 << 20;
+
+// Only the encoder needs these: a decoder follows whatever distances and
+// lengths the token stream holds, so none of them are recorded but min_match.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lz77Config {
+    // how far back matches are searched; time per byte grows with it
+    pub window_size: usize,
+    // shortest back-reference worth emitting; text likes 3, binary often 4+
+    pub min_match: usize,
+    // longer repeats are split into several matches
+    pub max_match: usize,
+    // window positions starting with the right byte that are compared,
+    // nearest first, before settling for the best so far
+    pub search_depth: usize,
+}
+
+impl Default for Lz77Config {
+    fn default() -> Self {
+        Lz77Config { window_size: LZ77_WINDOW, min_match: 3, max_match: MAX_MATCH, search_depth: usize::MAX }
+    }
+}
+
+impl Lz77Config {
+    pub fn validate(&self) -> Result<()> {
+        if !MIN_MATCH_RANGE.contains(&self.min_match) {
+            return Err(RsZipError::InvalidOption(format!("min_match {} is not between 2 and 8", self.min_match)));
+        }
+        if !WINDOW_RANGE.contains(&self.window_size) {
+            let msg = format!("window_size {} is not between 16 and {}", self.window_size, WINDOW_RANGE.end());
+            return Err(RsZipError::InvalidOption(msg));
+        }
+        if !(self.min_match..=MAX_MATCH).contains(&self.max_match) {
+            let msg = format!("max_match {} is below min_match {}", self.max_match, self.min_match);
+            return Err(RsZipError::InvalidOption(msg));
+        }
+        if self.search_depth == 0 {
+            return Err(RsZipError::InvalidOption("search_depth must be at least 1".into()));
+        }
+        Ok(())
+    }
+}
+
+// longest match for data[i..] inside the window, as (len, dist)
+fn find_longest_match(data: &[u8], i: usize, cfg: &Lz77Config) -> (usize, usize) {
+    let mut match_len = 0;
+    let mut match_dist = 0;
+    let search_start = i.saturating_sub(cfg.window_size);
+    let end = data.len().min(i.saturating_add(cfg.max_match));
+    if i == end {
+        return (0, 0);
+    }
+    let candidates = (search_start..i).rev().filter(|&j| data[j] == data[i]).take(cfg.search_depth);
+    for j in candidates {
+        let mut k = 1;
+        while i + k < end && data[j + k] == data[i + k] {
+            k += 1;
+        }
+        if k > match_len {
+            match_len = k;
+            match_dist = i - j;
+            if i + k == end {
+                break;
+            }
+        }
+    }
+    (match_len, match_dist)
+}
+
+// data[..start] is history (e.g. a preset dictionary) that matches may
+// reference but that is not itself encoded
+pub fn lz77_compress(data: &[u8], start: usize, cfg: &Lz77Config) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut literals = Vec::new();
+    let mut i = start;
+    while i < data.len() {
+        let (match_len, match_dist) = find_longest_match(data, i, cfg);
+        if match_len >= cfg.min_match {
+            if !literals.is_empty() {
+                out.push(Token::Literals(std::mem::take(&mut literals)));
+            }
+            out.push(Token::Match { dist: match_dist, len: match_len });
+            i += match_len;
+        } else {
+            literals.push(data[i]);
+            i += 1;
+        }
+    }
+    if !literals.is_empty() {
+        out.push(Token::Literals(literals));
+    }
+    out
+}
+
+// bit costs of the serialized token format (see serialize_lz); a run's
+// length varint is priced as for a typical run of 4-7
+const LITERAL_RUN_HEADER_BITS: u64 = 1 + 5;
+const LITERAL_BITS: u64 = 8;
+
+fn match_bits(dist: usize, len: usize) -> u64 {
+    let varint = |n: usize| 2 * (n as u64 + 1).ilog2() as u64 + 1;
+    1 + varint(dist) + varint(len)
+}
+// match lengths tried per position besides the longest one
+const OPTIMAL_MAX_TRIED_LEN: usize = 258;
+
+// Backward dynamic program over two states per position: `fresh` when the
+// previous token was a match (a literal would open a new run) and `in_run`
+// when it was a literal (another literal just extends the run).
+pub fn lz77_optimal_parse(data: &[u8], start: usize, cfg: &Lz77Config) -> Vec<Token> {
+    let min_match = cfg.min_match;
+    let n = data.len() - start;
+    let matches: Vec<(usize, usize)> = (start..data.len()).map(|i| find_longest_match(data, i, cfg)).collect();
+
+    let mut fresh = vec![0u64; n + 1];
+    let mut in_run = vec![0u64; n + 1];
+    // best match length at i, or 0 if a literal is cheaper
+    let mut fresh_choice = vec![0usize; n];
+    let mut run_choice = vec![0usize; n];
+    for i in (0..n).rev() {
+        let (longest, _) = matches[i];
+        let mut best_match = u64::MAX;
+        let mut best_len = 0;
+        if longest >= min_match {
+            let tried = (min_match..=longest.min(OPTIMAL_MAX_TRIED_LEN)).chain(std::iter::once(longest));
+            for len in tried {
+                let cost = match_bits(matches[i].1, len) + fresh[i + len];
+                if cost < best_match {
+                    best_match = cost;
+                    best_len = len;
+                }
+            }
+        }
+        let extend = LITERAL_BITS + in_run[i + 1];
+        let open = LITERAL_RUN_HEADER_BITS + extend;
+        (in_run[i], run_choice[i]) = if best_match < extend { (best_match, best_len) } else { (extend, 0) };
+        (fresh[i], fresh_choice[i]) = if best_match < open { (best_match, best_len) } else { (open, 0) };
+    }
+
+    let mut out = Vec::new();
+    let mut literals = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let len = if literals.is_empty() { fresh_choice[i] } else { run_choice[i] };
+        if len == 0 {
+            literals.push(data[start + i]);
+            i += 1;
+        } else {
+            if !literals.is_empty() {
+                out.push(Token::Literals(std::mem::take(&mut literals)));
+            }
+            out.push(Token::Match { dist: matches[i].1, len });
+            i += len;
+        }
+    }
+    if !literals.is_empty() {
+        out.push(Token::Literals(literals));
+    }
+    out
+}
+
+// Multi-core parsing: data[start..] is cut into PARALLEL_BLOCK pieces parsed
+// on their own. Unlike a stream, the bytes before a block are already known,
+// so its matches still reach back across the seam; they only stop at the
+// block's end, which lets the pieces' tokens concatenate into one stream.
+// The cuts depend on the input alone, so the output is the same for any
+// number of threads.
+pub const PARALLEL_BLOCK: usize = 128 << 10;
+
+pub fn parallel_parse(
+    data: &[u8],
+    start: usize,
+    threads: usize,
+    parse: impl Fn(&[u8], usize) -> Vec<Token> + Sync,
+) -> Vec<Token> {
+    let blocks: Vec<usize> = (start..data.len()).step_by(PARALLEL_BLOCK).collect();
+    let parse_block = |b: usize| parse(&data[..(b + PARALLEL_BLOCK).min(data.len())], b);
+    if threads <= 1 || blocks.len() <= 1 {
+        return blocks.into_iter().flat_map(parse_block).collect();
+    }
+    // workers take the next unparsed block until none are left
+    let next = AtomicUsize::new(0);
+    let mut parsed: Vec<Vec<Token>> = vec![Vec::new(); blocks.len()];
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(blocks.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let n = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&b) = blocks.get(n) else { break };
+                        done.push((n, parse_block(b)));
+                    }
+                    done
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (n, tokens) in worker.join().expect("LZ77 worker panicked") {
+                parsed[n] = tokens;
+            }
+        }
+    });
+    parsed.concat()
+}
+
+// Far-apart repetitions (VM images, tarballs) are found by indexing the hash
+// of every block-aligned 64-byte chunk seen so far and probing it with a
+// rolling hash at every position; the gaps between hits go to normal LZ77.
+pub const LONG_RANGE_BLOCK: usize = 64;
+pub const LONG_RANGE_HISTORY: usize = 8 << 20;
+
+pub fn long_range_compress(
+    data: &[u8],
+    start: usize,
+    max_match: usize,
+    parse: impl Fn(&[u8], usize) -> Vec<Token>,
+) -> Vec<Token> {
+    let mut out = Vec::new();
+    if data.len() - start < LONG_RANGE_BLOCK {
+        return parse(data, start);
+    }
+    let mut index: HashMap<u64, usize> = HashMap::new();
+    let mut next_block = 0;
+    let mut gap_start = start;
+    let mut i = start;
+    let mut hash = RollingHash::new(&data[i..i + LONG_RANGE_BLOCK]);
+    while i + LONG_RANGE_BLOCK <= data.len() {
+        while next_block + LONG_RANGE_BLOCK <= i {
+            index.insert(RollingHash::of(&data[next_block..next_block + LONG_RANGE_BLOCK]), next_block);
+            next_block += LONG_RANGE_BLOCK;
+        }
+        let candidate = index.get(&hash.value()).copied().filter(|&p| i - p <= LONG_RANGE_HISTORY);
+        if let Some(p) = candidate.filter(|&p| data[p..p + LONG_RANGE_BLOCK] == data[i..i + LONG_RANGE_BLOCK]) {
+            let mut len = LONG_RANGE_BLOCK;
+            while i + len < data.len() && data[p + len] == data[i + len] {
+                len += 1;
+            }
+            let mut back = 0;
+            while i - back > gap_start && p > back && data[p - back - 1] == data[i - back - 1] {
+                back += 1;
+            }
+            let match_start = i - back;
+            out.extend(parse(&data[..match_start], gap_start));
+            let mut left = len + back;
+            while left > 0 {
+                let piece = left.min(max_match);
+                out.push(Token::Match { dist: i - p, len: piece });
+                left -= piece;
+            }
+            i += len;
+            gap_start = i;
+            if i + LONG_RANGE_BLOCK > data.len() {
+                break;
+            }
+            hash = RollingHash::new(&data[i..i + LONG_RANGE_BLOCK]);
+            continue;
+        }
+        if i + LONG_RANGE_BLOCK < data.len() {
+            hash.roll(data[i], data[i + LONG_RANGE_BLOCK]);
+        }
+        i += 1;
+    }
+    out.extend(parse(data, gap_start));
+    out
+}
+
+// `history` must be the same bytes the compressor saw before `start`.
+// Tokens producing more than `limit` bytes are corrupt: a match length is
+// whatever the stream says, and a few bytes of tokens can claim gigabytes.
+pub fn lz77_decompress(tokens: &[Token], history: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut out = history.to_vec();
+    let end = history.len().saturating_add(limit);
+    for token in tokens {
+        let len = match token {
+            Token::Literals(bytes) => bytes.len(),
+            Token::Match { len, .. } => *len,
+        };
+        if len > end - out.len() {
+            return Err(RsZipError::CorruptData("tokens run past the end of the data"));
+        }
+        match token {
+            Token::Literals(bytes) => out.extend_from_slice(bytes),
+            Token::Match { dist, len } => {
+                if *dist == 0 || *dist > out.len() {
+                    return Err(RsZipError::CorruptData("match reaches before the start of the data"));
+                }
+                let start = out.len() - dist;
+                let mut i = 0;
+                while i < *len {
+                    // a long match may still be legitimate (a run), but its
+                    // memory is asked for as it's written, and may be refused
+                    let step = (*len - i).min(GROW_STEP);
+                    out.try_reserve(step).map_err(|_| RsZipError::TooLarge)?;
+                    for _ in 0..step {
+                        out.push(out[start + i]);
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(out.split_off(history.len()))
+}
+
+// Greedy parse of `data` as a token stream, see format.rs for the layout
+pub fn compress(data: &[u8], cfg: &Lz77Config) -> Result<Vec<u8>> {
+    cfg.validate()?;
+    serialize_lz(&lz77_compress(data, 0, cfg))
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    lz77_decompress(&deserialize_lz(data, FORMAT_VERSION)?, &[], MAX_OUTPUT)
+}
+
+// Streaming form: blocks of serialized tokens, see format.rs. Matches may
+// reach back into the previous block, so splitting a stream into blocks
+// costs nothing at the seams. `finish` writes the end marker and must be
+// called. Combine with HuffmanEncoder for the full pipeline:
+// `Lz77Encoder::new(HuffmanEncoder::new(w), 3)`.
+pub struct Lz77Encoder<W: Write> {
+    inner: W,
+    min_match: usize,
+    // the last LZ77_WINDOW bytes already encoded, followed by pending input
+    window: Vec<u8>,
+    history: usize,
+}
+
+impl<W: Write> Lz77Encoder<W> {
+    pub fn new(inner: W, min_match: usize) -> Lz77Encoder<W> {
+        Lz77Encoder { inner, min_match, window: Vec::new(), history: 0 }
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if self.window.len() == self.history {
+            return Ok(());
+        }
+        let cfg = Lz77Config { min_match: self.min_match, ..Default::default() };
+        cfg.validate()?;
+        let tokens = lz77_compress(&self.window, self.history, &cfg);
+        write_block(&mut self.inner, &serialize_lz(&tokens)?)?;
+        self.window.drain(..self.window.len().saturating_sub(LZ77_WINDOW));
+        self.history = self.window.len();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_block()?;
+        end_stream(&mut self.inner)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Lz77Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(self.history + STREAM_BLOCK - self.window.len());
+        self.window.extend_from_slice(&data[..n]);
+        if self.window.len() - self.history == STREAM_BLOCK {
+            self.end_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_block()?;
+        self.inner.flush()
+    }
+}
+
+pub struct Lz77Decoder<R: Read> {
+    inner: R,
+    // tail of the previous block, for matches that reach back into it
+    history: Vec<u8>,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Lz77Decoder<R> {
+    pub fn new(inner: R) -> Lz77Decoder<R> {
+        Lz77Decoder { inner, history: Vec::new(), block: Vec::new(), pos: 0, done: false }
+    }
+}
+
+impl<R: Read> Read for Lz77Decoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() && !self.done {
+            match read_block(&mut self.inner)? {
+                Some(serial) => {
+                    self.history.extend_from_slice(&self.block);
+                    self.history.drain(..self.history.len().saturating_sub(LZ77_WINDOW));
+                    let tokens = deserialize_lz(&serial, FORMAT_VERSION)?;
+                    self.block = lz77_decompress(&tokens, &self.history, STREAM_BLOCK)?;
+                    self.pos = 0;
+                }
+                None => self.done = true,
+            }
+        }
+        let n = out.len().min(self.block.len() - self.pos);
+        out[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman::{HuffmanDecoder, HuffmanEncoder};
+    use crate::testdata;
+
+    #[test]
+    fn round_trips_every_corpus() {
+        for (name, data) in testdata::corpora() {
+            assert_eq!(decompress(&compress(&data, &Lz77Config::default()).unwrap()).unwrap(), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn streams_through_huffman() {
+        let data = [testdata::text(100_000), testdata::runs(50_000)].concat();
+        let mut encoder = Lz77Encoder::new(HuffmanEncoder::new(Vec::new()), 3);
+        for piece in data.chunks(70_000) {
+            encoder.write_all(piece).unwrap();
+            encoder.flush().unwrap();
+        }
+        let stream = encoder.finish().unwrap().finish().unwrap();
+        let mut out = Vec::new();
+        Lz77Decoder::new(HuffmanDecoder::new(&stream[..])).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        let truncated = Lz77Decoder::new(HuffmanDecoder::new(&stream[..stream.len() / 2])).read_to_end(&mut Vec::new());
+        assert!(truncated.is_err());
+    }
+
+    #[test]
+    fn matches_past_the_output_limit_are_corrupt_not_allocated() {
+        // a dozen bytes of tokens asking for 4 GiB
+        let tokens = [Token::Literals(b"a".to_vec()), Token::Match { dist: 1, len: MAX_MATCH }];
+        let serial = serialize_lz(&tokens).unwrap();
+        assert!(matches!(lz77_decompress(&tokens, &[], STREAM_BLOCK), Err(RsZipError::CorruptData(_))));
+        let mut stream = Vec::new();
+        write_block(&mut stream, &serial).unwrap();
+        end_stream(&mut stream).unwrap();
+        let result = Lz77Decoder::new(&stream[..]).read_to_end(&mut Vec::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // up to the limit is fine
+        let tokens = [Token::Literals(b"a".to_vec()), Token::Match { dist: 1, len: 99 }];
+        assert_eq!(lz77_decompress(&tokens, &[], 100).unwrap(), [b'a'; 100]);
+        assert!(lz77_decompress(&tokens, &[], 99).is_err());
+    }
+}
@@ -1,394 +1,4350 @@
-use std::collections::{BinaryHeap, HashMap};
-use std::cmp::Ordering;
-use std::convert::TryInto;
+use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use rszip::adaptive_huffman::{AdaptiveHuffmanDecoder, AdaptiveHuffmanEncoder, ADAPTIVE_MAGIC};
+use rszip::archive::{
+    entry_child_name, is_appendable, is_archive, normalize_path, Archive, Entry, EntryKind, EntryMeta, Method,
+};
+use rszip::checksum::crc32;
+use rszip::crypto::{
+    chacha20_poly1305_open, chacha20_poly1305_seal, ct_eq, feistel_decrypt, feistel_encrypt,
+    generate_key_file, hex_lower, is_sealed, key_file_key, open, pbkdf2_sha256, poly1305, seal, sha256,
+    zeroize, Cipher, CipherMode, KeySchedule, SecretKey, DEFAULT_FEISTEL_ROUNDS, DEFAULT_KDF_ITERATIONS,
+    MIN_FEISTEL_ROUNDS,
+};
+use rszip::filter::{ENGLISH_WORDS, ENGLISH_WORDS_ID};
+use rszip::frame::{
+    frame_key, FrameDecoder, FrameEncoder, FRAME_BLOCK, FRAME_LABEL_API, FRAME_LABEL_CLIENT, FRAME_LABEL_FILE, FRAME_LABEL_SERVER,
+};
+use rszip::format::{
+    put_u16, read_filter, u32_at, upgrade_legacy, Header, Reader, CODEC_HUFFMAN, CODEC_LZ77, CODEC_LZW,
+    CODEC_ADAPTIVE, CODEC_BWT, CODEC_RANGE, CODEC_RLE, CODEC_STORED, CRITICAL_FLAGS, FLAGS_PRESENT, FLAG_BLOCKS, FLAG_CODEC, FLAG_CRC32, FLAG_ORDER1, FLAG_SEEK_TABLE, FORMAT_VERSION, MAGIC,
+};
+use rszip::blocks::{self, BLOCKS_RANGE};
+use rszip::codecs::{self, bwt};
+use rszip::huffman::{HuffmanDecoder, HuffmanEncoder};
+use rszip::repo::{diff_snapshots, AddStats, Repo};
+use rszip::lz77::{
+    Lz77Config, Lz77Decoder, Lz77Encoder, LONG_RANGE_BLOCK, LONG_RANGE_HISTORY, LZ77_WINDOW, MAX_MATCH,
+    WINDOW_RANGE,
+};
+use rszip::tar::{self, file_mode, is_tar, read_tar, set_file_mode, TarWriter};
+use rszip::{
+    compress, delta, compress_raw_with_stats, compress_with_stats, decompress, decompress_range, decompress_raw, decompress_recover,
+    decompress_threaded, deflate, gzip, len_u32, pack,
+    required_dictionary_id, train_dictionary, unpack, Algorithm, CompressOptions, CompressionLevel, ContextModel, Entropy, Filter, ParseMode, ProgressEvent,
+    ProgressFn, RsZipError, TokenStats, MIN_MATCH_RANGE,
+};
+
+// ======================
+// MEMORY ACCOUNTING
+// ======================
+// The global allocator keeps a count of live heap bytes and its high-water
+// mark, so `compress -v` can report real peak usage. `--max-memory` works
+// from an estimate made before compressing, see `fit_memory`.
+struct CountingAlloc;
+
+static HEAP_LIVE: AtomicUsize = AtomicUsize::new(0);
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            note_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        HEAP_LIVE.fetch_sub(layout.size(), AtomicOrdering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            HEAP_LIVE.fetch_sub(layout.size(), AtomicOrdering::Relaxed);
+            note_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn note_alloc(size: usize) {
+    let live = HEAP_LIVE.fetch_add(size, AtomicOrdering::Relaxed) + size;
+    HEAP_PEAK.fetch_max(live, AtomicOrdering::Relaxed);
+}
+
+fn peak_heap() -> usize {
+    HEAP_PEAK.load(AtomicOrdering::Relaxed)
+}
+
+// Rough bytes of heap per input byte, measured on text and binary corpora:
+// input, filtered window, tokens, token stream, Huffman output and the
+// final file are all alive at once near the end of a greedy parse.
+const GREEDY_BYTES_PER_INPUT: usize = 12;
+// match table plus four cost/choice arrays of one word per position
+const OPTIMAL_BYTES_PER_INPUT: usize = 40;
+// one hash-table entry per indexed 64-byte block
+const LONG_RANGE_BYTES_PER_BLOCK: usize = 48;
+
+fn estimated_memory(input_len: usize, opts: &CompressOptions) -> usize {
+    let window_len = input_len + opts.dictionary.as_ref().map_or(0, Vec::len);
+    let mut total = window_len * GREEDY_BYTES_PER_INPUT;
+    if opts.parse == ParseMode::Optimal {
+        total += input_len * OPTIMAL_BYTES_PER_INPUT;
+    }
+    if opts.long_range {
+        total += window_len.min(LONG_RANGE_HISTORY) / LONG_RANGE_BLOCK * LONG_RANGE_BYTES_PER_BLOCK;
+    }
+    total
+}
+
+// Downgrades `opts` until the estimate fits `cap`, cheapest loss first: the
+// optimal parse, then long-range matching. Returns what was given up, or the
+// smallest footprint possible when even plain greedy LZ77 doesn't fit.
+fn fit_memory(opts: &mut CompressOptions, input_len: usize, cap: usize) -> Result<Vec<&'static str>, usize> {
+    let mut dropped = Vec::new();
+    if estimated_memory(input_len, opts) > cap && opts.parse == ParseMode::Optimal {
+        opts.parse = ParseMode::Greedy;
+        dropped.push("optimal parsing (--best)");
+    }
+    if estimated_memory(input_len, opts) > cap && opts.long_range {
+        opts.long_range = false;
+        dropped.push("long-range matching (--long)");
+    }
+    match estimated_memory(input_len, opts) {
+        needed if needed > cap => Err(needed),
+        _ => Ok(dropped),
+    }
+}
+
+// The binary's own shared state crosses threads like the library's codecs
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<TarWriter<Vec<u8>>>();
+    send_sync::<Repo>();
+    send_sync::<Box<dyn Backend>>();
+};
+
+// ======================
+// MANIFEST
+// ======================
+// Deterministic JSON listing of what an archive holds, one entry per line,
+// so it can be diffed, audited and signed with external tools.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// `default_name` labels a compressed file that is not a tarball
+fn build_manifest(contents: &[u8], default_name: &str) -> Option<String> {
+    let mut lines = Vec::new();
+    if is_tar(contents) {
+        for entry in read_tar(contents).ok()? {
+            let kind = match entry.kind {
+                b'5' => "dir",
+                b'2' => "symlink",
+                b'1' => "hardlink",
+                _ => "file",
+            };
+            let mut line = format!(
+                "{{\"path\": {}, \"type\": \"{}\", \"size\": {}, \"mode\": \"{:04o}\"",
+                json_string(&entry.name), kind, entry.data.len(), entry.mode
+            );
+            if kind == "file" {
+                line.push_str(&format!(", \"sha256\": \"{}\"", hex_lower(&sha256(entry.data))));
+            } else if !entry.link.is_empty() {
+                line.push_str(&format!(", \"target\": {}", json_string(&entry.link)));
+            }
+            line.push('}');
+            lines.push(line);
+        }
+    } else {
+        lines.push(format!(
+            "{{\"path\": {}, \"type\": \"file\", \"size\": {}, \"sha256\": \"{}\"}}",
+            json_string(default_name), contents.len(), hex_lower(&sha256(contents))
+        ));
+    }
+    Some(format!("{{\"entries\": [\n  {}\n]}}\n", lines.join(",\n  ")))
+}
+
+// ======================
+// ARCHIVE CONTAINER
+// ======================
+// Filesystem side of `rszip add/list/extract` and of compressing a
+// directory; the container itself is rszip::archive. Names are stored the way
+// TarWriter stores them. Symlinks are stored as links, not followed:
+// following them could loop or pull in files from outside the tree.
+
+// archive name for a command-line path: its last normal components, so
+// `../src/` is stored as `src/...` and `.` contributes no prefix
+fn tar_root_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .next_back()
+        .unwrap_or_default()
+}
+
+// mtime, mode and, on Unix, owner of a path being archived
+fn entry_meta(meta: &fs::Metadata) -> EntryMeta {
+    let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+    #[cfg(unix)]
+    let (uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (meta.uid(), meta.gid())
+    };
+    #[cfg(not(unix))]
+    let (uid, gid) = (0, 0);
+    EntryMeta { mtime, mode: file_mode(meta), uid, gid }
+}
+
+// `perms` false (--no-perms) restores the mtime alone. Ownership is set
+// where the OS allows it, which for anyone else's ids takes root, as in tar.
+fn restore_meta(path: &Path, meta: &EntryMeta, perms: bool) -> io::Result<()> {
+    // Windows can't open a directory as a file; its mtime is left as it is
+    if let Ok(file) = fs::File::open(path) {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(meta.mtime))?;
+    }
+    if perms {
+        #[cfg(unix)]
+        let _ = std::os::unix::fs::chown(path, Some(meta.uid), Some(meta.gid));
+        set_file_mode(path, meta.mode)?;
+    }
+    Ok(())
+}
+
+// `dedup` stores files as chunks, each kept once in the archive
+fn archive_add_path(
+    archive: &mut Archive,
+    path: &Path,
+    name: &str,
+    opts: &CompressOptions,
+    dedup: bool,
+) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.file_type().is_symlink() {
+        archive.add_symlink(name, &fs::read_link(path)?.to_string_lossy())?;
+    } else if meta.is_dir() {
+        if !name.is_empty() {
+            archive.add_dir(name)?;
+            archive.set_meta(name, entry_meta(&meta))?;
+        }
+        let mut entries: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let child = entry_child_name(name, &entry.file_name().to_string_lossy());
+            archive_add_path(archive, &entry.path(), &child, opts, dedup)?;
+        }
+    } else {
+        let data = read_input(path)?;
+        if dedup {
+            archive.add_file_dedup(name, &data, opts)?;
+        } else {
+            archive.add_file(name, &data, opts)?;
+        }
+        archive.set_meta(name, entry_meta(&meta))?;
+    }
+    Ok(())
+}
+
+// "name" itself and everything below it; `name` is in stored form
+fn archive_entry_matches(path: &str, name: &str) -> bool {
+    path == name || path.strip_prefix(name).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Recreates the entries under `dest`, all of them when `wanted` is empty,
+// with their metadata; `perms` false leaves out modes and ownership.
+// Encrypted entries are opened with `keys`, to which any key asked for on
+// the way is added (see `extract_encrypted`). Stored paths are relative with no `..` (Archive::read refuses any other),
+// and a symlink already on disk is never written through.
+fn extract_archive(archive: &Archive, dest: &Path, wanted: &[String], perms: bool, keys: &mut Vec<SecretKey>) -> io::Result<usize> {
+    let mut extracted = 0;
+    // writing into a directory changes its mtime, and a read-only one would
+    // refuse the files, so directories get theirs once everything is out
+    let mut dirs = Vec::new();
+    for entry in archive.entries() {
+        if !wanted.is_empty() && !wanted.iter().any(|name| archive_entry_matches(&entry.path, name)) {
+            continue;
+        }
+        let target = dest.join(&entry.path);
+        let mut dir = dest.to_path_buf();
+        for part in Path::new(&entry.path).iter() {
+            dir.push(part);
+            if fs::symlink_metadata(long_path(&dir)).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(io::Error::other(format!("{} is a symlink, refusing to extract through it", dir.display())));
+            }
+        }
+        match entry.kind {
+            EntryKind::Dir => {
+                fs::create_dir_all(long_path(&target))?;
+                dirs.extend(entry.meta.map(|meta| (target, meta)));
+            }
+            EntryKind::File => {
+                let data = if entry.is_encrypted() {
+                    extract_encrypted(archive, entry, keys)
+                } else {
+                    archive.extract(entry)
+                };
+                let data = data.map_err(|e| io::Error::other(format!("{}: {}", entry.path, e)))?;
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(long_path(parent))?;
+                }
+                fs::write(long_path(&target), data)?;
+                if let Some(meta) = &entry.meta {
+                    restore_meta(&long_path(&target), meta, perms)?;
+                }
+            }
+            EntryKind::Symlink => {
+                let link = archive.extract(entry).map_err(|e| io::Error::other(format!("{}: {}", entry.path, e)))?;
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(long_path(parent))?;
+                }
+                // replaces a file from an earlier extraction, as fs::write would
+                if fs::symlink_metadata(long_path(&target)).is_ok_and(|m| !m.is_dir()) {
+                    fs::remove_file(long_path(&target))?;
+                }
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(String::from_utf8_lossy(&link).as_ref(), long_path(&target))?;
+                #[cfg(not(unix))]
+                eprintln!("Skipping symlink {} -> {}", entry.path, String::from_utf8_lossy(&link));
+            }
+        }
+        extracted += 1;
+    }
+    // deepest first, so a parent's mode can't lock out its children
+    for (dir, meta) in dirs.iter().rev() {
+        restore_meta(&long_path(dir), meta, perms)?;
+    }
+    Ok(extracted)
+}
+
+// Each `add --encrypt` run may use its own key, so an entry none of `keys`
+// opens asks for another, which is kept for the entries after it. An empty
+// answer, or one already tried, gives up on the entry.
+fn extract_encrypted(archive: &Archive, entry: &Entry, keys: &mut Vec<SecretKey>) -> Result<Vec<u8>, RsZipError> {
+    for key in keys.iter() {
+        match archive.extract_with_password(entry, key.as_bytes()) {
+            Err(RsZipError::BadKey(_)) => continue,
+            result => return result,
+        }
+    }
+    let mut wrong: Vec<SecretKey> = Vec::new();
+    loop {
+        if !keys.is_empty() || !wrong.is_empty() {
+            eprintln!("That key doesn't open {}.", entry.path);
+        }
+        let key = ask_key();
+        let tried = keys.iter().chain(&wrong).any(|k| ct_eq(k.as_bytes(), key.as_bytes()));
+        if key.as_bytes().is_empty() || tried {
+            return Err(RsZipError::BadKey("wrong password for encrypted archive entry"));
+        }
+        match archive.extract_with_password(entry, key.as_bytes()) {
+            Err(RsZipError::BadKey(_)) => wrong.push(key),
+            result => {
+                keys.push(key);
+                return result;
+            }
+        }
+    }
+}
+
+// ======================
+// STRUCTURE DUMP
+// ======================
+// Offset-annotated walk over a compressed file, for debugging interop and
+// corruption reports. Mirrors the parsing in `decompress`.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn dump_field(offset: usize, bytes: &[u8], name: &str, value: &str) {
+    println!("{:08x}  {:<24} {:<12} {}", offset, hex(bytes), name, value);
+}
+
+fn dump_symbol(b: u8) -> String {
+    if b.is_ascii_graphic() { format!("'{}'", b as char) } else { format!("0x{:02x}", b) }
+}
+
+// one line per (length, repeat) byte of a canonical table
+fn dump_code_table(data: &[u8], base: usize) -> Result<(), RsZipError> {
+    dump_field(base, &data[..1], "", "  canonical code lengths");
+    let mut symbol = 0usize;
+    for (i, &run) in data.iter().enumerate().skip(1) {
+        let (len, repeat) = (run >> 4, (run & 0x0f) as usize + 1);
+        let last = symbol + repeat - 1;
+        if last > 255 {
+            return Err(RsZipError::CorruptData("huffman code length table"));
+        }
+        let symbols = if repeat == 1 {
+            dump_symbol(symbol as u8)
+        } else {
+            format!("{}-{}", dump_symbol(symbol as u8), dump_symbol(last as u8))
+        };
+        let value = if len == 0 { format!("  {} unused", symbols) } else { format!("  {} {} bits", symbols, len) };
+        dump_field(base + i, &data[i..i + 1], "", &value);
+        symbol = last + 1;
+    }
+    if symbol < 256 {
+        return Err(RsZipError::Truncated("huffman table"));
+    }
+    Ok(())
+}
+
+fn dump_tree(data: &[u8], base: usize, idx: &mut usize, depth: usize) -> Result<(), RsZipError> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("huffman tree");
+    let offset = *idx;
+    let indent = "  ".repeat(depth);
+    if depth > 256 {
+        return Err(RsZipError::CorruptData("huffman tree too deep"));
+    }
+    if *data.get(*idx).ok_or(TRUNCATED)? == 1 {
+        let b = *data.get(*idx + 1).ok_or(TRUNCATED)?;
+        *idx += 2;
+        dump_field(base + offset, &data[offset..*idx], "", &format!("{}leaf {}", indent, dump_symbol(b)));
+    } else {
+        *idx += 1;
+        dump_field(base + offset, &data[offset..*idx], "", &format!("{}node", indent));
+        dump_tree(data, base, idx, depth + 1)?;
+        dump_tree(data, base, idx, depth + 1)?;
+    }
+    Ok(())
+}
+
+// fields are printed as they parse, so a damaged file shows up to the error
+// the codecs byte in words, which FLAG_CODEC and FLAG_BLOCKS empty
+fn describe_codecs(codecs: u8, flags: u16) -> String {
+    let stages = [
+        (CODEC_LZ77, "lz77"),
+        (CODEC_LZW, "lzw"),
+        (CODEC_RLE, "rle"),
+        (CODEC_BWT, "bwt"),
+        (CODEC_HUFFMAN, "huffman"),
+        (CODEC_RANGE, "range"),
+        (CODEC_ADAPTIVE, "adaptive huffman"),
+    ];
+    let names: Vec<&str> = stages
+        .iter()
+        .filter(|(bit, _)| codecs & bit != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    match codecs {
+        _ if flags & FLAG_CODEC != 0 => "none built in, a registered codec".to_string(),
+        _ if flags & FLAG_BLOCKS != 0 => "none here, each block has its own".to_string(),
+        CODEC_STORED => "none, stored".to_string(),
+        _ => names.join(" + "),
+    }
+}
+
+fn dump_file(filedata: &[u8], full: bool) -> Result<(), RsZipError> {
+    const TRUNCATED: RsZipError = RsZipError::Truncated("header");
+    let mut r = Reader::new(filedata);
+    let mut at = 0;
+    let mut show = |end: usize, name: &str, value: String| {
+        dump_field(at, &filedata[at..end], name, &value);
+        at = end;
+    };
+    let magic = r.bytes(MAGIC.len()).ok_or(TRUNCATED)?;
+    show(r.pos, "magic", if magic == MAGIC { "RSZ1".to_string() } else { "missing (legacy file?)".to_string() });
+    if magic != MAGIC {
+        return Err(RsZipError::CorruptHeader("no RSZ1 signature".into()));
+    }
+    let version = r.u8().ok_or(TRUNCATED)?;
+    show(r.pos, "version", format!("{} (this build reads up to {})", version, FORMAT_VERSION));
+    let codecs = r.u8().ok_or(TRUNCATED)?;
+    // FLAG_CODEC and FLAG_BLOCKS change what the codecs byte and lz_len
+    // mean, and the flags come later: min_match is at 14, their low byte at 19
+    let early_flags = match filedata.get(14) {
+        Some(m) if m & FLAGS_PRESENT != 0 => filedata.get(19).map_or(0, |&f| f as u16),
+        _ => 0,
+    };
+    let (registered, blocked) = (early_flags & FLAG_CODEC != 0, early_flags & FLAG_BLOCKS != 0);
+    show(r.pos, "codecs", describe_codecs(codecs, early_flags));
+    let lz_len = r.u32().ok_or(TRUNCATED)?;
+    let lz_what = match codecs {
+        _ if registered => "registered codec input",
+        _ if blocked => "original data",
+        CODEC_STORED => "stored data",
+        CODEC_LZW => "lzw output",
+        _ if codecs & CODEC_RLE != 0 => "run-length output",
+        _ if codecs & CODEC_BWT != 0 => "bwt blocks",
+        _ => "token stream",
+    };
+    show(r.pos, "lz_len", format!("{} bytes of {}", lz_len, lz_what));
+    let tree_len = r.u32().ok_or(TRUNCATED)? as usize;
+    show(r.pos, "tree_len", format!("{} bytes", tree_len));
+    let min_match = r.u8().ok_or(TRUNCATED)?;
+    let has_flags = min_match & FLAGS_PRESENT != 0;
+    show(r.pos, "min_match", format!("{}{}", min_match & !FLAGS_PRESENT, if has_flags { ", flags follow" } else { "" }));
+    let dict_id = r.u32().ok_or(TRUNCATED)?;
+    show(r.pos, "dict_id", if dict_id == 0 { "none".to_string() } else { format!("{:08x}", dict_id) });
+    if has_flags {
+        let flags = r.u16().ok_or(TRUNCATED)?;
+        let note = match flags {
+            _ if flags & FLAG_ORDER1 != 0 => " (order-1 range model)",
+            _ if flags & FLAG_CODEC != 0 => " (registered codec)",
+            _ if flags & FLAG_BLOCKS != 0 => " (independent blocks)",
+            _ => "",
+        };
+        show(r.pos, "flags", format!("critical {:#04x}{}, optional {:#04x}", flags & CRITICAL_FLAGS, note, flags >> 8));
+        if flags & !CRITICAL_FLAGS != 0 {
+            let extras_len = r.u32().ok_or(TRUNCATED)? as usize;
+            show(r.pos, "extras_len", format!("{} bytes", extras_len));
+            let extras_end = r.pos + extras_len;
+            if flags & FLAG_CRC32 != 0 {
+                let crc = r.u32().ok_or(TRUNCATED)?;
+                show(r.pos, "crc32", format!("{:08x} of the original data", crc));
+            }
+            if flags & FLAG_SEEK_TABLE != 0 {
+                let count = r.u32().ok_or(TRUNCATED)?;
+                show(r.pos, "seek table", format!("{} blocks", count));
+                for i in 0..count {
+                    let offset = r.u32().ok_or(TRUNCATED)?;
+                    show(r.pos, "offset", format!("block {} at body + {}", i, offset));
+                }
+            }
+            let rest = extras_end.saturating_sub(r.pos);
+            r.bytes(rest).ok_or(TRUNCATED)?;
+            if rest > 0 {
+                show(r.pos, "extras", "(skipped)".to_string());
+            }
+        }
+    }
+    let filter = read_filter(&mut r)?;
+    show(r.pos, "filter", filter.describe());
+    let mut idx = r.pos;
+
+    let huffman = codecs & CODEC_HUFFMAN != 0;
+    if huffman {
+        let tree_bytes = filedata.get(idx..idx + tree_len).ok_or(RsZipError::Truncated("huffman table"))?;
+        match tree_bytes.first() {
+            Some(0) => {
+                println!("{:08x}  huffman tree, pre-order (00 = node, 01 xx = leaf)", idx);
+                dump_tree(tree_bytes, idx, &mut 0, 1)?;
+            }
+            Some(_) => {
+                println!("{:08x}  huffman code table, (length << 4 | repeat - 1) per byte", idx);
+                dump_code_table(tree_bytes, idx)?;
+            }
+            None => return Err(RsZipError::Truncated("huffman table")),
+        }
+        idx += tree_len;
+    }
+
+    let payload = &filedata[idx..];
+    if let (true, Some(&id)) = (registered, payload.first()) {
+        let name = codecs::by_id(id).map_or("not registered here".to_string(), |c| c.name().to_string());
+        println!("{:08x}  codec id {} ({})", idx, id, name);
+    }
+    if blocked {
+        let block_size = u32_at(payload, 0).ok_or(RsZipError::Truncated("block size"))?;
+        println!("{:08x}  blocks of {} bytes, [index][packed length][rsz file] each", idx, block_size);
+        let mut at = 4;
+        while let (Some(index), Some(len)) = (u32_at(payload, at), u32_at(payload, at + 4)) {
+            println!("{:08x}  block {}, {} bytes packed", idx + at, index, len);
+            at += 8 + len as usize;
+        }
+    }
+    let body = match codecs {
+        _ if registered => "codec id + that codec's output",
+        _ if blocked => "blocks",
+        CODEC_STORED => "stored data",
+        CODEC_LZW => "lzw codes, 9 to 16 bits each",
+        CODEC_RLE => "run-length data",
+        CODEC_BWT => "bwt blocks, [primary][length][run-length data] each",
+        _ if codecs & CODEC_RANGE != 0 => "range coded bytes, adaptive order-0",
+        _ if codecs & CODEC_ADAPTIVE != 0 => "adaptive huffman bits",
+        _ if huffman => "huffman bits",
+        _ => "token stream",
+    };
+    println!("{:08x}  {}, {} bytes", idx, body, payload.len());
+    let shown = if full { payload.len() } else { payload.len().min(256) };
+    for (row, chunk) in payload[..shown].chunks(16).enumerate() {
+        println!("{:08x}  {}", idx + row * 16, hex(chunk));
+    }
+    if shown < payload.len() {
+        println!("          ... {} more bytes (--all to show)", payload.len() - shown);
+    }
+    println!("{:08x}  end of file", filedata.len());
+    Ok(())
+}
+
+// ======================
+// WINDOWS LONG PATHS
+// ======================
+// Win32 file calls fail past MAX_PATH (260 characters) unless the path has
+// the `\\?\` extended-length prefix, and backup trees routinely go deeper.
+// The prefix also disables `/` and `..` handling, so the path is made
+// absolute (which normalizes it) first. Applied to walk roots as well, since
+// children inherit the prefix from read_dir. A no-op elsewhere.
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    let Some(text) = path.to_str() else { return path.to_path_buf() };
+    if text.starts_with(r"\\?\") {
+        return path.to_path_buf();
+    }
+    let Some(abs) = std::path::absolute(path).ok().and_then(|p| p.to_str().map(str::to_string)) else {
+        return path.to_path_buf();
+    };
+    match abs.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", abs)),
+    }
+}
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// ======================
+// I/O THROTTLING
+// ======================
+// `--limit-rate` caps the combined read+write throughput of the process so
+// scheduled jobs leave disk bandwidth for everything else on the machine.
+const IO_CHUNK: usize = 64 * 1024;
+
+struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    done: u64,
+}
+
+static THROTTLE: Mutex<Option<Throttle>> = Mutex::new(None);
+
+fn set_rate_limit(bytes_per_sec: u64) {
+    *THROTTLE.lock().unwrap() = Some(Throttle { bytes_per_sec, start: Instant::now(), done: 0 });
+}
+
+fn rate_limited() -> bool {
+    THROTTLE.lock().unwrap().is_some()
+}
+
+// accounts for `n` transferred bytes, sleeping until the average is back
+// under the limit
+fn throttle(n: usize) {
+    let wait = {
+        let mut guard = THROTTLE.lock().unwrap();
+        let Some(t) = guard.as_mut() else { return };
+        let due = Duration::from_secs_f64(t.done as f64 / t.bytes_per_sec as f64);
+        // time spent compressing is not banked as credit for a later burst
+        if t.start.elapsed() > due + Duration::from_secs(1) {
+            t.start = Instant::now();
+            t.done = 0;
+        }
+        t.done += n as u64;
+        let due = Duration::from_secs_f64(t.done as f64 / t.bytes_per_sec as f64);
+        due.saturating_sub(t.start.elapsed())
+    };
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+// "20M", "512K", "1G" or plain bytes (per second, for rates)
+fn parse_size(spec: &str) -> Option<u64> {
+    let (digits, scale) = match spec.as_bytes().last()? {
+        b'K' | b'k' => (&spec[..spec.len() - 1], 1 << 10),
+        b'M' | b'm' => (&spec[..spec.len() - 1], 1 << 20),
+        b'G' | b'g' => (&spec[..spec.len() - 1], 1 << 30),
+        _ => (spec, 1),
+    };
+    digits.parse::<u64>().ok().filter(|&n| n > 0).map(|n| n * scale)
+}
+
+// http(s):// inputs are only read as streams, see `Download`
+fn read_input(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return read_throttled(&mut io::stdin().lock());
+    }
+    if path.to_str().is_some_and(is_url) {
+        let msg = "URL inputs are coded as they download, never held whole: use compress --stream or encrypt --stream";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    let path = long_path(path);
+    if !rate_limited() {
+        return fs::read(path);
+    }
+    read_throttled(&mut fs::File::open(path)?)
+}
+
+fn read_throttled(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; IO_CHUNK];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..n]);
+        throttle(n);
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("https://") || path.starts_with("http://")
+}
+
+// A URL downloaded with curl, read as it arrives, so nothing touches the
+// local disk or piles up in memory on the way to the compressor. A failed
+// download is an error at the end of the stream rather than a short one.
+struct Download {
+    child: std::process::Child,
+    stdout: std::process::ChildStdout,
+    url: String,
+}
+
+impl Download {
+    fn start(url: &str) -> io::Result<Download> {
+        let mut child = Command::new("curl").args(["-fsSL", "--", url]).stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Download { child, stdout, url: url.to_string() })
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("download of {} failed with {}", self.url, status)));
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn write_throttled(w: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(IO_CHUNK) {
+        w.write_all(chunk)?;
+        throttle(chunk.len());
+    }
+    w.flush()
+}
+
+// ======================
+// MAPPED INPUT
+// ======================
+// With the `mmap` feature (on by default) compress, decompress and cat map a
+// regular input file instead of reading it, so a 10 GB input is paged in
+// from the page cache rather than copied into a 10 GB buffer first. Pipes,
+// URLs, empty files and --limit-rate reads go through read_input, as does
+// everything when mapping fails. A file truncated while mapped ends the
+// process with SIGBUS, the usual cost of mapping.
+enum InputData {
+    Read(Vec<u8>),
+    #[cfg(all(unix, feature = "mmap"))]
+    Mapped(mapped::Mapping),
+}
+
+impl std::ops::Deref for InputData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputData::Read(data) => data,
+            #[cfg(all(unix, feature = "mmap"))]
+            InputData::Mapped(mapping) => mapping.bytes(),
+        }
+    }
+}
+
+fn open_input(path: &str) -> io::Result<InputData> {
+    #[cfg(all(unix, feature = "mmap"))]
+    if path != "-" && !is_url(path) && !rate_limited()
+        && let Some(mapping) = mapped::Mapping::open(&long_path(Path::new(path)))?
+    {
+        return Ok(InputData::Mapped(mapping));
+    }
+    read_input(path).map(InputData::Read)
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+mod mapped {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::raw::{c_int, c_long, c_void};
+    use std::path::Path;
+
+    const PROT_READ: c_int = 1;
+    const MAP_PRIVATE: c_int = 2;
+
+    unsafe extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    pub struct Mapping {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    // SAFETY: the mapping is read-only and owned, like a Box<[u8]>
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl Mapping {
+        // None for what has to be read instead: pipes and devices, empty
+        // files (which can't be mapped) and failed mappings
+        pub fn open(path: &Path) -> io::Result<Option<Mapping>> {
+            let file = File::open(path)?;
+            let meta = file.metadata()?;
+            if !meta.is_file() || meta.len() == 0 {
+                return Ok(None);
+            }
+            let Ok(len) = usize::try_from(meta.len()) else { return Ok(None) };
+            // SAFETY: a fresh private read-only mapping of an open file; the
+            // fd may close once it exists
+            let ptr = unsafe { mmap(std::ptr::null_mut(), len, PROT_READ, MAP_PRIVATE, file.as_raw_fd(), 0) };
+            // MAP_FAILED
+            if ptr as isize == -1 {
+                return Ok(None);
+            }
+            Ok(Some(Mapping { ptr, len }))
+        }
+
+        pub fn bytes(&self) -> &[u8] {
+            // SAFETY: `len` readable bytes from `ptr` until drop
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: unmaps exactly what `open` mapped, once
+            unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+// ======================
+// BACKGROUND PRIORITY
+// ======================
+// `--nice` drops CPU and I/O priority for the whole run. It is applied before
+// any thread is spawned, so worker threads (which on Linux carry their own
+// niceness) inherit it.
+#[cfg(unix)]
+mod os_priority {
+    use std::io;
+    use std::os::raw::{c_int, c_long, c_uint};
+
+    const PRIO_PROCESS: c_int = 0;
+    const NICE_LEVEL: c_int = 10;
+
+    unsafe extern "C" {
+        fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+        #[cfg(target_os = "linux")]
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    const SYS_IOPRIO_SET: Option<c_long> = Some(251);
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    const SYS_IOPRIO_SET: Option<c_long> = Some(30);
+    #[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    const SYS_IOPRIO_SET: Option<c_long> = None;
+
+    pub fn lower() -> io::Result<()> {
+        // SAFETY: plain syscall wrapper, no pointers involved
+        if unsafe { setpriority(PRIO_PROCESS, 0, NICE_LEVEL) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(number) = SYS_IOPRIO_SET {
+            // like `ionice -c2 -n7`: best-effort class, lowest level
+            const IOPRIO_WHO_PROCESS: c_long = 1;
+            const IOPRIO_PRIO: c_long = (2 << 13) | 7;
+            // SAFETY: ioprio_set takes three integers
+            if unsafe { syscall(number, IOPRIO_WHO_PROCESS, 0 as c_long, IOPRIO_PRIO) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod os_priority {
+    use std::ffi::c_void;
+    use std::io;
+
+    // also lowers I/O and memory priority
+    const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+
+    unsafe extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn SetPriorityClass(process: *mut c_void, class: u32) -> i32;
+    }
+
+    pub fn lower() -> io::Result<()> {
+        // SAFETY: the pseudo-handle from GetCurrentProcess needs no cleanup
+        if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod os_priority {
+    pub fn lower() -> std::io::Result<()> {
+        Err(std::io::Error::other("not supported on this platform"))
+    }
+}
+
+// ======================
+// OUTPUT BACKENDS
+// ======================
+// Where finished output goes. Remote backends pipe into the standard
+// client tools (aws cli, ssh), so nothing is staged on disk, but like every
+// backend they take the whole output at once, held in memory.
+// Send + Sync so one backend can be shared by upload threads
+trait Backend: Send + Sync {
+    fn store(&self, data: &[u8]) -> io::Result<()>;
+}
+
+struct StdoutBackend;
+impl Backend for StdoutBackend {
+    fn store(&self, data: &[u8]) -> io::Result<()> {
+        write_throttled(&mut StdoutPipe, data)
+    }
+}
+
+// stdout as the "-" output. A reader that stops early (`rszip cat x.rsz |
+// head`) ends rszip quietly, as it would cat, instead of with a Broken pipe
+// error: the exit status is 141, what shells show for death by SIGPIPE.
+struct StdoutPipe;
+
+impl StdoutPipe {
+    fn quiet<T>(result: io::Result<T>) -> io::Result<T> {
+        if result.as_ref().is_err_and(|e| e.kind() == io::ErrorKind::BrokenPipe) {
+            process::exit(141);
+        }
+        result
+    }
+}
+
+impl Write for StdoutPipe {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        StdoutPipe::quiet(io::stdout().lock().write(data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        StdoutPipe::quiet(io::stdout().lock().flush())
+    }
+}
+
+struct LocalBackend {
+    path: String,
+}
+impl Backend for LocalBackend {
+    fn store(&self, data: &[u8]) -> io::Result<()> {
+        write_throttled(&mut fs::File::create(long_path(Path::new(&self.path)))?, data)
+    }
+}
+
+// s3://bucket/key
+struct S3Backend {
+    url: String,
+}
+impl Backend for S3Backend {
+    fn store(&self, data: &[u8]) -> io::Result<()> {
+        pipe_to_command(Command::new("aws").args(["s3", "cp", "-", &self.url]), data)
+    }
+}
+
+// ssh://host/path, written by `cat` on the remote shell: plain ssh, not
+// the SFTP subsystem. A host can't start with '-', where ssh would take it
+// for an option.
+struct SshBackend {
+    host: String,
+    path: String,
+}
+impl Backend for SshBackend {
+    fn store(&self, data: &[u8]) -> io::Result<()> {
+        if self.host.is_empty() || self.host.starts_with('-') {
+            let msg = format!("bad ssh host {:?}", self.host);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let quoted = format!("'{}'", self.path.replace('\'', "'\\''"));
+        pipe_to_command(Command::new("ssh").args(["--", &self.host, "cat", ">", &quoted]), data)
+    }
+}
+
+fn pipe_to_command(cmd: &mut Command, data: &[u8]) -> io::Result<()> {
+    let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+    write_throttled(&mut child.stdin.take().unwrap(), data)?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("upload command failed with {}", status)));
+    }
+    Ok(())
+}
+
+fn backend_for(dest: &str) -> Box<dyn Backend> {
+    if dest == "-" {
+        Box::new(StdoutBackend)
+    } else if dest.starts_with("s3://") {
+        Box::new(S3Backend { url: dest.to_string() })
+    } else if let Some(rest) = dest.strip_prefix("ssh://") {
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        Box::new(SshBackend { host: host.to_string(), path: format!("/{}", path) })
+    } else {
+        Box::new(LocalBackend { path: dest.to_string() })
+    }
+}
+
+fn write_output(dest: &str, data: &[u8]) {
+    or_exit(backend_for(dest).store(data), dest);
+}
+
+// ======================
+// STREAMING MODE
+// ======================
+// `--stream` pipes data through the library's Lz77Encoder + HuffmanEncoder
+// chain, so memory stays at a block or two whatever the input size. The
+// output is the block stream from format.rs, not the whole-file format.
+// Inputs may be local files, "-" (stdin) or http(s):// URLs, outputs local
+// files or "-". With `--entropy adaptive` AdaptiveHuffmanEncoder replaces
+// HuffmanEncoder, and decoding tells the two apart by ADAPTIVE_MAGIC.
+// `encrypt --stream` writes sealed frames (rszip::frame) instead.
+struct Throttled<T>(T);
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        throttle(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        throttle(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn open_stream_input(path: &str) -> io::Result<Box<dyn Read>> {
+    Ok(match path {
+        "-" => Box::new(io::stdin().lock()),
+        url if is_url(url) => Box::new(Download::start(url)?),
+        _ => Box::new(fs::File::open(long_path(Path::new(path)))?),
+    })
+}
+
+fn open_stream_output(dest: &str) -> io::Result<Box<dyn Write>> {
+    Ok(match dest {
+        "-" => Box::new(StdoutPipe),
+        _ => Box::new(io::BufWriter::new(fs::File::create(long_path(Path::new(dest)))?)),
+    })
+}
+
+// whichever entropy decoder the stream was written with
+fn open_entropy_decoder<'a>(mut source: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut head = Vec::new();
+    (&mut source).take(ADAPTIVE_MAGIC.len() as u64).read_to_end(&mut head)?;
+    let adaptive = head == ADAPTIVE_MAGIC;
+    let source = io::Cursor::new(head).chain(source);
+    Ok(if adaptive { Box::new(AdaptiveHuffmanDecoder::new(source)) } else { Box::new(HuffmanDecoder::new(source)) })
+}
+
+// bytes written through it, for the summary after `compress --stream`
+struct Counted<W>(W, u64);
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1 += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// (bytes read from `input`, bytes written to `output`)
+fn stream_compress(input: &str, output: &str, min_match: usize, entropy: Entropy) -> io::Result<(u64, u64)> {
+    let sink = Throttled(Counted(open_stream_output(output)?, 0));
+    let mut source = Throttled(open_stream_input(input)?);
+    if entropy == Entropy::Adaptive {
+        let mut encoder = Lz77Encoder::new(AdaptiveHuffmanEncoder::new(sink), min_match);
+        let read = io::copy(&mut source, &mut encoder)?;
+        let mut sink = encoder.finish()?.finish()?;
+        sink.flush()?;
+        return Ok((read, sink.0.1));
+    }
+    let mut encoder = Lz77Encoder::new(HuffmanEncoder::new(sink), min_match);
+    let read = io::copy(&mut source, &mut encoder)?;
+    let mut sink = encoder.finish()?.finish()?;
+    sink.flush()?;
+    Ok((read, sink.0.1))
+}
+
+// bytes written to `output`
+fn stream_decompress(input: &str, output: &str) -> io::Result<u64> {
+    let mut decoder = Lz77Decoder::new(open_entropy_decoder(Throttled(open_stream_input(input)?))?);
+    let mut sink = Throttled(open_stream_output(output)?);
+    let written = io::copy(&mut decoder, &mut sink)?;
+    sink.flush()?;
+    Ok(written)
+}
+
+// `encrypt --stream`: a sealed framed stream (rszip::frame), compressed and
+// encrypted a block at a time, so a download is never held whole
+fn stream_seal(input: &str, output: &str, key: &SecretKey) -> io::Result<()> {
+    let sink = Throttled(open_stream_output(output)?);
+    let mut encoder = FrameEncoder::new(sink, Some(frame_key(key, FRAME_LABEL_FILE)))?;
+    io::copy(&mut Throttled(open_stream_input(input)?), &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+fn stream_open(input: &str, output: &str, key: &SecretKey) -> io::Result<()> {
+    let mut decoder = FrameDecoder::new(Throttled(open_stream_input(input)?), Some(frame_key(key, FRAME_LABEL_FILE)));
+    let mut sink = Throttled(open_stream_output(output)?);
+    io::copy(&mut decoder, &mut sink)?;
+    sink.flush()
+}
+
+// ======================
+// TCP TUNNEL
+// ======================
+// Two instances relay a TCP stream: the client side accepts plain
+// connections and forwards them compressed to the server side, which
+// restores them and connects to the real target. The compressed leg is a
+// framed stream (rszip::frame) flushed after every read from the plain socket.
+
+#[derive(Clone, Copy, PartialEq)]
+enum TunnelSide {
+    // plain on the listen side, compressed on the connect side
+    Client,
+    // compressed on the listen side, plain on the connect side
+    Server,
+}
+
+// the connections share one key per direction rather than copies of them
+fn run_tunnel(listen: &str, connect: &str, side: TunnelSide, key: Option<SecretKey>) -> io::Result<()> {
+    let (client, server) = (FRAME_LABEL_CLIENT, FRAME_LABEL_SERVER);
+    let (send, receive) = if side == TunnelSide::Client { (client, server) } else { (server, client) };
+    let keys = key.map(|key| (frame_key(&key, send), frame_key(&key, receive)));
+    let listener = TcpListener::bind(listen)?;
+    println!("Tunnel listening on {}, forwarding to {}", listen, connect);
+    for incoming in listener.incoming() {
+        let accepted = match incoming {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        let upstream = match TcpStream::connect(connect) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Connect to {} failed: {}", connect, e);
+                continue;
+            }
+        };
+        let (plain, tunneled) = match side {
+            TunnelSide::Client => (accepted, upstream),
+            TunnelSide::Server => (upstream, accepted),
+        };
+        let keys = keys.clone();
+        thread::spawn(move || {
+            if let Err(e) = relay_connection(plain, tunneled, keys) {
+                eprintln!("Tunnel connection closed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// `keys` are (sending, receiving)
+fn relay_connection(plain: TcpStream, tunneled: TcpStream, keys: Option<(Arc<SecretKey>, Arc<SecretKey>)>) -> io::Result<()> {
+    let (plain_in, tunnel_out) = (plain.try_clone()?, tunneled.try_clone()?);
+    let (send, receive) = keys.unzip();
+    let outbound = thread::spawn(move || pump_encode(plain_in, tunnel_out, send));
+    let inbound = pump_decode(tunneled, plain, receive);
+    let outbound = outbound.join().unwrap_or_else(|_| Err(io::Error::other("encoder thread panicked")));
+    inbound.and(outbound)
+}
+
+fn pump_encode(mut plain: TcpStream, tunneled: TcpStream, key: Option<Arc<SecretKey>>) -> io::Result<()> {
+    let mut encoder = FrameEncoder::new(tunneled, key)?;
+    let mut buf = vec![0u8; FRAME_BLOCK];
+    loop {
+        let n = plain.read(&mut buf)?;
+        if n == 0 {
+            return encoder.finish()?.shutdown(Shutdown::Write);
+        }
+        encoder.write_all(&buf[..n])?;
+        encoder.flush()?;
+    }
+}
+
+fn pump_decode(tunneled: TcpStream, mut plain: TcpStream, key: Option<Arc<SecretKey>>) -> io::Result<()> {
+    let mut decoder = FrameDecoder::new(tunneled, key);
+    io::copy(&mut decoder, &mut plain)?;
+    plain.shutdown(Shutdown::Write)
+}
+
+// ======================
+// CLIPBOARD
+// ======================
+// Compressed blobs travel through the clipboard as base64 text. The
+// clipboard itself is reached through the platform's stock tools.
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// None on characters outside the alphabet; whitespace is skipped
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            break;
+        }
+        let v = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = acc << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// (program, args) pairs to try in order for reading and writing
+#[cfg(target_os = "macos")]
+const CLIPBOARD_TOOLS: &[(&[&str], &[&str])] = &[(&["pbpaste"], &["pbcopy"])];
+#[cfg(windows)]
+const CLIPBOARD_TOOLS: &[(&[&str], &[&str])] = &[(
+    &["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"],
+    &["powershell", "-NoProfile", "-Command", "$input | Set-Clipboard"],
+)];
+#[cfg(not(any(target_os = "macos", windows)))]
+const CLIPBOARD_TOOLS: &[(&[&str], &[&str])] = &[
+    (&["wl-paste", "--no-newline"], &["wl-copy"]),
+    (&["xclip", "-selection", "clipboard", "-o"], &["xclip", "-selection", "clipboard"]),
+    (&["xsel", "--clipboard", "--output"], &["xsel", "--clipboard", "--input"]),
+];
+
+fn clipboard_read() -> io::Result<Vec<u8>> {
+    for (read, _) in CLIPBOARD_TOOLS {
+        match Command::new(read[0]).args(&read[1..]).stderr(Stdio::null()).output() {
+            Ok(out) if out.status.success() => return Ok(out.stdout),
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other("no working clipboard tool found"))
+}
+
+fn clipboard_write(data: &[u8]) -> io::Result<()> {
+    for (_, write) in CLIPBOARD_TOOLS {
+        match pipe_to_command(Command::new(write[0]).args(&write[1..]), data) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other("no working clipboard tool found"))
+}
+
+// ======================
+// DAEMON
+// ======================
+// `rszip daemon` serves jobs over a Unix socket so editors and tools can skip
+// process start-up. One request per line, fields separated by tabs:
+//   compress<TAB>in<TAB>out   or   decompress<TAB>in<TAB>out
+// answered by "progress <stage>" lines and a final "ok <in> <out>" (sizes)
+// or "error <message>". Jobs run as the daemon's user, so the socket is
+// only for that user (0600, in a 0700 directory by default) and jobs may
+// only name local files: no -, URLs or s3:// and ssh:// destinations.
+#[cfg(unix)]
+mod daemon_os {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+    use std::path::Path;
+
+    unsafe extern "C" {
+        fn getuid() -> u32;
+    }
+
+    pub fn uid() -> u32 {
+        // SAFETY: getuid takes nothing and can't fail
+        unsafe { getuid() }
+    }
+
+    // `dir`, created 0700 if missing; one already there must be a real
+    // directory of ours that no one else can enter
+    pub fn private_dir(dir: &Path) -> io::Result<()> {
+        match fs::DirBuilder::new().mode(0o700).create(dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            result => return result,
+        }
+        let meta = fs::symlink_metadata(dir)?;
+        if !meta.is_dir() || meta.uid() != uid() || meta.mode() & 0o077 != 0 {
+            let msg = format!("{} is not a private directory of this user", dir.display());
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        }
+        Ok(())
+    }
+
+    // a stale socket from a previous run would make bind fail, but only
+    // our own sockets are removed to make way
+    pub fn clear_stale_socket(socket: &Path) -> io::Result<()> {
+        let meta = match fs::symlink_metadata(socket) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        if !meta.file_type().is_socket() || meta.uid() != uid() {
+            let msg = format!("{} exists and is not a socket of this user", socket.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        }
+        fs::remove_file(socket)
+    }
+}
+
+#[cfg(unix)]
+fn default_socket_path() -> io::Result<PathBuf> {
+    if let Ok(dir) = env::var("XDG_RUNTIME_DIR") {
+        return Ok(Path::new(&dir).join("rszip.sock"));
+    }
+    let dir = env::temp_dir().join(format!("rszip-{}", daemon_os::uid()));
+    daemon_os::private_dir(&dir)?;
+    Ok(dir.join("rszip.sock"))
+}
+
+#[cfg(unix)]
+fn run_daemon(socket: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    daemon_os::clear_stale_socket(socket)?;
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    println!("Daemon listening on {}", socket.display());
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        thread::spawn(move || match stream.try_clone() {
+            Ok(reader) => serve_jobs(io::BufReader::new(reader), stream),
+            Err(e) => {
+                let _ = writeln!(stream, "error {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_jobs(reader: impl io::BufRead, mut out: impl Write) {
+    for line in reader.lines() {
+        let Ok(line) = line else { return };
+        let reply = match line.split('\t').collect::<Vec<_>>()[..] {
+            [op @ ("compress" | "decompress"), input, output] => {
+                match run_daemon_job(op, input, output, &mut out) {
+                    Ok((read, written)) => format!("ok {} {}", read, written),
+                    Err(e) => format!("error {}", e),
+                }
+            }
+            _ => "error expected compress|decompress<TAB>in<TAB>out".to_string(),
+        };
+        if writeln!(out, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn is_local_path(path: &str) -> bool {
+    !path.is_empty() && path != "-" && !path.contains("://")
+}
+
+fn run_daemon_job(op: &str, input: &str, output: &str, out: &mut impl Write) -> io::Result<(usize, usize)> {
+    if !is_local_path(input) || !is_local_path(output) {
+        let msg = "daemon jobs take local file paths only";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    writeln!(out, "progress reading")?;
+    let data = read_input(input)?;
+    writeln!(out, "progress {}", if op == "compress" { "compressing" } else { "decompressing" })?;
+    let result = if op == "compress" {
+        compress(&data, &CompressOptions::default())?
+    } else {
+        decompress(&data, None)?
+    };
+    writeln!(out, "progress writing")?;
+    LocalBackend { path: output.to_string() }.store(&result)?;
+    Ok((data.len(), result.len()))
+}
+
+// ======================
+// RPC SERVICE
+// ======================
+// `rszip serve-api` exposes the codecs over HTTP/1.1 for sidecar use:
+//   POST /compress?min_match=4&entropy=adaptive, the `compress --stream` format
+//   POST /decompress, the same streams back
+//   POST /encrypt, POST /decrypt: framed streams as `tunnel` sends, under
+//        the key the server was started with
+//   GET  /  lists the methods as JSON
+// Bodies are raw bytes; requests may use Content-Length or chunked
+// encoding and responses are always chunked. Bodies are coded as they
+// arrive, never held whole, and one over the size limit gets a 413; a head
+// with a line over API_MAX_LINE or more than API_MAX_HEADERS headers gets a
+// 431. It listens on 127.0.0.1 unless --bind says otherwise. One request per
+// connection. Archive commands (add, list, extract, ...) aren't served: they
+// need to seek in the archive, which a streamed body can't offer.
+const API_MAX_BODY: u64 = 1 << 30;
+const API_MAX_LINE: usize = 8 * 1024;
+const API_MAX_HEADERS: usize = 100;
+
+struct ApiConfig {
+    max_body: u64,
+    key: Option<Arc<SecretKey>>,
+}
+
+fn run_api(bind: &str, port: u16, config: ApiConfig) -> io::Result<()> {
+    let listener = TcpListener::bind((bind, port))?;
+    // the address bound, so --port 0 tells the caller which one it got
+    println!("API listening on {}", listener.local_addr()?);
+    let config = Arc::new(config);
+    for stream in listener.incoming() {
+        let (stream, config) = (stream?, config.clone());
+        thread::spawn(move || {
+            if let Err(e) = serve_api_request(stream, &config) {
+                eprintln!("API request failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+struct ApiRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+}
+
+impl ApiRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+// a line of the head or of chunked framing; one past API_MAX_LINE is a
+// FileTooLarge error rather than a buffer that grows as long as the peer sends
+fn read_api_line(reader: &mut impl io::BufRead, line: &mut String) -> io::Result<usize> {
+    let n = io::BufRead::read_line(&mut reader.take(API_MAX_LINE as u64 + 1), line)?;
+    if n > API_MAX_LINE {
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("line over {} bytes", API_MAX_LINE)));
+    }
+    Ok(n)
+}
+
+// FileTooLarge for a head over the limits, which becomes a 431
+fn read_api_head(reader: &mut impl io::BufRead) -> io::Result<ApiRequest> {
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut line = String::new();
+    read_api_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else { return Err(bad("bad request line")) };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query.split('&').filter(|q| !q.is_empty())
+        .map(|q| {
+            let (k, v) = q.split_once('=').unwrap_or((q, ""));
+            (k.to_string(), v.to_string())
+        })
+        .collect();
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        if read_api_line(reader, &mut header)? == 0 {
+            return Err(bad("connection closed in headers"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == API_MAX_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("more than {} headers", API_MAX_HEADERS)));
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| bad("bad header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(ApiRequest { method: method.to_string(), path: path.to_string(), query, headers })
+}
+
+// A request body as a reader: `left` bytes of a Content-Length body, or of
+// the current chunk, until the zero-size chunk. Going past `limit` bytes in
+// total is a FileTooLarge error, which becomes a 413.
+struct ApiBody<R> {
+    inner: R,
+    chunked: bool,
+    left: u64,
+    read: u64,
+    limit: u64,
+    done: bool,
+}
+
+fn api_body<R: io::BufRead>(inner: R, req: &ApiRequest, limit: u64) -> io::Result<ApiBody<R>> {
+    let chunked = req.header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    let left = match req.header("Content-Length") {
+        Some(v) if !chunked => v.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?,
+        _ => 0,
+    };
+    if left > limit {
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("body over {} bytes", limit)));
+    }
+    Ok(ApiBody { inner, chunked, left, read: 0, limit, done: !chunked && left == 0 })
+}
+
+impl<R: io::BufRead> ApiBody<R> {
+    // the next chunk's size line, or the trailers after the last one
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if self.read > 0 {
+            let mut crlf = [0u8; 2];
+            self.inner.read_exact(&mut crlf)?;
+        }
+        // a long line here is bad framing, not a big body
+        let framing = |e: io::Error| if e.kind() == io::ErrorKind::FileTooLarge { bad("chunk line too long") } else { e };
+        let mut size_line = String::new();
+        read_api_line(&mut self.inner, &mut size_line).map_err(framing)?;
+        let size_hex = size_line.trim().split(';').next().unwrap_or("");
+        self.left = u64::from_str_radix(size_hex, 16).map_err(|_| bad("bad chunk size"))?;
+        if self.read.saturating_add(self.left) > self.limit {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("body over {} bytes", self.limit)));
+        }
+        if self.left == 0 {
+            // trailers end with an empty line
+            loop {
+                let mut trailer = String::new();
+                if read_api_line(&mut self.inner, &mut trailer).map_err(framing)? == 0 || trailer.trim().is_empty() {
+                    break;
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::BufRead> Read for ApiBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 && self.chunked && !self.done {
+            self.next_chunk()?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = buf.len().min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in body"));
+        }
+        (self.left, self.read) = (self.left - n as u64, self.read + n as u64);
+        self.done = !self.chunked && self.left == 0;
+        Ok(n)
+    }
+}
+
+// A chunked 200 response, whose head goes out with the first chunk: until
+// then an error can still be answered with its own status. After it, the
+// connection is dropped without the final chunk, which tells the client the
+// body is incomplete.
+struct ApiResponse<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+    started: bool,
+}
+
+impl<W: Write> ApiResponse<W> {
+    fn new(out: W) -> ApiResponse<W> {
+        ApiResponse { out, buf: Vec::with_capacity(IO_CHUNK), started: false }
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if !self.started {
+            write!(self.out, "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n")?;
+            write!(self.out, "Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n")?;
+            self.started = true;
+        }
+        if !self.buf.is_empty() {
+            write!(self.out, "{:x}\r\n", self.buf.len())?;
+            self.out.write_all(&self.buf)?;
+            self.out.write_all(b"\r\n")?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        self.out.write_all(b"0\r\n\r\n")?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Write for ApiResponse<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= IO_CHUNK {
+            self.send_chunk()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn send_api_response(out: &mut impl Write, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(out, "HTTP/1.1 {}\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", status, content_type)?;
+    for chunk in body.chunks(IO_CHUNK) {
+        write!(out, "{:x}\r\n", chunk.len())?;
+        out.write_all(chunk)?;
+        out.write_all(b"\r\n")?;
+    }
+    out.write_all(b"0\r\n\r\n")?;
+    out.flush()
+}
+
+fn api_error(out: &mut impl Write, status: &str, message: &str) -> io::Result<()> {
+    let body = format!("{{\"error\": {}}}", json_string(message));
+    send_api_response(out, status, "application/json", body.as_bytes())
+}
+
+// min_match and entropy coder for /compress
+fn api_stream_options(query: &[(String, String)]) -> Result<(usize, Entropy), String> {
+    let (mut min_match, mut entropy) = (Lz77Config::default().min_match, Entropy::Huffman);
+    for (key, value) in query {
+        match key.as_str() {
+            "min_match" => {
+                min_match = value.parse().ok().filter(|n| MIN_MATCH_RANGE.contains(n)).ok_or("min_match must be 2 to 8")?;
+            }
+            "entropy" => {
+                entropy = match value.as_str() {
+                    "huffman" => Entropy::Huffman,
+                    "adaptive" => Entropy::Adaptive,
+                    _ => return Err("entropy must be huffman or adaptive".into()),
+                };
+            }
+            _ => return Err(format!("unknown option {}", key)),
+        }
+    }
+    Ok((min_match, entropy))
+}
+
+// the body of a POST, coded into `response` as it is read
+fn api_stream<R: io::BufRead, W: Write>(req: &ApiRequest, body: ApiBody<R>, response: &mut ApiResponse<W>,
+    options: (usize, Entropy), key: Option<Arc<SecretKey>>) -> io::Result<()> {
+    let mut body = body;
+    match req.path.as_str() {
+        "/compress" if options.1 == Entropy::Adaptive => {
+            let mut encoder = Lz77Encoder::new(AdaptiveHuffmanEncoder::new(response), options.0);
+            io::copy(&mut body, &mut encoder)?;
+            encoder.finish()?.finish().map(drop)
+        }
+        "/compress" => {
+            let mut encoder = Lz77Encoder::new(HuffmanEncoder::new(response), options.0);
+            io::copy(&mut body, &mut encoder)?;
+            encoder.finish()?.finish().map(drop)
+        }
+        "/decompress" => io::copy(&mut Lz77Decoder::new(open_entropy_decoder(body)?), response).map(drop),
+        "/encrypt" => {
+            let mut encoder = FrameEncoder::new(response, key)?;
+            io::copy(&mut body, &mut encoder)?;
+            encoder.finish().map(drop)
+        }
+        _ => io::copy(&mut FrameDecoder::new(body, key), response).map(drop),
+    }
+}
+
+fn serve_api_request(stream: TcpStream, config: &ApiConfig) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    let mut reader = io::BufReader::new(stream);
+    let req = match read_api_head(&mut reader) {
+        Ok(req) => req,
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+            return api_error(&mut out, "431 Request Header Fields Too Large", &e.to_string());
+        }
+        Err(e) => return Err(e),
+    };
+    if req.method == "GET" && req.path == "/" {
+        let methods = br#"{"methods": ["compress", "decompress", "encrypt", "decrypt"]}"#;
+        return send_api_response(&mut out, "200 OK", "application/json", methods);
+    }
+    if req.method != "POST" {
+        return api_error(&mut out, "405 Method Not Allowed", "use POST");
+    }
+    if !["/compress", "/decompress", "/encrypt", "/decrypt"].contains(&req.path.as_str()) {
+        return api_error(&mut out, "404 Not Found", "unknown method");
+    }
+    if req.path.ends_with("crypt") && config.key.is_none() {
+        return api_error(&mut out, "400 Bad Request", "the server was started without a key");
+    }
+    let options = match api_stream_options(if req.path == "/compress" { &req.query[..] } else { &[] }) {
+        Ok(options) => options,
+        Err(e) => return api_error(&mut out, "400 Bad Request", &e),
+    };
+    let body = match api_body(&mut reader, &req, config.max_body) {
+        Ok(body) => body,
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => return api_error(&mut out, "413 Content Too Large", &e.to_string()),
+        Err(e) => return api_error(&mut out, "400 Bad Request", &e.to_string()),
+    };
+    let mut response = ApiResponse::new(&mut out);
+    match api_stream(&req, body, &mut response, options, config.key.clone()) {
+        Ok(()) => response.finish(),
+        // the head is out, so all that is left is to cut the body short
+        Err(e) if response.started => Err(e),
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => api_error(&mut out, "413 Content Too Large", &e.to_string()),
+        Err(e) => api_error(&mut out, "422 Unprocessable Entity", &e.to_string()),
+    }
+}
+
+// ======================
+// KEYSTORE
+// ======================
+// Named keys saved in ~/.rszip/keystore (or $RSZIP_KEYSTORE), encrypted under
+// a master password. File: a sealed file (crypto::seal) of the plaintext,
+// which is "RSZK" followed by [name_len u8][name][key_len u16][key] per
+// entry. Keystores from before sealing are [plain_len u32][feistel
+// ciphertext], with the magic doubling as the wrong-password check; they are
+// rewritten sealed the next time they are saved.
+const KEYSTORE_MAGIC: &[u8; 4] = b"RSZK";
+
+// master password of the unlocked keystore, kept for the rest of the session
+static KEYSTORE_MASTER: Mutex<Option<Arc<SecretKey>>> = Mutex::new(None);
+
+struct Keystore {
+    keys: Vec<(String, SecretKey)>,
+}
+
+impl Keystore {
+    // None if the password is wrong or the file is damaged
+    fn load(path: &Path, master: &SecretKey) -> Option<Keystore> {
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Some(Keystore { keys: Vec::new() }),
+            Err(_) => return None,
+        };
+        let (mut plain, plain_len) = if is_sealed(&file) {
+            let plain = open(&file, master.as_bytes()).ok()?;
+            let len = plain.len();
+            (plain, len)
+        } else {
+            // Unlike bare Feistel files given to `decrypt`, this one is only
+            // ever the user's own 0600 file, read once and then rewritten
+            // sealed; the magic inside still rejects a wrong password, and
+            // anyone able to rewrite it could as well replace it outright.
+            (feistel_decrypt(file.get(4..)?, master.as_bytes(), KeySchedule::Direct).ok()?, u32_at(&file, 0)? as usize)
+        };
+        let keys = Keystore::parse(plain.get(..plain_len)?);
+        zeroize(&mut plain);
+        Some(Keystore { keys: keys? })
+    }
+
+    fn parse(plain: &[u8]) -> Option<Vec<(String, SecretKey)>> {
+        if plain.get(0..4)? != KEYSTORE_MAGIC {
+            return None;
+        }
+        let mut keys = Vec::new();
+        let mut r = Reader::new(plain);
+        r.pos = 4;
+        while !r.rest().is_empty() {
+            let name_len = r.u8()? as usize;
+            let name = String::from_utf8(r.bytes(name_len)?.to_vec()).ok()?;
+            let key_len = r.u16()? as usize;
+            let key = r.bytes(key_len)?;
+            std::str::from_utf8(key).ok()?;
+            keys.push((name, SecretKey::new(key.to_vec())));
+        }
+        Some(keys)
+    }
+
+    fn save(&self, path: &Path, master: &SecretKey) -> io::Result<()> {
+        let mut plain = KEYSTORE_MAGIC.to_vec();
+        for (name, key) in &self.keys {
+            let (Ok(name_len), Ok(key_len)) = (u8::try_from(name.len()), u16::try_from(key.as_bytes().len())) else {
+                zeroize(&mut plain);
+                let msg = format!("key '{}' is too long to save (names to 255 bytes, keys to 65535)", name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            };
+            plain.push(name_len);
+            plain.extend_from_slice(name.as_bytes());
+            put_u16(&mut plain, key_len);
+            plain.extend_from_slice(key.as_bytes());
+        }
+        let file = seal(&plain, master.as_bytes(), Cipher::ChaCha20Poly1305, DEFAULT_KDF_ITERATIONS);
+        zeroize(&mut plain);
+        let file = file?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // a fresh 0600 file renamed over the old one, so the keystore is
+        // never readable by others nor lost to a save cut short
+        let tmp = path.with_extension("tmp");
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut out = options.open(&tmp)?;
+        out.write_all(&file)?;
+        out.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    fn get(&self, name: &str) -> Option<&SecretKey> {
+        self.keys.iter().find(|(n, _)| n == name).map(|(_, k)| k)
+    }
+}
+
+fn keystore_path() -> PathBuf {
+    if let Ok(path) = env::var("RSZIP_KEYSTORE") {
+        return PathBuf::from(path);
+    }
+    let home = env::var("HOME").or_else(|_| env::var("USERPROFILE")).unwrap_or_else(|_| ".".to_string());
+    Path::new(&home).join(".rszip").join("keystore")
+}
+
+// Opens the keystore, asking for the master password only the first time in
+// a session ($RSZIP_KEYSTORE_PASS skips the prompt entirely).
+fn unlock_keystore() -> Option<(Keystore, Arc<SecretKey>)> {
+    let mut cached = KEYSTORE_MASTER.lock().unwrap();
+    let master = match cached.clone() {
+        Some(master) => master,
+        None => Arc::new(match env::var("RSZIP_KEYSTORE_PASS") {
+            Ok(pass) => SecretKey::from(pass),
+            Err(_) => prompt_secret("Keystore password: "),
+        }),
+    };
+    let store = Keystore::load(&keystore_path(), &master)?;
+    *cached = Some(master.clone());
+    Some((store, master))
+}
+
+// a copy of the saved key, wiped when the caller drops it
+fn lookup_key(name: &str) -> Option<SecretKey> {
+    let (store, _) = unlock_keystore()?;
+    store.get(name).map(|key| SecretKey::new(key.as_bytes().to_vec()))
+}
+
+// ======================
+// OS KEYCHAIN
+// ======================
+// Keys referenced as `keychain:<service>/<account>` live in the platform
+// secret store: macOS Keychain (security), Secret Service (secret-tool) or
+// the Windows Credential Manager, where the target name is service/account.
+fn parse_keychain_ref(spec: &str) -> Option<(&str, &str)> {
+    let (service, account) = spec.strip_prefix("keychain:")?.split_once('/')?;
+    (!service.is_empty() && !account.is_empty()).then_some((service, account))
+}
+
+#[cfg(not(windows))]
+fn keychain_get(service: &str, account: &str) -> io::Result<String> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut c = Command::new("security");
+        c.args(["find-generic-password", "-s", service, "-a", account, "-w"]);
+        c
+    } else {
+        let mut c = Command::new("secret-tool");
+        c.args(["lookup", "service", service, "account", account]);
+        c
+    };
+    let out = cmd.stderr(Stdio::null()).output()?;
+    if !out.status.success() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no such keychain entry"));
+    }
+    let secret = String::from_utf8(out.stdout).map_err(|_| io::Error::other("keychain entry is not text"))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(not(windows))]
+fn keychain_set(service: &str, account: &str, secret: &str) -> io::Result<()> {
+    if cfg!(target_os = "macos") {
+        // a bare -w, last, makes security ask for the secret (and again to
+        // confirm) and read the answers from stdin, so it never shows in argv
+        if secret.contains(['\r', '\n']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "keychain secrets are one line"));
+        }
+        let mut cmd = Command::new("security");
+        cmd.args(["add-generic-password", "-U", "-s", service, "-a", account, "-w"]);
+        let answers = SecretKey::new(format!("{0}\n{0}\n", secret).into_bytes());
+        pipe_to_command(&mut cmd, answers.as_bytes())
+    } else {
+        let label = format!("rszip {}/{}", service, account);
+        let mut cmd = Command::new("secret-tool");
+        cmd.args(["store", "--label", &label, "service", service, "account", account]);
+        pipe_to_command(&mut cmd, secret.as_bytes())
+    }
+}
+
+#[cfg(windows)]
+mod wincred {
+    use std::ffi::c_void;
+    use std::io;
+
+    const CRED_TYPE_GENERIC: u32 = 1;
+    const CRED_PERSIST_LOCAL_MACHINE: u32 = 2;
+
+    #[repr(C)]
+    struct Credential {
+        flags: u32,
+        kind: u32,
+        target_name: *mut u16,
+        comment: *mut u16,
+        last_written: [u32; 2],
+        blob_size: u32,
+        blob: *mut u8,
+        persist: u32,
+        attribute_count: u32,
+        attributes: *mut c_void,
+        target_alias: *mut u16,
+        user_name: *mut u16,
+    }
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn CredReadW(target: *const u16, kind: u32, flags: u32, credential: *mut *mut Credential) -> i32;
+        fn CredWriteW(credential: *const Credential, flags: u32) -> i32;
+        fn CredFree(buffer: *mut c_void);
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    pub fn get(target: &str) -> io::Result<String> {
+        let target = wide(target);
+        let mut cred: *mut Credential = std::ptr::null_mut();
+        // SAFETY: target is NUL-terminated; on success cred is freed below
+        if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut cred) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: CredReadW returned a valid credential with blob_size bytes at blob
+        let blob = unsafe { std::slice::from_raw_parts((*cred).blob, (*cred).blob_size as usize).to_vec() };
+        unsafe { CredFree(cred as *mut c_void) };
+        String::from_utf8(blob).map_err(|_| io::Error::other("credential is not text"))
+    }
+
+    pub fn set(target: &str, user: &str, secret: &str) -> io::Result<()> {
+        let mut target = wide(target);
+        let mut user = wide(user);
+        let mut blob = secret.as_bytes().to_vec();
+        let cred = Credential {
+            flags: 0,
+            kind: CRED_TYPE_GENERIC,
+            target_name: target.as_mut_ptr(),
+            comment: std::ptr::null_mut(),
+            last_written: [0, 0],
+            blob_size: blob.len() as u32,
+            blob: blob.as_mut_ptr(),
+            persist: CRED_PERSIST_LOCAL_MACHINE,
+            attribute_count: 0,
+            attributes: std::ptr::null_mut(),
+            target_alias: std::ptr::null_mut(),
+            user_name: user.as_mut_ptr(),
+        };
+        // SAFETY: every pointer in cred outlives the call
+        if unsafe { CredWriteW(&cred, 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn keychain_get(service: &str, account: &str) -> io::Result<String> {
+    wincred::get(&format!("{}/{}", service, account))
+}
+
+#[cfg(windows)]
+fn keychain_set(service: &str, account: &str, secret: &str) -> io::Result<()> {
+    wincred::set(&format!("{}/{}", service, account), account, secret)
+}
+
+// ======================
+// SELF-TEST
+// ======================
+// `rszip selftest` round-trips every codec setting, filter and the cipher
+// over synthetic corpora and checks the hashes against published vectors,
+// for confidence on platforms the tool was never run on. Everything else,
+// edge cases and damaged input included, is in the unit tests and tests/.
+fn selftest_corpora() -> Vec<(&'static str, Vec<u8>)> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let random: Vec<u8> = (0..20_000).map(|_| next() as u8).collect();
+    let mut text = Vec::new();
+    while text.len() < 20_000 {
+        text.extend_from_slice(ENGLISH_WORDS[next() as usize % ENGLISH_WORDS.len()].as_bytes());
+        text.push(if next() % 12 == 0 { b'\n' } else { b' ' });
+    }
+    let mut runs = Vec::new();
+    while runs.len() < 20_000 {
+        let (byte, len) = (next() as u8 % 4, 1 + next() as usize % 300);
+        runs.extend(std::iter::repeat_n(byte, len));
+    }
+    let mut corpora = vec![("empty", Vec::new()), ("random", random.clone()), ("text", text), ("runs", runs)];
+    // sizes straddling block and word boundaries
+    for len in [1, 7, 8, 9, LONG_RANGE_BLOCK - 1, LONG_RANGE_BLOCK + 1, FRAME_BLOCK + 1] {
+        let name: &'static str = Box::leak(format!("random-{}", len).into_boxed_str());
+        corpora.push((name, random.iter().cycle().take(len).copied().collect()));
+    }
+    corpora
+}
+
+fn run_selftest() -> (usize, Vec<String>) {
+    let mut passed = 0;
+    let mut failures = Vec::new();
+    // errors fail the check like a wrong result; a panic is always a bug
+    let mut check = |name: String, test: &dyn Fn() -> Result<bool, RsZipError>| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(test)) {
+            Ok(Ok(true)) => passed += 1,
+            Ok(Ok(false)) => failures.push(format!("{}: wrong result", name)),
+            Ok(Err(e)) => failures.push(format!("{}: {}", name, e)),
+            Err(_) => failures.push(format!("{}: panicked", name)),
+        }
+    };
+
+    let hex = |data: &[u8]| hex_lower(data);
+    let unhex = |text: &str| -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    };
+    check("crc32 check value".into(), &|| Ok(crc32(b"123456789") == 0xcbf43926));
+    check("sha256 abc".into(), &|| {
+        Ok(hex(&sha256(b"abc")) == "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    });
+    check("pbkdf2-sha256 rfc 7914".into(), &|| {
+        Ok(hex(pbkdf2_sha256(b"passwd", b"salt", 1, 32).as_bytes()) == "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc")
+    });
+    check("poly1305 rfc 8439".into(), &|| {
+        let key: [u8; 32] = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").try_into().unwrap();
+        Ok(hex(&poly1305(&key, b"Cryptographic Forum Research Group")) == "a8061dc1305136c6c22b8baf0c0127a9")
+    });
+    check("chacha20-poly1305 rfc 8439".into(), &|| {
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plain = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+                      sunscreen would be it.";
+        let sealed = chacha20_poly1305_seal(&key, &nonce, &aad, plain);
+        let (ciphertext, tag) = sealed.split_at(plain.len());
+        let opened = chacha20_poly1305_open(&key, &nonce, &aad, &sealed)?;
+        Ok(hex(&ciphertext[..16]) == "d31a8d34648e60db7b86afbc53ef7ec2" && hex(tag) == "1ae10b594f09e26a7e902ecbd0600691"
+            && opened == plain)
+    });
+    check("length field at the 4 GiB boundary".into(), &|| {
+        let at_limit = len_u32(u32::MAX as usize)? == u32::MAX;
+        let past_limit = usize::try_from(1u64 << 32).map_or(true, |n| len_u32(n).is_err());
+        Ok(at_limit && past_limit)
+    });
+    let filters = [
+        Filter::None,
+        Filter::Sub { stride: 64, bpp: 3 },
+        Filter::Up { stride: 64, bpp: 1 },
+        Filter::Paeth { stride: 96, bpp: 4 },
+        Filter::Text { dict: ENGLISH_WORDS_ID },
+        Filter::X86,
+        Filter::Arm,
+    ];
+    let corpora = selftest_corpora();
+    let dictionary = corpora[2].1[..LZ77_WINDOW].to_vec();
+    for (corpus, data) in &corpora {
+        check(format!("gzip/{}", corpus), &|| {
+            Ok(gzip::decompress(&gzip::compress(data, CompressionLevel::Default, Some(corpus), 0))? == *data)
+        });
+        for level in [CompressionLevel::Fast, CompressionLevel::Default, CompressionLevel::Best] {
+            check(format!("deflate {:?}/{}", level, corpus), &|| {
+                Ok(deflate::decompress(&deflate::compress(data, level))? == *data)
+            });
+        }
+    }
+    for (corpus, data) in &corpora {
+        let level_options = |level| {
+            let mut opts = CompressOptions::default();
+            opts.set_level(level);
+            opts
+        };
+        let settings: [(&str, CompressOptions); 20] = [
+            ("greedy", CompressOptions::default()),
+            ("optimal", CompressOptions { parse: ParseMode::Optimal, ..Default::default() }),
+            ("level fast", level_options(CompressionLevel::Fast)),
+            ("level best", level_options(CompressionLevel::Best)),
+            ("long-range", CompressOptions { long_range: true, ..Default::default() }),
+            ("min-match 2", CompressOptions { lz77: Lz77Config { min_match: 2, ..Default::default() }, ..Default::default() }),
+            ("min-match 8", CompressOptions { lz77: Lz77Config { min_match: 8, ..Default::default() }, ..Default::default() }),
+            ("window 4K", CompressOptions { lz77: Lz77Config { window_size: 4096, ..Default::default() }, ..Default::default() }),
+            ("max-match 5", CompressOptions { lz77: Lz77Config { max_match: 5, ..Default::default() }, ..Default::default() }),
+            ("dictionary", CompressOptions { dictionary: Some(dictionary.clone()), ..Default::default() }),
+            ("lzw", CompressOptions { algo: Algorithm::Lzw, ..Default::default() }),
+            ("rle", CompressOptions { algo: Algorithm::Rle, ..Default::default() }),
+            ("rle alone", CompressOptions { algo: Algorithm::Rle, huffman: false, ..Default::default() }),
+            ("bwt 1K", CompressOptions { algo: Algorithm::Bwt, block_size: 1024, ..Default::default() }),
+            ("range", CompressOptions { entropy: Entropy::Range, ..Default::default() }),
+            ("bwt range", CompressOptions { algo: Algorithm::Bwt, entropy: Entropy::Range, ..Default::default() }),
+            ("adaptive", CompressOptions { entropy: Entropy::Adaptive, ..Default::default() }),
+            ("range order-1", CompressOptions { entropy: Entropy::Range, model: ContextModel::Order1, ..Default::default() }),
+            ("rle adaptive", CompressOptions { algo: Algorithm::Rle, entropy: Entropy::Adaptive, ..Default::default() }),
+            ("blocks", CompressOptions { blocks: Some(64 << 10), ..Default::default() }),
+        ];
+        for (setting, opts) in &settings {
+            check(format!("{}/{}", setting, corpus), &|| {
+                Ok(decompress(&compress(data, opts)?, opts.dictionary.as_deref())? == *data)
+            });
+        }
+        check(format!("registered codecs/{}", corpus), &|| {
+            let mut ok = true;
+            for (id, _) in codecs::registered().into_iter().filter(|&(id, _)| id < codecs::FIRST_USER_ID) {
+                let opts = CompressOptions { algo: Algorithm::Registered(id), ..Default::default() };
+                ok &= decompress(&compress(data, &opts)?, None)? == *data;
+            }
+            Ok(ok)
+        });
+        check(format!("raw/{}", corpus), &|| {
+            let opts = CompressOptions::default();
+            Ok(decompress_raw(&compress_raw_with_stats(data, &opts)?.0, None, Filter::None)? == *data)
+        });
+        for filter in filters.iter().skip(1) {
+            check(format!("filter {}/{}", filter.describe(), corpus), &|| {
+                let opts = CompressOptions { filter: *filter, ..Default::default() };
+                Ok(decompress(&compress(data, &opts)?, None)? == *data)
+            });
+        }
+        let ciphers = [
+            Cipher::ChaCha20Poly1305,
+            Cipher::Feistel(CipherMode::Ecb, DEFAULT_FEISTEL_ROUNDS),
+            Cipher::Feistel(CipherMode::Cbc, MIN_FEISTEL_ROUNDS),
+            Cipher::Feistel(CipherMode::Ctr, u8::MAX),
+        ];
+        for cipher in ciphers {
+            check(format!("sealed {:?}/{}", cipher, corpus), &|| {
+                Ok(open(&seal(data, b"selftest key", cipher, 2)?, b"selftest key")? == *data)
+            });
+        }
+    }
+    (passed, failures)
+}
 
 // ======================
-// HUFFMAN TREE
+// Rs-Zip CLI
 // ======================
-#[derive(Clone)]
-struct Node {
-    freq: u32,
-    byte: Option<u8>,
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
+fn main() {
+    let args = take_global_options(env::args().skip(1).collect());
+    if !args.is_empty() {
+        run_command(&args);
+        return;
+    }
+    loop {
+        println!("\n=== Rs-Zip CLI Tool ===");
+        println!("1) Compress file");
+        println!("2) Decompress file");
+        println!("3) Encrypt file");
+        println!("4) Decrypt file");
+        println!("5) Exit");
+        eprint!("Choose option: ");
+        io::stderr().flush().unwrap();
+        let mut choice = String::new();
+        io::stdin().read_line(&mut choice).unwrap();
+        match choice.trim() {
+            "1" => {
+                let (input, output) = ask_paths();
+                let mut opts = CompressOptions::default();
+                if ask_yes_no("Maximum compression (slow)?") {
+                    opts.set_level(CompressionLevel::Best);
+                }
+                opts.long_range = ask_yes_no("Long-range matching (large files)?");
+                let result = ask_dictionary().and_then(|dictionary| {
+                    opts.dictionary = dictionary;
+                    compress(&read_input(&input)?, &opts)
+                });
+                finish_menu_job(result, &output, "Compressed successfully!");
+            }
+            "2" => {
+                let (input, output) = ask_paths();
+                let result = read_input(&input).map_err(RsZipError::from).and_then(|filedata| {
+                    let dictionary = if required_dictionary_id(&filedata)? != 0 { ask_dictionary()? } else { None };
+                    decompress(&filedata, dictionary.as_deref())
+                });
+                finish_menu_job(result, &output, "Decompressed successfully!");
+            }
+            "3" => {
+                let (input, output) = ask_paths();
+                let key = ask_new_key();
+                let result = read_input(&input).map_err(RsZipError::from).and_then(|data| encrypt_file(&data, &key));
+                finish_menu_job(result, &output, "File encrypted!");
+            }
+            "4" => {
+                let (input, output) = ask_paths();
+                let key = ask_key();
+                let result = read_input(&input).map_err(RsZipError::from).and_then(|data| open(&data, key.as_bytes()));
+                finish_menu_job(result, &output, "File decrypted!");
+            }
+            "5" => {
+                println!("Exiting Rs-Zip.");
+                break;
+            }
+            _ => {
+                println!("Invalid choice!");
+                pause();
+            }
+        }
+    }
+}
+
+// a failed menu job is reported and the menu carries on
+fn finish_menu_job(result: Result<Vec<u8>, RsZipError>, output: &str, done: &str) {
+    match result.and_then(|data| Ok(backend_for(output).store(&data)?)) {
+        Ok(()) => println!("{}", done),
+        Err(e) => println!("Failed: {}", e),
+    }
+    pause();
+}
+
+const USAGE: &str = "Usage:
+  rszip [--limit-rate 20M] ...            global: cap disk/upload throughput (K/M/G per second)
+  rszip [--nice] ...                      global: run at background CPU and I/O priority
+  rszip                                   interactive menu
+  rszip compress <in> <out> [options]     --level 1-9|fast|default|best, --fast, --best, --long,
+                                          --algo lz77|lzw|rle|bwt (default lz77; decompress detects it),
+                                          --block-size <size> (bwt, 1K to 8M, default 900K),
+                                          --entropy huffman|range|adaptive (default huffman),
+                                          --model order0|order1 (range coder context; order1 implies range),
+                                          --codec <name> (a registered codec in place of all of these),
+                                          --blocks <size> (64K to 64M: independent blocks, see --recover),
+                                          --min-match N, --window <size>, --max-match N,
+                                          --raw, --progress, --dict <file>,
+                                          -v (stage stats to stderr), -vv (and token histograms),
+                                          --dry-run (sizes and ratio only, nothing written),
+                                          -q (no sizes, ratio, time and rate line on stderr after),
+                                          --filter text|x86|arm|kind:stride[:bpp],
+                                          --max-memory <size>, --threads N (default: one per core)
+  rszip compress --format deflate|gz [--level N] <in> <out>
+                                          raw DEFLATE (RFC 1951) or a .gz file gunzip can open
+  rszip compress --stream [--min-match N] [--entropy huffman|adaptive] <in> <out>
+                                          constant-memory block stream, for huge files and pipes
+  rszip compress <files or patterns...> --suffix .rsz [options]
+                                          each file to <file>.rsz beside it; *, ? and [a-z] are expanded
+                                          even where the shell doesn't. One line per file, and exit 1 if
+                                          any failed
+      compress refuses to replace an existing <out> without --force (-f), and --rm deletes each
+      input once its output is written (--keep, the default, leaves it).
+  rszip decompress <in> <out> [--dict <file>] [--raw [--filter <spec>]] [--format rsz|deflate|gz]
+                   [--expect-sha256 <hex> | --checksums <SHA256SUMS>]
+                   [--recover] [--threads N] [--no-perms]
+                   --recover: zero-fill damaged blocks of a --blocks file instead of failing;
+                   --threads: --blocks files decode on N threads (default: one per core)
+  rszip decompress --stream <in> <out>
+      With <in> alone compress writes <in>.rsz (.rszs with --stream, .gz, .deflate), and
+      decompress strips that ending, refusing a file whose header is another format.
+      \"-\" as <in> or <out> is stdin or stdout; prompts go to stderr, so piped output stays clean.
+      decompress reads .gz files as-is.
+      A directory <in> is compressed into an archive of its tree, and
+      decompressing an archive recreates that tree under <out>, mtimes and
+      modes included (--no-perms: mtimes only).
+      <out> may also be given as --output <dest>; s3://bucket/key and
+      ssh://host/path destinations are uploaded whole with the aws and ssh tools,
+      http(s):// inputs are downloaded with curl, and only with --stream, compressed as they arrive
+  rszip encrypt|decrypt <in> <out> --key <key> | --key-name <name> | --key-from keychain:<service>/<account>
+                   | --key-file <file>, or $RSZIP_KEY; prompts for keys are not echoed
+                   encrypt: [--cipher chacha20-poly1305|feistel [--mode cbc|ctr|ecb] [--rounds 16-255]]
+                   [--kdf-iterations N]; Feistel runs 32 rounds unless --rounds says otherwise
+                   ChaCha20-Poly1305 by default; decrypt detects which one a file uses
+  rszip encrypt|decrypt --stream <in> <out> --key...
+                   sealed frames as `tunnel` sends, compressed too, in constant memory;
+                   <in> may be an http(s):// URL, encrypted as it downloads
+  rszip pack <in> <out> --key... [--best | --level N] [--long] [--dict <file>] [--cipher ... [--mode ...]]
+                   [--rounds N] [--kdf-iterations N]
+  rszip unpack <in> <out> --key... [--dict <file>]
+                                          compress and encrypt in one step, and back
+  rszip train --dict <out.dict> <samples...>
+  rszip train-dict <samples...> <out.dict>  the same; a sample directory stands for every file under it.
+                                          Compress and decompress many small, similar files with
+                                          --dict <out.dict>, which seeds the LZ77 window
+  rszip tunnel --listen <addr> --connect <addr> --client|--server
+               [--key <key> | --key-name <name> | --key-from keychain:<service>/<account> | --key-file <file>]
+  rszip key add <name> | rm <name> | list   saved keys, encrypted under a master password
+  rszip keychain set <service>/<account>    store a key in the OS keychain
+  rszip keygen <key file>                   a random 256-bit key, used in place of a passphrase by --key-file
+  rszip bench-crypto [--target-ms 250]      cipher/KDF speed and suggested KDF iterations
+  rszip bench-entropy [files...]          Huffman, range and adaptive Huffman: sizes and speeds
+  rszip bench <file>                        every codec and level on <file>: size, ratio and speeds
+  rszip codecs                              the registered codecs and their ids
+  rszip selftest                            round-trip every codec, filter and cipher
+  rszip clip --compress | --decompress      rewrite the clipboard as base64 rszip data
+  rszip dump <file.rsz> [--all]             annotated hex view of the file structure
+  rszip info <file.rsz> [--dict <file>]     sizes, ratio, codecs, flags, block layout and decode speed
+  rszip diff <old> <new> <patch.rsz> [--fast | --best | --level N]
+                                          a compressed delta: copies out of <old> and inserted bytes
+  rszip patch <old> <patch.rsz> <new>       rebuild <new>; refuses an <old> the patch wasn't made from
+  rszip upgrade <old.rsz> <new.rsz>         add the RSZ1 signature to a file from an older rszip
+  rszip czf <out.tar.rsz> <paths...>        tar the paths and compress in one step
+  rszip ctar <paths...> <out.tar.rsz>       the same as a constant-memory stream (see --stream)
+  rszip xtar <in.tar.rsz> <dir>             unpack a ctar stream into <dir>
+  rszip add <archive.rsz> <paths...> [--encrypt] [--key... ] [--dedup]
+                                          store files and directories in a multi-file archive;
+                                          an existing one only has its table rewritten. --encrypt
+                                          seals these files under a password, asked for when absent;
+                                          --dedup cuts them into content-defined chunks and stores
+                                          each chunk once, however many files in the archive hold it
+  rszip rm <archive.rsz> <paths...> [--vacuum]   remove entries, directories with their contents
+  rszip mv <archive.rsz> <old> <new> [--vacuum]  rename an entry or a directory's contents;
+                                          both append a new table, leaving the old one and the old
+                                          data as dead space that --vacuum drops by writing it whole
+  rszip list <archive.rsz>                  size, packed size, method, offset and path per entry
+  rszip test <files...> [--dict <file>] [--stream]
+                                          decode and check every entry and block, writing nothing;
+                                          names what is damaged and exits 1 if anything is
+  rszip extract <archive.rsz> [entries...] [-C <dir>] [--no-perms] [--key...]
+                                          all entries, or the named ones, with their mtimes,
+                                          modes and (as root) owners; --no-perms keeps the mtimes only.
+                                          Encrypted entries ask for the password unless --key gives it,
+                                          and again for each entry added under another one
+  rszip cat <file.rsz> [entry] [--range start-end] [--dict <file>] [--threads N]
+                                          a file or archive entry to stdout, whole or a byte range;
+                                          --blocks files decode only the blocks in range
+  rszip manifest <archive.rsz> [-o <file>]  JSON list of entries with sizes, modes and SHA-256
+  rszip repo init <dir>                     deduplicating chunk repository
+  rszip repo add <dir> <paths...> [--name <snapshot>] [--progress]
+  rszip repo restore <dir> <snapshot> <dest>
+  rszip repo gc <dir> | check <dir>         drop unreferenced chunks / verify every chunk hash
+  rszip repo snapshots <dir>                sizes and dedup savings per snapshot
+  rszip repo diff <dir> <old> <new>         files added (+), removed (-) and modified (M)
+  rszip daemon [--socket <path>]            serve compress/decompress jobs on local files over a
+                                            Unix socket only this user can reach
+  rszip serve-api [--port 7070] [--bind 127.0.0.1] [--max-body 1G] [--key...]
+                                          HTTP RPC service: POST /compress, /decompress, streamed;
+                                          /encrypt and /decrypt use the key given here";
+
+// options valid for every command (and the menu), removed from `args`
+fn take_global_options(args: Vec<String>) -> Vec<String> {
+    let mut rest = Vec::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--limit-rate" {
+            let rate = iter.next().as_deref().and_then(parse_size)
+                .unwrap_or_else(|| usage_error("--limit-rate expects a rate such as 20M"));
+            set_rate_limit(rate);
+        } else if arg == "--nice" {
+            if let Err(e) = os_priority::lower() {
+                eprintln!("Warning: could not lower priority: {}", e);
+            }
+        } else {
+            rest.push(arg);
+        }
+    }
+    rest
+}
+
+// non-interactive entry point: `rszip <command> [args...]`
+fn run_command(args: &[String]) {
+    match args[0].as_str() {
+        "compress" => cmd_compress(&args[1..]),
+        "decompress" => cmd_decompress(&args[1..]),
+        "encrypt" => cmd_cipher(&args[1..], true),
+        "decrypt" => cmd_cipher(&args[1..], false),
+        "pack" => cmd_pack(&args[1..], true),
+        "unpack" => cmd_pack(&args[1..], false),
+        "train" => cmd_train(&args[1..]),
+        "train-dict" => cmd_train_dict(&args[1..]),
+        "tunnel" => cmd_tunnel(&args[1..]),
+        "key" => cmd_key(&args[1..]),
+        "clip" => cmd_clip(&args[1..]),
+        "dump" => cmd_dump(&args[1..]),
+        "info" => cmd_info(&args[1..]),
+        "diff" => cmd_diff(&args[1..]),
+        "patch" => cmd_patch(&args[1..]),
+        "upgrade" => cmd_upgrade(&args[1..]),
+        "czf" => cmd_czf(&args[1..]),
+        "ctar" => cmd_ctar(&args[1..]),
+        "xtar" => cmd_xtar(&args[1..]),
+        "add" => cmd_add(&args[1..]),
+        "rm" => cmd_rm(&args[1..]),
+        "mv" => cmd_mv(&args[1..]),
+        "list" => cmd_list(&args[1..]),
+        "test" => cmd_test(&args[1..]),
+        "extract" => cmd_extract(&args[1..]),
+        "cat" => cmd_cat(&args[1..]),
+        "daemon" => cmd_daemon(&args[1..]),
+        "serve-api" => cmd_serve_api(&args[1..]),
+        "keychain" => cmd_keychain(&args[1..]),
+        "keygen" => cmd_keygen(&args[1..]),
+        "manifest" => cmd_manifest(&args[1..]),
+        "repo" => cmd_repo(&args[1..]),
+        "bench-crypto" => cmd_bench_crypto(&args[1..]),
+        "bench-entropy" => cmd_bench_entropy(&args[1..]),
+        "bench" => cmd_bench(&args[1..]),
+        "codecs" => cmd_codecs(&args[1..]),
+        "selftest" => cmd_selftest(&args[1..]),
+        other => usage_error(&format!("Unknown command: {}", other)),
+    }
+}
+
+// the command's one-line failure message in place of a panic and backtrace:
+// "rszip: <what>: <error>"
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("rszip: {}: {}", what, e);
+        process::exit(1);
+    })
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// `<in> <out>` or `<in> --output <dest>`
+fn input_output<'a>(paths: &[&'a String], output: Option<&'a String>, cmd: &str) -> (&'a str, &'a str) {
+    match (paths, output) {
+        (&[input], Some(output)) | (&[input, output], None) => (input, output),
+        _ => usage_error(&format!("{} needs <in> and <out> (or --output <dest>)", cmd)),
+    }
+}
+
+// The endings compress adds when given <in> alone and decompress strips
+// again; only local files get a name made for them.
+const ENDINGS: [&str; 4] = [".rsz", ".rszs", ".gz", ".deflate"];
+
+fn can_name_output(input: &str) -> bool {
+    input != "-" && !is_url(input)
+}
+
+fn compressed_name(input: &str, format: OutputFormat, stream: bool) -> String {
+    let ending = match format {
+        OutputFormat::Gzip => ".gz",
+        OutputFormat::Deflate => ".deflate",
+        OutputFormat::Rsz if stream => ".rszs",
+        OutputFormat::Rsz => ".rsz",
+    };
+    // a directory given as `dir/` becomes `dir.rsz`, not `dir/.rsz`
+    format!("{}{}", input.trim_end_matches(['/', '\\']), ending)
+}
+
+// (<out>, the ending taken off) for decompress given <in> alone
+fn decompressed_name(input: &str) -> Option<(String, &'static str)> {
+    ENDINGS.iter().find_map(|&ending| {
+        let stem = input.strip_suffix(ending).filter(|stem| !stem.is_empty() && !stem.ends_with(['/', '\\']))?;
+        Some((stem.to_string(), ending))
+    })
+}
+
+// Whether a file's first bytes hold what its ending promises. Streams and
+// raw DEFLATE carry no signature of their own, so for them it's enough not
+// to start with another format's (adaptive streams share "RSZA" with
+// archives). Output from --raw has no header to look at.
+fn matches_ending(head: &[u8], ending: &str, raw: bool) -> bool {
+    let (rsz, gz) = (head.starts_with(MAGIC), gzip::is_gzip(head));
+    match ending {
+        ".rsz" => raw || rsz || is_archive(head),
+        ".gz" => gz,
+        ".rszs" => !rsz && !gz,
+        _ => !rsz && !gz && !is_archive(head),
+    }
+}
+
+fn cmd_compress(args: &[String]) {
+    let mut opts = CompressOptions::default();
+    let mut paths = Vec::new();
+    let mut output = None;
+    let (mut raw, mut stream, mut dry_run) = (false, false, false);
+    // 1 for -v, 2 for -vv
+    let mut verbose = 0;
+    let (mut max_memory, mut suffix) = (None, None);
+    let (mut force, mut remove, mut quiet) = (false, false, false);
+    let (mut level, mut format) = (CompressionLevel::Default, OutputFormat::Rsz);
+    let started = Instant::now();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--fast" | "--best" | "--level" => {
+                level = match arg.as_str() {
+                    "--fast" => CompressionLevel::Fast,
+                    "--best" => CompressionLevel::Best,
+                    _ => level_option(iter.next()),
+                };
+                opts.set_level(level);
+            }
+            "--format" => format = format_option(iter.next()),
+            "--algo" => opts.algo = algo_option(iter.next()),
+            "--codec" => opts.algo = codec_option(iter.next()),
+            "--long" => opts.long_range = true,
+            "--threads" => opts.threads = threads_option(iter.next()),
+            "--min-match" => {
+                opts.lz77.min_match = iter.next().and_then(|n| n.parse().ok())
+                    .filter(|n| MIN_MATCH_RANGE.contains(n))
+                    .unwrap_or_else(|| usage_error("--min-match needs a number from 2 to 8"));
+            }
+            "--window" => {
+                opts.lz77.window_size = iter.next().and_then(|s| parse_size(s)).map(|n| n as usize)
+                    .filter(|n| WINDOW_RANGE.contains(n))
+                    .unwrap_or_else(|| usage_error("--window needs a size from 16 to 1M"));
+            }
+            "--entropy" => {
+                opts.entropy = iter.next().and_then(|v| Entropy::parse(v))
+                    .unwrap_or_else(|| usage_error("--entropy needs huffman, range or adaptive"));
+            }
+            "--model" => {
+                opts.model = iter.next().and_then(|v| ContextModel::parse(v))
+                    .unwrap_or_else(|| usage_error("--model needs order0 or order1"));
+            }
+            "--block-size" => {
+                opts.block_size = iter.next().and_then(|s| parse_size(s)).map(|n| n as usize)
+                    .filter(|n| bwt::BLOCK_RANGE.contains(n))
+                    .unwrap_or_else(|| usage_error("--block-size needs a size from 1K to 8M"));
+            }
+            "--blocks" => {
+                opts.blocks = Some(iter.next().and_then(|s| parse_size(s)).map(|n| n as usize)
+                    .filter(|n| BLOCKS_RANGE.contains(n))
+                    .unwrap_or_else(|| usage_error("--blocks needs a size from 64K to 64M")));
+            }
+            "--max-match" => {
+                opts.lz77.max_match = iter.next().and_then(|n| n.parse().ok())
+                    .filter(|n| (2..=MAX_MATCH).contains(n))
+                    .unwrap_or_else(|| usage_error("--max-match needs a number from 2 up"));
+            }
+            "--dict" => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                opts.dictionary = Some(or_exit(fs::read(path), path));
+            }
+            "--filter" => opts.filter = parse_filter_arg(iter.next()),
+            "-o" | "--output" => output = iter.next(),
+            "--raw" => raw = true,
+            "--stream" => stream = true,
+            "--keep" => remove = false,
+            "--rm" => remove = true,
+            "-f" | "--force" => force = true,
+            "--suffix" => suffix = Some(iter.next().filter(|s| !s.is_empty()).unwrap_or_else(|| usage_error("--suffix needs an ending like .rsz"))),
+            "--progress" => opts.on_progress = Some(progress_printer()),
+            "-v" | "--verbose" => verbose = (verbose + 1).min(2),
+            "-vv" => verbose = 2,
+            "--dry-run" => dry_run = true,
+            "-q" | "--quiet" => quiet = true,
+            "--max-memory" => {
+                let cap = iter.next().and_then(|s| parse_size(s))
+                    .unwrap_or_else(|| usage_error("--max-memory needs a size like 512M"));
+                max_memory = Some(cap as usize);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    // the order-1 model only exists for the range coder
+    if opts.model == ContextModel::Order1 && opts.entropy == Entropy::Huffman {
+        opts.entropy = Entropy::Range;
+    }
+    if let Err(e) = opts.lz77.validate() {
+        usage_error(&e.to_string());
+    }
+    if format != OutputFormat::Rsz {
+        let mut preset = CompressOptions::default();
+        preset.set_level(level);
+        let rsz_only = opts.lz77 != preset.lz77 || opts.algo != Algorithm::Lz77 || opts.entropy != Entropy::Huffman || opts.long_range || opts.dictionary.is_some() || opts.filter != Filter::None
+            || opts.on_progress.is_some() || opts.threads != 0 || opts.blocks.is_some() || raw || stream || max_memory.is_some();
+        if rsz_only {
+            usage_error("--format deflate|gz can only be combined with --level, --fast, --best and -v");
+        }
+    }
+    if let Some(suffix) = suffix {
+        if output.is_some() || stream || max_memory.is_some() {
+            usage_error("--suffix writes each output beside its input: no --output, --stream or --max-memory");
+        }
+        if paths.is_empty() || paths.iter().any(|p| *p == "-") {
+            usage_error("compress --suffix needs <files or patterns...>, not -");
+        }
+        compress_batch(&paths, suffix, &Batch { opts: &opts, raw, format, level, verbose, dry_run, force, remove });
+        return;
+    }
+    let named;
+    let (input, output) = match (&paths[..], output) {
+        (&[input], None) if can_name_output(input) => {
+            named = compressed_name(input, format, stream);
+            (input.as_str(), named.as_str())
+        }
+        _ => input_output(&paths, output, "compress"),
+    };
+    if remove && (input == "-" || is_url(input) || input == output) {
+        usage_error("--rm deletes an input file once it's compressed: not -, a URL or the output itself");
+    }
+    if !force {
+        or_exit(refuse_existing(output), output);
+    }
+    if format != OutputFormat::Rsz {
+        let metadata = fs::metadata(long_path(Path::new(input)));
+        if metadata.as_ref().is_ok_and(|m| m.is_dir()) {
+            usage_error("--format deflate|gz compresses single files, not directories");
+        }
+        let data = or_exit(open_input(input), input);
+        let compressed = compress_foreign(input, &data, metadata.ok(), format, level);
+        if !dry_run {
+            write_output(output, &compressed);
+            if remove {
+                remove_input(input);
+            }
+        }
+        let line = compress_line(input, output, data.len() as u64, compressed.len() as u64, started.elapsed(), dry_run);
+        report_compress(&line, dry_run, quiet);
+        return;
+    }
+    if stream {
+        let custom_window = opts.lz77.window_size != LZ77_WINDOW || opts.lz77.max_match != MAX_MATCH;
+        let whole_file_only = custom_window || opts.algo != Algorithm::Lz77 || opts.entropy == Entropy::Range || !opts.huffman || opts.lz77.search_depth != usize::MAX || opts.parse != ParseMode::Greedy || opts.long_range || opts.dictionary.is_some()
+            || opts.filter != Filter::None || opts.on_progress.is_some() || opts.threads != 0 || opts.blocks.is_some()
+            || raw || verbose > 0 || dry_run || max_memory.is_some();
+        if whole_file_only {
+            usage_error("--stream can only be combined with --min-match and --entropy huffman|adaptive");
+        }
+        let (read, written) = or_exit(stream_compress(input, output, opts.lz77.min_match, opts.entropy), input);
+        if remove {
+            remove_input(input);
+        }
+        let line = compress_line(input, output, read, written, started.elapsed(), false);
+        report_compress(&line, false, quiet);
+        return;
+    }
+    if fs::metadata(long_path(Path::new(input))).is_ok_and(|m| m.is_dir()) {
+        if raw || max_memory.is_some() || remove {
+            usage_error("--raw, --max-memory and --rm apply to single files, not directories");
+        }
+        let mut archive = Archive::new();
+        or_exit(archive_add_path(&mut archive, &long_path(Path::new(input)), "", &opts, false), input);
+        let bytes = or_exit(archive.to_bytes(), input);
+        let total: u64 = archive.entries().iter().map(|e| e.size as u64).sum();
+        if !dry_run {
+            write_output(output, &bytes);
+        }
+        let line = compress_line(input, output, total, bytes.len() as u64, started.elapsed(), dry_run);
+        report_compress(&line, dry_run, quiet);
+        if verbose > 0 {
+            eprintln!("{}: {} entries", input, archive.entries().len());
+        }
+        return;
+    }
+    opts.entry = Some(input.to_string());
+    let data = or_exit(open_input(input), input);
+    if let Some(cap) = max_memory {
+        match fit_memory(&mut opts, data.len(), cap) {
+            Ok(dropped) => {
+                for what in dropped {
+                    eprintln!("--max-memory: disabled {} to stay under {} bytes", what, cap);
+                }
+            }
+            Err(needed) => {
+                eprintln!("{} needs about {} bytes to compress, over the --max-memory cap of {}.", input, needed, cap);
+                process::exit(1);
+            }
+        }
+    }
+    let result = if raw { compress_raw_with_stats(&data, &opts) } else { compress_with_stats(&data, &opts) };
+    let (compressed, stats) = or_exit(result, input);
+    if !dry_run {
+        write_output(output, &compressed);
+    }
+    let line = compress_line(input, output, data.len() as u64, compressed.len() as u64, started.elapsed(), dry_run);
+    report_compress(&line, dry_run, quiet);
+    if verbose > 0 {
+        eprintln!("{}: peak memory {} bytes", input, peak_heap());
+        print_stats(&stats, verbose);
+    }
+    if remove && !dry_run {
+        // a mapped input is unmapped before its file goes
+        drop(data);
+        remove_input(input);
+    }
+}
+
+// raw DEFLATE or gzip, which records the base name and modification time
+// as gzip(1) does
+fn compress_foreign(input: &str, data: &[u8], metadata: Option<fs::Metadata>, format: OutputFormat, level: CompressionLevel) -> Vec<u8> {
+    if format != OutputFormat::Gzip {
+        return deflate::compress(data, level);
+    }
+    let name = Path::new(input).file_name().and_then(|n| n.to_str()).filter(|_| input != "-");
+    let mtime = metadata.and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs().min(u32::MAX as u64) as u32);
+    gzip::compress(data, level, name, mtime)
+}
+
+// "108894 -> 65654 bytes (60.3%)"
+fn ratio_summary(before: u64, after: u64) -> String {
+    let ratio = if before == 0 { 100.0 } else { after as f64 * 100.0 / before as f64 };
+    format!("{} -> {} bytes ({:.1}%)", before, after, ratio)
+}
+
+// "0.12 s, 0.9 MB/s"
+fn rate_summary(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    format!("{:.2} s, {:.1} MB/s", secs, bytes as f64 / secs.max(1e-6) / 1e6)
+}
+
+// the rate counted in original bytes
+fn size_summary(before: u64, after: u64, elapsed: Duration) -> String {
+    format!("{} in {}", ratio_summary(before, after), rate_summary(before, elapsed))
+}
+
+fn compress_line(input: &str, output: &str, before: u64, after: u64, elapsed: Duration, dry_run: bool) -> String {
+    format!("{} -> {}: {}{}", input, output, size_summary(before, after, elapsed), if dry_run { ", nothing written" } else { "" })
+}
+
+// After compressing, on stderr so piped output stays clean and left out
+// with --quiet; --dry-run's goes to stdout, being all it does.
+fn report_compress(line: &str, dry_run: bool, quiet: bool) {
+    if dry_run {
+        println!("{}", line);
+    } else if !quiet {
+        eprintln!("{}", line);
+    }
+}
+
+// -v: a line per stage; -vv adds the token breakdown and histograms
+fn print_stats(stats: &TokenStats, verbose: u8) {
+    if verbose > 0 {
+        eprint!("{}", stats.stages());
+    }
+    if verbose > 1 {
+        eprint!("{}", stats.report());
+    }
+}
+
+// Without --force, compress won't replace a local file that's already there.
+fn refuse_existing(output: &str) -> io::Result<()> {
+    let local = output != "-" && !output.starts_with("s3://") && !output.starts_with("ssh://");
+    if local && fs::symlink_metadata(long_path(Path::new(output))).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "already exists (--force overwrites it)"));
+    }
+    Ok(())
+}
+
+// --rm, once the output is written
+fn remove_input(input: &str) {
+    or_exit(fs::remove_file(long_path(Path::new(input))), input);
+}
+
+// the `compress` settings a batch applies to every file
+struct Batch<'a> {
+    opts: &'a CompressOptions,
+    raw: bool,
+    format: OutputFormat,
+    level: CompressionLevel,
+    verbose: u8,
+    dry_run: bool,
+    // replace outputs already there; delete each input once its output is written
+    force: bool,
+    remove: bool,
+}
+
+// `compress --suffix`: every input to `<input><suffix>` beside it, with
+// patterns expanded here for shells that pass them through (cmd.exe and
+// PowerShell never expand them). One line per file, as `test` prints, and
+// exit 1 when any file failed; the others are still written.
+fn compress_batch(patterns: &[&String], suffix: &str, batch: &Batch) {
+    let (mut done, mut failed) = (0, 0);
+    for pattern in patterns {
+        let inputs = expand_glob(pattern, suffix);
+        if inputs.is_empty() {
+            println!("{}: FAILED no matching files", pattern);
+            failed += 1;
+        }
+        for input in inputs {
+            let output = format!("{}{}", input, suffix);
+            let started = Instant::now();
+            match compress_one(&input, &output, batch) {
+                Ok((before, after, stats)) => {
+                    done += 1;
+                    let elapsed = started.elapsed();
+                    println!("{}", compress_line(&input, &output, before as u64, after as u64, elapsed, batch.dry_run));
+                    if let Some(stats) = stats {
+                        print_stats(&stats, batch.verbose);
+                    }
+                }
+                Err(e) => {
+                    failed += 1;
+                    println!("{}: FAILED {}", input, e);
+                }
+            }
+        }
+    }
+    println!("{} compressed, {} failed", done, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// One file of a batch: (input bytes, output bytes, token stats for rsz).
+fn compress_one(input: &str, output: &str, batch: &Batch) -> Result<(usize, usize, Option<TokenStats>), RsZipError> {
+    let metadata = fs::metadata(long_path(Path::new(input)))?;
+    if metadata.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory, compress it on its own").into());
+    }
+    if !batch.force {
+        refuse_existing(output)?;
+    }
+    let data = open_input(input)?;
+    let (compressed, stats) = if batch.format != OutputFormat::Rsz {
+        (compress_foreign(input, &data, Some(metadata), batch.format, batch.level), None)
+    } else {
+        let opts = CompressOptions { entry: Some(input.to_string()), ..batch.opts.clone() };
+        let result = if batch.raw { compress_raw_with_stats(&data, &opts) } else { compress_with_stats(&data, &opts) };
+        let (compressed, stats) = result?;
+        (compressed, Some(stats))
+    };
+    let len = data.len();
+    if batch.dry_run {
+        return Ok((len, compressed.len(), stats));
+    }
+    backend_for(output).store(&compressed)?;
+    if batch.remove {
+        drop(data);
+        fs::remove_file(long_path(Path::new(input)))?;
+    }
+    Ok((len, compressed.len(), stats))
+}
+
+fn has_wildcards(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+// The files a pattern names, sorted; wildcards may sit in any component but
+// don't cross a separator, and only a pattern starting with `.` matches
+// hidden names. Files already ending in `suffix` are left out so a rerun
+// over `*` doesn't compress its own output. A name without wildcards is
+// returned as it is, for the open to report if it's missing.
+fn expand_glob(pattern: &str, suffix: &str) -> Vec<String> {
+    if !has_wildcards(pattern) {
+        return vec![pattern.to_string()];
+    }
+    let mut found = vec![PathBuf::new()];
+    for part in Path::new(pattern).components() {
+        let wanted = part.as_os_str().to_string_lossy();
+        if !has_wildcards(&wanted) {
+            found.iter_mut().for_each(|path| path.push(part));
+            continue;
+        }
+        found = found.iter()
+            .flat_map(|dir| {
+                let listing = fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir });
+                listing.into_iter().flatten().flatten()
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|name| glob_match(&wanted, name) && (!name.starts_with('.') || wanted.starts_with('.')))
+                    .map(|name| dir.join(name))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+    let mut found: Vec<String> = found.into_iter()
+        .filter(|path| fs::metadata(path).is_ok_and(|m| m.is_file()))
+        .filter_map(|path| path.into_os_string().into_string().ok())
+        .filter(|path| !path.ends_with(suffix))
+        .collect();
+    found.sort();
+    found
+}
+
+// `*` any run of characters, `?` any one, `[a-z]` or `[!a-z]` one in or out
+// of a set; a `[` without its `]` is an ordinary character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // where to resume after the last `*` when what followed it stops matching
+    let mut star = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => class_match(&pattern[p..], name[n]),
+            Some(&c) => (c == name[n]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((after, from))) => {
+                star = Some((after, from + 1));
+                (p, n) = (after, from + 1);
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// The pattern length a `[...]` at the start of `class` takes up when `c`
+// matches it.
+fn class_match(class: &[char], c: char) -> Option<usize> {
+    let negate = matches!(class.get(1), Some('!' | '^'));
+    let start = 1 + negate as usize;
+    // a `]` straight after the opening is one of the set
+    let Some(end) = class.iter().skip(start + 1).position(|&x| x == ']').map(|i| start + 1 + i) else {
+        return (c == '[').then_some(1);
+    };
+    let set = &class[start..end];
+    let (mut hit, mut i) = (false, 0);
+    while i < set.len() {
+        if set.get(i + 1) == Some(&'-') && i + 2 < set.len() {
+            hit |= (set[i]..=set[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            hit |= set[i] == c;
+            i += 1;
+        }
+    }
+    (hit != negate).then_some(end + 1)
+}
+
+const PROGRESS_BAR_WIDTH: u64 = 30;
+
+// One stderr line per stage, and while LZ77 runs a bar redrawn in place:
+//   big.tar: [###############---------------]  50%   12.3 MB/s  ETA 0:04
+fn progress_printer() -> ProgressFn {
+    // when the match stage was entered, and whether the bar is on screen
+    let state = Mutex::new((Instant::now(), false));
+    Arc::new(move |event: ProgressEvent| {
+        let name = event.entry.as_deref().unwrap_or("-");
+        let mut state = state.lock().unwrap();
+        let (started, bar_shown) = &mut *state;
+        if event.total == 0 {
+            if std::mem::take(bar_shown) {
+                eprintln!();
+            }
+            eprintln!("{}: {:?} ({} bytes read, {} bytes written)", name, event.stage, event.bytes_read, event.bytes_written);
+            *started = Instant::now();
+            return;
+        }
+        *bar_shown = true;
+        let elapsed = started.elapsed().as_secs_f64();
+        let done = event.done.min(event.total);
+        let rate = done as f64 / elapsed.max(0.001);
+        let left = ((event.total - done) as f64 / rate.max(1.0)).round() as u64;
+        let filled = done * PROGRESS_BAR_WIDTH / event.total;
+        eprint!(
+            "\r{}: [{}{}] {:>3}% {:>7.1} MB/s  ETA {}:{:02}",
+            name,
+            "#".repeat(filled as usize),
+            "-".repeat((PROGRESS_BAR_WIDTH - filled) as usize),
+            done * 100 / event.total,
+            rate / 1e6,
+            left / 60,
+            left % 60
+        );
+    })
+}
+
+fn parse_filter_arg(spec: Option<&String>) -> Filter {
+    spec.and_then(|spec| Filter::parse(spec))
+        .unwrap_or_else(|| usage_error("--filter expects text, x86, arm or sub|up|paeth:stride[:bpp]"))
+}
+
+fn cmd_decompress(args: &[String]) {
+    let mut dictionary = None;
+    let mut paths = Vec::new();
+    let mut output = None;
+    let (mut raw, mut stream, mut recover) = (false, false, false);
+    let mut threads = 0;
+    let mut perms = true;
+    // for encrypted archive entries
+    let mut key = None;
+    // only consulted for --raw input, normal files carry their own filter
+    let mut filter = Filter::None;
+    let (mut expected, mut checksums) = (None, None);
+    let mut format = OutputFormat::Rsz;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => format = format_option(iter.next()),
+            "--dict" => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                dictionary = Some(or_exit(fs::read(path), path));
+            }
+            "-o" | "--output" => output = iter.next(),
+            "--raw" => raw = true,
+            "--stream" => stream = true,
+            "--recover" => recover = true,
+            "--threads" => threads = threads_option(iter.next()),
+            "--no-perms" => perms = false,
+            "--key" | "--key-name" | "--key-from" | "--key-file" => key = Some(key_option(arg, iter.next())),
+            "--filter" => filter = parse_filter_arg(iter.next()),
+            "--expect-sha256" => {
+                let hex = iter.next().unwrap_or_else(|| usage_error("--expect-sha256 needs a digest"));
+                expected = Some(hex.to_ascii_lowercase());
+            }
+            "--checksums" => checksums = iter.next(),
+            _ => paths.push(arg),
+        }
+    }
+    // <in> alone: the output is <in> without its ending, which also says
+    // what format to expect
+    let named;
+    let (input, output) = match (&paths[..], output) {
+        (&[input], None) if can_name_output(input) => {
+            let Some((stem, ending)) = decompressed_name(input) else {
+                usage_error(&format!("decompress needs <out> for files not ending in {}", ENDINGS.join(", ")))
+            };
+            match ending {
+                ".gz" if format == OutputFormat::Rsz => format = OutputFormat::Gzip,
+                ".deflate" if format == OutputFormat::Rsz => format = OutputFormat::Deflate,
+                ".rszs" => stream = true,
+                _ => {}
+            }
+            let mut head = Vec::new();
+            or_exit(fs::File::open(long_path(Path::new(input))).and_then(|f| f.take(8).read_to_end(&mut head)), input);
+            if !matches_ending(&head, ending, raw) {
+                eprintln!("rszip: {}: not the format its {} ending says; give <out> to decompress it anyway", input, ending);
+                process::exit(1);
+            }
+            named = stem;
+            (input.as_str(), named.as_str())
+        }
+        _ => input_output(&paths, output, "decompress"),
+    };
+    if format != OutputFormat::Rsz && (raw || stream || recover || dictionary.is_some()) {
+        usage_error("--format deflate|gz can't be combined with --raw, --stream, --recover or --dict");
+    }
+    if recover && (raw || stream) {
+        usage_error("--recover applies to files written with --blocks, not --raw or --stream");
+    }
+    if stream {
+        if raw || dictionary.is_some() || expected.is_some() || checksums.is_some() {
+            usage_error("--stream can't be combined with --raw, --dict or checksum options");
+        }
+        or_exit(stream_decompress(input, output), input);
+        return;
+    }
+    if let Some(list) = checksums {
+        let text = or_exit(fs::read_to_string(list), list);
+        let Some(hex) = checksum_for(&text, output) else {
+            eprintln!("{} has no entry for {}.", list, output);
+            process::exit(1);
+        };
+        expected = Some(hex);
+    }
+    let filedata = or_exit(open_input(input), input);
+    if format == OutputFormat::Rsz && !raw && gzip::is_gzip(&filedata) {
+        format = OutputFormat::Gzip;
+    }
+    if format == OutputFormat::Rsz && !raw && is_archive(&filedata) {
+        // a compressed directory: <out> is the directory to recreate it in
+        if dictionary.is_some() || expected.is_some() {
+            usage_error("--dict and checksum options apply to single files, not archives");
+        }
+        let archive = or_exit(Archive::read(&filedata), input);
+        let extracted = or_exit(extract_archive(&archive, Path::new(output), &[], perms, &mut key.into_iter().collect()), input);
+        println!("Extracted {} entries into {}", extracted, output);
+        return;
+    }
+    let mut lost_blocks = Vec::new();
+    let result = if format == OutputFormat::Gzip {
+        gzip::decompress(&filedata)
+    } else if format == OutputFormat::Deflate {
+        deflate::decompress(&filedata)
+    } else if raw {
+        decompress_raw(&filedata, dictionary.as_deref(), filter)
+    } else if recover {
+        decompress_recover(&filedata, dictionary.as_deref(), threads).map(|(data, lost)| {
+            lost_blocks = lost;
+            data
+        })
+    } else {
+        decompress_threaded(&filedata, dictionary.as_deref(), threads)
+    };
+    let decompressed = or_exit(result, input);
+    if let Some(hex) = expected {
+        let actual = hex_lower(&sha256(&decompressed));
+        if actual != hex {
+            eprintln!("SHA-256 mismatch for {}: expected {}, got {}.", output, hex, actual);
+            process::exit(1);
+        }
+    }
+    write_output(output, &decompressed);
+    if !lost_blocks.is_empty() {
+        let lost: Vec<String> = lost_blocks.iter().map(|i| i.to_string()).collect();
+        eprintln!("{}: damaged blocks zero-filled in {}: {}", input, output, lost.join(", "));
+        process::exit(1);
+    }
+}
+
+// Looks `output` up in `sha256sum`-style lines ("<hex>  <name>", with an
+// optional `*` binary marker), matching by full path first, then file name.
+fn checksum_for(text: &str, output: &str) -> Option<String> {
+    let entries: Vec<(&str, &str)> = text
+        .lines()
+        .filter_map(|line| {
+            let (hex, name) = line.trim_end().split_once(char::is_whitespace)?;
+            Some((hex, name.trim_start().trim_start_matches('*')))
+        })
+        .collect();
+    let base = Path::new(output).file_name()?.to_str()?;
+    entries
+        .iter()
+        .find(|(_, name)| *name == output)
+        .or_else(|| entries.iter().find(|(_, name)| Path::new(name).file_name().and_then(|n| n.to_str()) == Some(base)))
+        .map(|(hex, _)| hex.to_ascii_lowercase())
+}
+
+fn cmd_train(args: &[String]) {
+    let mut dict_path = None;
+    let mut samples = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--dict" {
+            dict_path = iter.next();
+        } else {
+            add_samples(arg, &mut samples);
+        }
+    }
+    let Some(dict_path) = dict_path else { usage_error("train needs --dict <out.dict>") };
+    train_into(dict_path, &samples);
+}
+
+// `train-dict <samples...> <dict>`: train with the output last
+fn cmd_train_dict(args: &[String]) {
+    let [inputs @ .., dict_path] = args else { usage_error("train-dict needs <samples...> <out.dict>") };
+    let mut samples = Vec::new();
+    for input in inputs {
+        add_samples(input, &mut samples);
+    }
+    train_into(dict_path, &samples);
+}
+
+// a sample file, or every file under a sample directory in name order;
+// symlinks inside it are passed over, so a loop can't recurse forever
+fn add_samples(path: &str, samples: &mut Vec<Vec<u8>>) {
+    if !fs::metadata(long_path(Path::new(path))).is_ok_and(|m| m.is_dir()) {
+        samples.push(or_exit(read_input(path), path));
+        return;
+    }
+    let mut entries: Vec<PathBuf> = or_exit(fs::read_dir(long_path(Path::new(path))), path)
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| !t.is_symlink()))
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        add_samples(&entry.to_string_lossy(), samples);
+    }
+}
+
+fn train_into(dict_path: &str, samples: &[Vec<u8>]) {
+    if samples.len() < 2 {
+        eprintln!("Need at least two sample files to find shared content.");
+        process::exit(2);
+    }
+    let dict = train_dictionary(samples, LZ77_WINDOW);
+    or_exit(fs::write(dict_path, &dict), dict_path);
+    println!("Trained {}-byte dictionary from {} samples.", dict.len(), samples.len());
+}
+
+fn cmd_tunnel(args: &[String]) {
+    let (mut listen, mut connect, mut side, mut key) = (None, None, None, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--listen" => listen = iter.next(),
+            "--connect" => connect = iter.next(),
+            "--client" => side = Some(TunnelSide::Client),
+            "--server" => side = Some(TunnelSide::Server),
+            "--key" | "--key-name" | "--key-from" | "--key-file" => key = Some(key_option(arg, iter.next())),
+            other => usage_error(&format!("Unknown tunnel option: {}", other)),
+        }
+    }
+    let (Some(listen), Some(connect), Some(side)) = (listen, connect, side) else {
+        usage_error("tunnel needs --listen, --connect and --client or --server")
+    };
+    if let Err(e) = run_tunnel(listen, connect, side, key) {
+        eprintln!("Tunnel failed: {}", e);
+        process::exit(1);
+    }
+}
+
+// value of --key, --key-name, --key-from or --key-file, resolved to the key itself
+fn key_option(flag: &str, value: Option<&String>) -> SecretKey {
+    match flag {
+        "--key" => SecretKey::from(value.cloned().unwrap_or_else(|| usage_error("--key needs a key"))),
+        "--key-name" => saved_key_or_exit(value.unwrap_or_else(|| usage_error("--key-name needs a name"))),
+        "--key-file" => key_file_or_exit(value.unwrap_or_else(|| usage_error("--key-file needs a file"))),
+        _ => key_from_or_exit(value.unwrap_or_else(|| usage_error("--key-from needs keychain:<service>/<account>"))),
+    }
+}
+
+// a key file from `keygen` as it is, for `seal` to use its key directly;
+// any other file is a passphrase, less one trailing line ending
+fn key_file_or_exit(path: &str) -> SecretKey {
+    let mut bytes = or_exit(fs::read(path), path);
+    if key_file_key(&bytes).is_some() {
+        return SecretKey::new(bytes);
+    }
+    let len = bytes.strip_suffix(b"\n").map_or(bytes.len(), |b| b.strip_suffix(b"\r").unwrap_or(b).len());
+    let key = SecretKey::new(bytes[..len].to_vec());
+    zeroize(&mut bytes);
+    if key.is_empty() {
+        eprintln!("{} holds an empty key.", path);
+        process::exit(1);
+    }
+    key
+}
+
+// $RSZIP_KEY, for scripts: used wherever a key would otherwise be prompted
+// for or required
+fn env_key() -> Option<SecretKey> {
+    env::var("RSZIP_KEY").ok().filter(|k| !k.is_empty()).map(SecretKey::from)
+}
+
+// `encrypt` and `decrypt`: the menu's Feistel options, for scripts
+fn algo_option(value: Option<&String>) -> Algorithm {
+    value.and_then(|v| Algorithm::parse(v)).unwrap_or_else(|| usage_error("--algo needs lz77, lzw, rle or bwt"))
+}
+
+fn threads_option(value: Option<&String>) -> usize {
+    value.and_then(|n| n.parse().ok()).unwrap_or_else(|| usage_error("--threads needs a number (0 for one per core)"))
+}
+
+fn codec_option(value: Option<&String>) -> Algorithm {
+    value.and_then(|v| codecs::by_name(v)).map(|(id, _)| Algorithm::Registered(id)).unwrap_or_else(|| {
+        let names: Vec<String> = codecs::registered().into_iter().map(|(_, name)| name).collect();
+        usage_error(&format!("--codec needs one of: {}", names.join(", ")))
+    })
+}
+
+fn level_option(value: Option<&String>) -> CompressionLevel {
+    value.and_then(|v| CompressionLevel::parse(v))
+        .unwrap_or_else(|| usage_error("--level needs 1 to 9, fast, default or best"))
+}
+
+// what compress writes and decompress reads: rszip's own format, or a raw
+// DEFLATE stream or .gz file other tools can read
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Rsz,
+    Deflate,
+    Gzip,
+}
+
+fn format_option(value: Option<&String>) -> OutputFormat {
+    match value.map(String::as_str) {
+        Some("rsz") => OutputFormat::Rsz,
+        Some("deflate") => OutputFormat::Deflate,
+        Some("gz") => OutputFormat::Gzip,
+        _ => usage_error("--format needs rsz, deflate or gz"),
+    }
+}
+
+fn cipher_option(value: Option<&String>) -> Cipher {
+    match value.map(String::as_str) {
+        Some("chacha20-poly1305") => Cipher::ChaCha20Poly1305,
+        Some("feistel") => Cipher::Feistel(CipherMode::Cbc, DEFAULT_FEISTEL_ROUNDS),
+        _ => usage_error("--cipher needs chacha20-poly1305 or feistel"),
+    }
+}
+
+fn mode_option(value: Option<&String>) -> CipherMode {
+    match value.map(String::as_str) {
+        Some("ecb") => CipherMode::Ecb,
+        Some("cbc") => CipherMode::Cbc,
+        Some("ctr") => CipherMode::Ctr,
+        _ => usage_error("--mode needs ecb, cbc or ctr"),
+    }
+}
+
+fn rounds_option(value: Option<&String>) -> u8 {
+    value.and_then(|n| n.parse().ok()).filter(|&n| n >= MIN_FEISTEL_ROUNDS)
+        .unwrap_or_else(|| usage_error(&format!("--rounds needs {} to 255", MIN_FEISTEL_ROUNDS)))
+}
+
+// --mode and --rounds only mean something for the block cipher
+fn with_mode(cipher: Cipher, mode: Option<CipherMode>, rounds: Option<u8>) -> Cipher {
+    match (cipher, mode, rounds) {
+        (Cipher::Feistel(default_mode, default_rounds), _, _) => {
+            Cipher::Feistel(mode.unwrap_or(default_mode), rounds.unwrap_or(default_rounds))
+        }
+        (cipher, None, None) => cipher,
+        (_, Some(_), _) => usage_error("--mode applies to --cipher feistel only"),
+        (_, None, Some(_)) => usage_error("--rounds applies to --cipher feistel only"),
+    }
 }
-impl Eq for Node {}
-impl PartialEq for Node {
-    fn eq(&self, other: &Self) -> bool { self.freq == other.freq }
+
+fn kdf_iterations_option(value: Option<&String>) -> u32 {
+    value.and_then(|n| n.parse().ok()).filter(|&n| n > 0)
+        .unwrap_or_else(|| usage_error("--kdf-iterations needs a positive number"))
 }
-impl Ord for Node {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.freq.cmp(&self.freq)
-    }
+
+fn encrypt_file(data: &[u8], key: &SecretKey) -> Result<Vec<u8>, RsZipError> {
+    seal(data, key.as_bytes(), Cipher::ChaCha20Poly1305, DEFAULT_KDF_ITERATIONS)
 }
-impl PartialOrd for Node {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+fn cmd_cipher(args: &[String], encrypting: bool) {
+    let cmd = if encrypting { "encrypt" } else { "decrypt" };
+    let (mut paths, mut output, mut key) = (Vec::new(), None, None);
+    let (mut cipher, mut mode, mut rounds) = (None, None, None);
+    let (mut iterations, mut stream) = (DEFAULT_KDF_ITERATIONS, false);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = iter.next(),
+            "--key" | "--key-name" | "--key-from" | "--key-file" => key = Some(key_option(arg, iter.next())),
+            "--stream" => stream = true,
+            "--cipher" => cipher = Some(cipher_option(iter.next())),
+            "--mode" => mode = Some(mode_option(iter.next())),
+            "--rounds" => rounds = Some(rounds_option(iter.next())),
+            "--kdf-iterations" => iterations = kdf_iterations_option(iter.next()),
+            _ => paths.push(arg),
+        }
+    }
+    let (input, output) = input_output(&paths, output, cmd);
+    let Some(key) = key.or_else(env_key) else {
+        usage_error(&format!("{} needs --key, --key-name, --key-from, --key-file or $RSZIP_KEY", cmd))
+    };
+    let custom = cipher.is_some() || mode.is_some() || rounds.is_some() || iterations != DEFAULT_KDF_ITERATIONS;
+    if stream {
+        if custom {
+            usage_error("--stream always seals with ChaCha20-Poly1305 frames: no --cipher, --mode, --rounds or --kdf-iterations");
+        }
+        let result = if encrypting { stream_seal(input, output, &key) } else { stream_open(input, output, &key) };
+        or_exit(result, input);
+        return;
+    }
+    if !encrypting && custom {
+        usage_error("decrypt reads the cipher and KDF settings from the file");
     }
+    let data = or_exit(read_input(input), input);
+    let out = if encrypting {
+        seal(&data, key.as_bytes(), with_mode(cipher.unwrap_or(Cipher::ChaCha20Poly1305), mode, rounds), iterations)
+    } else {
+        open(&data, key.as_bytes())
+    };
+    write_output(output, &or_exit(out, input));
 }
 
-// build huffman
-fn build_huffman_tree(data: &[u8]) -> Node {
-    let mut freq_map = HashMap::new();
-    for &b in data {
-        *freq_map.entry(b).or_insert(0u32) += 1;
+fn cmd_pack(args: &[String], packing: bool) {
+    let cmd = if packing { "pack" } else { "unpack" };
+    let (mut paths, mut output, mut key) = (Vec::new(), None, None);
+    let mut opts = CompressOptions::default();
+    let (mut cipher, mut mode, mut rounds) = (Cipher::ChaCha20Poly1305, None, None);
+    let (mut iterations, mut dictionary) = (DEFAULT_KDF_ITERATIONS, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match (arg.as_str(), packing) {
+            ("-o" | "--output", _) => output = iter.next(),
+            ("--key" | "--key-name" | "--key-from" | "--key-file", _) => key = Some(key_option(arg, iter.next())),
+            ("--dict", _) => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                dictionary = Some(or_exit(fs::read(path), path));
+            }
+            ("--best", true) => opts.set_level(CompressionLevel::Best),
+            ("--level", true) => opts.set_level(level_option(iter.next())),
+            ("--long", true) => opts.long_range = true,
+            ("--cipher", true) => cipher = cipher_option(iter.next()),
+            ("--mode", true) => mode = Some(mode_option(iter.next())),
+            ("--rounds", true) => rounds = Some(rounds_option(iter.next())),
+            ("--kdf-iterations", true) => iterations = kdf_iterations_option(iter.next()),
+            _ => paths.push(arg),
+        }
     }
-    if freq_map.len() == 1 {
-        // edge case: only one symbol
-        let (b, f) = freq_map.into_iter().next().unwrap();
-        return Node{ freq:f, byte:Some(b), left:None, right:None };
+    let (input, output) = input_output(&paths, output, cmd);
+    let Some(key) = key.or_else(env_key) else {
+        usage_error(&format!("{} needs --key, --key-name, --key-from, --key-file or $RSZIP_KEY", cmd))
+    };
+    let data = or_exit(read_input(input), input);
+    let out = if packing {
+        opts.dictionary = dictionary;
+        pack(&data, &opts, key.as_bytes(), with_mode(cipher, mode, rounds), iterations)
+    } else {
+        unpack(&data, key.as_bytes(), dictionary.as_deref())
+    };
+    write_output(output, &or_exit(out, input));
+}
+
+fn cmd_clip(args: &[String]) {
+    let compressing = match args.first().map(String::as_str) {
+        Some("--compress") => true,
+        Some("--decompress") => false,
+        _ => usage_error("clip needs --compress or --decompress"),
+    };
+    let content = clipboard_read().unwrap_or_else(|e| {
+        eprintln!("Cannot read clipboard: {}", e);
+        process::exit(1);
+    });
+    let result = if compressing {
+        base64_encode(&or_exit(compress(&content, &CompressOptions::default()), "clipboard")).into_bytes()
+    } else {
+        let text = String::from_utf8_lossy(&content);
+        let Some(blob) = base64_decode(&text) else {
+            eprintln!("Clipboard does not hold base64 data.");
+            process::exit(1);
+        };
+        or_exit(decompress(&blob, None), "clipboard")
+    };
+    if let Err(e) = clipboard_write(&result) {
+        eprintln!("Cannot write clipboard: {}", e);
+        process::exit(1);
     }
-    let mut heap = BinaryHeap::new();
-    for (b, f) in freq_map {
-        heap.push(Node{ freq:f, byte:Some(b), left:None, right:None });
+    println!("Clipboard: {} -> {} bytes", content.len(), result.len());
+}
+
+fn cmd_dump(args: &[String]) {
+    let full = args.iter().any(|a| a == "--all");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--all").collect();
+    let [path] = paths[..] else { usage_error("dump needs <file.rsz>") };
+    let filedata = or_exit(read_input(path), path);
+    or_exit(dump_file(&filedata, full), path);
+}
+
+fn cmd_info(args: &[String]) {
+    let (mut paths, mut dictionary) = (Vec::new(), None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dict" => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                dictionary = Some(or_exit(fs::read(path), path));
+            }
+            _ => paths.push(arg),
+        }
     }
-    while heap.len() > 1 {
-        let a = heap.pop().unwrap();
-        let b = heap.pop().unwrap();
-        heap.push(Node{ freq:a.freq+b.freq, byte:None, left:Some(Box::new(a)), right:Some(Box::new(b)) });
+    let [path] = paths[..] else { usage_error("info needs <file.rsz> [--dict <file>]") };
+    let filedata = or_exit(open_input(path), path);
+    for line in or_exit(file_info(&filedata, dictionary.as_deref()), path) {
+        println!("{}", line);
     }
-    heap.pop().unwrap()
 }
 
-fn build_codes(node: &Node, prefix: Vec<bool>, table: &mut HashMap<u8, Vec<bool>>) {
-    if let Some(b) = node.byte {
-        table.insert(b, prefix);
-    } else {
-        if let Some(ref l) = node.left {
-            let mut p = prefix.clone();
-            p.push(false);
-            build_codes(l, p, table);
+// What `rszip info` shows: sizes and ratio, the codecs and flags from the
+// header, the block layout, and how long decoding the file takes, which is
+// skipped when it needs a dictionary that wasn't given. Archives get their
+// entry counts instead, as decoding entries may need a password.
+fn file_info(filedata: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<String>, RsZipError> {
+    let field = |name: &str, value: String| format!("{:<12}{}", format!("{}:", name), value);
+    let packed = filedata.len() as u64;
+    if is_archive(filedata) {
+        let archive = Archive::read(filedata)?;
+        let entries = archive.entries();
+        let files = entries.iter().filter(|e| e.kind == EntryKind::File).count();
+        let encrypted = entries.iter().filter(|e| e.is_encrypted()).count();
+        let total: u64 = entries.iter().map(|e| e.size as u64).sum();
+        let kind = if is_appendable(filedata) { "rszip archive, appendable" } else { "rszip archive" };
+        let mut lines = vec![
+            field("format", kind.to_string()),
+            field("entries", format!("{} ({} files, {} encrypted)", entries.len(), files, encrypted)),
+            field("size", ratio_summary(total, packed)),
+        ];
+        let (mut chunks, mut refs, mut chunk_bytes) = (0, 0, 0);
+        for chunk in archive.chunks() {
+            chunks += 1;
+            refs += chunk.refs as u64;
+            chunk_bytes += chunk.packed_len() as u64;
         }
-        if let Some(ref r) = node.right {
-            let mut p = prefix.clone();
-            p.push(true);
-            build_codes(r, p, table);
+        if chunks > 0 {
+            let shared = format!("{} unique, {} references, {} bytes packed", chunks, refs, chunk_bytes);
+            lines.push(field("chunks", shared));
         }
+        return Ok(lines);
     }
+    if gzip::is_gzip(filedata) {
+        let started = Instant::now();
+        let original = gzip::decompress(filedata)?.len() as u64;
+        let elapsed = started.elapsed();
+        return Ok(vec![field("format", "gzip".into()), field("size", ratio_summary(original, packed)),
+            field("decode", rate_summary(original, elapsed))]);
+    }
+    let mut r = Reader::new(filedata);
+    let header = Header::read(&mut r)?;
+    let body = r.rest();
+    let mut lines = vec![field("format", format!("rszip version {}", header.version))];
+    let mut codecs = describe_codecs(header.codecs, header.flags);
+    if header.flags & FLAG_CODEC != 0 {
+        let name = body.first().and_then(|&id| codecs::by_id(id)).map_or("unregistered".to_string(), |c| c.name().to_string());
+        codecs = format!("{} ({})", codecs, name);
+    }
+    lines.push(field("codecs", codecs));
+    let mut flags = Vec::new();
+    if let Some(crc) = header.crc32 {
+        flags.push(format!("crc32 {:08x}", crc));
+    }
+    for (flag, name) in [(FLAG_ORDER1, "order-1 model"), (FLAG_CODEC, "registered codec"), (FLAG_BLOCKS, "independent blocks"),
+        (FLAG_SEEK_TABLE, "seek table")] {
+        if header.flags & flag != 0 {
+            flags.push(name.to_string());
+        }
+    }
+    lines.push(field("flags", if flags.is_empty() { "none".to_string() } else { flags.join(", ") }));
+    lines.push(field("filter", header.filter.describe()));
+    lines.push(field("dictionary", if header.dict_id == 0 { "none".to_string() } else { format!("{:08x}", header.dict_id) }));
+    if header.flags & FLAG_BLOCKS != 0 {
+        let (block_size, sizes) = blocks::layout(&header, body)?;
+        let (smallest, largest) = (sizes.iter().min().copied().unwrap_or(0), sizes.iter().max().copied().unwrap_or(0));
+        lines.push(field("blocks", format!("{} of {} bytes, each packed into {} to {} bytes", sizes.len(), block_size, smallest, largest)));
+    }
+    if header.dict_id != 0 && dictionary.is_none() {
+        lines.push(field("decode", "skipped, the dictionary is needed (--dict)".into()));
+        return Ok(lines);
+    }
+    let started = Instant::now();
+    let original = decompress_threaded(filedata, dictionary, 0)?.len() as u64;
+    let elapsed = started.elapsed();
+    lines.insert(1, field("size", ratio_summary(original, packed)));
+    lines.push(field("decode", rate_summary(original, elapsed)));
+    Ok(lines)
 }
 
-fn huffman_compress(data: &[u8]) -> (Vec<u8>, Node, usize) {
-    let tree = build_huffman_tree(data);
-    let mut table = HashMap::new();
-    build_codes(&tree, Vec::new(), &mut table);
-    let mut bits = Vec::new();
-    for &b in data {
-        if let Some(code) = table.get(&b) {
-            bits.extend_from_slice(code);
+// `diff <old> <new> <patch>`: a delta that turns old into new
+fn cmd_diff(args: &[String]) {
+    let mut opts = CompressOptions::default();
+    let mut paths = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--fast" => opts.set_level(CompressionLevel::Fast),
+            "--best" => opts.set_level(CompressionLevel::Best),
+            "--level" => opts.set_level(level_option(iter.next())),
+            _ => paths.push(arg),
         }
     }
-    let mut out = Vec::new();
-    let mut current = 0u8;
-    let mut count = 0;
-    for bit in bits {
-        current <<= 1;
-        if bit { current |= 1; }
-        count += 1;
-        if count == 8 {
-            out.push(current);
-            current = 0;
-            count = 0;
+    let [old, new, patch] = paths[..] else { usage_error("diff needs <old> <new> <patch.rsz> [--fast | --best | --level N]") };
+    let started = Instant::now();
+    let (old_data, new_data) = (or_exit(open_input(old), old), or_exit(open_input(new), new));
+    let delta = or_exit(delta::diff(&old_data, &new_data, &opts), new);
+    write_output(patch, &delta);
+    eprintln!("{}", compress_line(new, patch, new_data.len() as u64, delta.len() as u64, started.elapsed(), false));
+}
+
+// `patch <old> <patch> <new>`: the new file back from the old one
+fn cmd_patch(args: &[String]) {
+    let [old, patch, new] = args else { usage_error("patch needs <old> <patch.rsz> <new>") };
+    let (old_data, delta) = (or_exit(open_input(old), old), or_exit(open_input(patch), patch));
+    write_output(new, &or_exit(delta::patch(&old_data, &delta), patch));
+}
+
+fn cmd_upgrade(args: &[String]) {
+    let [input, output] = args else { usage_error("upgrade needs <old.rsz> <new.rsz>") };
+    let upgraded = or_exit(upgrade_legacy(&or_exit(read_input(input), input)), input);
+    write_output(output, &upgraded);
+}
+
+fn cmd_czf(args: &[String]) {
+    let [output, inputs @ ..] = args else { usage_error("czf needs <out.tar.rsz> <paths...>") };
+    if inputs.is_empty() {
+        usage_error("czf needs at least one path to archive");
+    }
+    let tar = or_exit(write_tar(inputs, TarWriter::new(Vec::new())), output);
+    write_output(output, &or_exit(compress(&tar, &CompressOptions::default()), output));
+    println!("Archived {} tar bytes into {}", tar.len(), output);
+}
+
+// tars `inputs` into `out`, each stored under its last path component
+fn write_tar<W: Write>(inputs: &[String], mut tar: TarWriter<W>) -> Result<W, RsZipError> {
+    for input in inputs {
+        let path = Path::new(input);
+        if let Err(e) = tar.append_path(&long_path(path), &tar_root_name(path)) {
+            eprintln!("Cannot archive {}: {}", input, e);
+            process::exit(1);
         }
     }
-    if count > 0 {
-        current <<= 8 - count;
-        out.push(current);
+    tar.finish()
+}
+
+// czf with the block stream of `compress --stream`: the tree is compressed
+// as it is walked, so memory stays flat however large it is
+fn cmd_ctar(args: &[String]) {
+    let [inputs @ .., output] = args else { usage_error("ctar needs <paths...> <out.tar.rsz>") };
+    if inputs.is_empty() {
+        usage_error("ctar needs at least one path to archive");
     }
-    (out, tree, data.len())
+    let sink = or_exit(open_stream_output(output), output);
+    let encoder = Lz77Encoder::new(HuffmanEncoder::new(Throttled(sink)), 3);
+    or_exit(write_tar(inputs, TarWriter::new(encoder)).and_then(|e| Ok(e.finish()?.finish()?)), output);
+}
+
+// unpacks what ctar wrote (or `compress --stream` of any tarball) into <dir>
+fn cmd_xtar(args: &[String]) {
+    let [input, dir] = args else { usage_error("xtar needs <in.tar.rsz> <dir>") };
+    let source = or_exit(open_stream_input(input), input);
+    let decoder = Lz77Decoder::new(or_exit(open_entropy_decoder(Throttled(source)), input));
+    or_exit(fs::create_dir_all(long_path(Path::new(dir))), dir);
+    let extracted = or_exit(tar::unpack(decoder, &long_path(Path::new(dir))), input);
+    println!("Extracted {} entries into {}", extracted, dir);
 }
 
-fn huffman_decompress(data: &[u8], tree: &Node, orig_len: usize) -> Vec<u8> {
-    let mut bits = Vec::<bool>::new();
-    for &byte in data {
-        for i in (0..8).rev() {
-            bits.push(((byte >> i) & 1) == 1);
+fn cmd_add(args: &[String]) {
+    let (mut paths, mut encrypt, mut key, mut dedup) = (Vec::new(), false, None, false);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--encrypt" => encrypt = true,
+            "--dedup" => dedup = true,
+            "--key" | "--key-name" | "--key-from" | "--key-file" => key = Some(key_option(arg, iter.next())),
+            _ => paths.push(arg),
         }
     }
-    let mut out = Vec::new();
-    let mut node = tree;
-    for bit in bits {
-        node = if !bit { node.left.as_ref().unwrap() } else { node.right.as_ref().unwrap() };
-        if let Some(b) = node.byte {
-            out.push(b);
-            if out.len() == orig_len {
-                break;
-            }
-            node = tree;
+    let [archive_path, inputs @ ..] = &paths[..] else {
+        usage_error("add needs <archive.rsz> <paths...> [--encrypt | --dedup]")
+    };
+    if inputs.is_empty() {
+        usage_error("add needs at least one path to store");
+    }
+    if dedup && (encrypt || key.is_some()) {
+        usage_error("--dedup and --encrypt don't combine: chunks shared between files can't be sealed per file");
+    }
+    let mut added = Archive::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let opts = CompressOptions::default();
+        or_exit(archive_add_path(&mut added, &long_path(path), &tar_root_name(path), &opts, dedup), input);
+    }
+    // a key alone asks for encryption too
+    if encrypt || key.is_some() {
+        let key = key.unwrap_or_else(ask_new_key);
+        let files: Vec<String> = added.entries().iter().filter(|e| e.kind == EntryKind::File).map(|e| e.path.clone()).collect();
+        for path in files {
+            or_exit(added.encrypt(&path, key.as_bytes(), DEFAULT_KDF_ITERATIONS), &path);
         }
     }
-    out
+    let (before, after) = edit_archive(archive_path, false, |archive| archive.extend(added));
+    println!("{} entries in {} ({} new)", after, archive_path, after - before);
 }
 
-// serialize tree: pre-order traversal
-fn serialize_tree(node: &Node, out: &mut Vec<u8>) {
-    if let Some(b) = node.byte {
-        out.push(1);
-        out.push(b);
+// Applies `edit` to the archive at `archive_path`: in place when its table
+// is last, otherwise, for older archives, a new one, or with `vacuum` to
+// drop the dead space in-place edits leave, by writing it whole. Returns the
+// entry counts before and after.
+fn edit_archive(archive_path: &str, vacuum: bool, edit: impl FnOnce(&mut Archive) -> rszip::Result<()>) -> (usize, usize) {
+    let local = long_path(Path::new(archive_path));
+    let mut start = Vec::new();
+    match fs::File::open(&local) {
+        Ok(file) => {
+            or_exit(file.take(8).read_to_end(&mut start), archive_path);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => or_exit(Err(e), archive_path),
+    }
+    if is_appendable(&start) && !vacuum {
+        let mut file = or_exit(fs::OpenOptions::new().read(true).write(true).open(&local), archive_path);
+        return or_exit(Archive::update(&mut file, edit), archive_path);
+    }
+    let mut archive = if start.is_empty() {
+        Archive::new()
     } else {
-        out.push(0);
-        serialize_tree(node.left.as_ref().unwrap(), out);
-        serialize_tree(node.right.as_ref().unwrap(), out);
+        or_exit(Archive::read(&or_exit(fs::read(&local), archive_path)), archive_path)
+    };
+    let before = archive.entries().len();
+    or_exit(edit(&mut archive), archive_path);
+    write_output(archive_path, &or_exit(archive.to_bytes(), archive_path));
+    (before, archive.entries().len())
+}
+
+fn cmd_rm(args: &[String]) {
+    let vacuum = args.iter().any(|a| a == "--vacuum");
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--vacuum").collect();
+    let [archive_path, paths @ ..] = &args[..] else { usage_error("rm needs <archive.rsz> <paths...>") };
+    if paths.is_empty() {
+        usage_error("rm needs at least one path to remove");
     }
+    or_exit(fs::metadata(long_path(Path::new(archive_path.as_str()))), archive_path);
+    let (before, after) = edit_archive(archive_path, vacuum, |archive| paths.iter().try_for_each(|p| archive.remove(p).map(drop)));
+    println!("{} entries in {} ({} removed)", after, archive_path, before - after);
 }
-fn deserialize_tree(data: &[u8], idx: &mut usize) -> Node {
-    let flag = data[*idx]; *idx += 1;
-    if flag == 1 {
-        let b = data[*idx]; *idx += 1;
-        Node { freq:0, byte:Some(b), left:None, right:None }
-    } else {
-        let left = deserialize_tree(data, idx);
-        let right = deserialize_tree(data, idx);
-        Node { freq:0, byte:None, left:Some(Box::new(left)), right:Some(Box::new(right)) }
+
+fn cmd_mv(args: &[String]) {
+    let vacuum = args.iter().any(|a| a == "--vacuum");
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--vacuum").collect();
+    let [archive_path, from, to] = &args[..] else { usage_error("mv needs <archive.rsz> <old> <new>") };
+    or_exit(fs::metadata(long_path(Path::new(archive_path.as_str()))), archive_path);
+    let mut moved = 0;
+    edit_archive(archive_path, vacuum, |archive| archive.rename(from, to).map(|n| moved = n));
+    println!("{} entr{} renamed in {}", moved, if moved == 1 { "y" } else { "ies" }, archive_path);
+}
+
+fn cmd_list(args: &[String]) {
+    let [archive_path] = args else { usage_error("list needs <archive.rsz>") };
+    let archive = or_exit(Archive::read(&or_exit(read_input(archive_path), archive_path)), archive_path);
+    for entry in archive.entries() {
+        let (method, name) = match (entry.kind, entry.method) {
+            (EntryKind::Dir, _) => ("dir", format!("{}/", entry.path)),
+            (EntryKind::Symlink, _) => {
+                let target = or_exit(archive.extract(entry), &entry.path);
+                ("link", format!("{} -> {}", entry.path, String::from_utf8_lossy(&target)))
+            }
+            (EntryKind::File, Method::Stored) => ("stored", entry.path.clone()),
+            (EntryKind::File, Method::Compressed) => ("rszip", entry.path.clone()),
+            (EntryKind::File, Method::Chunked) => ("chunks", format!("{} ({} chunks)", entry.path, entry.chunk_count())),
+        };
+        let name = if entry.is_encrypted() { format!("{} (encrypted)", name) } else { name };
+        println!("{:>10} {:>10} {:<6} {:>10}  {}", entry.size, entry.packed_len(), method, entry.offset, name);
     }
 }
 
-// ======================
-// LZ77 IMPLEMENTATION
-// ======================
-fn lz77_compress(data: &[u8]) -> Vec<(usize, usize, u8)> {
-    let mut out = Vec::new();
-    let window_size = 1024;
-    let mut i = 0;
-    while i < data.len() {
-        let mut match_len = 0;
-        let mut match_dist = 0;
-        let search_start = if i >= window_size { i - window_size } else { 0 };
-        for j in search_start..i {
-            let mut k = 0;
-            while i + k < data.len() && data[j + k] == data[i + k] {
-                k += 1;
-            }
-            if k > match_len {
-                match_len = k;
-                match_dist = i - j;
-            }
-        }
-        if match_len >= 3 {
-            let next = if i + match_len < data.len() { data[i + match_len] } else { 0 };
-            out.push((match_dist, match_len, next));
-            i += match_len + 1;
-        } else {
-            out.push((0, 0, data[i]));
-            i += 1;
+// What `rszip test` found in one file: a summary when it is sound, or each
+// damaged part. Archive entries and independent blocks are checked one by
+// one, so a bad one is named rather than failing the whole file.
+fn test_data(data: &[u8], dictionary: Option<&[u8]>) -> Result<String, Vec<String>> {
+    if is_archive(data) {
+        let archive = Archive::read(data).map_err(|e| vec![format!("archive table: {}", e)])?;
+        let files: Vec<_> = archive.entries().iter().filter(|e| e.kind == EntryKind::File).collect();
+        // checking these would take the password, which test doesn't ask for
+        let (sealed, plain): (Vec<_>, Vec<_>) = files.iter().copied().partition(|e| e.is_encrypted());
+        let damaged: Vec<String> =
+            plain.iter().filter_map(|e| archive.extract(e).err().map(|err| format!("{}: {}", e.path, err))).collect();
+        let mut summary = format!("OK, {} file{}", files.len(), if files.len() == 1 { "" } else { "s" });
+        if !sealed.is_empty() {
+            summary += &format!(" ({} encrypted, not checked)", sealed.len());
         }
+        return if damaged.is_empty() { Ok(summary) } else { Err(damaged) };
+    }
+    if is_sealed(data) {
+        return Ok("encrypted; only `decrypt` or `unpack` with the key can check it".to_string());
+    }
+    if gzip::is_gzip(data) {
+        return gzip::decompress(data).map(|out| format!("OK, {} bytes", out.len())).map_err(|e| vec![e.to_string()]);
+    }
+    match decompress_recover(data, dictionary, 0) {
+        Ok((out, lost)) if lost.is_empty() => Ok(format!("OK, {} bytes", out.len())),
+        Ok((_, lost)) => Err(lost.iter().map(|i| format!("block {}", i)).collect()),
+        Err(e) => Err(vec![e.to_string()]),
     }
-    out
 }
-fn lz77_decompress(tokens: &[(usize, usize, u8)]) -> Vec<u8> {
-    let mut out = Vec::new();
-    for &(dist, len, next) in tokens {
-        if dist == 0 && len == 0 {
-            out.push(next);
+
+fn cmd_test(args: &[String]) {
+    let (mut paths, mut dictionary, mut stream) = (Vec::new(), None, false);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dict" => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                dictionary = Some(or_exit(fs::read(path), path));
+            }
+            "--stream" => stream = true,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    if paths.is_empty() {
+        usage_error("test needs <files...> [--dict <file>] [--stream]");
+    }
+    let mut damaged = 0;
+    for path in paths {
+        let result = if stream {
+            // streams carry no checksums; decoding them cleanly to the end is the test
+            open_stream_input(path)
+                .and_then(open_entropy_decoder)
+                .and_then(|decoder| io::copy(&mut Lz77Decoder::new(decoder), &mut io::sink()))
+                .map(|n| format!("OK, {} bytes", n))
+                .map_err(|e| vec![e.to_string()])
         } else {
-            let start = out.len() - dist;
-            for i in 0..len {
-                out.push(out[start + i]);
+            match open_input(path) {
+                Ok(data) => test_data(&data, dictionary.as_deref()),
+                Err(e) => Err(vec![e.to_string()]),
+            }
+        };
+        match result {
+            Ok(summary) => println!("{}: {}", path, summary),
+            Err(problems) => {
+                damaged += 1;
+                for problem in problems {
+                    println!("{}: DAMAGED {}", path, problem);
+                }
             }
-            out.push(next);
         }
     }
-    out
+    if damaged > 0 {
+        process::exit(1);
+    }
 }
 
-// helper to serialize/deserialize lz tokens
-fn serialize_lz(tokens: &[(usize, usize, u8)]) -> Vec<u8> {
-    let mut out = Vec::new();
-    let count = tokens.len() as u32;
-    out.extend_from_slice(&count.to_le_bytes());
-    for (d, l, n) in tokens {
-        out.extend_from_slice(&(*d as u32).to_le_bytes());
-        out.extend_from_slice(&(*l as u32).to_le_bytes());
-        out.push(*n);
+fn cmd_extract(args: &[String]) {
+    let mut names = Vec::new();
+    let mut dest = ".";
+    let (mut perms, mut key) = (true, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-C" => dest = iter.next().unwrap_or_else(|| usage_error("-C needs a directory")),
+            "--no-perms" => perms = false,
+            "--key" | "--key-name" | "--key-from" | "--key-file" => key = Some(key_option(arg, iter.next())),
+            _ => names.push(arg.as_str()),
+        }
     }
-    out
-}
-fn deserialize_lz(data: &[u8]) -> Vec<(usize, usize, u8)> {
-    let mut idx = 0;
-    let count = u32::from_le_bytes(data[idx..idx+4].try_into().unwrap()) as usize;
-    idx += 4;
-    let mut tokens = Vec::with_capacity(count);
-    for _ in 0..count {
-        let d = u32::from_le_bytes(data[idx..idx+4].try_into().unwrap()) as usize;
-        idx += 4;
-        let l = u32::from_le_bytes(data[idx..idx+4].try_into().unwrap()) as usize;
-        idx += 4;
-        let n = data[idx];
-        idx += 1;
-        tokens.push((d, l, n));
+    let [archive_path, wanted @ ..] = &names[..] else { usage_error("extract needs <archive.rsz> [entries...] [-C dir]") };
+    let archive = or_exit(Archive::read(&or_exit(read_input(archive_path), archive_path)), archive_path);
+    let wanted: Vec<String> = wanted.iter().map(|name| or_exit(normalize_path(name), name)).collect();
+    for name in &wanted {
+        if !archive.entries().iter().any(|e| archive_entry_matches(&e.path, name)) {
+            eprintln!("No entry named '{}' in {}.", name, archive_path);
+            process::exit(1);
+        }
     }
-    tokens
+    let extracted = or_exit(extract_archive(&archive, Path::new(dest), &wanted, perms, &mut key.into_iter().collect()), archive_path);
+    println!("Extracted {} entries into {}", extracted, dest);
 }
 
-// ======================
-// FEISTEL ENCRYPTION
-// ======================
-fn round_function(input: u32, key: u32) -> u32 {
-    let x = input.wrapping_add(key);
-    x.rotate_left(5) ^ (x >> 3)
+// `start-end`, end exclusive and optional (`start-` runs to the end); both
+// take K/M/G like sizes do
+fn parse_range(spec: &str) -> Option<std::ops::Range<usize>> {
+    let offset = |s: &str| if s == "0" { Some(0) } else { parse_size(s).map(|n| n as usize) };
+    let (start, end) = spec.split_once('-')?;
+    let end = if end.is_empty() { usize::MAX } else { offset(end)? };
+    Some(offset(start)?..end).filter(|r| r.start <= r.end)
 }
-fn feistel_encrypt_block(mut left: u32, mut right: u32, keys: &[u32]) -> (u32, u32) {
-    for &k in keys {
-        let f = round_function(right, k);
-        let new_left = right;
-        let new_right = left ^ f;
-        left = new_left;
-        right = new_right;
+
+// To stdout: a compressed file, or one entry of an archive, whole or a byte
+// range of it. Files written with --blocks decode only the blocks in range.
+fn cmd_cat(args: &[String]) {
+    let (mut names, mut range, mut dictionary, mut threads) = (Vec::new(), 0..usize::MAX, None, 0);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--range" => {
+                range = iter.next().and_then(|s| parse_range(s))
+                    .unwrap_or_else(|| usage_error("--range needs start-end, like 1M-2M or 4096-"));
+            }
+            "--dict" => {
+                let path = iter.next().unwrap_or_else(|| usage_error("--dict needs a file"));
+                dictionary = Some(or_exit(fs::read(path), path));
+            }
+            "--threads" => threads = threads_option(iter.next()),
+            _ => names.push(arg.as_str()),
+        }
     }
-    (left, right)
+    let (input, entry) = match names[..] {
+        [input] => (input, None),
+        [input, entry] => (input, Some(entry)),
+        _ => usage_error("cat needs <file.rsz> [entry] [--range start-end]"),
+    };
+    let filedata = or_exit(open_input(input), input);
+    let bytes = if is_archive(&filedata) {
+        let archive = or_exit(Archive::read(&filedata), input);
+        let Some(entry) = entry.and_then(|name| archive.find(name)).filter(|e| e.kind == EntryKind::File) else {
+            eprintln!("{} is an archive: name one of its files, see `rszip list {}`.", input, input);
+            process::exit(1);
+        };
+        or_exit(archive.extract_range(entry, range), input)
+    } else if entry.is_some() {
+        usage_error(&format!("{} is not an archive, so it has no entries", input))
+    } else {
+        or_exit(decompress_range(&filedata, range, dictionary.as_deref(), threads), input)
+    };
+    write_output("-", &bytes);
 }
-fn feistel_decrypt_block(mut left: u32, mut right: u32, keys: &[u32]) -> (u32, u32) {
-    for &k in keys.iter().rev() {
-        let f = round_function(left, k);
-        let new_right = left;
-        let new_left = right ^ f;
-        left = new_left;
-        right = new_right;
+
+fn cmd_repo(args: &[String]) {
+    let fail = |what: &str, e: RsZipError| -> ! {
+        eprintln!("{} failed: {}", what, e);
+        process::exit(1);
+    };
+    let open = |dir: &str| Repo::open(&long_path(Path::new(dir))).unwrap_or_else(|e| fail(&format!("Opening {}", dir), e));
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["init", dir] => {
+            Repo::init(&long_path(Path::new(dir))).unwrap_or_else(|e| fail("repo init", e));
+            println!("Initialised repository in {}", dir);
+        }
+        ["add", dir, ref rest @ ..] => {
+            let repo = open(dir);
+            let mut name = None;
+            let mut opts = CompressOptions::default();
+            let mut inputs = Vec::new();
+            let mut iter = rest.iter();
+            while let Some(&arg) = iter.next() {
+                match arg {
+                    "--name" => name = iter.next().copied(),
+                    "--progress" => opts.on_progress = Some(progress_printer()),
+                    _ => inputs.push(arg),
+                }
+            }
+            if inputs.is_empty() {
+                usage_error("repo add needs <repo> <paths...> [--name <snapshot>]");
+            }
+            let name = name.map(str::to_string).unwrap_or_else(|| {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+                now.as_secs().to_string()
+            });
+            if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+                usage_error("snapshot names must be plain file names");
+            }
+            if repo.snapshot_path(&name).exists() {
+                eprintln!("Snapshot {} already exists.", name);
+                process::exit(1);
+            }
+            let (mut entries, mut stats) = (Vec::new(), AddStats::default());
+            for input in inputs {
+                let path = Path::new(input);
+                repo.add_path(&long_path(path), &tar_root_name(path), &opts, &mut entries, &mut stats)
+                    .unwrap_or_else(|e| fail(&format!("Adding {}", input), e));
+            }
+            repo.write_snapshot(&name, &entries).unwrap_or_else(|e| fail("Writing snapshot", e));
+            println!(
+                "Snapshot {}: {} entries, {} bytes, {} new chunks ({} bytes stored)",
+                name, stats.entries, stats.bytes, stats.new_chunks, stats.stored_bytes
+            );
+        }
+        ["restore", dir, snapshot, dest] => {
+            let count = open(dir).restore(snapshot, &long_path(Path::new(dest))).unwrap_or_else(|e| fail("repo restore", e));
+            println!("Restored {} entries into {}", count, dest);
+        }
+        ["gc", dir] => {
+            let (files, bytes) = open(dir).gc().unwrap_or_else(|e| fail("repo gc", e));
+            println!("Removed {} unreferenced chunks ({} bytes)", files, bytes);
+        }
+        ["check", dir] => {
+            let (count, problems) = open(dir).check().unwrap_or_else(|e| fail("repo check", e));
+            for problem in &problems {
+                eprintln!("{}", problem);
+            }
+            if !problems.is_empty() {
+                eprintln!("{} problems in {}", problems.len(), dir);
+                process::exit(1);
+            }
+            println!("All {} chunks verified", count);
+        }
+        ["snapshots", dir] => {
+            let summaries = open(dir).summarize().unwrap_or_else(|e| fail("repo snapshots", e));
+            println!("{:<24} {:>8} {:>14} {:>14} {:>14}", "snapshot", "entries", "size", "referenced", "added");
+            let (mut logical, mut stored) = (0, 0);
+            for s in &summaries {
+                println!("{:<24} {:>8} {:>14} {:>14} {:>14}", s.name, s.entries, s.bytes, s.referenced, s.added);
+                logical += s.bytes;
+                stored += s.added;
+            }
+            if logical > 0 {
+                println!(
+                    "{} bytes in {} snapshots stored as {} bytes ({:.1}% saved)",
+                    logical, summaries.len(), stored, 100.0 - stored as f64 * 100.0 / logical as f64
+                );
+            }
+        }
+        ["diff", dir, a, b] => {
+            let repo = open(dir);
+            let old = repo.read_snapshot(a).unwrap_or_else(|e| fail(&format!("Reading snapshot {}", a), e));
+            let new = repo.read_snapshot(b).unwrap_or_else(|e| fail(&format!("Reading snapshot {}", b), e));
+            for (marker, path, detail) in diff_snapshots(&old, &new) {
+                println!("{} {}  ({})", marker, path, detail);
+            }
+        }
+        _ => usage_error("repo needs init, add, restore, gc, check, snapshots or diff"),
     }
-    (left, right)
 }
-fn derive_keys(key_material: &[u8]) -> Vec<u32> {
-    let mut keys = Vec::new();
-    for chunk in key_material.chunks(4) {
-        let mut kbytes = [0u8; 4];
-        for (i, &b) in chunk.iter().enumerate() {
-            kbytes[i] = b;
+
+fn cmd_daemon(args: &[String]) {
+    #[cfg(unix)]
+    {
+        let socket = match args {
+            [] => or_exit(default_socket_path(), "daemon socket"),
+            [flag, path] if flag == "--socket" => PathBuf::from(path),
+            _ => usage_error("daemon takes only --socket <path>"),
+        };
+        if let Err(e) = run_daemon(&socket) {
+            eprintln!("Daemon failed: {}", e);
+            process::exit(1);
         }
-        keys.push(u32::from_le_bytes(kbytes));
     }
-    keys
+    #[cfg(not(unix))]
+    {
+        let _ = args;
+        eprintln!("The daemon needs Unix domain sockets, which this platform lacks.");
+        process::exit(1);
+    }
 }
-fn feistel_encrypt(data: &[u8], key_material: &[u8]) -> Vec<u8> {
-    let keys = derive_keys(key_material);
-    let mut out = Vec::new();
-    for chunk in data.chunks(8) {
-        let mut block = [0u8; 8];
-        for (i, &b) in chunk.iter().enumerate() {
-            block[i] = b;
+
+fn cmd_serve_api(args: &[String]) {
+    let (mut bind, mut port, mut max_body, mut key) = ("127.0.0.1", 7070, API_MAX_BODY, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--port" => port = iter.next().and_then(|p| p.parse().ok()).unwrap_or_else(|| usage_error("--port needs a number")),
+            "--bind" => bind = iter.next().unwrap_or_else(|| usage_error("--bind needs an address")),
+            "--max-body" => {
+                max_body = iter.next().and_then(|n| parse_size(n)).unwrap_or_else(|| usage_error("--max-body needs a size"));
+            }
+            "--key" | "--key-name" | "--key-from" | "--key-file" => {
+                key = Some(frame_key(&key_option(arg, iter.next()), FRAME_LABEL_API));
+            }
+            other => usage_error(&format!("Unknown serve-api option: {}", other)),
         }
-        let left = u32::from_le_bytes(block[0..4].try_into().unwrap());
-        let right = u32::from_le_bytes(block[4..8].try_into().unwrap());
-        let (el, er) = feistel_encrypt_block(left, right, &keys);
-        out.extend_from_slice(&el.to_le_bytes());
-        out.extend_from_slice(&er.to_le_bytes());
     }
-    out
+    if let Err(e) = run_api(bind, port, ApiConfig { max_body, key }) {
+        eprintln!("API server failed: {}", e);
+        process::exit(1);
+    }
 }
-fn feistel_decrypt(data: &[u8], key_material: &[u8]) -> Vec<u8> {
-    let keys = derive_keys(key_material);
-    let mut out = Vec::new();
-    for chunk in data.chunks(8) {
-        let mut block = [0u8; 8];
-        for (i, &b) in chunk.iter().enumerate() {
-            block[i] = b;
+
+fn key_from_or_exit(spec: &str) -> SecretKey {
+    let Some((service, account)) = parse_keychain_ref(spec) else {
+        usage_error("--key-from expects keychain:<service>/<account>")
+    };
+    let key = keychain_get(service, account).unwrap_or_else(|e| {
+        eprintln!("Cannot read {} from the OS keychain: {}", spec, e);
+        process::exit(1);
+    });
+    SecretKey::from(key)
+}
+
+fn cmd_keychain(args: &[String]) {
+    let [op, entry] = args else { usage_error("keychain needs set <service>/<account>") };
+    let spec = format!("keychain:{}", entry);
+    let Some((service, account)) = parse_keychain_ref(&spec).filter(|_| op == "set") else {
+        usage_error("keychain needs set <service>/<account>")
+    };
+    let secret = prompt_new_secret(&format!("Key for {}: ", entry));
+    let Ok(secret) = std::str::from_utf8(secret.as_bytes()) else { usage_error("keychain keys must be UTF-8 text") };
+    if let Err(e) = keychain_set(service, account, secret) {
+        eprintln!("Cannot store key in the OS keychain: {}", e);
+        process::exit(1);
+    }
+}
+
+// a fresh key file, never over an existing one: whatever that file
+// encrypted would be lost with it
+fn cmd_keygen(args: &[String]) {
+    let [path] = args else { usage_error("keygen needs <key file>") };
+    let key = or_exit(generate_key_file(), path);
+    let mut new_file = fs::OpenOptions::new();
+    new_file.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        new_file.mode(0o600);
+    }
+    or_exit(new_file.open(path).and_then(|mut file| file.write_all(key.as_bytes())), path);
+    println!("Wrote a 256-bit key to {}. Keep a copy: nothing encrypted with it opens without it.", path);
+}
+
+fn cmd_manifest(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut output = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = iter.next(),
+            _ => paths.push(arg),
         }
-        let left = u32::from_le_bytes(block[0..4].try_into().unwrap());
-        let right = u32::from_le_bytes(block[4..8].try_into().unwrap());
-        let (dl, dr) = feistel_decrypt_block(left, right, &keys);
-        out.extend_from_slice(&dl.to_le_bytes());
-        out.extend_from_slice(&dr.to_le_bytes());
     }
-    out
+    let [archive] = paths[..] else { usage_error("manifest needs <archive.rsz> [-o manifest.json]") };
+    let contents = or_exit(decompress(&or_exit(read_input(archive), archive), None), archive);
+    let name = Path::new(archive).file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    let Some(manifest) = build_manifest(&contents, name.strip_suffix(".rsz").unwrap_or(&name)) else {
+        eprintln!("{} holds a damaged tar archive.", archive);
+        process::exit(1);
+    };
+    match output {
+        Some(dest) => write_output(dest, manifest.as_bytes()),
+        None => print!("{}", manifest),
+    }
 }
 
-// ======================
-// Rs-Zip CLI
-// ======================
-fn main() {
-    loop {
-        println!("\n=== Rs-Zip CLI Tool ===");
-        println!("1) Compress file");
-        println!("2) Decompress file");
-        println!("3) Encrypt file");
-        println!("4) Decrypt file");
-        println!("5) Exit");
-        print!("Choose option: ");
-        io::stdout().flush().unwrap();
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice).unwrap();
-        match choice.trim() {
-            "1" => {
-                let (input, output) = ask_paths();
-                let data = fs::read(&input).expect("Failed to read input");
-                let tokens = lz77_compress(&data);
-                let lz_serial = serialize_lz(&tokens);
-                let (huff, tree, orig_len) = huffman_compress(&lz_serial);
-
-                let mut tree_bytes = Vec::new();
-                serialize_tree(&tree, &mut tree_bytes);
-
-                let mut final_out = Vec::new();
-                final_out.extend_from_slice(&(orig_len as u32).to_le_bytes());
-                final_out.extend_from_slice(&(tree_bytes.len() as u32).to_le_bytes());
-                final_out.extend_from_slice(&tree_bytes);
-                final_out.extend_from_slice(&huff);
-
-                fs::write(&output, final_out).unwrap();
-                println!("Compressed successfully!");
-                pause();
+// like `cryptsetup benchmark`: in-memory throughput of each primitive, and
+// the PBKDF2 iteration count that takes `--target-ms` on this machine
+fn cmd_bench_crypto(args: &[String]) {
+    let target_ms: u64 = match args {
+        [] => 250,
+        [flag, ms] if flag == "--target-ms" => ms.parse().unwrap_or_else(|_| usage_error("--target-ms needs a number")),
+        _ => usage_error("bench-crypto takes only --target-ms <ms>"),
+    };
+    let data: Vec<u8> = (0..16u32 << 20).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+    let mib_per_s = |start: Instant| (data.len() as f64 / (1 << 20) as f64) / start.elapsed().as_secs_f64();
+    println!("# Tests are approximate, using memory only (no storage I/O).");
+
+    let key = b"bench-crypto-key";
+    let start = Instant::now();
+    // a non-empty key and whole blocks, so neither call can fail
+    let encrypted = feistel_encrypt(&data, key, KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS)).unwrap();
+    let enc = mib_per_s(start);
+    let start = Instant::now();
+    feistel_decrypt(&encrypted, key, KeySchedule::Expanded(DEFAULT_FEISTEL_ROUNDS)).unwrap();
+    println!("{:<20} {:>9.1} MiB/s encryption {:>9.1} MiB/s decryption", "feistel", enc, mib_per_s(start));
+
+    let start = Instant::now();
+    chacha20_poly1305_seal(&[7; 32], &[0; 12], b"", &data);
+    println!("{:<20} {:>9.1} MiB/s", "chacha20-poly1305", mib_per_s(start));
+
+    let start = Instant::now();
+    sha256(&data);
+    println!("{:<20} {:>9.1} MiB/s", "sha256", mib_per_s(start));
+
+    // grow the trial until it is long enough to time reliably
+    let mut iterations = 1000u32;
+    let per_second = loop {
+        let start = Instant::now();
+        pbkdf2_sha256(b"password", b"bench-crypto-salt", iterations, 32);
+        let elapsed = start.elapsed();
+        if elapsed >= Duration::from_millis(100) || iterations >= 1 << 30 {
+            break iterations as f64 / elapsed.as_secs_f64();
+        }
+        iterations *= 4;
+    };
+    println!("{:<20} {:>9.0} iterations per second for 256-bit key", "pbkdf2-sha256", per_second);
+    let suggested = (per_second * target_ms as f64 / 1000.0).max(1.0) as u64;
+    println!("Suggested: pbkdf2-sha256 with {} iterations for a {} ms unlock", suggested, target_ms);
+}
+
+// Huffman, adaptive Huffman and the range coder behind the same first stage: sizes, and
+// in-memory compression / decompression speed. Without files it runs on the
+// selftest's text, runs and random samples.
+fn cmd_bench_entropy(args: &[String]) {
+    let inputs: Vec<(String, Vec<u8>)> = if args.is_empty() {
+        selftest_corpora().into_iter()
+            .filter(|(name, _)| ["text", "runs", "random"].contains(name))
+            .map(|(name, data)| (name.to_string(), data))
+            .collect()
+    } else {
+        args.iter().map(|path| (path.clone(), or_exit(read_input(path), path))).collect()
+    };
+    let mib_per_s = |len: usize, start: Instant| (len as f64 / (1 << 20) as f64) / start.elapsed().as_secs_f64();
+    println!("# Tests are approximate, using memory only (no storage I/O).");
+    let coders = [
+        ("huffman", Entropy::Huffman, ContextModel::Order0),
+        ("range", Entropy::Range, ContextModel::Order0),
+        ("range o1", Entropy::Range, ContextModel::Order1),
+        ("adaptive", Entropy::Adaptive, ContextModel::Order0),
+    ];
+    print!("{:<20} {:<5}", "input", "algo");
+    for (label, _, _) in coders {
+        print!(" {:>10} {:>21}", label, "MiB/s comp / decomp");
+    }
+    println!();
+    for (name, data) in &inputs {
+        for algo in [Algorithm::Lz77, Algorithm::Bwt] {
+            let mut row = format!("{:<20} {:<5}", name, format!("{:?}", algo).to_lowercase());
+            for (_, entropy, model) in coders {
+                let opts = CompressOptions { algo, entropy, model, ..Default::default() };
+                let start = Instant::now();
+                let packed = or_exit(compress(data, &opts), name);
+                let packing = mib_per_s(data.len(), start);
+                let start = Instant::now();
+                or_exit(decompress(&packed, None), name);
+                row += &format!(" {:>10} {:>9.1} / {:>9.1}", packed.len(), packing, mib_per_s(data.len(), start));
             }
-            "2" => {
-                let (input, output) = ask_paths();
-                let filedata = fs::read(&input).expect("Failed to read compressed file");
-                let mut idx = 0;
-                let orig_len = u32::from_le_bytes(filedata[idx..idx+4].try_into().unwrap()) as usize;
-                idx += 4;
-                let tree_size = u32::from_le_bytes(filedata[idx..idx+4].try_into().unwrap()) as usize;
-                idx += 4;
-                let tree_bytes = &filedata[idx..idx+tree_size];
-                idx += tree_size;
-                let huff_data = &filedata[idx..];
-
-                let mut tree_idx = 0;
-                let tree = deserialize_tree(tree_bytes, &mut tree_idx);
-                let lz_serial = huffman_decompress(huff_data, &tree, orig_len);
-                let tokens = deserialize_lz(&lz_serial);
-                let decompressed = lz77_decompress(&tokens);
-
-                fs::write(&output, decompressed).unwrap();
-                println!("Decompressed successfully!");
-                pause();
+            println!("{}", row);
+        }
+    }
+}
+
+// Every codec and level `compress` offers, each named by the flags that
+// pick it, with what bench needs to run it.
+fn bench_settings() -> Vec<(String, OutputFormat, CompressionLevel, CompressOptions)> {
+    let levels = [(CompressionLevel::Fast, "--fast"), (CompressionLevel::Default, ""), (CompressionLevel::Best, "--best")];
+    let mut settings = Vec::new();
+    for (algo, name) in [(Algorithm::Lz77, ""), (Algorithm::Lzw, "--algo lzw"), (Algorithm::Rle, "--algo rle"), (Algorithm::Bwt, "--algo bwt")] {
+        for (level, flag) in levels {
+            let mut opts = CompressOptions { algo, ..CompressOptions::default() };
+            opts.set_level(level);
+            settings.push((format!("{} {}", name, flag), OutputFormat::Rsz, level, opts));
+        }
+    }
+    let lz77 = [
+        ("--long", CompressOptions { long_range: true, ..CompressOptions::default() }),
+        ("--entropy range", CompressOptions { entropy: Entropy::Range, ..CompressOptions::default() }),
+        ("--model order1", CompressOptions { entropy: Entropy::Range, model: ContextModel::Order1, ..CompressOptions::default() }),
+        ("--entropy adaptive", CompressOptions { entropy: Entropy::Adaptive, ..CompressOptions::default() }),
+    ];
+    for (flags, opts) in lz77 {
+        settings.push((flags.to_string(), OutputFormat::Rsz, CompressionLevel::Default, opts));
+    }
+    for (id, name) in codecs::registered() {
+        let opts = CompressOptions { algo: Algorithm::Registered(id), ..CompressOptions::default() };
+        settings.push((format!("--codec {}", name), OutputFormat::Rsz, CompressionLevel::Default, opts));
+    }
+    for (level, flag) in levels {
+        settings.push((format!("--format deflate {}", flag), OutputFormat::Deflate, level, CompressOptions::default()));
+    }
+    for setting in &mut settings {
+        setting.0 = setting.0.trim().to_string();
+        if setting.0.is_empty() {
+            setting.0 = "(defaults)".to_string();
+        }
+    }
+    settings
+}
+
+// (packed length, compress time, decompress time) for one bench setting,
+// failing unless the data comes back whole
+fn bench_run(data: &[u8], format: OutputFormat, level: CompressionLevel, opts: &CompressOptions)
+    -> Result<(usize, Duration, Duration), RsZipError> {
+    let start = Instant::now();
+    let packed = if format == OutputFormat::Deflate { deflate::compress(data, level) } else { compress(data, opts)? };
+    let packing = start.elapsed();
+    let start = Instant::now();
+    let unpacked = if format == OutputFormat::Deflate { deflate::decompress(&packed)? } else { decompress(&packed, None)? };
+    let unpacking = start.elapsed();
+    if unpacked != data {
+        return Err(RsZipError::CorruptData("bench round trip"));
+    }
+    Ok((packed.len(), packing, unpacking))
+}
+
+// `rszip bench <file>`: the table bench-entropy prints, over every setting
+// rather than just the entropy coders, ending with the smallest and the
+// fastest so one can be picked for data like this.
+fn cmd_bench(args: &[String]) {
+    let [path] = args else { usage_error("bench needs <file>") };
+    let data = or_exit(read_input(path), path);
+    let mib_per_s = |len: usize, elapsed: Duration| (len as f64 / (1 << 20) as f64) / elapsed.as_secs_f64().max(1e-9);
+    println!("# Tests are approximate, using memory only (no storage I/O).");
+    println!("{:<30} {:>10} {:>7} {:>21}", "setting", "bytes", "ratio", "MiB/s comp / decomp");
+    // (packed length, setting) and (MiB/s, setting)
+    let mut smallest: Option<(usize, String)> = None;
+    let mut fastest: Option<(f64, String)> = None;
+    for (name, format, level, opts) in bench_settings() {
+        match bench_run(&data, format, level, &opts) {
+            Ok((len, packing, unpacking)) => {
+                let ratio = if data.is_empty() { 100.0 } else { len as f64 * 100.0 / data.len() as f64 };
+                let speed = mib_per_s(data.len(), packing);
+                println!("{:<30} {:>10} {:>6.1}% {:>9.1} / {:>9.1}", name, len, ratio, speed, mib_per_s(data.len(), unpacking));
+                if smallest.as_ref().is_none_or(|(best, _)| len < *best) {
+                    smallest = Some((len, name.clone()));
+                }
+                if fastest.as_ref().is_none_or(|(best, _)| speed > *best) {
+                    fastest = Some((speed, name));
+                }
             }
-            "3" => {
-                let (input, output) = ask_paths();
-                let key = ask_key();
-                let data = fs::read(&input).expect("Failed to read input");
-                let enc = feistel_encrypt(&data, key.as_bytes());
-                fs::write(&output, enc).unwrap();
-                println!("File encrypted!");
-                pause();
+            Err(e) => println!("{:<30} FAILED {}", name, e),
+        }
+    }
+    if let (Some((_, smallest)), Some((_, fastest))) = (smallest, fastest) {
+        println!("smallest: {}; fastest to compress: {}", smallest, fastest);
+    }
+}
+
+// what `compress --codec` accepts; the ids are what files record
+fn cmd_codecs(args: &[String]) {
+    if !args.is_empty() {
+        usage_error("codecs takes no arguments");
+    }
+    for (id, name) in codecs::registered() {
+        println!("{:>3}  {}", id, name);
+    }
+}
+
+fn cmd_selftest(args: &[String]) {
+    if !args.is_empty() {
+        usage_error("selftest takes no arguments");
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let (passed, failures) = run_selftest();
+    std::panic::set_hook(default_hook);
+    for failure in &failures {
+        println!("FAIL {}", failure);
+    }
+    println!("{} passed, {} failed", passed, failures.len());
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn saved_key_or_exit(name: &str) -> SecretKey {
+    lookup_key(name).unwrap_or_else(|| {
+        eprintln!("No saved key named '{}', or wrong keystore password.", name);
+        process::exit(1);
+    })
+}
+
+fn cmd_key(args: &[String]) {
+    let Some((mut store, master)) = unlock_keystore() else {
+        eprintln!("Wrong keystore password, or {} is damaged.", keystore_path().display());
+        process::exit(1);
+    };
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["add", name] => {
+            if name.len() > 255 {
+                usage_error("key names are limited to 255 bytes");
             }
-            "4" => {
-                let (input, output) = ask_paths();
-                let key = ask_key();
-                let data = fs::read(&input).expect("Failed to read input");
-                let dec = feistel_decrypt(&data, key.as_bytes());
-                fs::write(&output, dec).unwrap();
-                println!("File decrypted!");
-                pause();
+            let key = prompt_new_secret(&format!("Key for '{}': ", name));
+            if key.as_bytes().len() > u16::MAX as usize {
+                eprintln!("Saved keys are limited to 65535 bytes.");
+                process::exit(1);
             }
-            "5" => {
-                println!("Exiting Rs-Zip.");
-                break;
+            store.keys.retain(|(n, _)| n != name);
+            store.keys.push((name.to_string(), key));
+        }
+        ["rm", name] => {
+            let before = store.keys.len();
+            store.keys.retain(|(n, _)| n != name);
+            if store.keys.len() == before {
+                eprintln!("No saved key named '{}'.", name);
+                process::exit(1);
             }
-            _ => {
-                println!("Invalid choice!");
-                pause();
+        }
+        ["list"] => {
+            for (name, _) in &store.keys {
+                println!("{}", name);
             }
+            return;
         }
+        _ => usage_error("key needs add <name>, rm <name> or list"),
     }
+    or_exit(store.save(&keystore_path(), &master), "keystore");
 }
 
 fn ask_paths() -> (String, String) {
-    print!("Input file path: ");
-    io::stdout().flush().unwrap();
+    eprint!("Input file path: ");
+    io::stderr().flush().unwrap();
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
     let input = input.trim().to_string();
 
-    print!("Output file path: ");
-    io::stdout().flush().unwrap();
+    eprint!("Output file path: ");
+    io::stderr().flush().unwrap();
     let mut output = String::new();
     io::stdin().read_line(&mut output).unwrap();
     let output = output.trim().to_string();
@@ -396,17 +4352,229 @@ fn ask_paths() -> (String, String) {
     (input, output)
 }
 
-fn ask_key() -> String {
-    print!("Enter key (any string): ");
-    io::stdout().flush().unwrap();
-    let mut key = String::new();
-    io::stdin().read_line(&mut key).unwrap();
-    key.trim().to_string()
+fn ask_key() -> SecretKey {
+    env_key().unwrap_or_else(|| saved_or_typed(prompt_secret("Enter key (any string, or @name for a saved key): ")))
+}
+
+// ask_key for a key about to encrypt something: a typed key is asked for
+// twice, since a typo would lock the data away
+fn ask_new_key() -> SecretKey {
+    env_key().unwrap_or_else(|| {
+        saved_or_typed(prompt_new_secret("Enter key (any string, or @name for a saved key): "))
+    })
+}
+
+fn saved_or_typed(key: SecretKey) -> SecretKey {
+    match key.as_bytes().strip_prefix(b"@") {
+        Some(name) => saved_key_or_exit(&String::from_utf8_lossy(name)),
+        None => key,
+    }
+}
+
+// prompt_secret, then again to confirm until both match; piped input, where
+// there is nothing to mistype, is read once
+fn prompt_new_secret(prompt: &str) -> SecretKey {
+    loop {
+        let key = prompt_secret(prompt);
+        if !io::stdin().is_terminal() || key.as_bytes().starts_with(b"@") {
+            return key;
+        }
+        if ct_eq(prompt_secret("Confirm: ").as_bytes(), key.as_bytes()) {
+            return key;
+        }
+        eprintln!("The two entries differ, try again.");
+    }
+}
+
+// One line of input for a passphrase: the terminal doesn't echo what is
+// typed (stty turns echo off on Unix; elsewhere, and for piped input, the
+// line is read as is), and it goes straight into a SecretKey. Prompts, here
+// and below, go to stderr, so they never end up inside a "-" output.
+fn prompt_secret(prompt: &str) -> SecretKey {
+    eprint!("{}", prompt);
+    io::stderr().flush().unwrap();
+    let stty = |arg: &str| Command::new("stty").arg(arg).status().is_ok_and(|s| s.success());
+    let hidden = cfg!(unix) && io::stdin().is_terminal() && stty("-echo");
+    // room enough that reading never reallocates and leaves a copy behind
+    let mut line = String::with_capacity(1024);
+    let read = io::stdin().read_line(&mut line);
+    if hidden {
+        stty("echo");
+        eprintln!();
+    }
+    read.unwrap();
+    let key = SecretKey::new(line.trim().as_bytes().to_vec());
+    zeroize(&mut line.into_bytes());
+    key
+}
+
+fn ask_dictionary() -> Result<Option<Vec<u8>>, RsZipError> {
+    eprint!("Preset dictionary file (blank for none): ");
+    io::stderr().flush().unwrap();
+    let mut path = String::new();
+    io::stdin().read_line(&mut path).unwrap();
+    let path = path.trim();
+    if path.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read(path)?))
+}
+
+fn ask_yes_no(question: &str) -> bool {
+    eprint!("{} [y/N]: ", question);
+    io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
 }
 
 fn pause() {
-    print!("\nPress ENTER to continue...");
-    io::stdout().flush().unwrap();
+    eprint!("\nPress ENTER to continue...");
+    io::stderr().flush().unwrap();
     let mut _buf = String::new();
     io::stdin().read_line(&mut _buf).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty scratch directory under the system temp dir
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rszip-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn glob_patterns_match_as_the_shell_would() {
+        let cases = [("*.log", "a.log", true), ("*.log", "a.log.rsz", false), ("a?c", "abc", true), ("a?c", "ac", false),
+            ("*", "", true), ("[a-c]x", "bx", true), ("[!a-c]x", "bx", false), ("[]]", "]", true), ("a[", "a[", true),
+            ("*b*b", "abbab", true), ("*b*b", "abba", false)];
+        for (pattern, name, want) in cases {
+            assert_eq!(glob_match(pattern, name), want, "{:?} against {:?}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn glob_expansion_skips_hidden_names_directories_and_outputs() {
+        let root = scratch("glob");
+        fs::create_dir_all(root.join("sub.log")).unwrap();
+        for name in ["a.log", "b.log", ".hidden.log", "c.txt", "d.log.rsz"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let names = |pattern: &str, suffix: &str| -> Vec<String> {
+            let found = expand_glob(&root.join(pattern).to_string_lossy(), suffix);
+            found.iter().map(|p| Path::new(p).file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+        assert_eq!(names("*.log", ".rsz"), ["a.log", "b.log"]);
+        assert_eq!(names(".*.log", ".rsz"), [".hidden.log"]);
+        assert_eq!(names("*", ".rsz"), ["a.log", "b.log", "c.txt"]);
+        assert!(names("*.zip", ".rsz").is_empty());
+        // without wildcards the name comes back as it is, for the open to report
+        assert_eq!(expand_glob("no-such-file", ".rsz"), ["no-such-file"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn outputs_are_named_from_the_input_ending_and_its_header() {
+        assert_eq!(compressed_name("notes.txt", OutputFormat::Rsz, false), "notes.txt.rsz");
+        assert_eq!(compressed_name("logs/", OutputFormat::Rsz, false), "logs.rsz");
+        assert_eq!(compressed_name("a.txt", OutputFormat::Gzip, false), "a.txt.gz");
+        assert_eq!(compressed_name("big.log", OutputFormat::Rsz, true), "big.log.rszs");
+        assert_eq!(decompressed_name("notes.txt.rsz"), Some(("notes.txt".into(), ".rsz")));
+        assert_eq!(decompressed_name("big.log.rszs"), Some(("big.log".into(), ".rszs")));
+        assert_eq!(decompressed_name("notes.txt"), None);
+        assert_eq!(decompressed_name("dir/.gz"), None);
+        let rsz = compress(b"named", &CompressOptions::default()).unwrap();
+        let gz = gzip::compress(b"named", CompressionLevel::Default, None, 0);
+        assert!(matches_ending(&rsz, ".rsz", false) && !matches_ending(&rsz, ".gz", false));
+        assert!(matches_ending(&gz, ".gz", false) && !matches_ending(&gz, ".rsz", false));
+        assert!(!matches_ending(&rsz, ".deflate", false));
+        assert!(matches_ending(&ADAPTIVE_MAGIC, ".rszs", false) && matches_ending(b"\x01raw", ".rsz", true));
+    }
+
+    #[test]
+    fn key_files_lose_one_trailing_line_ending() {
+        let path = scratch("key").join("key");
+        for (contents, key) in [(&b"key one\r\n"[..], &b"key one"[..]), (b"key two\n\n", b"key two\n"), (b" key three", b" key three")] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(key_file_or_exit(&path.to_string_lossy()).as_bytes(), key);
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keystores_refuse_to_save_keys_their_lengths_cant_hold() {
+        let path = scratch("keystore").join("keystore");
+        let master = SecretKey::from("master".to_string());
+        let mut store = Keystore { keys: vec![("short".into(), SecretKey::from("key".to_string()))] };
+        store.save(&path, &master).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        store.keys.push(("long".into(), SecretKey::new(vec![b'k'; 65536])));
+        assert_eq!(store.save(&path, &master).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // the file saved before is left as it was
+        let loaded = Keystore::load(&path, &master).unwrap();
+        assert_eq!(loaded.keys.len(), 1);
+        assert_eq!(loaded.get("short").unwrap().as_bytes(), b"key");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn mapped_input_reads_like_a_plain_read() {
+        let path = scratch("mapped").join("input");
+        for data in [&selftest_corpora()[2].1[..], &[][..]] {
+            fs::write(&path, data).unwrap();
+            assert_eq!(*open_input(&path.to_string_lossy()).unwrap(), *data);
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for (name, data) in selftest_corpora() {
+            assert_eq!(base64_decode(&base64_encode(&data)), Some(data), "{}", name);
+        }
+        assert_eq!(base64_encode(b"any carnal pleas"), "YW55IGNhcm5hbCBwbGVhcw==");
+        assert_eq!(base64_decode("not base64!"), None);
+    }
+
+    #[test]
+    fn bench_runs_every_setting_and_gets_the_data_back() {
+        let data = b"bench every codec and level ".repeat(200);
+        let settings = bench_settings();
+        let names: Vec<&str> = settings.iter().map(|(name, ..)| name.as_str()).collect();
+        for name in ["(defaults)", "--algo bwt --best", "--codec deflate", "--format deflate --fast"] {
+            assert!(names.contains(&name), "{}", name);
+        }
+        for (name, format, level, opts) in &settings {
+            assert!(bench_run(&data, *format, *level, opts).unwrap().0 > 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn dictionary_samples_come_from_every_file_under_a_directory() {
+        let root = scratch("samples");
+        fs::create_dir_all(root.join("nested")).unwrap();
+        let payload = |n: u32| format!("{{\"id\":{},\"status\":\"ok\",\"items\":[\"alpha\",\"beta\"]}}", n);
+        fs::write(root.join("a.json"), payload(1)).unwrap();
+        fs::write(root.join("nested/b.json"), payload(2)).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&root, root.join("nested/loop")).unwrap();
+        let mut samples = Vec::new();
+        add_samples(&root.to_string_lossy(), &mut samples);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(samples, [payload(1).into_bytes(), payload(2).into_bytes()]);
+        let dict = train_dictionary(&samples, LZ77_WINDOW);
+        let opts = CompressOptions { dictionary: Some(dict.clone()), ..CompressOptions::default() };
+        let warm = compress(payload(3).as_bytes(), &opts).unwrap();
+        assert!(warm.len() < compress(payload(3).as_bytes(), &CompressOptions::default()).unwrap().len());
+        assert_eq!(decompress(&warm, Some(&dict)).unwrap(), payload(3).as_bytes());
+    }
+}
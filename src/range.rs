@@ -0,0 +1,153 @@
+// Range coding with an adaptive model, in place of Huffman coding.
+// Huffman spends a whole number of bits on every byte, so a byte that is
+// 95% likely still costs one bit where its information is 0.07; the range
+// coder spends fractions of a bit, and its model adapts as it goes, so no
+// table is stored either.
+//
+// Each byte is coded as 8 binary decisions down a tree of 255 adaptive
+// probabilities, which together model the byte distribution: one tree for
+// order-0, or one per previous byte for order-1, which learns that 'u'
+// follows 'q' and suits text. The coder is LZMA's: a 32-bit range, carries
+// propagated through the pending 0xff bytes.
+use crate::{ContextModel, Result, RsZipError};
+
+const PROB_BITS: u32 = 11;
+const PROB_ONE: u16 = 1 << PROB_BITS;
+// each decision moves its probability 1/32 of the way towards what happened
+const ADAPT_SHIFT: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+struct Encoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    // bytes held back for a carry: `cache` and the 0xff bytes after it
+    pending: u64,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *prob += (PROB_ONE - *prob) >> ADAPT_SHIFT;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low >= 1 << 32 {
+            let carry = (self.low >> 32) as u8;
+            self.out.push(self.cache.wrapping_add(carry));
+            for _ in 1..self.pending {
+                self.out.push(0xffu8.wrapping_add(carry));
+            }
+            self.pending = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    at: usize,
+    range: u32,
+    code: u32,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let b = *self.data.get(self.at).ok_or(RsZipError::Truncated("range coded data"))?;
+        self.at += 1;
+        Ok(b)
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> Result<bool> {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *prob += (PROB_ONE - *prob) >> ADAPT_SHIFT;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.byte()? as u32;
+        }
+        Ok(bit)
+    }
+}
+
+// one tree of 256 (node 0 unused) per context
+fn model_probs(model: ContextModel) -> Vec<u16> {
+    let contexts = match model {
+        ContextModel::Order0 => 1,
+        ContextModel::Order1 => 256,
+    };
+    vec![PROB_ONE / 2; contexts * 256]
+}
+
+// the tree for the byte after `prev`
+fn context(model: ContextModel, prev: u8) -> usize {
+    match model {
+        ContextModel::Order0 => 0,
+        ContextModel::Order1 => prev as usize * 256,
+    }
+}
+
+pub fn compress(data: &[u8], model: ContextModel) -> Vec<u8> {
+    let mut e = Encoder { low: 0, range: u32::MAX, cache: 0, pending: 1, out: Vec::with_capacity(data.len() / 2) };
+    let mut probs = model_probs(model);
+    let mut prev = 0;
+    for &byte in data {
+        let tree = &mut probs[context(model, prev)..][..256];
+        let mut node = 1;
+        for i in (0..8).rev() {
+            let bit = byte >> i & 1 != 0;
+            e.bit(&mut tree[node], bit);
+            node = node << 1 | bit as usize;
+        }
+        prev = byte;
+    }
+    // flush all of `low`, so the decoder never reads past the end
+    for _ in 0..5 {
+        e.shift_low();
+    }
+    e.out
+}
+
+// `len` is the decoded length from the header; `model` must be the one
+// `compress` used
+pub fn decompress(body: &[u8], len: usize, model: ContextModel) -> Result<Vec<u8>> {
+    let mut d = Decoder { data: body, at: 0, range: u32::MAX, code: 0 };
+    // the first byte is the encoder's initial cache, always 0
+    for _ in 0..5 {
+        d.code = d.code << 8 | d.byte()? as u32;
+    }
+    let mut probs = model_probs(model);
+    let mut out = Vec::new();
+    let mut prev = 0;
+    while out.len() < len {
+        let tree = &mut probs[context(model, prev)..][..256];
+        let mut node = 1;
+        while node < 256 {
+            node = node << 1 | d.bit(&mut tree[node])? as usize;
+        }
+        prev = node as u8;
+        out.push(prev);
+    }
+    Ok(out)
+}
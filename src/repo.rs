@@ -0,0 +1,438 @@
+// A block repository, behind `rszip repo`: compressed chunks addressed by
+// the SHA-256 of their plain bytes, and snapshots that list each stored
+// path as chunk references. Chunk boundaries follow the content
+// (`content_chunks`), so data shared between snapshots or shifted inside a
+// file is stored once.
+//   <repo>/config             "rszip-repo 1"
+//   <repo>/chunks/ab/cdef...  chunk in the normal compressed format
+//   <repo>/snapshots/<name>   lines of kind \t mode \t size \t path \t refs
+// refs are comma-separated chunk hashes for files and the target for symlinks.
+// Snapshots and chunks are checked as they are read, like any other input:
+// refs must be hashes, chunks must hash to their name, and restored paths
+// stay under the destination.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{content_chunks, entry_child_name};
+use crate::crypto::{hex_lower, sha256};
+use crate::tar::{file_mode, safe_target, set_file_mode};
+use crate::{compress, decompress, CompressOptions, Result, RsZipError};
+
+const REPO_MAGIC: &str = "rszip-repo 1";
+
+pub struct SnapshotEntry {
+    pub kind: String,
+    pub mode: u32,
+    pub size: u64,
+    pub path: String,
+    pub refs: String,
+}
+
+impl SnapshotEntry {
+    pub fn chunk_refs(&self) -> impl Iterator<Item = &str> {
+        let refs = if self.kind == "file" { self.refs.as_str() } else { "" };
+        refs.split(',').filter(|r| !r.is_empty())
+    }
+}
+
+#[derive(Default)]
+pub struct AddStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub new_chunks: usize,
+    pub stored_bytes: u64,
+}
+
+pub struct Repo {
+    root: PathBuf,
+}
+
+impl Repo {
+    // `root` is used as given, so the binary passes it through long_path
+    pub fn init(root: &Path) -> Result<Repo> {
+        if root.join("config").exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "repository already initialised").into());
+        }
+        fs::create_dir_all(root.join("chunks"))?;
+        fs::create_dir_all(root.join("snapshots"))?;
+        fs::write(root.join("config"), format!("{}\n", REPO_MAGIC))?;
+        Ok(Repo { root: root.to_path_buf() })
+    }
+
+    pub fn open(root: &Path) -> Result<Repo> {
+        let config = fs::read_to_string(root.join("config"))?;
+        if config.trim() != REPO_MAGIC {
+            return Err(RsZipError::CorruptHeader("not an rszip repository".into()));
+        }
+        Ok(Repo { root: root.to_path_buf() })
+    }
+
+    // `hash` as `is_chunk_hash` checks it
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join("chunks").join(&hash[..2]).join(&hash[2..])
+    }
+
+    pub fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.root.join("snapshots").join(name)
+    }
+
+    // stores `data` unless an identical chunk is already present;
+    // the second value is the number of bytes newly written
+    pub fn put_chunk(&self, data: &[u8], opts: &CompressOptions) -> Result<(String, u64)> {
+        let hash = hex_lower(&sha256(data));
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, 0));
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        let compressed = compress(data, opts)?;
+        // write then rename, so an interrupted add never leaves a torn chunk
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &compressed)?;
+        fs::rename(&tmp, &path)?;
+        Ok((hash, compressed.len() as u64))
+    }
+
+    // the plain bytes of a chunk, which must hash to its name
+    pub fn get_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        let plain = decompress(&fs::read(self.chunk_path(hash))?, None)?;
+        if hex_lower(&sha256(&plain)) != hash {
+            return Err(RsZipError::CorruptData("repository chunk does not match its hash"));
+        }
+        Ok(plain)
+    }
+
+    // adds `path` and everything below it under the snapshot name `name`
+    // `opts` only carries the progress callback; chunks always use the
+    // default codec settings so identical data compresses identically
+    pub fn add_path(&self, path: &Path, name: &str, opts: &CompressOptions, entries: &mut Vec<SnapshotEntry>, stats: &mut AddStats) -> Result<()> {
+        if name.contains(['\t', '\n']) {
+            return Err(RsZipError::InvalidOption(format!("tab or newline in path: {:?}", name)));
+        }
+        let meta = fs::symlink_metadata(path)?;
+        let mode = file_mode(&meta);
+        stats.entries += 1;
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(path)?.to_string_lossy().into_owned();
+            entries.push(SnapshotEntry { kind: "symlink".into(), mode, size: 0, path: name.into(), refs: target });
+        } else if meta.is_dir() {
+            if !name.is_empty() {
+                entries.push(SnapshotEntry { kind: "dir".into(), mode, size: 0, path: name.into(), refs: String::new() });
+            }
+            let mut children: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+            children.sort_by_key(|e| e.file_name());
+            for child in children {
+                let child_name = entry_child_name(name, &child.file_name().to_string_lossy());
+                self.add_path(&child.path(), &child_name, opts, entries, stats)?;
+            }
+        } else {
+            let data = fs::read(path)?;
+            let opts = CompressOptions { on_progress: opts.on_progress.clone(), entry: Some(name.to_string()), ..Default::default() };
+            let mut refs = Vec::new();
+            for chunk in content_chunks(&data) {
+                let (hash, written) = self.put_chunk(chunk, &opts)?;
+                if written > 0 {
+                    stats.new_chunks += 1;
+                    stats.stored_bytes += written;
+                }
+                refs.push(hash);
+            }
+            stats.bytes += data.len() as u64;
+            entries.push(SnapshotEntry { kind: "file".into(), mode, size: data.len() as u64, path: name.into(), refs: refs.join(",") });
+        }
+        Ok(())
+    }
+
+    pub fn write_snapshot(&self, name: &str, entries: &[SnapshotEntry]) -> Result<()> {
+        let mut text = String::new();
+        for e in entries {
+            text.push_str(&format!("{}\t{:o}\t{}\t{}\t{}\n", e.kind, e.mode, e.size, e.path, e.refs));
+        }
+        let path = self.snapshot_path(name);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn read_snapshot(&self, name: &str) -> Result<Vec<SnapshotEntry>> {
+        let text = fs::read_to_string(self.snapshot_path(name))?;
+        text.lines()
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                let bad = || RsZipError::CorruptHeader(format!("bad snapshot line in {}: {}", name, line));
+                let [kind, mode, size, path, refs] = fields[..] else { return Err(bad()) };
+                let hashes_ok = kind != "file" || refs.split(',').filter(|r| !r.is_empty()).all(is_chunk_hash);
+                if !matches!(kind, "file" | "dir" | "symlink") || !hashes_ok {
+                    return Err(bad());
+                }
+                Ok(SnapshotEntry {
+                    kind: kind.into(),
+                    mode: u32::from_str_radix(mode, 8).map_err(|_| bad())?,
+                    size: size.parse().map_err(|_| bad())?,
+                    path: path.into(),
+                    refs: refs.into(),
+                })
+            })
+            .collect()
+    }
+
+    // oldest first
+    pub fn snapshot_names(&self) -> Result<Vec<String>> {
+        let mut named = Vec::new();
+        for entry in fs::read_dir(self.root.join("snapshots"))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".tmp") {
+                named.push((entry.metadata()?.modified()?, name));
+            }
+        }
+        named.sort();
+        Ok(named.into_iter().map(|(_, name)| name).collect())
+    }
+
+    // every file under chunks/, as (hash, path); torn `.tmp` writes included
+    fn chunk_files(&self) -> Result<Vec<(String, PathBuf)>> {
+        let mut files = Vec::new();
+        for dir in fs::read_dir(self.root.join("chunks"))? {
+            let dir = dir?;
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let hash = format!("{}{}", dir.file_name().to_string_lossy(), file.file_name().to_string_lossy());
+                files.push((hash, file.path()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn referenced_chunks(&self) -> Result<HashSet<String>> {
+        let mut refs = HashSet::new();
+        for name in self.snapshot_names()? {
+            for entry in self.read_snapshot(&name)? {
+                refs.extend(entry.chunk_refs().map(str::to_string));
+            }
+        }
+        Ok(refs)
+    }
+
+    // removes chunks no snapshot points at; returns (files, bytes) freed
+    pub fn gc(&self) -> Result<(usize, u64)> {
+        // a snapshot that fails to parse aborts before anything is deleted
+        let live = self.referenced_chunks()?;
+        let (mut files, mut bytes) = (0, 0);
+        for (hash, path) in self.chunk_files()? {
+            if !live.contains(&hash) {
+                bytes += fs::metadata(&path)?.len();
+                fs::remove_file(&path)?;
+                files += 1;
+            }
+        }
+        Ok((files, bytes))
+    }
+
+    // one message per problem: damaged or mislabelled chunks, and chunks a
+    // snapshot references that are missing
+    pub fn check(&self) -> Result<(usize, Vec<String>)> {
+        let mut problems = Vec::new();
+        let chunks = self.chunk_files()?;
+        for (hash, path) in &chunks {
+            let verdict = match fs::read(path) {
+                Ok(data) => match decompress(&data, None) {
+                    Ok(plain) if hex_lower(&sha256(&plain)) == *hash => continue,
+                    Ok(_) => "contents do not match its hash".to_string(),
+                    Err(e) => format!("does not decompress ({})", e),
+                },
+                Err(e) => e.to_string(),
+            };
+            problems.push(format!("chunk {}: {}", hash, verdict));
+        }
+        let present: HashSet<&String> = chunks.iter().map(|(h, _)| h).collect();
+        for name in self.snapshot_names()? {
+            for entry in self.read_snapshot(&name)? {
+                for hash in entry.chunk_refs() {
+                    if !present.contains(&hash.to_string()) {
+                        problems.push(format!("snapshot {}: {} needs missing chunk {}", name, entry.path, hash));
+                    }
+                }
+            }
+        }
+        Ok((chunks.len(), problems))
+    }
+
+    pub fn restore(&self, snapshot: &str, dest: &Path) -> Result<usize> {
+        let entries = self.read_snapshot(snapshot)?;
+        for e in &entries {
+            // no `..`, and never through a symlink an earlier entry made
+            let target = safe_target(dest, &e.path)?;
+            match e.kind.as_str() {
+                "dir" => fs::create_dir_all(&target)?,
+                // left out where the OS has no symlinks to make
+                "symlink" => {
+                    #[cfg(unix)]
+                    std::os::unix::fs::symlink(&e.refs, &target)?;
+                }
+                _ => {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    let mut data = Vec::new();
+                    for hash in e.chunk_refs() {
+                        data.extend_from_slice(&self.get_chunk(hash)?);
+                    }
+                    if data.len() as u64 != e.size {
+                        return Err(RsZipError::CorruptHeader(format!("chunks of {} don't add up to its size", e.path)));
+                    }
+                    fs::write(&target, &data)?;
+                    set_file_mode(&target, e.mode)?;
+                }
+            }
+        }
+        Ok(entries.len())
+    }
+}
+
+pub struct SnapshotSummary {
+    pub name: String,
+    pub entries: usize,
+    pub bytes: u64,
+    // compressed size of the distinct chunks it references, and of those no
+    // older snapshot had
+    pub referenced: u64,
+    pub added: u64,
+}
+
+impl Repo {
+    pub fn summarize(&self) -> Result<Vec<SnapshotSummary>> {
+        let mut seen = HashSet::new();
+        let mut sizes = HashMap::new();
+        let mut summaries = Vec::new();
+        for name in self.snapshot_names()? {
+            let entries = self.read_snapshot(&name)?;
+            let mut own = HashSet::new();
+            let mut summary = SnapshotSummary { name, entries: entries.len(), bytes: 0, referenced: 0, added: 0 };
+            for entry in &entries {
+                summary.bytes += entry.size;
+                for hash in entry.chunk_refs() {
+                    if !own.insert(hash.to_string()) {
+                        continue;
+                    }
+                    let size = match sizes.get(hash) {
+                        Some(&size) => size,
+                        None => {
+                            let size = fs::metadata(self.chunk_path(hash)).map_or(0, |m| m.len());
+                            sizes.insert(hash.to_string(), size);
+                            size
+                        }
+                    };
+                    summary.referenced += size;
+                    if seen.insert(hash.to_string()) {
+                        summary.added += size;
+                    }
+                }
+            }
+            summaries.push(summary);
+        }
+        Ok(summaries)
+    }
+}
+
+// the lowercase hex SHA-256 chunks are named by
+fn is_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// (marker, path, detail) for every path that differs, sorted by path
+pub fn diff_snapshots(old: &[SnapshotEntry], new: &[SnapshotEntry]) -> Vec<(char, String, String)> {
+    let old_by_path: HashMap<&str, &SnapshotEntry> = old.iter().map(|e| (e.path.as_str(), e)).collect();
+    let new_by_path: HashMap<&str, &SnapshotEntry> = new.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut changes = Vec::new();
+    for e in new {
+        match old_by_path.get(e.path.as_str()) {
+            None => changes.push(('+', e.path.clone(), format!("{} {} bytes", e.kind, e.size))),
+            Some(o) if o.kind != e.kind => changes.push(('M', e.path.clone(), format!("{} -> {}", o.kind, e.kind))),
+            Some(o) if o.refs != e.refs => changes.push(('M', e.path.clone(), format!("{} -> {} bytes", o.size, e.size))),
+            Some(o) if o.mode != e.mode => changes.push(('M', e.path.clone(), format!("mode {:o} -> {:o}", o.mode, e.mode))),
+            Some(_) => {}
+        }
+    }
+    for o in old {
+        if !new_by_path.contains_key(o.path.as_str()) {
+            changes.push(('-', o.path.clone(), format!("{} {} bytes", o.kind, o.size)));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+    use std::{env, process};
+
+    #[test]
+    fn snapshots_share_chunks_and_gc_keeps_only_referenced_ones() {
+        let root = env::temp_dir().join(format!("rszip-test-repo-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (tree, store, back) = (root.join("tree"), root.join("repo"), root.join("back"));
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        let text = testdata::text(300_000);
+        fs::write(tree.join("a.txt"), &text).unwrap();
+        fs::write(tree.join("sub/b.txt"), &text[1000..]).unwrap();
+        let repo = Repo::init(&store).unwrap();
+        assert!(Repo::init(&store).is_err());
+
+        let snapshot = |name: &str| {
+            let (mut entries, mut stats) = (Vec::new(), AddStats::default());
+            repo.add_path(&tree, "tree", &CompressOptions::default(), &mut entries, &mut stats).unwrap();
+            repo.write_snapshot(name, &entries).unwrap();
+            stats
+        };
+        let first = snapshot("one");
+        assert_eq!((first.entries, first.bytes), (4, 599_000));
+        // the same bytes again store nothing new
+        fs::write(tree.join("c.txt"), &text).unwrap();
+        assert_eq!(snapshot("two").new_chunks, 0);
+        let changes = diff_snapshots(&repo.read_snapshot("one").unwrap(), &repo.read_snapshot("two").unwrap());
+        assert_eq!(changes, [('+', "tree/c.txt".to_string(), "file 300000 bytes".to_string())]);
+
+        fs::write(tree.join("a.txt"), testdata::random(5000)).unwrap();
+        snapshot("three");
+        fs::remove_file(repo.snapshot_path("three")).unwrap();
+        assert!(repo.gc().unwrap().0 > 0);
+        assert!(repo.check().unwrap().1.is_empty());
+        assert_eq!(Repo::open(&store).unwrap().restore("two", &back).unwrap(), 5);
+        assert_eq!(fs::read(back.join("tree/c.txt")).unwrap(), text);
+        assert_eq!(fs::read(back.join("tree/sub/b.txt")).unwrap(), &text[1000..]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn hostile_snapshots_and_chunks_are_refused() {
+        let root = env::temp_dir().join(format!("rszip-test-repo-hostile-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (store, back) = (root.join("repo"), root.join("back"));
+        let repo = Repo::init(&store).unwrap();
+        let (hash, _) = repo.put_chunk(b"hello", &CompressOptions::default()).unwrap();
+        let restore = |lines: &str| {
+            fs::write(repo.snapshot_path("bad"), lines).unwrap();
+            repo.restore("bad", &back)
+        };
+        // a ref too short to name a chunk, and a size the chunks don't add up to
+        assert!(restore("file\t644\t1\tx.txt\ta\n").is_err());
+        assert!(restore(&format!("file\t644\t99999999999\tx.txt\t{}\n", hash)).is_err());
+        assert!(restore(&format!("file\t644\t5\t../x.txt\t{}\n", hash)).is_err());
+        #[cfg(unix)]
+        assert!(restore(&format!("symlink\t777\t0\tout\t{}\nfile\t644\t5\tout/x.txt\t{}\n", root.display(), hash)).is_err());
+        assert!(!root.join("x.txt").exists());
+
+        assert_eq!(restore(&format!("file\t644\t5\tok.txt\t{}\n", hash)).unwrap(), 1);
+        let other = compress(b"world", &CompressOptions::default()).unwrap();
+        fs::write(repo.chunk_path(&hash), other).unwrap();
+        assert!(repo.get_chunk(&hash).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
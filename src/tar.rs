@@ -0,0 +1,413 @@
+// ustar archives: a 512-byte header per entry, then its data padded to the
+// block size, and two zero blocks at the end. Names past ustar's 100+155
+// bytes use the GNU long-name extension and sizes past 8 GiB its base-256
+// field, both of which GNU tar and bsdtar read.
+//
+// TarWriter streams into any Write, so a tree can be fed to a compressor as
+// it is walked; unpack reads from any Read and, like archive.rs, refuses
+// paths that would land outside the target directory.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::archive::normalize_path;
+use crate::{Result, RsZipError};
+
+pub const TAR_BLOCK: usize = 512;
+// largest size the 11 octal digits of the size field hold
+const MAX_OCTAL_SIZE: u64 = (1 << 33) - 1;
+// a GNU long name longer than this is an attack, not a path
+const MAX_LONG_NAME: u64 = 64 * 1024;
+
+pub struct TarWriter<W: Write> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out, written: 0 }
+    }
+
+    // adds `path` (file, directory or symlink) and everything below it,
+    // stored under the archive name `name`
+    pub fn append_path(&mut self, path: &Path, name: &str) -> Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let mtime = meta.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let mode = file_mode(&meta);
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(path)?;
+            self.write_header(name, b'2', mode, 0, mtime, &target.to_string_lossy())?;
+        } else if meta.is_dir() {
+            let dir_name = format!("{}/", name.trim_end_matches('/'));
+            if !name.is_empty() {
+                self.write_header(&dir_name, b'5', mode, 0, mtime, "")?;
+            }
+            let mut entries: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                let child = if name.is_empty() {
+                    entry.file_name().to_string_lossy().into_owned()
+                } else {
+                    format!("{}{}", dir_name, entry.file_name().to_string_lossy())
+                };
+                self.append_path(&entry.path(), &child)?;
+            }
+        } else {
+            // copied straight through; the header already holds the size
+            let size = meta.len();
+            self.write_header(name, b'0', mode, size, mtime, "")?;
+            let copied = io::copy(&mut fs::File::open(path)?.take(size), &mut self.out)?;
+            if copied != size {
+                return Err(io::Error::other(format!("{} changed size while being archived", path.display())).into());
+            }
+            self.written += size;
+            self.pad()?;
+        }
+        Ok(())
+    }
+
+    // a regular file from memory
+    pub fn append_file(&mut self, name: &str, mode: u32, mtime: u64, data: &[u8]) -> Result<()> {
+        self.write_header(name, b'0', mode, data.len() as u64, mtime, "")?;
+        self.write(data)?;
+        self.pad()
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_header(&mut self, name: &str, kind: u8, mode: u32, size: u64, mtime: u64, link: &str) -> Result<()> {
+        let (prefix, short) = match split_ustar_name(name) {
+            Some(split) => split,
+            None => {
+                // GNU long-name extension: the real name travels as file data
+                let mut long = name.as_bytes().to_vec();
+                long.push(0);
+                self.write_header("././@LongLink", b'L', 0, long.len() as u64, 0, "")?;
+                self.write(&long)?;
+                self.pad()?;
+                // the header keeps the tail, cut where a character starts
+                ("", &name[name.ceil_char_boundary(name.len().saturating_sub(99))..])
+            }
+        };
+        if link.len() > 100 {
+            return Err(RsZipError::InvalidOption(format!("symlink target too long for tar: {}", link)));
+        }
+        let mut h = [0u8; TAR_BLOCK];
+        h[..short.len()].copy_from_slice(short.as_bytes());
+        write_octal(&mut h[100..108], mode as u64);
+        write_octal(&mut h[108..116], 0);
+        write_octal(&mut h[116..124], 0);
+        if size > MAX_OCTAL_SIZE {
+            // GNU base-256: high bit set, big-endian value
+            h[124] = 0x80;
+            h[128..136].copy_from_slice(&size.to_be_bytes());
+        } else {
+            write_octal(&mut h[124..136], size);
+        }
+        write_octal(&mut h[136..148], mtime);
+        h[156] = kind;
+        h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        // checksum is computed with its own field filled with spaces
+        h[148..156].copy_from_slice(b"        ");
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        write_octal(&mut h[148..155], sum as u64);
+        h[155] = b' ';
+        self.write(&h)
+    }
+
+    fn pad(&mut self) -> Result<()> {
+        let rem = (self.written % TAR_BLOCK as u64) as usize;
+        if rem != 0 {
+            self.write(&[0; TAR_BLOCK][rem..])?;
+        }
+        Ok(())
+    }
+
+    // writes the end-of-archive blocks and hands back the writer
+    pub fn finish(mut self) -> Result<W> {
+        self.write(&[0; 2 * TAR_BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// ustar splits long names into a 155-byte prefix and a 100-byte name at a '/'
+fn split_ustar_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    let bytes = name.as_bytes();
+    (0..bytes.len())
+        .filter(|&i| bytes[i] == b'/' && i <= 155 && bytes.len() - i - 1 <= 100 && i + 1 < bytes.len())
+        .map(|i| (&name[..i], &name[i + 1..]))
+        .next()
+}
+
+// zero-padded octal digits followed by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+#[cfg(unix)]
+pub fn file_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+#[cfg(not(unix))]
+pub fn file_mode(meta: &fs::Metadata) -> u32 {
+    if meta.is_dir() { 0o755 } else if meta.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+#[cfg(not(unix))]
+pub fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, perms)
+}
+
+// Entries as TarWriter writes them, plus what GNU tar and other ustar
+// writers emit for regular files, directories and links. `kind` is the
+// typeflag byte: b'0' file, b'1' hard link, b'2' symlink, b'5' directory.
+pub struct TarEntry<'a> {
+    pub name: String,
+    pub kind: u8,
+    pub mode: u32,
+    pub link: String,
+    pub data: &'a [u8],
+}
+
+// one header block; the name is still ustar's, before any GNU long name
+struct Header {
+    name: String,
+    kind: u8,
+    mode: u32,
+    size: u64,
+    link: String,
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?;
+    let digits = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// None for a zero block, which ends the archive
+fn parse_header(h: &[u8]) -> Result<Option<Header>> {
+    if h.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    let corrupt = |field: &str| RsZipError::CorruptHeader(format!("tar header {}", field));
+    let stored = parse_octal(&h[148..156]).ok_or_else(|| corrupt("checksum"))?;
+    let sum: u64 = h[..148].iter().chain(b"        ").chain(&h[156..]).map(|&b| b as u64).sum();
+    if stored != sum {
+        return Err(corrupt("checksum"));
+    }
+    let size = if h[124] & 0x80 != 0 {
+        u64::from_be_bytes(h[128..136].try_into().unwrap())
+    } else {
+        parse_octal(&h[124..136]).ok_or_else(|| corrupt("size"))?
+    };
+    let prefix = tar_string(&h[345..500]);
+    let short = tar_string(&h[0..100]);
+    Ok(Some(Header {
+        name: if prefix.is_empty() { short } else { format!("{}/{}", prefix, short) },
+        kind: if h[156] == 0 { b'0' } else { h[156] },
+        mode: parse_octal(&h[100..108]).ok_or_else(|| corrupt("mode"))? as u32,
+        size,
+        link: tar_string(&h[157..257]),
+    }))
+}
+
+pub fn is_tar(data: &[u8]) -> bool {
+    data.len() >= TAR_BLOCK && &data[257..262] == b"ustar"
+}
+
+// every entry of an archive held in memory, borrowing the data
+pub fn read_tar(data: &[u8]) -> Result<Vec<TarEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut long_name = None;
+    let mut pos = 0;
+    while pos + TAR_BLOCK <= data.len() {
+        let Some(header) = parse_header(&data[pos..pos + TAR_BLOCK])? else { break };
+        let body_start = pos + TAR_BLOCK;
+        let size = usize::try_from(header.size).map_err(|_| RsZipError::TooLarge)?;
+        let body = body_start.checked_add(size).and_then(|end| data.get(body_start..end))
+            .ok_or(RsZipError::Truncated("tar entry"))?;
+        pos = body_start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        if header.kind == b'L' {
+            long_name = Some(tar_string(body));
+            continue;
+        }
+        entries.push(TarEntry {
+            name: long_name.take().unwrap_or(header.name),
+            kind: header.kind,
+            mode: header.mode,
+            link: header.link,
+            data: body,
+        });
+    }
+    Ok(entries)
+}
+
+// false at a clean end of input
+fn read_block(input: &mut impl Read, block: &mut [u8; TAR_BLOCK]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < TAR_BLOCK {
+        match input.read(&mut block[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(RsZipError::Truncated("tar header")),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+fn skip(input: &mut impl Read, n: u64) -> Result<()> {
+    if io::copy(&mut input.take(n), &mut io::sink())? != n {
+        return Err(RsZipError::Truncated("tar entry"));
+    }
+    Ok(())
+}
+
+// `dest` joined with `name`, refusing to pass through a symlink another
+// entry may have planted
+pub fn safe_target(dest: &Path, name: &str) -> Result<std::path::PathBuf> {
+    let rel = normalize_path(name)?;
+    let mut target = dest.to_path_buf();
+    for part in rel.split('/') {
+        target.push(part);
+        if fs::symlink_metadata(&target).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(RsZipError::InvalidOption(format!("{} is a symlink, refusing to extract through it", target.display())));
+        }
+    }
+    Ok(target)
+}
+
+// Extracts a tar stream under `dest` and returns how many entries it wrote.
+// Entry kinds other than files, directories and links are skipped, as are
+// symlinks where the platform has none.
+pub fn unpack(mut input: impl Read, dest: &Path) -> Result<usize> {
+    let mut block = [0u8; TAR_BLOCK];
+    let mut long_name = None;
+    let mut extracted = 0;
+    while read_block(&mut input, &mut block)? {
+        let Some(header) = parse_header(&block)? else { break };
+        let padding = header.size.next_multiple_of(TAR_BLOCK as u64) - header.size;
+        if header.kind == b'L' {
+            if header.size > MAX_LONG_NAME {
+                return Err(RsZipError::CorruptHeader("tar long name".into()));
+            }
+            let mut name = Vec::new();
+            (&mut input).take(header.size).read_to_end(&mut name)?;
+            long_name = Some(tar_string(&name));
+            skip(&mut input, padding)?;
+            continue;
+        }
+        let name = long_name.take().unwrap_or(header.name);
+        // "./" and the like name the extraction root itself
+        if normalize_path(&name).is_err() && name.split(['/', '\\']).all(|p| p.is_empty() || p == ".") {
+            skip(&mut input, header.size + padding)?;
+            continue;
+        }
+        let target = safe_target(dest, &name)?;
+        let mut body_left = header.size;
+        let wrote = match header.kind {
+            b'5' => {
+                fs::create_dir_all(&target)?;
+                true
+            }
+            b'0' | b'7' => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = fs::File::create(&target)?;
+                if io::copy(&mut (&mut input).take(header.size), &mut file)? != header.size {
+                    return Err(RsZipError::Truncated("tar entry"));
+                }
+                body_left = 0;
+                set_file_mode(&target, header.mode)?;
+                true
+            }
+            b'1' => {
+                fs::hard_link(safe_target(dest, &header.link)?, &target)?;
+                true
+            }
+            #[cfg(unix)]
+            b'2' => {
+                std::os::unix::fs::symlink(&header.link, &target)?;
+                true
+            }
+            _ => false,
+        };
+        extracted += wrote as usize;
+        skip(&mut input, body_left + padding)?;
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let long = format!("{}/{}", "d".repeat(160), "f".repeat(120));
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_file("a.txt", 0o644, 0, b"first").unwrap();
+        tar.append_file(&long, 0o600, 1, &[7; 700]).unwrap();
+        let bytes = tar.finish().unwrap();
+        assert!(is_tar(&bytes));
+        assert_eq!(bytes.len() % 512, 0);
+        let entries = read_tar(&bytes).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].name.as_str(), entries[0].data), ("a.txt", &b"first"[..]));
+        assert_eq!((entries[1].name.as_str(), entries[1].mode, entries[1].data), (long.as_str(), 0o600, &[7; 700][..]));
+        let mut bad_sum = bytes.clone();
+        bad_sum[0] ^= 1;
+        assert!(read_tar(&bad_sum).is_err());
+    }
+
+    #[test]
+    fn long_multibyte_names_round_trip() {
+        let name = format!("dir/{}", "é".repeat(60));
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_file(&name, 0o644, 0, b"accented").unwrap();
+        let bytes = tar.finish().unwrap();
+        assert_eq!(read_tar(&bytes).unwrap()[0].name, name);
+    }
+
+    #[test]
+    fn unpack_stays_inside_the_target() {
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_file("../escape", 0o644, 0, b"x").unwrap();
+        let dest = Path::new("rszip-test-never-created");
+        assert!(matches!(unpack(&tar.finish().unwrap()[..], dest), Err(RsZipError::InvalidOption(_))));
+        assert!(!dest.exists());
+    }
+}
@@ -0,0 +1,70 @@
+// Synthetic inputs for the unit tests: the kinds of data `rszip selftest`
+// builds its corpora from, random, text-like and runs, at any length.
+use crate::filter::ENGLISH_WORDS;
+
+// xorshift64, so every run sees the same bytes
+fn generator(mut state: u64) -> impl FnMut() -> u64 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
+
+pub fn random(len: usize) -> Vec<u8> {
+    let mut next = generator(0x2545_f491_4f6c_dd1d);
+    (0..len).map(|_| next() as u8).collect()
+}
+
+// English words from the text filter's list, with spaces and line breaks
+pub fn text(len: usize) -> Vec<u8> {
+    let mut next = generator(0x9e37_79b9_7f4a_7c15);
+    let mut text = Vec::new();
+    while text.len() < len {
+        text.extend_from_slice(ENGLISH_WORDS[next() as usize % ENGLISH_WORDS.len()].as_bytes());
+        text.push(if next().is_multiple_of(12) { b'\n' } else { b' ' });
+    }
+    text.truncate(len);
+    text
+}
+
+// runs of up to 300 of four byte values
+pub fn runs(len: usize) -> Vec<u8> {
+    let mut next = generator(0xd1b5_4a32_d192_ed03);
+    let mut runs = Vec::new();
+    while runs.len() < len {
+        let (byte, run) = (next() as u8 % 4, 1 + next() as usize % 300);
+        runs.extend(std::iter::repeat_n(byte, run));
+    }
+    runs.truncate(len);
+    runs
+}
+
+// `len` bytes, each `byte` of the next state of an LCG
+pub fn lcg(seed: u32, len: usize, mut byte: impl FnMut(u32) -> u8) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            byte(state)
+        })
+        .collect()
+}
+
+pub fn unhex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+// every kind, and short random inputs either side of a word
+pub fn corpora() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("empty", Vec::new()),
+        ("random", random(4_000)),
+        ("text", text(4_000)),
+        ("runs", runs(4_000)),
+        ("random-1", random(1)),
+        ("random-7", random(7)),
+        ("random-9", random(9)),
+    ]
+}
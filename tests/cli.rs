@@ -0,0 +1,462 @@
+// The rszip command line, run as a user would: real files in a scratch
+// directory, and the exit status and output checked.
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Output, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
+
+use rszip::archive::Archive;
+use rszip::{compress, CompressOptions};
+
+fn rszip(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rszip")).args(args).output().expect("rszip should run")
+}
+
+// an empty scratch directory under the system temp dir
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rszip-cli-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().expect("scratch paths are UTF-8")
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+// `rszip decompress` of `file` to a scratch name beside it, read back
+fn decompressed(file: &Path) -> Vec<u8> {
+    let back = file.with_extension("back");
+    let out = rszip(&["decompress", path(file), path(&back)]);
+    assert!(out.status.success(), "decompress {}: {}", file.display(), String::from_utf8_lossy(&out.stderr));
+    let data = fs::read(&back).unwrap();
+    fs::remove_file(&back).unwrap();
+    data
+}
+
+#[test]
+fn compress_suffix_expands_patterns_and_reports_each_file() {
+    let root = scratch("batch");
+    fs::create_dir_all(root.join("sub.log")).unwrap();
+    for name in ["a.log", "b.log", ".hidden.log", "c.txt"] {
+        fs::write(root.join(name), name.repeat(50)).unwrap();
+    }
+    // quoted, as on a shell that leaves patterns alone
+    let out = rszip(&["compress", path(&root.join("*.log")), "--suffix", ".rsz"]);
+    assert!(out.status.success());
+    let lines: Vec<String> = stdout(&out).lines().map(str::to_string).collect();
+    assert_eq!(lines.len(), 3, "{:?}", lines);
+    assert!(lines[0].contains("a.log -> ") && lines[1].contains("b.log -> "));
+    assert_eq!(lines[2], "2 compressed, 0 failed");
+    for name in ["a.log", "b.log"] {
+        assert_eq!(decompressed(&root.join(format!("{}.rsz", name))), name.repeat(50).as_bytes());
+    }
+    assert!(!root.join(".hidden.log.rsz").exists());
+
+    // a rerun skips the outputs, which end in the suffix, and refuses to replace them
+    let out = rszip(&["compress", path(&root.join("*.log")), "--suffix", ".rsz"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).ends_with("0 compressed, 2 failed\n"));
+
+    let out = rszip(&["compress", path(&root.join("c.txt")), "--suffix", ".rsz", "--dry-run", "--rm"]);
+    assert!(out.status.success());
+    assert!(stdout(&out).contains("250 -> ") && stdout(&out).contains("nothing written"));
+    assert!(!root.join("c.txt.rsz").exists() && root.join("c.txt").exists());
+
+    let out = rszip(&["compress", path(&root.join("*.zip")), path(&root.join("sub.log")), "--suffix", ".rsz"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).contains("*.zip: FAILED no matching files") && stdout(&out).contains("is a directory"));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn compress_refuses_to_clobber_outputs_and_removes_inputs_only_with_rm() {
+    let root = scratch("clobber");
+    let (input, output) = (root.join("in.txt"), root.join("in.txt.rsz"));
+    fs::write(&input, b"compressed, then gone").unwrap();
+    fs::write(&output, b"already here").unwrap();
+    let out = rszip(&["compress", path(&input), path(&output), "--rm"]);
+    assert!(!out.status.success());
+    assert_eq!(fs::read(&output).unwrap(), b"already here");
+    assert!(input.exists());
+
+    let out = rszip(&["compress", path(&input), path(&output), "--rm", "--force"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!input.exists());
+    assert_eq!(decompressed(&output), b"compressed, then gone");
+
+    // --keep, the default, leaves the input
+    fs::write(&input, b"kept").unwrap();
+    assert!(rszip(&["compress", path(&input), "-f", "--keep"]).status.success());
+    assert!(input.exists());
+    assert_eq!(decompressed(&output), b"kept");
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn info_reports_sizes_flags_and_block_layout() {
+    let root = scratch("info");
+    let input = root.join("in.txt");
+    fs::write(&input, b"info, block by block ".repeat(8000)).unwrap();
+    let (blocked, plain) = (root.join("blocked.rsz"), root.join("plain.rsz"));
+    assert!(rszip(&["compress", path(&input), path(&blocked), "--blocks", "64K"]).status.success());
+    assert!(rszip(&["compress", path(&input), path(&plain)]).status.success());
+    let out = rszip(&["info", path(&blocked)]);
+    assert!(out.status.success());
+    let lines = stdout(&out);
+    assert!(lines.lines().any(|l| l.starts_with("size:       168000 -> ")), "{}", lines);
+    assert!(lines.lines().any(|l| l.starts_with("blocks:     3 of 65536 bytes")), "{}", lines);
+    assert!(lines.contains("independent blocks, seek table"), "{}", lines);
+    let lines = stdout(&rszip(&["info", path(&plain)]));
+    assert!(lines.lines().any(|l| l == "codecs:     lz77 + huffman"), "{}", lines);
+    assert!(lines.lines().any(|l| l.starts_with("decode:")), "{}", lines);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn train_dict_takes_sample_directories() {
+    let root = scratch("train");
+    fs::create_dir_all(root.join("samples/nested")).unwrap();
+    let payload = |n: u32| format!("{{\"id\":{},\"status\":\"ok\",\"items\":[\"alpha\",\"beta\"]}}", n);
+    fs::write(root.join("samples/a.json"), payload(1)).unwrap();
+    fs::write(root.join("samples/nested/b.json"), payload(2)).unwrap();
+    let dict = root.join("json.dict");
+    assert!(rszip(&["train-dict", path(&root.join("samples")), path(&dict)]).status.success());
+    let input = root.join("c.json");
+    fs::write(&input, payload(3)).unwrap();
+    let (cold, warm) = (root.join("cold.rsz"), root.join("warm.rsz"));
+    assert!(rszip(&["compress", path(&input), path(&cold)]).status.success());
+    assert!(rszip(&["compress", path(&input), path(&warm), "--dict", path(&dict)]).status.success());
+    assert!(fs::metadata(&warm).unwrap().len() < fs::metadata(&cold).unwrap().len());
+    let back = root.join("c.back");
+    assert!(rszip(&["decompress", path(&warm), path(&back), "--dict", path(&dict)]).status.success());
+    assert_eq!(fs::read(&back).unwrap(), payload(3).as_bytes());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn archive_entries_keep_mtime_and_mode() {
+    let root = scratch("meta");
+    let (src, out, bare, archive) = (root.join("src"), root.join("out"), root.join("bare"), root.join("a.rsz"));
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("sub/run.sh"), b"#!/bin/sh\n").unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    fs::File::open(src.join("sub/run.sh")).unwrap().set_modified(mtime).unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(src.join("sub/run.sh"), fs::Permissions::from_mode(0o751)).unwrap();
+    fs::File::open(src.join("sub")).unwrap().set_modified(mtime).unwrap();
+    assert!(rszip(&["add", path(&archive), path(&src.join("sub"))]).status.success());
+    assert!(rszip(&["extract", path(&archive), "-C", path(&out)]).status.success());
+    assert!(rszip(&["extract", path(&archive), "-C", path(&bare), "--no-perms"]).status.success());
+    let (file, dir) = (fs::metadata(out.join("sub/run.sh")).unwrap(), fs::metadata(out.join("sub")).unwrap());
+    let bare_file = fs::metadata(bare.join("sub/run.sh")).unwrap();
+    assert_eq!(file.modified().unwrap(), mtime);
+    assert_eq!(dir.modified().unwrap(), mtime);
+    assert_eq!(bare_file.modified().unwrap(), mtime);
+    #[cfg(unix)]
+    {
+        assert_eq!(file.permissions().mode() & 0o7777, 0o751);
+        assert_ne!(bare_file.permissions().mode() & 0o7777, 0o751);
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn archive_keeps_symlinks_and_empty_directories() {
+    let root = scratch("links");
+    let (src, out, archive) = (root.join("src"), root.join("out"), root.join("a.rsz"));
+    fs::create_dir_all(src.join("empty")).unwrap();
+    fs::write(src.join("file.txt"), b"linked to").unwrap();
+    std::os::unix::fs::symlink("file.txt", src.join("link")).unwrap();
+    // a loop, which following links would never finish
+    std::os::unix::fs::symlink("..", src.join("up")).unwrap();
+    let paths: Vec<PathBuf> = ["empty", "file.txt", "link", "up"].iter().map(|p| src.join(p)).collect();
+    let mut args = vec!["add", path(&archive)];
+    args.extend(paths.iter().map(|p| path(p)));
+    assert!(rszip(&args).status.success());
+    assert!(rszip(&["extract", path(&archive), "-C", path(&out)]).status.success());
+    let listing = stdout(&rszip(&["list", path(&archive)]));
+    assert!(listing.contains(" link -> file.txt\n") && listing.contains(" up -> ..\n"), "{}", listing);
+    assert_eq!(fs::read_link(out.join("link")).unwrap(), Path::new("file.txt"));
+    assert_eq!(fs::read(out.join("link")).unwrap(), b"linked to");
+    assert_eq!(fs::read_link(out.join("up")).unwrap(), Path::new(".."));
+    assert!(fs::read_dir(out.join("empty")).unwrap().next().is_none());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_names_damaged_entries_and_blocks() {
+    let root = scratch("damaged");
+    let text = b"entries and blocks, damaged and sound ".repeat(2000);
+    let mut archive = Archive::new();
+    archive.add_file("a.txt", &text, &CompressOptions::default()).unwrap();
+    archive.add_file("b.txt", &text[..5000], &CompressOptions::default()).unwrap();
+    let sound = archive.to_bytes().unwrap();
+    // the last byte of b.txt, just before the table
+    let b = &archive.entries()[1];
+    let mut damaged = sound.clone();
+    damaged[8 + b.offset as usize + b.packed_len() - 1] ^= 0xff;
+    let blocks: Vec<u8> = (0..4u8).flat_map(|i| text.iter().map(move |b| b ^ i)).collect();
+    // 312000 bytes, so the last byte is in block 4
+    let mut file = compress(&blocks, &CompressOptions { blocks: Some(64 << 10), ..Default::default() }).unwrap();
+    let last = file.len() - 1;
+    file[last] ^= 0xff;
+    for (name, bytes) in [("sound.rsz", &sound), ("damaged.rsz", &damaged), ("blocks.rsz", &file)] {
+        fs::write(root.join(name), bytes).unwrap();
+    }
+    assert!(rszip(&["test", path(&root.join("sound.rsz"))]).status.success());
+    let out = rszip(&["test", path(&root.join("sound.rsz")), path(&root.join("damaged.rsz")), path(&root.join("blocks.rsz"))]);
+    assert_eq!(out.status.code(), Some(1));
+    let damaged: Vec<String> = stdout(&out).lines().filter(|l| l.contains(": DAMAGED ")).map(str::to_string).collect();
+    assert_eq!(damaged.len(), 2, "{:?}", damaged);
+    assert!(damaged[0].contains("damaged.rsz: DAMAGED b.txt: "), "{}", damaged[0]);
+    assert!(damaged[1].contains("blocks.rsz: DAMAGED block 4"), "{}", damaged[1]);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+// `rszip serve-api` on a port of its own, and the address it reports
+struct Api(Child, String);
+
+impl Api {
+    fn start(args: &[&str]) -> Api {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rszip")).args(["serve-api", "--port", "0"]).args(args)
+            .stdout(Stdio::piped()).spawn().expect("rszip should run");
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let addr = line.trim().strip_prefix("API listening on ").expect("the address bound").to_string();
+        Api(child, addr)
+    }
+
+    // one request, and the status line and de-chunked body of the answer
+    fn post(&self, path: &str, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.1).unwrap();
+        write!(stream, "POST {} HTTP/1.1\r\nHost: test\r\n{}\r\n", path, head).unwrap();
+        // a server that answers early may close before the body is all sent
+        let _ = stream.write_all(body);
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let mut data = Vec::new();
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            let start = data.len();
+            data.resize(start + size + 2, 0);
+            reader.read_exact(&mut data[start..]).unwrap();
+            data.truncate(start + size);
+        }
+        (status.trim().to_string(), data)
+    }
+}
+
+impl Drop for Api {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// `body` in chunks of 1000 bytes
+fn chunked(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in body.chunks(1000) {
+        out.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"0\r\n\r\n");
+    out
+}
+
+#[test]
+fn serve_api_streams_bodies_and_refuses_big_ones() {
+    let api = Api::start(&["--max-body", "200K", "--key", "api key"]);
+    assert!(api.1.starts_with("127.0.0.1:"), "{}", api.1);
+    let text = b"streamed through the codec as it arrives ".repeat(4000);
+    let length = |body: &[u8]| format!("Content-Length: {}\r\n", body.len());
+    let (status, packed) = api.post("/compress?min_match=4", &length(&text), &text);
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(packed.len() < text.len() / 10);
+    let (status, back) = api.post("/decompress", "Transfer-Encoding: chunked\r\n", &chunked(&packed));
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(back, text);
+    let (_, sealed) = api.post("/encrypt", &length(&text), &text);
+    assert!(!sealed.windows(16).any(|w| w == &text[..16]));
+    assert_eq!(api.post("/decrypt", &length(&sealed), &sealed).1, text);
+    // each response is keyed afresh, so the same body never seals the same way
+    assert_ne!(api.post("/encrypt", &length(&text), &text).1, sealed);
+
+    // refused from the length alone, so the body needn't be sent
+    assert_eq!(api.post("/compress", "Content-Length: 300000\r\n", b"").0, "HTTP/1.1 413 Content Too Large");
+    // 150000 bytes, then a chunk that would take it past 200K
+    let under = chunked(&text[..150_000]);
+    let over = [&under[..under.len() - 5], b"ea60\r\n"].concat();
+    assert_eq!(api.post("/compress", "Transfer-Encoding: chunked\r\n", &over).0, "HTTP/1.1 413 Content Too Large");
+    assert_eq!(api.post("/decompress", &length(b"not a stream"), b"not a stream").0, "HTTP/1.1 422 Unprocessable Entity");
+    assert_eq!(api.post("/compress?best", &length(b"x"), b"x").0, "HTTP/1.1 400 Bad Request");
+    // heads are read a bounded line at a time, and only so many lines
+    let too_big = "HTTP/1.1 431 Request Header Fields Too Large";
+    assert_eq!(api.post("/compress", &format!("X-Long: {}\r\n", "a".repeat(9000)), b"").0, too_big);
+    assert_eq!(api.post("/compress", &"X-Many: 1\r\n".repeat(101), b"").0, too_big);
+    assert_eq!(api.post("/compress?min_match=4", &("X-Many: 1\r\n".repeat(98) + &length(b"x")), b"x").0, "HTTP/1.1 200 OK");
+
+    let keyless = Api::start(&[]);
+    assert_eq!(keyless.post("/encrypt", &length(b"x"), b"x").0, "HTTP/1.1 400 Bad Request");
+}
+
+#[test]
+fn stream_commands_read_urls_as_they_download() {
+    let root = scratch("url");
+    let text = b"fetched and compressed on the way in ".repeat(5000);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/data.txt", listener.local_addr().unwrap());
+    let body = text.clone();
+    // one download for compress --stream, one for encrypt --stream
+    let server = thread::spawn(move || {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    let (packed, back) = (root.join("data.rszs"), root.join("data.txt"));
+    let out = rszip(&["compress", "--stream", &url, path(&packed)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(rszip(&["decompress", "--stream", path(&packed), path(&back)]).status.success());
+    assert_eq!(fs::read(&back).unwrap(), text);
+
+    let (sealed, opened) = (root.join("data.enc"), root.join("opened.txt"));
+    let out = rszip(&["encrypt", "--stream", &url, path(&sealed), "--key", "url key"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    server.join().unwrap();
+    assert!(!fs::read(&sealed).unwrap().windows(16).any(|w| w == &text[..16]));
+    assert!(!rszip(&["decrypt", "--stream", path(&sealed), path(&opened), "--key", "wrong key"]).status.success());
+    assert!(rszip(&["decrypt", "--stream", path(&sealed), path(&opened), "--key", "url key"]).status.success());
+    assert_eq!(fs::read(&opened).unwrap(), text);
+
+    // held whole is refused before anything is fetched
+    let out = rszip(&["compress", &url, path(&root.join("whole.rsz"))]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--stream"));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_keeps_its_socket_private_and_jobs_local() {
+    use std::os::unix::net::UnixStream;
+    let root = scratch("daemon");
+    let socket = root.join("rszip.sock");
+
+    // whatever already sits at the path is left alone
+    fs::write(&socket, b"not a socket").unwrap();
+    let out = rszip(&["daemon", "--socket", path(&socket)]);
+    assert!(!out.status.success());
+    assert_eq!(fs::read(&socket).unwrap(), b"not a socket");
+    fs::remove_file(&socket).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rszip")).args(["daemon", "--socket", path(&socket)])
+        .stdout(Stdio::piped()).spawn().expect("rszip should run");
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let input = root.join("in.txt");
+    fs::write(&input, b"a job for the daemon ".repeat(100)).unwrap();
+    let stream = UnixStream::connect(&socket).unwrap();
+    let mut replies = BufReader::new(stream.try_clone().unwrap());
+    let mut reply = |job: String| {
+        writeln!(&stream, "{}", job).unwrap();
+        let mut line = String::new();
+        while !line.starts_with("ok ") && !line.starts_with("error ") {
+            line.clear();
+            replies.read_line(&mut line).unwrap();
+        }
+        line
+    };
+    let packed = root.join("in.txt.rsz");
+    assert!(reply(format!("compress\t{}\t{}", path(&input), path(&packed))).starts_with("ok 2100 "));
+    assert_eq!(decompressed(&packed), b"a job for the daemon ".repeat(100));
+    for output in ["-", "s3://bucket/key", "ssh://-oProxyCommand=false/x"] {
+        let line = reply(format!("compress\t{}\t{}", path(&input), output));
+        assert!(line.starts_with("error daemon jobs take local file paths only"), "{}", line);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn ssh_destinations_refuse_hosts_ssh_would_read_as_options() {
+    let root = scratch("ssh");
+    let input = root.join("in.txt");
+    fs::write(&input, b"never uploaded").unwrap();
+    let out = rszip(&["compress", path(&input), "--output", "ssh://-oProxyCommand=false/x.rsz"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("bad ssh host"), "{}", String::from_utf8_lossy(&out.stderr));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn extract_asks_for_each_key_an_archive_was_encrypted_with() {
+    let root = scratch("keys");
+    let archive = root.join("both.rsz");
+    // as two `add --encrypt` runs would leave it, with a quicker KDF
+    let mut both = Archive::new();
+    for (name, key) in [("one.txt", "first"), ("two.txt", "second")] {
+        both.add_file(name, format!("sealed under the {} key", key).as_bytes(), &CompressOptions::default()).unwrap();
+        both.encrypt(name, key.as_bytes(), 1000).unwrap();
+    }
+    fs::write(&archive, both.to_bytes().unwrap()).unwrap();
+
+    let extract = |answers: &[u8]| {
+        let out = root.join("out");
+        let _ = fs::remove_dir_all(&out);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rszip"))
+            .args(["extract", path(&archive), "-C", path(&out), "--key", "first"])
+            .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().expect("rszip should run");
+        child.stdin.take().unwrap().write_all(answers).unwrap();
+        (child.wait().unwrap().success(), fs::read(out.join("two.txt")).ok())
+    };
+    // a wrong answer is asked again, a right one opens the entry
+    assert_eq!(extract(b"wrong\nsecond\n"), (true, Some(b"sealed under the second key".to_vec())));
+    // nothing more to answer gives up
+    assert_eq!(extract(b"wrong\n"), (false, None));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn decrypt_refuses_files_encrypt_did_not_seal() {
+    let root = scratch("unsealed");
+    let (input, output) = (root.join("noise.bin"), root.join("out"));
+    fs::write(&input, (0..64u8).map(|b| b.wrapping_mul(151).wrapping_add(7)).collect::<Vec<_>>()).unwrap();
+    let out = rszip(&["decrypt", path(&input), path(&output), "--key", "foo"]);
+    assert!(!out.status.success());
+    assert!(!output.exists());
+    fs::remove_dir_all(&root).unwrap();
+}